@@ -57,6 +57,16 @@ rec {
         packageId = "lorri";
       };
 
+      # Debug support which might change between releases.
+      # File a bug if you depend on any for non-debug work!
+      debug = internal.debugCrate { inherit packageId; };
+    };
+    "lorri-client" = rec {
+      packageId = "lorri-client";
+      build = internal.buildRustCrateWithFeatures {
+        packageId = "lorri-client";
+      };
+
       # Debug support which might change between releases.
       # File a bug if you depend on any for non-debug work!
       debug = internal.debugCrate { inherit packageId; };
@@ -1257,6 +1267,11 @@ rec {
             name = "lazy_static";
             packageId = "lazy_static";
           }
+          {
+            name = "lorri-client";
+            packageId = "lorri-client";
+            features = [ "slog" ];
+          }
           {
             name = "md5";
             packageId = "md5";
@@ -1308,6 +1323,10 @@ rec {
             name = "thiserror";
             packageId = "thiserror";
           }
+          {
+            name = "toml";
+            packageId = "toml";
+          }
           {
             name = "vec1";
             packageId = "vec1";
@@ -1323,6 +1342,61 @@ rec {
         ];
 
       };
+      "lorri-client" = rec {
+        crateName = "lorri-client";
+        version = "0.1.0";
+        edition = "2018";
+        src = lib.cleanSourceWith { filter = sourceFilter;  src = ./lorri-client; };
+        authors = [
+          "Graham Christensen <graham.christensen@target.com>"
+          "Profpatsch <mail@profpatsch.de>"
+        ];
+        dependencies = [
+          {
+            name = "bincode";
+            packageId = "bincode";
+          }
+          {
+            name = "directories";
+            packageId = "directories";
+          }
+          {
+            name = "nix";
+            packageId = "nix 0.20.2";
+          }
+          {
+            name = "serde";
+            packageId = "serde";
+          }
+          {
+            name = "serde_derive";
+            packageId = "serde_derive";
+          }
+          {
+            name = "serde_json";
+            packageId = "serde_json";
+          }
+          {
+            name = "slog";
+            packageId = "slog";
+            optional = true;
+          }
+          {
+            name = "thiserror";
+            packageId = "thiserror";
+          }
+        ];
+        devDependencies = [
+          {
+            name = "tempfile";
+            packageId = "tempfile";
+          }
+        ];
+        features = {
+          "slog" = [ "dep:slog" ];
+        };
+        resolvedDefaultFeatures = [ "slog" ];
+      };
       "maybe-uninit" = rec {
         crateName = "maybe-uninit";
         version = "2.0.0";
@@ -72,6 +72,12 @@ cargoLorri.override {
           LICENSE \
           MAINTAINERS.md
         cp -r contrib/ $doc/share/doc/lorri/contrib
+
+        # and install the shell completions
+        mkdir -p $out/share/bash-completion/completions $out/share/zsh/site-functions $out/share/fish/vendor_completions.d
+        $out/bin/lorri completions bash > $out/share/bash-completion/completions/lorri
+        $out/bin/lorri completions zsh > $out/share/zsh/site-functions/_lorri
+        $out/bin/lorri completions fish > $out/share/fish/vendor_completions.d/lorri.fish
       '';
     };
   };
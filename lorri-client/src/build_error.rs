@@ -0,0 +1,294 @@
+//! Why a build failed, and what nix printed.
+
+use std::ffi::OsString;
+use std::fmt;
+use std::os::unix::ffi::OsStrExt;
+use std::process::{Command, ExitStatus};
+
+/// An error that can occur during a build.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum BuildError {
+    /// A system-level IO error occurred during the build.
+    Io {
+        /// Error message of the underlying error. Stored as a string because we need `BuildError`
+        /// to implement `Copy`, but `io::Error` does not implement `Copy`.
+        msg: String,
+    },
+
+    /// An error occurred while spawning a Nix process.
+    ///
+    /// Usually this means that the relevant Nix executable was not on the $PATH.
+    Spawn {
+        /// The command that failed. Stored as a string because we need `BuildError` to implement
+        /// `Copy`, but `Command` does not implement `Copy`.
+        cmd: String,
+
+        /// Error message of the underlying error. Stored as a string because we need `BuildError`
+        /// to implement `Copy`, but `io::Error` does not implement `Copy`.
+        msg: String,
+    },
+
+    /// The Nix process returned with a non-zero exit code.
+    Exit {
+        /// The command that failed. Stored as a string because we need `BuildError` to implement
+        /// `Copy`, but `Command` does not implement `Copy`.
+        cmd: String,
+
+        /// The `ExitStatus` of the command. The smart constructor `BuildError::exit` asserts that
+        /// it is non-successful.
+        status: Option<i32>,
+
+        /// Error logs of the failed process.
+        logs: Vec<LogLine>,
+    },
+
+    /// The Nix process failed because it could not use the remote builders,
+    /// for example because they are unreachable.
+    RemoteBuilder {
+        /// The line of the logs describing the problem.
+        reason: String,
+
+        /// The command that failed, see `Exit`.
+        cmd: String,
+
+        /// The `ExitStatus` of the command.
+        status: Option<i32>,
+
+        /// Error logs of the failed process.
+        logs: Vec<LogLine>,
+    },
+
+    /// There was something wrong with the output of the Nix command.
+    ///
+    /// This error may for example indicate that the wrong number of outputs was produced.
+    Output {
+        /// Error message explaining the nature of the output error.
+        msg: String,
+    },
+
+    /// The build took longer than the project’s `build_timeout_secs`, so it was killed.
+    Timeout {
+        /// The timeout which was exceeded.
+        timeout_secs: u64,
+    },
+
+    /// The Nix expression was evaluated, but building the shell failed.
+    Build {
+        /// Why the build failed.
+        error: Box<BuildError>,
+    },
+
+    /// The installed nix is too old for what the project needs, e.g. flakes.
+    Unsupported {
+        /// Error message explaining what is missing and what to do about it.
+        msg: String,
+    },
+}
+
+impl From<std::io::Error> for BuildError {
+    fn from(e: std::io::Error) -> BuildError {
+        BuildError::io(e)
+    }
+}
+
+impl From<serde_json::Error> for BuildError {
+    fn from(e: serde_json::Error) -> BuildError {
+        BuildError::io(e)
+    }
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::Io { msg } => write!(f, "I/O error: {}", msg),
+            BuildError::Spawn { cmd, msg } => write!(
+                f,
+                "failed to spawn Nix process. Is Nix installed and on the $PATH?\n\
+                 $ {}\n\
+                 {}",
+                cmd, msg,
+            ),
+            BuildError::Exit { cmd, status, logs } => write!(
+                f,
+                "Nix process returned exit code {}.\n\
+                 $ {}\n\
+                 {}",
+                status.map_or("<unknown>".to_string(), |c| i32::to_string(&c)),
+                cmd,
+                LogLinesDisplay(logs)
+            ),
+            BuildError::RemoteBuilder {
+                reason,
+                cmd,
+                status,
+                logs,
+            } => write!(
+                f,
+                "Nix could not use the remote builders: {}\n\
+                 Nix process returned exit code {}.\n\
+                 $ {}\n\
+                 {}",
+                reason,
+                status.map_or("<unknown>".to_string(), |c| i32::to_string(&c)),
+                cmd,
+                LogLinesDisplay(logs)
+            ),
+            BuildError::Output { msg } => write!(f, "{}", msg),
+            BuildError::Timeout { timeout_secs } => write!(
+                f,
+                "The build did not finish within {} seconds and was killed. \
+                 Is there an infinite recursion in the nix expression?",
+                timeout_secs
+            ),
+            BuildError::Build { error } => write!(f, "{}", error),
+            BuildError::Unsupported { msg } => write!(f, "{}", msg),
+        }
+    }
+}
+
+// TODO: rethink these constructors
+impl BuildError {
+    /// Smart constructor for `BuildError::Io`
+    pub fn io<D>(e: D) -> BuildError
+    where
+        D: fmt::Debug,
+    {
+        BuildError::Io {
+            msg: format!("{:?}", e),
+        }
+    }
+
+    /// Smart constructor for `BuildError::Spawn`
+    pub fn spawn<D>(cmd: &Command, e: D) -> BuildError
+    where
+        D: fmt::Display,
+    {
+        BuildError::Spawn {
+            cmd: format!("{:?}", cmd),
+            msg: format!("{}", e),
+        }
+    }
+
+    /// Smart constructor for `BuildError::Exit`,
+    /// or `BuildError::RemoteBuilder` if the logs show that remote building failed.
+    pub fn exit(cmd: &Command, status: ExitStatus, logs: Vec<OsString>) -> BuildError {
+        assert!(
+            !status.success(),
+            "cannot create an exit error from a successful status code"
+        );
+        let logs: Vec<LogLine> = logs.iter().map(|l| LogLine::from(l.clone())).collect();
+        BuildError::failed(format!("{:?}", cmd), status.code(), logs)
+    }
+
+    /// `BuildError::Exit` or `BuildError::RemoteBuilder` for the failure
+    /// of `cmd`, which need not be a process (e.g. a request to the nix daemon).
+    pub fn failed(cmd: String, status: Option<i32>, logs: Vec<LogLine>) -> BuildError {
+        match remote_builder_failure(&logs) {
+            Some(reason) => BuildError::RemoteBuilder {
+                reason,
+                cmd,
+                status,
+                logs,
+            },
+            None => BuildError::Exit { cmd, status, logs },
+        }
+    }
+
+    /// Smart constructor for `BuildError::Output`
+    pub fn output(msg: String) -> BuildError {
+        BuildError::Output { msg }
+    }
+
+    /// Smart constructor for `BuildError::Build`
+    pub fn build(error: BuildError) -> BuildError {
+        BuildError::Build {
+            error: Box::new(error),
+        }
+    }
+
+    /// Is there something the user can do about this error?
+    pub fn is_actionable(&self) -> bool {
+        match self {
+            BuildError::Io { .. } => false,
+            BuildError::Spawn { .. } => true, // install Nix or fix $PATH
+            BuildError::Exit { .. } => true,  // fix Nix expression
+            BuildError::RemoteBuilder { .. } => true, // fix the builders
+            BuildError::Output { .. } => true, // fix Nix expression
+            BuildError::Timeout { .. } => true, // fix Nix expression or raise the timeout
+            BuildError::Build { error } => error.is_actionable(),
+            BuildError::Unsupported { .. } => true, // upgrade nix or change the project
+        }
+    }
+}
+
+/// A line from stderr log output.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogLine(pub OsString);
+
+impl From<OsString> for LogLine {
+    fn from(oss: OsString) -> Self {
+        LogLine(oss)
+    }
+}
+
+impl From<String> for LogLine {
+    fn from(s: String) -> Self {
+        LogLine(OsString::from(s))
+    }
+}
+
+/// Messages nix prints when it can’t reach or use a remote builder.
+const REMOTE_BUILDER_ERRORS: &[&str] = &[
+    "failed to start SSH connection",
+    "unable to open SSH connection",
+    "cannot connect to '",
+    "cannot build on '",
+    "unable to start any build",
+];
+
+/// Find the log line which says that remote building failed, if any.
+fn remote_builder_failure(logs: &[LogLine]) -> Option<String> {
+    logs.iter()
+        .map(|l| String::from_utf8_lossy(l.0.as_bytes()))
+        .find(|line| REMOTE_BUILDER_ERRORS.iter().any(|e| line.contains(e)))
+        .map(|line| line.trim().to_string())
+}
+
+struct LogLinesDisplay<'a>(&'a [LogLine]);
+
+impl<'a> fmt::Display for LogLinesDisplay<'a> {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for l in self.0 {
+            let mut s = String::from_utf8_lossy(l.0.as_bytes()).into_owned();
+            s.push('\n');
+            formatter.write_str(&s)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Failures to reach a remote builder are told apart from failing builds.
+    #[test]
+    fn remote_builder_failures() {
+        let logs = |lines: &[&str]| -> Vec<LogLine> {
+            lines.iter().map(|l| LogLine::from(l.to_string())).collect()
+        };
+        assert_eq!(
+            remote_builder_failure(&logs(&[
+                "building '/nix/store/abc-foo.drv' on 'ssh://builder'...",
+                "error: cannot build on 'ssh://builder': error: failed to start SSH connection to 'builder'",
+            ])),
+            Some(String::from("error: cannot build on 'ssh://builder': error: failed to start SSH connection to 'builder'"))
+        );
+        assert_eq!(
+            remote_builder_failure(&logs(&[
+                "builder for '/nix/store/abc-foo.drv' failed with exit code 1",
+            ])),
+            None
+        );
+    }
+}
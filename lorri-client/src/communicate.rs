@@ -0,0 +1,692 @@
+//! Communication with the lorri daemon.
+//!
+//! We provide a fixed number of `CommunicationType`s that are sent
+//! between the lorri daemon and lorri clients wishing to connect to it.
+//!
+//! `listener` implements the daemon side, which provides an `accept`
+//! method which is similar to how `accept()` works on a plain Unix socket.
+//!
+//! `client` implements a set of clients specialized to the communications
+//! we support.
+
+use std::collections::BTreeMap;
+use std::os::unix::net::UnixStream;
+use thiserror::Error;
+
+use crate::event::{EventCursor, LogEvent, SequencedEvent};
+use crate::path::{BindError, BindLock, SocketPath};
+use crate::read_writer::{ReadError, ReadWriteError, ReadWriter, Timeout};
+use crate::status::{DaemonStatus, ProjectStatus};
+use crate::watch::WatchedPath;
+use crate::NixFile;
+
+/// The version of the protocol spoken on the socket.
+///
+/// Client and daemon exchange it before anything else and only talk
+/// if their versions are equal, so it has to be increased whenever
+/// a message changes in an incompatible way. Adding a new
+/// `CommunicationType` does not need a new version, clients check
+/// the daemon’s capabilities for it instead.
+pub const PROTOCOL_VERSION: u32 = 1007;
+
+/// Versions start at 1000, so that they can’t be confused with the
+/// `CommunicationType` that clients from before the handshake sent first.
+const FIRST_PROTOCOL_VERSION: u32 = 1000;
+
+/// We declare 1s as the time readers should wait
+/// for the other side to send something.
+pub const DEFAULT_READ_TIMEOUT: Timeout = Timeout::from_millis(1000);
+
+/// Binds a client request type to a server response.
+///
+/// For example, the handler for `Pause` has the response type `NoMessage`,
+/// because the server does not reply to pauses.
+pub trait Handler {
+    /// The response returned to the client for the given request type.
+    type Resp;
+    /// The `CommunicationType` that corresponds to these types.
+    fn communication_type() -> CommunicationType;
+}
+
+/// Enum of all communication modes the lorri daemon supports.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub enum CommunicationType {
+    /// Ping the daemon from a project to tell it to watch & evaluate
+    Ping,
+    /// Stream events that happen in the daemon to the client, as they happen.
+    StreamEvents,
+    /// Stream the build output of a project to the client, as it happens.
+    StreamLogs,
+    /// Ask which paths the daemon watches for a project.
+    WatchedPaths,
+    /// Ask for the status of all projects the daemon builds.
+    Projects,
+    /// Pause or resume the builds of a project, or of all projects.
+    Pause,
+    /// Stop watching and building a project.
+    Untrack,
+    /// Show, rebuild, pause or resume all projects with a tag.
+    Group,
+}
+
+impl CommunicationType {
+    /// Every communication type this version of lorri supports.
+    pub const ALL: &'static [CommunicationType] = &[
+        CommunicationType::Ping,
+        CommunicationType::StreamEvents,
+        CommunicationType::StreamLogs,
+        CommunicationType::WatchedPaths,
+        CommunicationType::Projects,
+        CommunicationType::Pause,
+        CommunicationType::Untrack,
+        CommunicationType::Group,
+    ];
+
+    /// The name of the capability a daemon announces if it supports
+    /// this communication type.
+    pub fn capability(self) -> &'static str {
+        match self {
+            CommunicationType::Ping => "ping",
+            CommunicationType::StreamEvents => "stream-events",
+            CommunicationType::StreamLogs => "stream-logs",
+            CommunicationType::WatchedPaths => "watched-paths",
+            CommunicationType::Projects => "projects",
+            CommunicationType::Pause => "pause",
+            CommunicationType::Untrack => "untrack",
+            CommunicationType::Group => "group",
+        }
+    }
+}
+
+/// No message can be sent through this socket end (empty type).
+#[derive(Serialize, Deserialize)]
+pub enum NoMessage {}
+
+/// Message sent by the client to ask the server to start
+/// watching `nix_file`. See `CommunicationType::Ping`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Ping {
+    /// The nix file to watch and build on changes.
+    pub nix_file: NixFile,
+    /// The attribute of the nix file to build, if not the whole file.
+    pub attr: Option<String>,
+    /// When/whether to start the build.
+    pub rebuild: Rebuild,
+    /// The values of the project’s `pass_env` variables in the client’s environment.
+    /// bincode can’t leave out fields, so clients from before it was
+    /// added can’t talk to the daemon (the protocol version tells them).
+    pub env: BTreeMap<String, String>,
+}
+
+/// In which cases a ping will trigger a rebuild
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Rebuild {
+    /// Only if the nix_file is not yet watched
+    OnlyIfNotYetWatching,
+    /// Always
+    Always,
+}
+
+impl Handler for Ping {
+    /// The daemon replies with its state, e.g. for health checks.
+    type Resp = DaemonStatus;
+
+    fn communication_type() -> CommunicationType {
+        CommunicationType::Ping
+    }
+}
+
+/// Stream events to the client, as they happen.
+///
+/// First the client gets the latest event of each project, or the events
+/// it missed, followed by an `Event::SectionEnd`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StreamEvents {
+    /// The last event the client saw before it reconnected.
+    /// The daemon replays the events after it, if it still has them.
+    pub since: Option<EventCursor>,
+}
+
+impl Handler for StreamEvents {
+    type Resp = SequencedEvent;
+
+    fn communication_type() -> CommunicationType {
+        CommunicationType::StreamEvents
+    }
+}
+
+/// Stream the build output of `nix_file` to the client,
+/// starting with what the current build printed so far.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StreamLogs {
+    /// The nix file of the project.
+    pub nix_file: NixFile,
+    /// The attribute of the nix file the project builds, if not the whole file.
+    pub attr: Option<String>,
+}
+
+impl Handler for StreamLogs {
+    type Resp = LogEvent;
+
+    fn communication_type() -> CommunicationType {
+        CommunicationType::StreamLogs
+    }
+}
+
+/// Ask the daemon which paths it watches for `nix_file`, and why.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WatchedPaths {
+    /// The nix file of the project.
+    pub nix_file: NixFile,
+    /// The attribute of the nix file the project builds, if not the whole file.
+    pub attr: Option<String>,
+}
+
+impl Handler for WatchedPaths {
+    /// `None` if the daemon does not watch the project.
+    type Resp = Option<Vec<WatchedPath>>;
+
+    fn communication_type() -> CommunicationType {
+        CommunicationType::WatchedPaths
+    }
+}
+
+/// Ask the daemon for the status of all projects it builds.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Projects {}
+
+impl Handler for Projects {
+    type Resp = Vec<ProjectStatus>;
+
+    fn communication_type() -> CommunicationType {
+        CommunicationType::Projects
+    }
+}
+
+/// Message sent by the client to pause or resume builds.
+/// See `CommunicationType::Pause`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Pause {
+    /// The project to pause or resume, all projects if `None`.
+    pub nix_file: Option<NixFile>,
+    /// The attribute of the nix file the project builds, if not the whole file.
+    pub attr: Option<String>,
+    /// Whether to pause (requested builds wait) or resume.
+    pub paused: bool,
+}
+
+impl Handler for Pause {
+    type Resp = NoMessage;
+
+    fn communication_type() -> CommunicationType {
+        CommunicationType::Pause
+    }
+}
+
+/// Message sent by the client to make the daemon stop watching a project.
+/// See `CommunicationType::Untrack`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Untrack {
+    /// The nix file of the project.
+    pub nix_file: NixFile,
+    /// The attribute of the nix file the project builds, if not the whole file.
+    pub attr: Option<String>,
+    /// Also delete the project’s GC roots and state.
+    pub remove_roots: bool,
+}
+
+impl Handler for Untrack {
+    type Resp = NoMessage;
+
+    fn communication_type() -> CommunicationType {
+        CommunicationType::Untrack
+    }
+}
+
+/// Message sent by the client to act on all projects the daemon builds
+/// whose `.lorri.toml` has `tag` in its `tags`. See `CommunicationType::Group`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Group {
+    /// The tag of the projects.
+    pub tag: String,
+    /// What to do with them.
+    pub action: GroupAction,
+}
+
+/// What to do with the projects of a `Group`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GroupAction {
+    /// Only reply with their status
+    Status,
+    /// Build them again
+    Rebuild,
+    /// Pause their builds
+    Pause,
+    /// Resume their builds
+    Resume,
+}
+
+impl Handler for Group {
+    /// The status of the projects with the tag, before the action.
+    type Resp = Vec<ProjectStatus>;
+
+    fn communication_type() -> CommunicationType {
+        CommunicationType::Group
+    }
+}
+
+/// `Listener` and possible errors.
+pub mod listener {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+
+    /// If a connection on the socket is attempted and the message
+    /// after the hellos is of a `ConnectionType`, the `Listener` returns
+    /// this message as an ack.
+    /// In all other cases the `Listener` returns no answer (the
+    /// bad client should time out after some time).
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct ConnectionAccepted();
+
+    /// The first message a client sends after connecting.
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct ClientHello {
+        /// The `PROTOCOL_VERSION` of the client.
+        pub protocol_version: u32,
+    }
+
+    /// The `Listener`’s answer to a `ClientHello`.
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct ServerHello {
+        /// The `PROTOCOL_VERSION` of the daemon.
+        pub protocol_version: u32,
+        /// The `CommunicationType::capability` of every communication
+        /// type the daemon supports.
+        pub capabilities: Vec<String>,
+    }
+
+    impl ServerHello {
+        /// The hello of this version of the daemon.
+        fn current() -> ServerHello {
+            ServerHello {
+                protocol_version: PROTOCOL_VERSION,
+                capabilities: CommunicationType::ALL
+                    .iter()
+                    .map(|ct| ct.capability().to_string())
+                    .collect(),
+            }
+        }
+    }
+
+    /// Server-side part of a socket transmission,
+    /// listening for incoming messages.
+    pub struct Listener {
+        /// Bound Unix socket.
+        listener: UnixListener,
+        /// Lock that keeps our socket exclusive.
+        // `bind_lock` is never actually used anywhere,
+        // it is released when `Listener`’s lifetime ends.
+        // We can ignore the “dead code” warning.
+        #[allow(dead_code)]
+        bind_lock: BindLock,
+        /// How long to wait for the client to send its
+        /// first message after opening the connection.
+        accept_timeout: Timeout,
+    }
+
+    /// Standing connection to the client.
+    /// The `communication_type` determines which handler the user has to call in `Handlers`,
+    /// but the missing dependent types makes it hard to express;
+    /// Handlers should probably be a trait instead.
+    pub struct Connection {
+        /// The kind of communication the client requested us to talk to it.
+        pub communication_type: CommunicationType,
+        /// The handlers, being able to
+        pub handlers: Handlers,
+    }
+
+    /// Errors in `accept()`ing a new connection.
+    #[derive(Debug)]
+    pub enum AcceptError {
+        /// something went wrong in the `accept()` syscall.
+        Accept(std::io::Error),
+        /// The client’s message could not be decoded.
+        Message(ReadWriteError),
+        /// The client is from a lorri version before the protocol handshake.
+        OutdatedClient,
+        /// The client speaks a different protocol version.
+        IncompatibleClient {
+            /// The `PROTOCOL_VERSION` of the client.
+            protocol_version: u32,
+        },
+    }
+
+    impl Listener {
+        /// Create a new `daemon` by binding to `socket_path`,
+        /// or by taking over the socket systemd listens on for us.
+        pub fn new(socket_path: &SocketPath) -> Result<Listener, BindError> {
+            let (l, lock) = socket_path.bind_or_inherit()?;
+            Ok(Listener {
+                listener: l,
+                bind_lock: lock,
+                accept_timeout: DEFAULT_READ_TIMEOUT,
+            })
+        }
+
+        /// Accept a new connection on the socket, and read the communication type,
+        /// then return the open socket.
+        ///
+        /// This method blocks until a client tries to connect.
+        pub fn accept(&self) -> Result<Connection, AcceptError> {
+            // - socket accept
+            let (unix_stream, _) = self.listener.accept().map_err(AcceptError::Accept)?;
+            // - exchange the protocol versions
+            let mut hello_rw = ReadWriter::<ClientHello, ServerHello>::new(&unix_stream);
+            let ClientHello { protocol_version } = hello_rw
+                .read(self.accept_timeout)
+                .map_err(|e| AcceptError::Message(ReadWriteError::R(e)))?;
+            // old clients sent their `CommunicationType` first,
+            // and would not understand our answer
+            if protocol_version < FIRST_PROTOCOL_VERSION {
+                return Err(AcceptError::OutdatedClient);
+            }
+            hello_rw
+                .write(self.accept_timeout, &ServerHello::current())
+                .map_err(|e| AcceptError::Message(ReadWriteError::W(e)))?;
+            // the client tells its user which side needs to be upgraded
+            if protocol_version != PROTOCOL_VERSION {
+                return Err(AcceptError::IncompatibleClient { protocol_version });
+            }
+            // - read the next message as a `CommunicationType`
+            let communication_type: CommunicationType =
+                ReadWriter::<CommunicationType, ConnectionAccepted>::new(&unix_stream)
+                    .react(self.accept_timeout, |_| ConnectionAccepted())
+                    .map_err(AcceptError::Message)?;
+            // spawn a thread with the accept handler
+            Ok(Connection {
+                handlers: Handlers {
+                    socket: unix_stream,
+                },
+                communication_type,
+            })
+        }
+    }
+
+    /// A wrapper that is returned by accept and provides a `ReadWriter` for each of the `CommunicationType`s.
+    pub struct Handlers {
+        socket: UnixStream,
+    }
+
+    /// All handlers we have available to read messages and reply.
+    impl Handlers {
+        /// React to a ping message
+        pub fn ping(&self) -> ReadWriter<'_, Ping, <Ping as Handler>::Resp> {
+            ReadWriter::new(&self.socket)
+        }
+
+        /// Stream events to the client as they happen
+        pub fn stream_events(
+            &self,
+        ) -> ReadWriter<'_, StreamEvents, <StreamEvents as Handler>::Resp> {
+            ReadWriter::new(&self.socket)
+        }
+
+        /// Stream build output to the client as it happens
+        pub fn stream_logs(&self) -> ReadWriter<'_, StreamLogs, <StreamLogs as Handler>::Resp> {
+            ReadWriter::new(&self.socket)
+        }
+
+        /// Reply with the paths watched for a project
+        pub fn watched_paths(
+            &self,
+        ) -> ReadWriter<'_, WatchedPaths, <WatchedPaths as Handler>::Resp> {
+            ReadWriter::new(&self.socket)
+        }
+
+        /// Reply with the status of all projects
+        pub fn projects(&self) -> ReadWriter<'_, Projects, <Projects as Handler>::Resp> {
+            ReadWriter::new(&self.socket)
+        }
+
+        /// React to a pause message
+        pub fn pause(&self) -> ReadWriter<'_, Pause, <Pause as Handler>::Resp> {
+            ReadWriter::new(&self.socket)
+        }
+
+        /// React to an untrack message
+        pub fn untrack(&self) -> ReadWriter<'_, Untrack, <Untrack as Handler>::Resp> {
+            ReadWriter::new(&self.socket)
+        }
+
+        /// React to a group message and reply with the projects of the group
+        pub fn group(&self) -> ReadWriter<'_, Group, <Group as Handler>::Resp> {
+            ReadWriter::new(&self.socket)
+        }
+    }
+}
+
+/// Clients that can talk to a `Listener`.
+///
+/// `R` is the type of messages this client reads.
+/// `W` is the type of messages this client writes.
+///
+/// The construction of `Client` is only exported for
+/// the pre-defined interactions with the `Listener` we support.
+pub mod client {
+    use super::*;
+    use std::marker::PhantomData;
+
+    /// A `Client` that can talk to a `Listener`.
+    pub struct Client<R, W> {
+        /// Type of interaction with the `Listener`.
+        comm_type: CommunicationType,
+        /// Connected socket.
+        socket: Option<UnixStream>,
+        /// Timeout for reads/writes.
+        timeout: Timeout,
+        read_type: PhantomData<R>,
+        write_type: PhantomData<W>,
+    }
+
+    /// Error when talking to the `Listener`.
+    #[derive(Error, Debug)]
+    pub enum Error {
+        /// Not connected to the `Listener` socket.
+        #[error("Not connected to the daemon socket")]
+        NotConnected,
+        /// Read error or write error.
+        #[error("Unable to send a message to the daemon")]
+        Message(#[source] ReadWriteError),
+    }
+
+    /// Error when initializing connection with the `Listener`.
+    #[derive(Error, Debug)]
+    pub enum InitError {
+        /// `connect()` syscall failed.
+        #[error("Unable to connect to socket at {0}, is the daemon running?")]
+        SocketConnect(SocketPath, #[source] std::io::Error),
+        /// The socket file belongs to another user, who could impersonate the daemon.
+        #[error("The socket at {0} belongs to another user (uid {1}), refusing to connect")]
+        ForeignSocket(SocketPath, u32),
+        /// Nothing listens on the socket file anymore.
+        #[error("The socket at {0} was left behind by a lorri daemon which is not running anymore (did it crash?), please start `lorri daemon`")]
+        StaleSocket(SocketPath),
+        /// Handshake failed (write `ConnectionType`, read `ConnectionAccepted`).
+        #[error("Server Handshake failed: {0}")]
+        ServerHandshake(ReadWriteError),
+        /// The daemon did not understand the protocol handshake.
+        #[error("The lorri daemon did not understand this client, it is from before the protocol handshake. Please restart the daemon with the same version of lorri.")]
+        OutdatedDaemon,
+        /// The daemon speaks an older protocol version.
+        #[error("The lorri daemon is older than this client (protocol version {daemon_version} instead of {}). Please restart the daemon with the same version of lorri.", PROTOCOL_VERSION)]
+        DaemonTooOld {
+            /// The `PROTOCOL_VERSION` of the daemon.
+            daemon_version: u32,
+        },
+        /// The daemon speaks a newer protocol version.
+        #[error("The lorri daemon is newer than this client (protocol version {daemon_version} instead of {}). Please upgrade lorri, or use the lorri of the daemon.", PROTOCOL_VERSION)]
+        DaemonTooNew {
+            /// The `PROTOCOL_VERSION` of the daemon.
+            daemon_version: u32,
+        },
+        /// The daemon does not support the requested communication type.
+        #[error("The lorri daemon does not support `{}` requests, it is older than this client. Please restart the daemon with the same version of lorri.", .0.capability())]
+        Unsupported(CommunicationType),
+    }
+
+    /// Create a Client for a given `Handler` type.
+    /// Every enum in `CommunicationType` will have an instance for the type,
+    /// named after the request (e.g. `CommunicationType::Ping` has a handler instance for `Ping`.
+    pub fn new<T>(timeout: Timeout) -> client::Client<T::Resp, T>
+    where
+        T: Handler,
+    {
+        client::Client::bake(timeout, T::communication_type())
+    }
+
+    // builder pattern for timeouts?
+    // TODO: turn around the types in Client?
+    impl<R, W> Client<R, W> {
+        /// “Bake” a Client, aka set its communication type (and message type arguments).
+        /// Not exported.
+        fn bake(timeout: Timeout, comm_type: CommunicationType) -> Client<R, W> {
+            Client {
+                comm_type,
+                socket: None,
+                timeout,
+                read_type: PhantomData,
+                write_type: PhantomData,
+            }
+        }
+
+        /// Connect to the `Listener` listening on `socket_path`.
+        /// TODO: remove the split between new() and connect(), and then remove `Error::NotConnected`
+        pub fn connect(self, socket_path: &SocketPath) -> Result<Client<R, W>, InitError> {
+            // TODO: check if the file exists and is a socket
+            // a missing socket is reported by `connect`
+            if let Ok(Some(owner)) = socket_path.foreign_owner() {
+                return Err(InitError::ForeignSocket(socket_path.clone(), owner));
+            }
+
+            // - connect to `socket_path`
+            let socket = socket_path.connect().map_err(|e| match e.kind() {
+                std::io::ErrorKind::ConnectionRefused => {
+                    InitError::StaleSocket(socket_path.clone())
+                }
+                _ => InitError::SocketConnect(socket_path.clone(), e),
+            })?;
+
+            // - exchange the protocol versions
+            let listener::ServerHello {
+                protocol_version,
+                capabilities,
+            } = ReadWriter::new(&socket)
+                .communicate(
+                    self.timeout,
+                    &listener::ClientHello {
+                        protocol_version: PROTOCOL_VERSION,
+                    },
+                )
+                .map_err(|e| match e {
+                    // daemons from before the handshake hang up on the hello
+                    ReadWriteError::R(ReadError::Deserialize(_)) => InitError::OutdatedDaemon,
+                    e => InitError::ServerHandshake(e),
+                })?;
+            if protocol_version < PROTOCOL_VERSION {
+                return Err(InitError::DaemonTooOld {
+                    daemon_version: protocol_version,
+                });
+            }
+            if protocol_version > PROTOCOL_VERSION {
+                return Err(InitError::DaemonTooNew {
+                    daemon_version: protocol_version,
+                });
+            }
+            if !capabilities
+                .iter()
+                .any(|c| c == self.comm_type.capability())
+            {
+                return Err(InitError::Unsupported(self.comm_type));
+            }
+
+            // - send the message with the CommunicationType
+            // - wait for server to acknowledge connect
+            let _: listener::ConnectionAccepted = ReadWriter::new(&socket)
+                .communicate(self.timeout, &self.comm_type)
+                .map_err(InitError::ServerHandshake)?;
+
+            Ok(Client {
+                comm_type: self.comm_type,
+                socket: Some(socket),
+                timeout: self.timeout,
+                read_type: PhantomData,
+                write_type: PhantomData,
+            })
+        }
+
+        /// Read a message returned by the connected `Listener`.
+        pub fn read(&self) -> Result<R, Error>
+        where
+            R: serde::de::DeserializeOwned,
+        {
+            let sock = self.socket.as_ref().ok_or(Error::NotConnected)?;
+            let rw: ReadWriter<R, W> = ReadWriter::new(sock);
+            rw.read(self.timeout)
+                .map_err(|e| Error::Message(ReadWriteError::R(e)))
+        }
+
+        /// Write a message to the connected `Listener`.
+        pub fn write(&self, mes: &W) -> Result<(), Error>
+        where
+            W: serde::Serialize,
+        {
+            let sock = self.socket.as_ref().ok_or(Error::NotConnected)?;
+            let mut rw: ReadWriter<R, W> = ReadWriter::new(sock);
+            rw.write(self.timeout, mes)
+                .map_err(|e| Error::Message(ReadWriteError::W(e)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::AbsPathBuf;
+
+    fn socket_path(dir: &tempfile::TempDir) -> SocketPath {
+        SocketPath::from(AbsPathBuf::new(dir.path().join("socket")).unwrap())
+    }
+
+    #[test]
+    fn handshake_accepts_clients_of_the_same_version() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = socket_path(&tempdir);
+        let listener = listener::Listener::new(&path).unwrap();
+        let accept =
+            std::thread::spawn(move || listener.accept().map(|conn| conn.communication_type));
+        client::new::<Projects>(DEFAULT_READ_TIMEOUT)
+            .connect(&path)
+            .unwrap();
+        match accept.join().unwrap() {
+            Ok(CommunicationType::Projects) => {}
+            other => panic!("unexpected accept result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn handshake_rejects_clients_from_before_the_handshake() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = socket_path(&tempdir);
+        let listener = listener::Listener::new(&path).unwrap();
+        let accept = std::thread::spawn(move || listener.accept().map(|_| ()));
+        // old clients started with their communication type
+        let socket = path.connect().unwrap();
+        ReadWriter::<listener::ConnectionAccepted, CommunicationType>::new(&socket)
+            .write(DEFAULT_READ_TIMEOUT, &CommunicationType::Ping)
+            .unwrap();
+        match accept.join().unwrap() {
+            Err(listener::AcceptError::OutdatedClient) => {}
+            other => panic!("unexpected accept result: {:?}", other),
+        }
+    }
+}
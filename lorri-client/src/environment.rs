@@ -0,0 +1,136 @@
+//! What changed in a project’s environment between two builds.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+/// What changed between two environments.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EnvDiff {
+    /// Variables only the new environment sets, with their values
+    pub added: BTreeMap<String, String>,
+    /// Variables only the old environment sets, with their values
+    pub removed: BTreeMap<String, String>,
+    /// Variables whose value changed, with the old and the new value
+    pub changed: BTreeMap<String, (String, String)>,
+    /// Store paths only the new environment refers to
+    pub added_store_paths: BTreeSet<PathBuf>,
+    /// Store paths only the old environment refers to
+    pub removed_store_paths: BTreeSet<PathBuf>,
+}
+
+impl EnvDiff {
+    /// The changes from `old` to `new`, whose store paths are in `store_dir`.
+    pub fn between(
+        old: &BTreeMap<String, String>,
+        new: &BTreeMap<String, String>,
+        store_dir: &Path,
+    ) -> EnvDiff {
+        let mut diff = EnvDiff::default();
+        for (name, value) in new {
+            match old.get(name) {
+                None => {
+                    diff.added.insert(name.clone(), value.clone());
+                }
+                Some(old_value) if old_value != value => {
+                    diff.changed
+                        .insert(name.clone(), (old_value.clone(), value.clone()));
+                }
+                Some(_) => {}
+            }
+        }
+        for (name, value) in old {
+            if !new.contains_key(name) {
+                diff.removed.insert(name.clone(), value.clone());
+            }
+        }
+        let old_paths = store_paths(old, store_dir);
+        let new_paths = store_paths(new, store_dir);
+        diff.added_store_paths = new_paths.difference(&old_paths).cloned().collect();
+        diff.removed_store_paths = old_paths.difference(&new_paths).cloned().collect();
+        diff
+    }
+
+    /// Whether nothing changed.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.changed.is_empty()
+            && self.added_store_paths.is_empty()
+            && self.removed_store_paths.is_empty()
+    }
+}
+
+/// The store paths (`<store_dir>/<hash>-<name>`) the values of `vars` mention.
+fn store_paths(vars: &BTreeMap<String, String>, store_dir: &Path) -> BTreeSet<PathBuf> {
+    let prefix = format!("{}/", store_dir.display());
+    let mut paths = BTreeSet::new();
+    for value in vars.values() {
+        let mut rest = value.as_str();
+        while let Some(start) = rest.find(&prefix) {
+            let after = &rest[start + prefix.len()..];
+            // the characters nix allows in store path names
+            let len = after
+                .find(|c: char| !(c.is_ascii_alphanumeric() || "+-._?=".contains(c)))
+                .unwrap_or(after.len());
+            if len > 0 {
+                paths.insert(PathBuf::from(format!("{}{}", prefix, &after[..len])));
+            }
+            rest = &after[len..];
+        }
+    }
+    paths
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(vars: &[(&str, &str)]) -> BTreeMap<String, String> {
+        vars.iter()
+            .map(|(n, v)| (n.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn diff_variables_and_store_paths() {
+        let old = vars(&[
+            (
+                "PATH",
+                "/nix/store/aaa-hello-2.10/bin:/nix/store/bbb-git-2.30/bin",
+            ),
+            ("FOO", "foo"),
+            ("GONE", "/nix/store/ccc-gone"),
+        ]);
+        let new = vars(&[
+            (
+                "PATH",
+                "/nix/store/aaa-hello-2.10/bin:/nix/store/ddd-git-2.31/bin",
+            ),
+            ("FOO", "foo"),
+            ("NEW", "new"),
+        ]);
+        let diff = EnvDiff::between(&old, &new, Path::new("/nix/store"));
+        assert_eq!(diff.added, vars(&[("NEW", "new")]));
+        assert_eq!(diff.removed, vars(&[("GONE", "/nix/store/ccc-gone")]));
+        assert_eq!(
+            diff.changed.keys().collect::<Vec<_>>(),
+            vec![&String::from("PATH")]
+        );
+        assert_eq!(
+            diff.added_store_paths,
+            vec![PathBuf::from("/nix/store/ddd-git-2.31")]
+                .into_iter()
+                .collect()
+        );
+        assert_eq!(
+            diff.removed_store_paths,
+            vec![
+                PathBuf::from("/nix/store/bbb-git-2.30"),
+                PathBuf::from("/nix/store/ccc-gone")
+            ]
+            .into_iter()
+            .collect()
+        );
+        assert!(EnvDiff::between(&old, &old, Path::new("/nix/store")).is_empty());
+    }
+}
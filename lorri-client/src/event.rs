@@ -0,0 +1,375 @@
+//! The build events the daemon sends to clients.
+
+use crate::build_error::{BuildError, LogLine};
+use crate::environment::EnvDiff;
+use crate::{AbsPathBuf, NixFile};
+use std::path::PathBuf;
+
+/// Build events that can happen.
+/// Abstracting over its internal to make different serialize instances possible.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum EventI<NixFile, Reason, OutputPath, BuildError> {
+    /// Demarks a stream of events from recent history becoming live
+    SectionEnd,
+    /// A build is waiting in the daemon’s build queue
+    Queued {
+        /// The shell.nix file for the building project
+        nix_file: NixFile,
+        /// The attribute of the nix file the project builds, if not the whole file
+        attr: Option<String>,
+        /// How many builds will start before this one (0 means it is next)
+        position: usize,
+    },
+    /// A build has started
+    Started {
+        /// The shell.nix file for the building project
+        nix_file: NixFile,
+        /// The attribute of the nix file the project builds, if not the whole file
+        attr: Option<String>,
+        /// The reason the build started
+        reason: Reason,
+    },
+    /// A build completed successfully
+    Completed {
+        /// The shell.nix file for the building project
+        nix_file: NixFile,
+        /// The attribute of the nix file the project builds, if not the whole file
+        attr: Option<String>,
+        /// the output paths of the build
+        rooted_output_paths: OutputPath,
+        /// What changed in the environment since the previous build,
+        /// if there was one and the environments could be compared
+        changes: Option<EnvDiff>,
+    },
+    /// A build command returned a failing exit status
+    Failure {
+        /// The shell.nix file for the building project
+        nix_file: NixFile,
+        /// The attribute of the nix file the project builds, if not the whole file
+        attr: Option<String>,
+        /// The error that exited the build
+        failure: BuildError,
+    },
+    /// A build was killed because it exceeded the project’s `build_timeout_secs`
+    BuildTimeout {
+        /// The shell.nix file for the building project
+        nix_file: NixFile,
+        /// The attribute of the nix file the project builds, if not the whole file
+        attr: Option<String>,
+        /// The timeout which was exceeded
+        timeout_secs: u64,
+        /// How long the project waits before it is built again
+        backoff_secs: u64,
+    },
+    /// The daemon stopped watching and building the project
+    Removed {
+        /// The shell.nix file for the project
+        nix_file: NixFile,
+        /// The attribute of the nix file the project builds, if not the whole file
+        attr: Option<String>,
+    },
+    /// Some of the project’s files can’t be watched natively, because the
+    /// file system’s watch limit (e.g. inotify’s `max_user_watches`) was reached
+    WatchLimitReached {
+        /// The shell.nix file for the project
+        nix_file: NixFile,
+        /// The attribute of the nix file the project builds, if not the whole file
+        attr: Option<String>,
+        /// Whether the files are polled for changes instead,
+        /// otherwise changes to them are missed
+        polling: bool,
+    },
+    /// The built environment was pushed to a binary cache,
+    /// as the project’s `[push]` table asks for
+    Pushed {
+        /// The shell.nix file for the project
+        nix_file: NixFile,
+        /// The attribute of the nix file the project builds, if not the whole file
+        attr: Option<String>,
+        /// Where it was pushed to, e.g. `cachix:team` or `s3://team-cache`
+        target: String,
+        /// The pushed store paths (with their closure)
+        paths: Vec<PathBuf>,
+        /// Why the push failed, if it did
+        error: Option<String>,
+    },
+}
+
+/// The events the daemon sends to clients which stream them.
+pub type Event = EventI<NixFile, ReasonI<NixFile>, OutputPath<RootPath>, BuildError>;
+
+impl<NixFile, Reason, OutputPath, BuildError> EventI<NixFile, Reason, OutputPath, BuildError> {
+    /// The shell.nix file of the project the event is about, if any.
+    pub fn nix_file(&self) -> Option<&NixFile> {
+        use EventI::*;
+        match self {
+            SectionEnd => None,
+            Queued { nix_file, .. }
+            | Started { nix_file, .. }
+            | Completed { nix_file, .. }
+            | Failure { nix_file, .. }
+            | BuildTimeout { nix_file, .. }
+            | Removed { nix_file, .. }
+            | WatchLimitReached { nix_file, .. }
+            | Pushed { nix_file, .. } => Some(nix_file),
+        }
+    }
+
+    /// The attribute the project the event is about builds, if not the whole file.
+    pub fn attr(&self) -> Option<&str> {
+        use EventI::*;
+        match self {
+            SectionEnd => None,
+            Queued { attr, .. }
+            | Started { attr, .. }
+            | Completed { attr, .. }
+            | Failure { attr, .. }
+            | BuildTimeout { attr, .. }
+            | Removed { attr, .. }
+            | WatchLimitReached { attr, .. }
+            | Pushed { attr, .. } => attr.as_deref(),
+        }
+    }
+
+    /// Map over the inner types.
+    pub fn map<F, G, H, I, NixFile2, Reason2, OutputPaths2, BuildError2>(
+        self,
+        nix_file_f: F,
+        reason_f: G,
+        output_paths_f: H,
+        build_error_f: I,
+    ) -> EventI<NixFile2, Reason2, OutputPaths2, BuildError2>
+    where
+        F: Fn(NixFile) -> NixFile2,
+        G: Fn(Reason) -> Reason2,
+        H: Fn(OutputPath) -> OutputPaths2,
+        I: Fn(BuildError) -> BuildError2,
+    {
+        use EventI::*;
+        match self {
+            SectionEnd => SectionEnd,
+            Started {
+                nix_file,
+                attr,
+                reason,
+            } => Started {
+                nix_file: nix_file_f(nix_file),
+                attr,
+                reason: reason_f(reason),
+            },
+            Queued {
+                nix_file,
+                attr,
+                position,
+            } => Queued {
+                nix_file: nix_file_f(nix_file),
+                attr,
+                position,
+            },
+            Completed {
+                nix_file,
+                attr,
+                rooted_output_paths,
+                changes,
+            } => Completed {
+                nix_file: nix_file_f(nix_file),
+                attr,
+                rooted_output_paths: output_paths_f(rooted_output_paths),
+                changes,
+            },
+            Failure {
+                nix_file,
+                attr,
+                failure,
+            } => Failure {
+                nix_file: nix_file_f(nix_file),
+                attr,
+                failure: build_error_f(failure),
+            },
+            BuildTimeout {
+                nix_file,
+                attr,
+                timeout_secs,
+                backoff_secs,
+            } => BuildTimeout {
+                nix_file: nix_file_f(nix_file),
+                attr,
+                timeout_secs,
+                backoff_secs,
+            },
+            Removed { nix_file, attr } => Removed {
+                nix_file: nix_file_f(nix_file),
+                attr,
+            },
+            WatchLimitReached {
+                nix_file,
+                attr,
+                polling,
+            } => WatchLimitReached {
+                nix_file: nix_file_f(nix_file),
+                attr,
+                polling,
+            },
+            Pushed {
+                nix_file,
+                attr,
+                target,
+                paths,
+                error,
+            } => Pushed {
+                nix_file: nix_file_f(nix_file),
+                attr,
+                target,
+                paths,
+                error,
+            },
+        }
+    }
+}
+
+/// The log output of a project’s builds, as streamed to clients.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum LogEvent {
+    /// Demarks the logs of the build so far from the live output that follows
+    SectionEnd,
+    /// A new build started, so the following lines belong to it
+    BuildStarted,
+    /// A line nix printed during the build
+    Line(LogLine),
+}
+
+/// Description of the project change that triggered a build.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum ReasonI<NixFile> {
+    /// When a project is presented to Lorri to track, it's built for this reason.
+    ProjectAdded(NixFile),
+    /// When a ping is received.
+    PingReceived,
+    /// When there is a filesystem change, the first changed file is recorded,
+    /// along with a count of other filesystem events.
+    FilesChanged(Vec<PathBuf>),
+}
+
+impl<NixFile> ReasonI<NixFile> {
+    /// Map over the inner types.
+    pub fn map<F, NixFile2>(self, nix_file_f: F) -> ReasonI<NixFile2>
+    where
+        F: Fn(NixFile) -> NixFile2,
+    {
+        use ReasonI::*;
+        match self {
+            ProjectAdded(nix_file) => ProjectAdded(nix_file_f(nix_file)),
+            PingReceived => PingReceived,
+            FilesChanged(vec) => FilesChanged(vec),
+        }
+    }
+
+    /// A short description, as `lorri history` lists it.
+    pub fn describe(&self) -> String {
+        use ReasonI::*;
+        match self {
+            ProjectAdded(_) => String::from("project added"),
+            PingReceived => String::from("ping"),
+            FilesChanged(paths) => match paths.split_first() {
+                None => String::from("files changed"),
+                Some((first, [])) => format!("changed {}", first.display()),
+                Some((first, rest)) => {
+                    format!("changed {} and {} more", first.display(), rest.len())
+                }
+            },
+        }
+    }
+}
+
+/// Output path generated by `logged-evaluation.nix`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutputPath<T> {
+    /// Shell path modified to work as a gc root
+    pub shell_gc_root: T,
+}
+
+impl<T> OutputPath<T> {
+    /// map over the inner type.
+    pub fn map<F, T2>(self, f: F) -> OutputPath<T2>
+    where
+        F: Fn(T) -> T2,
+    {
+        OutputPath {
+            shell_gc_root: f(self.shell_gc_root),
+        }
+    }
+}
+
+/// A path to a gc root.
+#[derive(Hash, PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
+pub struct RootPath(pub AbsPathBuf);
+
+impl RootPath {
+    /// `display` the path.
+    pub fn display(&self) -> std::path::Display<'_> {
+        self.0.display()
+    }
+}
+
+impl OutputPath<RootPath> {
+    /// Check whether all all GC roots exist.
+    pub fn all_exist(&self) -> bool {
+        let OutputPath { shell_gc_root } = self;
+
+        shell_gc_root.0.as_path().exists()
+    }
+}
+
+/// A build event, numbered in the order the daemon passed them on.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SequencedEvent {
+    /// When the daemon which numbered the event started,
+    /// in milliseconds since the epoch. A restarted daemon starts over with `seq`.
+    pub epoch: u64,
+    /// Increases by one with every event, starting at 1 when the daemon starts.
+    /// An `Event::SectionEnd` has the number of the last event before it.
+    pub seq: u64,
+    /// The event
+    pub event: Event,
+}
+
+impl SequencedEvent {
+    /// Where a listener which saw this event continues.
+    pub fn cursor(&self) -> EventCursor {
+        EventCursor {
+            epoch: self.epoch,
+            seq: self.seq,
+        }
+    }
+}
+
+/// The last event a listener saw, to continue after it when it reconnects.
+/// Written as `<epoch>-<seq>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EventCursor {
+    /// The `SequencedEvent::epoch` of the event.
+    pub epoch: u64,
+    /// The `SequencedEvent::seq` of the event.
+    pub seq: u64,
+}
+
+impl std::fmt::Display for EventCursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}", self.epoch, self.seq)
+    }
+}
+
+impl std::str::FromStr for EventCursor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<EventCursor, String> {
+        let mut parts = s.splitn(2, '-');
+        match (parts.next().map(str::parse), parts.next().map(str::parse)) {
+            (Some(Ok(epoch)), Some(Ok(seq))) => Ok(EventCursor { epoch, seq }),
+            _ => Err(format!(
+                "`{}` is not an event cursor, which looks like `<epoch>-<seq>`",
+                s
+            )),
+        }
+    }
+}
@@ -0,0 +1,222 @@
+//! # lorri-client
+//! Talk to the lorri daemon over its socket, like `lorri` itself does.
+//!
+//! The daemon listens on `path::SocketPath::of_daemon`. A client
+//! connects for one kind of request (see `communicate::Handler`),
+//! after both sides checked they speak the same
+//! `communicate::PROTOCOL_VERSION`:
+//!
+//! ```no_run
+//! use lorri_client::communicate::{client, Projects, DEFAULT_READ_TIMEOUT};
+//! use lorri_client::path::SocketPath;
+//!
+//! let socket = SocketPath::of_daemon().unwrap();
+//! let client = client::new::<Projects>(DEFAULT_READ_TIMEOUT)
+//!     .connect(&socket)
+//!     .unwrap();
+//! client.write(&Projects {}).unwrap();
+//! for project in client.read().unwrap() {
+//!     println!("{}: {:?}", project.nix_file.display(), project.status);
+//! }
+//! ```
+//!
+//! The crate follows semantic versioning on its own, independent of lorri:
+//! every change of the `PROTOCOL_VERSION` is a new major version.
+
+#![warn(missing_docs)]
+
+#[macro_use]
+extern crate serde_derive;
+
+pub mod build_error;
+pub mod communicate;
+pub mod environment;
+pub mod event;
+pub mod path;
+pub mod read_writer;
+pub mod status;
+pub mod watch;
+
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+
+/// Path guaranteed to be absolute by construction.
+///
+/// Human-readable formats (JSON) get it as a string, so it has to be
+/// valid UTF-8 for them. Binary formats (the daemon’s socket) get its
+/// bytes, so any path works there. For paths which are UTF-8,
+/// that is the same as a string.
+#[derive(Hash, PartialEq, Eq, Clone, Debug)]
+pub struct AbsPathBuf(PathBuf);
+
+impl serde::Serialize for AbsPathBuf {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serde::Serialize::serialize(&self.0, serializer)
+        } else {
+            serializer.serialize_bytes(self.0.as_os_str().as_bytes())
+        }
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for AbsPathBuf {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct BytesVisitor;
+        impl<'de> serde::de::Visitor<'de> for BytesVisitor {
+            type Value = PathBuf;
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("the bytes of a path")
+            }
+            fn visit_bytes<E: serde::de::Error>(self, bytes: &[u8]) -> Result<PathBuf, E> {
+                Ok(PathBuf::from(OsStr::from_bytes(bytes)))
+            }
+            fn visit_byte_buf<E: serde::de::Error>(self, bytes: Vec<u8>) -> Result<PathBuf, E> {
+                Ok(PathBuf::from(OsString::from_vec(bytes)))
+            }
+        }
+        if deserializer.is_human_readable() {
+            <PathBuf as serde::Deserialize>::deserialize(deserializer).map(AbsPathBuf)
+        } else {
+            deserializer
+                .deserialize_byte_buf(BytesVisitor)
+                .map(AbsPathBuf)
+        }
+    }
+}
+
+impl AbsPathBuf {
+    /// Convert from a path to an absolute path.
+    ///
+    /// If the path is not absolute, the original `PathBuf`
+    /// is returned (similar to `OsString.into_string()`)
+    pub fn new(path: PathBuf) -> Result<Self, PathBuf> {
+        if path.is_absolute() {
+            Ok(Self::new_unchecked(path))
+        } else {
+            Err(path)
+        }
+    }
+
+    /// Convert from a known absolute path.
+    ///
+    /// Passing a relative path is a programming bug (unchecked).
+    pub fn new_unchecked(path: PathBuf) -> Self {
+        AbsPathBuf(path)
+    }
+
+    /// The absolute path, as `&Path`.
+    pub fn as_path(&self) -> &Path {
+        &self.0
+    }
+
+    /// Proxy through the `Display` class for `PathBuf`.
+    pub fn display(&self) -> std::path::Display<'_> {
+        self.0.display()
+    }
+
+    /// Joins a path to the end of this absolute path.
+    /// If the path is absolute, it will replace this absolute path.
+    pub fn join<P: AsRef<Path>>(&self, pb: P) -> Self {
+        let mut new = self.0.to_owned();
+        new.push(pb);
+        Self::new_unchecked(new)
+    }
+
+    /// Proxy through `with_file_name` for `PathBuf`
+    pub fn with_file_name<S: AsRef<OsStr>>(&self, file_name: S) -> Self {
+        // replacing the file name will never make the path relative
+        Self::new_unchecked(self.0.with_file_name(file_name))
+    }
+}
+
+impl AsRef<Path> for AbsPathBuf {
+    fn as_ref(&self) -> &Path {
+        self.as_path()
+    }
+}
+
+/// A .nix file.
+///
+/// Is guaranteed to have an absolute path by construction.
+#[derive(Hash, PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
+pub struct NixFile(AbsPathBuf);
+
+impl NixFile {
+    /// Absolute path of this file.
+    pub fn as_absolute_path(&self) -> &Path {
+        self.0.as_path()
+    }
+}
+
+impl NixFile {
+    /// Whether this is the `flake.nix` of a flake,
+    /// in which case its dev shell is built instead of the file itself.
+    pub fn is_flake(&self) -> bool {
+        self.as_absolute_path().file_name() == Some(std::ffi::OsStr::new(FLAKE_FILE_NAME))
+    }
+
+    /// `display` the path.
+    pub fn display(&self) -> std::path::Display<'_> {
+        self.0.display()
+    }
+
+    /// The same file, with the symlinks (and `..`) of its directory resolved,
+    /// so that a project is the same however its directory was reached.
+    ///
+    /// The file itself is not resolved: a symlinked `shell.nix` belongs to
+    /// the project it is linked into, whose directory nix evaluates it in.
+    /// If the directory does not exist, the path is kept as it is.
+    pub fn canonical(self) -> NixFile {
+        let path = self.as_absolute_path();
+        match (path.parent(), path.file_name()) {
+            (Some(dir), Some(file_name)) => match std::fs::canonicalize(dir) {
+                Ok(dir) => NixFile(AbsPathBuf::new_unchecked(dir.join(file_name))),
+                Err(_) => self,
+            },
+            _ => self,
+        }
+    }
+}
+
+impl From<AbsPathBuf> for NixFile {
+    fn from(abs_path: AbsPathBuf) -> Self {
+        NixFile(abs_path)
+    }
+}
+
+#[cfg(feature = "slog")]
+impl slog::Value for NixFile {
+    fn serialize(
+        &self,
+        _record: &slog::Record,
+        key: slog::Key,
+        serializer: &mut dyn slog::Serializer,
+    ) -> slog::Result {
+        serializer.emit_arguments(key, &format_args!("{}", self.as_absolute_path().display()))
+    }
+}
+
+/// File name of a flake’s nix file.
+pub const FLAKE_FILE_NAME: &str = "flake.nix";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Projects can live in directories whose names aren’t UTF-8.
+    #[test]
+    fn non_utf8_paths_go_over_the_socket() {
+        let path = AbsPathBuf::new_unchecked(PathBuf::from(OsStr::from_bytes(
+            b"/home/user/caf\xe9 project/shell.nix",
+        )));
+        let bytes = bincode::serialize(&path).unwrap();
+        assert_eq!(bincode::deserialize::<AbsPathBuf>(&bytes).unwrap(), path);
+
+        let path = AbsPathBuf::new_unchecked(PathBuf::from("/home/user/my project/shell.nix"));
+        assert_eq!(
+            serde_json::to_string(&path).unwrap(),
+            "\"/home/user/my project/shell.nix\""
+        );
+    }
+}
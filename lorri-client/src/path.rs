@@ -0,0 +1,328 @@
+//! `bind()`ing & `connect()`ing to sockets.
+
+use crate::AbsPathBuf;
+use std::fmt;
+use std::io::{Read, Write};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use thiserror::Error;
+
+/// Overrides the path of the daemon socket, for daemon and clients.
+pub const SOCKET_PATH_VAR: &str = "LORRI_DAEMON_SOCKET";
+
+/// The platform’s cache, configuration and runtime directories for lorri,
+/// `None` if `$HOME` is not set.
+pub fn project_dirs() -> Option<directories::ProjectDirs> {
+    directories::ProjectDirs::from("com.github.nix-community.lorri", "lorri", "lorri")
+}
+
+/// Small wrapper that makes sure lorri sockets are handled correctly.
+#[derive(Clone, Debug)]
+pub struct SocketPath(AbsPathBuf);
+
+/// Binding to the socket failed.
+#[derive(Error, Debug)]
+pub enum BindError {
+    /// Another process is listening on the socket
+    #[error("{} is listening on the socket ({lockfile}), do you have another lorri daemon running?", holder(.pid))]
+    OtherProcessListening {
+        /// The lock file of the socket
+        lockfile: String,
+        /// The process holding the lock, if it could be read
+        pid: Option<i32>,
+    },
+    /// Another process holds the lock, but does not accept connections on the socket
+    #[error("{} holds the lock of the socket ({lockfile}), but does not accept connections, it might hang. Stop it to start a new lorri daemon.", holder(.pid))]
+    OtherProcessNotListening {
+        /// The lock file of the socket
+        lockfile: String,
+        /// The process holding the lock, if it could be read
+        pid: Option<i32>,
+    },
+    /// I/O error
+    #[error("IO error binding to socket")]
+    Io(#[source] std::io::Error),
+    /// nix library I/O error (like Io)
+    #[error("Unix error binding to socket")]
+    Unix(#[source] nix::Error),
+}
+
+/// The socket of the daemon can’t be found, see `SocketPath::of_daemon`.
+#[derive(Error, Debug)]
+pub enum SocketPathError {
+    /// `LORRI_DAEMON_SOCKET` is not an absolute path.
+    #[error("LORRI_DAEMON_SOCKET must be an absolute path, but is {0}")]
+    NotAbsolute(String),
+    /// lorri has no runtime directory, e.g. because `$HOME` is not set.
+    #[error("Could not determine the lorri runtime directory, please set $HOME")]
+    NoRuntimeDir,
+}
+
+impl From<std::io::Error> for BindError {
+    fn from(e: std::io::Error) -> BindError {
+        BindError::Io(e)
+    }
+}
+
+/// Describe the process holding the lock of a socket.
+fn holder(pid: &Option<i32>) -> String {
+    match pid {
+        Some(pid) => format!("Another process (pid {})", pid),
+        None => String::from("Another process"),
+    }
+}
+
+/// Locks the socket the server is bound to. Drop to release.
+///
+/// The lock file contains the pid of the process holding it.
+pub struct BindLock(#[allow(dead_code)] std::fs::File);
+
+impl SocketPath {
+    /// Create from the path of the socket.
+    /// Must be passed a valid socket file path (ending in a file name).
+    pub fn from(socket_path: AbsPathBuf) -> SocketPath {
+        SocketPath(socket_path)
+    }
+
+    /// The socket the lorri daemon of the current user listens on:
+    /// `$LORRI_DAEMON_SOCKET`, or `daemon.socket` in lorri’s runtime
+    /// directory (its cache directory on platforms without one).
+    pub fn of_daemon() -> Result<SocketPath, SocketPathError> {
+        // sandboxes might not be able to reach the runtime directory
+        if let Some(path) = std::env::var_os(SOCKET_PATH_VAR) {
+            return AbsPathBuf::new(path.into())
+                .map(SocketPath)
+                .map_err(|path| SocketPathError::NotAbsolute(path.display().to_string()));
+        }
+        let dirs = project_dirs().ok_or(SocketPathError::NoRuntimeDir)?;
+        let runtime_dir = dirs.runtime_dir().unwrap_or_else(|| dirs.cache_dir());
+        AbsPathBuf::new(runtime_dir.join("daemon.socket"))
+            .map(SocketPath)
+            .map_err(|_| SocketPathError::NoRuntimeDir)
+    }
+
+    /// Try to lock the lock file to find out whether another process is listening.
+    pub fn lock(&self) -> Result<BindLock, BindError> {
+        let mut h = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            // the pid of whoever holds the lock stays until we get it
+            .truncate(false)
+            .mode(0o600)
+            .open(self.lockfile())?;
+        // we try to get an exclusive lock, nonblocking
+        match nix::fcntl::flock(h.as_raw_fd(), nix::fcntl::FlockArg::LockExclusiveNonblock) {
+            // if the lock would block, another process is listening
+            Err(nix::Error::Sys(nix::errno::EWOULDBLOCK)) => Err(self.lock_holder_error(&mut h)),
+            other => other.map_err(BindError::Unix),
+        }?;
+        // so that the next process can tell who holds the lock
+        h.set_len(0)?;
+        write!(h, "{}", nix::unistd::getpid())?;
+        Ok(BindLock(h))
+    }
+
+    /// Find out whether the process holding the lock still listens on the socket.
+    /// If it crashed, the kernel would have released the lock.
+    fn lock_holder_error(&self, lock: &mut std::fs::File) -> BindError {
+        let mut contents = String::new();
+        let pid = lock
+            .read_to_string(&mut contents)
+            .ok()
+            .and_then(|_| contents.trim().parse().ok());
+        let lockfile = self.lockfile().display().to_string();
+        match self.connect() {
+            Ok(_) => BindError::OtherProcessListening { lockfile, pid },
+            Err(_) => BindError::OtherProcessNotListening { lockfile, pid },
+        }
+    }
+
+    /// `bind(2)` to this socket path.
+    ///
+    /// Uses a lock file to guarantee no other process is listening to the same socket.
+    /// A socket file left over by a process which did not hold the lock anymore,
+    /// e.g. because it crashed, is removed.
+    /// The lock file is the socket file with a `.lock` file ending appended.
+    ///
+    /// The lock file is released automatically when the returned `BindLock` is dropped.
+    ///
+    /// Uses the `flock(2)` trick decribed in
+    /// <https://gavv.github.io/articles/unix-socket-reuse/>
+    pub fn bind(&self) -> Result<(UnixListener, BindLock), BindError> {
+        // - try to lock lockfile (open and flock exclusive nonblocking)
+        let lock = self.lock()?;
+        // - remove socket file if it exists
+        std::fs::remove_file(self.as_absolute_path()).or_else(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => Ok(()),
+            _ => Err(e),
+        })?;
+        // - bind to socket
+        let l = UnixListener::bind(self.as_absolute_path())?;
+        // - only our user may talk to the daemon
+        std::fs::set_permissions(
+            self.as_absolute_path(),
+            std::fs::Permissions::from_mode(0o600),
+        )?;
+        Ok((l, lock))
+    }
+
+    /// Take over the socket systemd listens on for us (socket activation),
+    /// or `bind` if we were not started that way.
+    ///
+    /// The socket is still locked like in `bind`.
+    pub fn bind_or_inherit(&self) -> Result<(UnixListener, BindLock), BindError> {
+        match inherited_listener()? {
+            Some(l) => Ok((l, self.lock()?)),
+            None => self.bind(),
+        }
+    }
+
+    /// The owner of the socket file, if it is not the current user.
+    pub fn foreign_owner(&self) -> std::io::Result<Option<u32>> {
+        let owner = std::fs::metadata(self.as_absolute_path())?.uid();
+        Ok(if owner == nix::unistd::getuid().as_raw() {
+            None
+        } else {
+            Some(owner)
+        })
+    }
+
+    /// `connect(2)` to this socket path.
+    pub fn connect(&self) -> std::io::Result<UnixStream> {
+        UnixStream::connect(self.as_absolute_path())
+    }
+
+    /// The absolute path of the socket.
+    pub fn as_absolute_path(&self) -> &Path {
+        self.0.as_ref()
+    }
+
+    /// `display` the path.
+    pub fn display(&self) -> std::path::Display<'_> {
+        self.0.display()
+    }
+
+    fn lockfile(&self) -> AbsPathBuf {
+        self.0.with_file_name({
+            let mut s = self
+                .0
+                .as_path()
+                .file_name()
+                .unwrap_or_else(|| panic!("Socket file ({:?}) must end in a file name", self.0))
+                .to_owned();
+            s.push(".lock");
+            s
+        })
+    }
+}
+
+/// The first file descriptor systemd passes, see `sd_listen_fds(3)`.
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Whether systemd passed listening sockets to this process.
+fn has_inherited_listener() -> bool {
+    // the variables stay set, but our children see they are not meant for them
+    let for_us = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<i32>().ok())
+        == Some(nix::unistd::getpid().as_raw());
+    let fds = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|fds| fds.parse::<i32>().ok())
+        .unwrap_or(0);
+    for_us && fds >= 1
+}
+
+/// Set whether the socket systemd passed to this process survives `exec(2)`.
+fn set_cloexec(cloexec: bool) -> Result<(), BindError> {
+    let flags = if cloexec {
+        nix::fcntl::FdFlag::FD_CLOEXEC
+    } else {
+        nix::fcntl::FdFlag::empty()
+    };
+    nix::fcntl::fcntl(SD_LISTEN_FDS_START, nix::fcntl::FcntlArg::F_SETFD(flags))
+        .map(|_| ())
+        .map_err(BindError::Unix)
+}
+
+/// The listening socket systemd passed to this process, if any.
+///
+/// If systemd passed more than one, only the first is used.
+fn inherited_listener() -> Result<Option<UnixListener>, BindError> {
+    if !has_inherited_listener() {
+        return Ok(None);
+    }
+    // don’t leak the socket into the nix processes we start
+    set_cloexec(true)?;
+    // systemd hands us ownership of its listening sockets
+    Ok(Some(unsafe {
+        UnixListener::from_raw_fd(SD_LISTEN_FDS_START)
+    }))
+}
+
+/// Hand the socket systemd passed to this process on to the program
+/// it is about to `exec(2)` (if `keep`), or stop doing so again (if not).
+///
+/// `exec` keeps the pid, so the new program finds the socket in
+/// `bind_or_inherit` as long as `LISTEN_PID` and `LISTEN_FDS` stay set.
+/// Returns whether there is such a socket.
+pub fn keep_inherited_listener_on_exec(keep: bool) -> Result<bool, BindError> {
+    if !has_inherited_listener() {
+        return Ok(false);
+    }
+    set_cloexec(!keep)?;
+    Ok(true)
+}
+
+impl fmt::Display for SocketPath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.display())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lock_is_exclusive() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let p = AbsPathBuf::new(tempdir.path().join("socket")).unwrap();
+        let _lock = SocketPath(p.clone())
+            .lock()
+            .expect("first locking attempt should succeed");
+        assert!(
+            SocketPath(p).lock().is_err(),
+            "second locking attempt should fail because we still hold the lock"
+        );
+    }
+
+    #[test]
+    fn lock_holder_is_probed() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let socket = SocketPath(AbsPathBuf::new(tempdir.path().join("socket")).unwrap());
+        let own_pid = Some(nix::unistd::getpid().as_raw());
+
+        let lock = socket.lock().unwrap();
+        match socket.lock() {
+            Err(BindError::OtherProcessNotListening { pid, .. }) => assert_eq!(pid, own_pid),
+            other => panic!(
+                "expected the lock holder not to listen: {:?}",
+                other.map(|_| ())
+            ),
+        }
+        drop(lock);
+
+        let _bound = socket.bind().unwrap();
+        match socket.lock() {
+            Err(BindError::OtherProcessListening { pid, .. }) => assert_eq!(pid, own_pid),
+            other => panic!(
+                "expected the lock holder to listen: {:?}",
+                other.map(|_| ())
+            ),
+        }
+    }
+}
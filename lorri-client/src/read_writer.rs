@@ -201,10 +201,7 @@ impl<'a, R, W> ReadWriter<'a, R, W> {
     /// Check if the underlying socket timed out when serializing/deserializing.
     fn is_timed_out(e: &bincode::ErrorKind) -> bool {
         match e {
-            bincode::ErrorKind::Io(io) => match io.kind() {
-                std::io::ErrorKind::TimedOut => true,
-                _ => false,
-            },
+            bincode::ErrorKind::Io(io) => io.kind() == std::io::ErrorKind::TimedOut,
             _ => false,
         }
     }
@@ -252,9 +249,9 @@ impl<'a, R, W> ReadWriter<'a, R, W> {
 mod timeout {
     extern crate nix;
 
-    use self::nix::libc;
     use self::nix::poll;
     use super::{Millis, Timeout};
+    use std::os::raw::c_int;
     use std::os::unix::io::AsRawFd;
     use std::os::unix::net::UnixStream;
 
@@ -263,14 +260,14 @@ mod timeout {
     /// Copied from <https://docs.rs/crate/timeout-readwrite/0.2.0/source/src/utils.rs>
     /// written by Jonathan Creekmore and published under Apache-2.0.
     fn wait_until_ready<R: AsRawFd>(
-        timeout: libc::c_int,
+        timeout: c_int,
         to_fd: &R,
         events: poll::PollFlags,
     ) -> std::io::Result<()> {
         let mut pfd = poll::PollFd::new(to_fd.as_raw_fd(), events);
-        let mut s = unsafe { std::slice::from_raw_parts_mut(&mut pfd, 1) };
+        let s = unsafe { std::slice::from_raw_parts_mut(&mut pfd, 1) };
 
-        let retval = poll::poll(&mut s, timeout)
+        let retval = poll::poll(s, timeout)
             .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
         if retval == 0 {
             return Err(std::io::Error::new(
@@ -283,16 +280,16 @@ mod timeout {
 
     pub struct TimeoutReadWriter<'a> {
         socket: &'a UnixStream,
-        timeout: libc::c_int,
+        timeout: c_int,
     }
 
     /// Convert timeout to the form that `poll(2)` expects.
-    fn to_poll_2_timeout(t: Timeout) -> libc::c_int {
+    fn to_poll_2_timeout(t: Timeout) -> c_int {
         match t {
             // negative number is infinite timeout
             Timeout::Infinite => -1,
             // otherwise a duration in milliseconds
-            Timeout::D(Millis(u)) => libc::c_int::from(u),
+            Timeout::D(Millis(u)) => c_int::from(u),
         }
     }
 
@@ -0,0 +1,90 @@
+//! What the daemon knows about itself and its projects.
+
+use crate::event::Event;
+use crate::NixFile;
+use std::time::{Duration, SystemTime};
+
+/// The state of a project’s builds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BuildStatus {
+    /// A build is waiting in the build queue or running
+    Building,
+    /// The last build succeeded
+    Success,
+    /// The last build failed or timed out
+    Failure,
+}
+
+/// The state of the whole daemon, as replied to a ping.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DaemonStatus {
+    /// The `communicate::PROTOCOL_VERSION` of the daemon
+    pub protocol_version: u32,
+    /// How long the daemon has been running
+    pub uptime: Duration,
+    /// How many projects the daemon watches
+    pub projects: usize,
+    /// How many builds are running
+    pub building: usize,
+    /// How many builds wait in the build queue
+    pub queued: usize,
+}
+
+/// What the daemon knows about a project, as listed by `lorri ps`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProjectStatus {
+    /// The shell.nix file of the project
+    pub nix_file: NixFile,
+    /// The attribute of the nix file the project builds, if not the whole file
+    pub attr: Option<String>,
+    /// The state of its builds
+    pub status: BuildStatus,
+    /// When the current or last build started
+    pub build_started: SystemTime,
+    /// When the last build finished, `None` if none finished yet
+    pub last_build_finished: Option<SystemTime>,
+    /// How long the last finished build took
+    pub last_build_duration: Option<Duration>,
+}
+
+impl ProjectStatus {
+    /// The status after `ev` happened at `now`, `None` if `ev`
+    /// says nothing about the project’s builds.
+    pub fn update(
+        status: Option<&ProjectStatus>,
+        ev: &Event,
+        now: SystemTime,
+    ) -> Option<ProjectStatus> {
+        let (nix_file, attr, finished) = match ev {
+            Event::Started { nix_file, attr, .. } => {
+                return Some(ProjectStatus {
+                    nix_file: nix_file.clone(),
+                    attr: attr.clone(),
+                    status: BuildStatus::Building,
+                    build_started: now,
+                    last_build_finished: status.and_then(|s| s.last_build_finished),
+                    last_build_duration: status.and_then(|s| s.last_build_duration),
+                })
+            }
+            Event::Completed { nix_file, attr, .. } => (nix_file, attr, BuildStatus::Success),
+            Event::Failure { nix_file, attr, .. } | Event::BuildTimeout { nix_file, attr, .. } => {
+                (nix_file, attr, BuildStatus::Failure)
+            }
+            Event::SectionEnd
+            | Event::Queued { .. }
+            | Event::Removed { .. }
+            | Event::WatchLimitReached { .. }
+            | Event::Pushed { .. } => return None,
+        };
+        // a build can fail before it started, e.g. if the watcher couldn’t be set up
+        let build_started = status.map_or(now, |s| s.build_started);
+        Some(ProjectStatus {
+            nix_file: nix_file.clone(),
+            attr: attr.clone(),
+            status: finished,
+            build_started,
+            last_build_finished: Some(now),
+            last_build_duration: now.duration_since(build_started).ok(),
+        })
+    }
+}
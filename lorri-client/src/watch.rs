@@ -0,0 +1,80 @@
+//! The paths the daemon watches for a project.
+
+use std::path::{Path, PathBuf};
+
+/// Represents if a path to watch should be watched recursively by the watcher or not
+#[derive(Debug, Clone, Ord, PartialOrd, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub enum WatchPathBuf {
+    /// This path should be watched recursively. Equivalent to Normal for non-directory.
+    Recursive(PathBuf),
+    /// This path should not be watched recursively. For directories, only the list of files is
+    /// watched.
+    Normal(PathBuf),
+}
+
+impl AsRef<Path> for WatchPathBuf {
+    fn as_ref(&self) -> &Path {
+        match self {
+            WatchPathBuf::Recursive(path) => path.as_ref(),
+            WatchPathBuf::Normal(path) => path.as_ref(),
+        }
+    }
+}
+
+impl AsMut<PathBuf> for WatchPathBuf {
+    fn as_mut(&mut self) -> &mut PathBuf {
+        match self {
+            WatchPathBuf::Recursive(ref mut path) => path,
+            WatchPathBuf::Normal(ref mut path) => path,
+        }
+    }
+}
+
+impl WatchPathBuf {
+    /// Create a new WatchPathBuf of the same variant, but with this PathBuf instead.
+    pub fn replace(&self, path: PathBuf) -> WatchPathBuf {
+        match self {
+            WatchPathBuf::Normal(_) => WatchPathBuf::Normal(path),
+            WatchPathBuf::Recursive(_) => WatchPathBuf::Recursive(path),
+        }
+    }
+}
+
+/// Why a path is watched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WatchReason {
+    /// It is the project’s nix file.
+    NixFile,
+    /// The evaluation of the nix file read it.
+    Evaluation,
+    /// It is the project’s `.lorri.toml`.
+    ProjectConfig,
+    /// A `watch` pattern of the project’s `.lorri.toml` matches it.
+    ConfiguredWatch,
+    /// A channel of the `NIX_PATH` is switched there.
+    NixPath,
+    /// It is a `path` input of the project’s flake.
+    FlakeInput,
+}
+
+impl std::fmt::Display for WatchReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            WatchReason::NixFile => "nix file",
+            WatchReason::Evaluation => "read by evaluation",
+            WatchReason::ProjectConfig => "project config",
+            WatchReason::ConfiguredWatch => "watch in .lorri.toml",
+            WatchReason::NixPath => "NIX_PATH",
+            WatchReason::FlakeInput => "flake input",
+        })
+    }
+}
+
+/// A path which is watched, and why.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WatchedPath {
+    /// The path, and whether it is watched recursively.
+    pub path: WatchPathBuf,
+    /// Why it is watched.
+    pub reason: WatchReason,
+}
@@ -139,7 +139,7 @@ let
       test = writeCargo "cargo-test"
         # the tests need bash and nix and direnv
         (pathPrependBins [ pkgs.coreutils pkgs.bash pkgs.nix pkgs.direnv ])
-        [ "test" "--no-fail-fast" ];
+        [ "test" "--workspace" "--no-fail-fast" ];
     };
 
     cargo-clippy = {
@@ -154,7 +154,24 @@ let
         runWithoutNetwork
         "if" [ "cargo-clippy" "--version" ]
         "export" "RUSTFLAGS" "-D warnings"
-      ] [ "clippy" "--offline" ];
+      ] [ "clippy" "--workspace" "--offline" ];
+    };
+
+    # the `nix-daemon` feature talks to the nix daemon’s socket itself,
+    # which is off by default and would otherwise go unchecked
+    cargo-nix-daemon = {
+      description = "build, clippy and test with --features nix-daemon";
+      test = allCommandsSucceed "cargo-nix-daemon" [
+        (writeCargo "cargo-build-nix-daemon" []
+          [ "build" "--features" "nix-daemon" ])
+        (writeCargo "cargo-clippy-nix-daemon" [
+          "export" "RUSTFLAGS" "-D warnings"
+        ] [ "clippy" "--all-targets" "--features" "nix-daemon" ])
+        (writeCargo "cargo-test-nix-daemon"
+          # the tests need bash and nix and direnv
+          (pathPrependBins [ pkgs.coreutils pkgs.bash pkgs.nix pkgs.direnv ])
+          [ "test" "--no-fail-fast" "--features" "nix-daemon" ])
+      ];
     };
 
     # TODO: it would be good to sandbox this (it changes files in the tree)
@@ -1,122 +1,68 @@
 //! Uses `builder` and filesystem watch code to repeatedly
 //! evaluate and build a given Nix file.
 
-use crate::builder::{self, BuildError};
+use crate::builder::{self, BuildError, LogLine};
+use crate::daemon::build_queue::{BuildQueue, Permit};
 use crate::daemon::LoopHandlerEvent;
+use crate::daemon::{hook, push};
+use crate::environment::{self, EnvDiff};
+use crate::logging::rotate::{LogRotation, RotatingFile};
 use crate::nix::options::NixOptions;
 use crate::pathreduction::reduce_paths;
+use crate::project::config::{Notify, ProjectConfig};
+use crate::project::history::{self, BuildRecord, Outcome};
+use crate::project::status::{self, State, Status};
 use crate::project::{self, Project};
 use crate::run_async::Async;
-use crate::watch::{Watch, WatchPathBuf};
-use crate::NixFile;
+use crate::watch::{SharedWatcher, Watch, WatchPathBuf, WatchReason, WatchedPath};
+use crate::{AbsPathBuf, NixFile};
 use anyhow::{anyhow, Context};
 use crossbeam_channel as chan;
 use slog::debug;
-use std::path::PathBuf;
-
-/// Build events that can happen.
-/// Abstracting over its internal to make different serialize instances possible.
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub enum EventI<NixFile, Reason, OutputPath, BuildError> {
-    /// Demarks a stream of events from recent history becoming live
-    SectionEnd,
-    /// A build has started
-    Started {
-        /// The shell.nix file for the building project
-        nix_file: NixFile,
-        /// The reason the build started
-        reason: Reason,
-    },
-    /// A build completed successfully
-    Completed {
-        /// The shell.nix file for the building project
-        nix_file: NixFile,
-        /// the output paths of the build
-        rooted_output_paths: OutputPath,
-    },
-    /// A build command returned a failing exit status
-    Failure {
-        /// The shell.nix file for the building project
-        nix_file: NixFile,
-        /// The error that exited the build
-        failure: BuildError,
-    },
-}
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-/// Builder events sent back over `BuildLoop.tx`.
-pub type Event = EventI<NixFile, Reason, builder::OutputPath<project::RootPath>, BuildError>;
-
-impl<NixFile, Reason, OutputPath, BuildError> EventI<NixFile, Reason, OutputPath, BuildError> {
-    /// Map over the inner types.
-    pub fn map<F, G, H, I, NixFile2, Reason2, OutputPaths2, BuildError2>(
-        self,
-        nix_file_f: F,
-        reason_f: G,
-        output_paths_f: H,
-        build_error_f: I,
-    ) -> EventI<NixFile2, Reason2, OutputPaths2, BuildError2>
-    where
-        F: Fn(NixFile) -> NixFile2,
-        G: Fn(Reason) -> Reason2,
-        H: Fn(OutputPath) -> OutputPaths2,
-        I: Fn(BuildError) -> BuildError2,
-    {
-        use EventI::*;
-        match self {
-            SectionEnd => SectionEnd,
-            Started { nix_file, reason } => Started {
-                nix_file: nix_file_f(nix_file),
-                reason: reason_f(reason),
-            },
-            Completed {
-                nix_file,
-                rooted_output_paths,
-            } => Completed {
-                nix_file: nix_file_f(nix_file),
-                rooted_output_paths: output_paths_f(rooted_output_paths),
-            },
-            Failure { nix_file, failure } => Failure {
-                nix_file: nix_file_f(nix_file),
-                failure: build_error_f(failure),
-            },
-        }
-    }
-}
+pub use lorri_client::event::{Event, EventI, LogEvent, ReasonI};
 
-/// Description of the project change that triggered a build.
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub enum ReasonI<NixFile> {
-    /// When a project is presented to Lorri to track, it's built for this reason.
-    ProjectAdded(NixFile),
-    /// When a ping is received.
-    PingReceived,
-    /// When there is a filesystem change, the first changed file is recorded,
-    /// along with a count of other filesystem events.
-    FilesChanged(Vec<PathBuf>),
-}
+/// How long to wait before building a project again after its build timed out.
+/// Doubles with every further timeout, up to `MAX_TIMEOUT_BACKOFF`.
+const TIMEOUT_BACKOFF: Duration = Duration::from_secs(60);
+const MAX_TIMEOUT_BACKOFF: Duration = Duration::from_secs(60 * 60);
 
-impl<NixFile> ReasonI<NixFile> {
-    /// Map over the inner types.
-    pub fn map<F, NixFile2>(self, nix_file_f: F) -> ReasonI<NixFile2>
-    where
-        F: Fn(NixFile) -> NixFile2,
-    {
-        use ReasonI::*;
-        match self {
-            ProjectAdded(nix_file) => ProjectAdded(nix_file_f(nix_file)),
-            PingReceived => PingReceived,
-            FilesChanged(vec) => FilesChanged(vec),
-        }
-    }
-}
+/// How long to wait before building a project again after its build failed,
+/// so that a broken project isn’t rebuilt on every keystroke.
+/// Doubles with every further failure, up to `MAX_FAILURE_BACKOFF`.
+const FAILURE_BACKOFF: Duration = Duration::from_secs(2);
+const MAX_FAILURE_BACKOFF: Duration = Duration::from_secs(2 * 60);
+
+/// When a project’s build log is rotated: it only has to hold
+/// the last few builds, but there is one for every project.
+const BUILD_LOG_ROTATION: LogRotation = LogRotation {
+    max_size_mb: Some(1),
+    max_age_days: None,
+    keep: 1,
+};
+
+/// How long no files may change before a build starts, by default.
+const DEFAULT_DEBOUNCE: Duration = Duration::from_millis(100);
 
 type Reason = ReasonI<NixFile>;
 
 /// The BuildLoop repeatedly builds the Nix expression in
 /// `project` each time a source file influencing
 /// a previous build changes.
-/// If a build is ongoing, it will finish the build first.
-/// If there was intermediate requests for new builds, it will schedule a build to be run right after.
+/// If a build is ongoing, it is cancelled and restarted with the latest changes
+/// (or, if `cancel_builds` is disabled, finished first,
+/// with a build scheduled to be run right after).
+/// File changes are batched until none happened for `debounce`.
+/// With a `build_queue`, builds wait for their turn before they start.
+/// After a build times out or fails, the next one waits for an increasing backoff,
+/// unless it is requested explicitly by a ping.
 /// Additionally, we create GC roots for the build results.
 pub struct BuildLoop<'a> {
     /// Project to be built.
@@ -126,34 +72,133 @@ pub struct BuildLoop<'a> {
     /// Watches all input files for changes.
     /// As new input files are discovered, they are added to the watchlist.
     watch: Watch,
+    /// Everything `watch` watches, and why.
+    watched: BTreeMap<WatchPathBuf, WatchReason>,
     user: project::Username,
     logger: slog::Logger,
+    /// Whether a running build is killed when a new build is requested.
+    pub cancel_builds: bool,
+    /// If set, builds wait for their turn in this queue before they start.
+    pub build_queue: Option<BuildQueue>,
+    /// Whether to build locally when the remote builders fail.
+    pub local_build_fallback: bool,
+    /// Whether to skip paths ignored by the `.gitignore` and `.ignore`
+    /// files of watched directories.
+    pub respect_ignore_files: bool,
+    /// How long no files may change before a build starts,
+    /// unless the project’s `.lorri.toml` sets `debounce_ms`.
+    pub debounce: Duration,
+    /// If set, files which can’t be watched because the watch limit
+    /// is reached are polled in this interval instead.
+    pub poll_fallback: Option<Duration>,
+    /// The values of the project’s `pass_env` variables for the evaluation,
+    /// as the client which last entered the project sent them.
+    pub passed_env: Arc<Mutex<BTreeMap<String, String>>>,
+    /// Whether builds are paused: requested builds wait until they are resumed.
+    pub paused: bool,
+    /// Pauses (`true`) and resumes (`false`) the builds.
+    pub rx_pause: chan::Receiver<bool>,
+    /// Whether the next build may reuse the result of an earlier
+    /// daemon run (only true for the first build of the loop).
+    use_eval_cache: std::cell::Cell<bool>,
+    /// The hashes of the inputs of the last successful build, to skip
+    /// rebuilds when files changed but their contents did not.
+    /// `None` if a build failed since, or files changed during the build.
+    input_hashes: Option<BTreeMap<WatchPathBuf, Option<String>>>,
+    /// The files which changed while the running build evaluated.
+    changed_while_building: Vec<PathBuf>,
+    /// How many builds in a row timed out.
+    consecutive_timeouts: u32,
+    /// How many builds in a row failed.
+    consecutive_failures: u32,
+    /// Why the latest build was requested, for the project’s history.
+    requested_reason: RefCell<Option<Reason>>,
+    /// The project’s `.lorri.toml` as the latest build read it,
+    /// which the later stages of that build use as well.
+    config: RefCell<ProjectConfig>,
+    /// The project’s build log, if it could be opened.
+    build_log: RefCell<Option<RotatingFile>>,
+    /// The log output of all builds, which `forever` passes on.
+    tx_log_lines: chan::Sender<LogLine>,
+    rx_log_lines: chan::Receiver<LogLine>,
 }
 
 enum BuildState {
     /// No build is currently running.
     NotRunning,
+    /// A build is waiting for its turn in the build queue.
+    Queued(chan::Receiver<Permit>),
     /// A build is running.
-    Running(Async<BuildResult>),
+    Running(RunningBuild),
     /// A build is running and another build is scheduled to run immediately after it finishes.
-    RunningAndScheduled(Async<BuildResult>),
+    RunningAndScheduled(RunningBuild),
+    /// The last build timed out or failed, so the next one waits until `rx_done` fires.
+    BackingOff {
+        rx_done: chan::Receiver<Instant>,
+        /// Whether a build was requested in the meantime.
+        scheduled: bool,
+    },
 }
 type BuildResult = Result<builder::RunResult, BuildError>;
 
+struct RunningBuild {
+    result: Async<BuildResult>,
+    cancellation: builder::Cancellation,
+    /// Keeps the build’s slot in the build queue.
+    permit: Option<Permit>,
+    /// When the build started.
+    started: SystemTime,
+    /// Why it started, see `Reason::describe`.
+    reason: String,
+}
+
 impl BuildState {
     fn result_chan(&self) -> chan::Receiver<BuildResult> {
         match self {
-            Self::NotRunning => chan::never(),
-            Self::Running(build) => build.chan(),
-            Self::RunningAndScheduled(build) => build.chan(),
+            Self::NotRunning | Self::Queued(_) | Self::BackingOff { .. } => chan::never(),
+            Self::Running(build) => build.result.chan(),
+            Self::RunningAndScheduled(build) => build.result.chan(),
         }
     }
 
+    /// The running build, if there is one.
+    fn running(&self) -> Option<&RunningBuild> {
+        match self {
+            Self::Running(build) | Self::RunningAndScheduled(build) => Some(build),
+            Self::NotRunning | Self::Queued(_) | Self::BackingOff { .. } => None,
+        }
+    }
+
+    fn permit_chan(&self) -> chan::Receiver<Permit> {
+        match self {
+            Self::Queued(rx_permit) => rx_permit.clone(),
+            _ => chan::never(),
+        }
+    }
+
+    fn backoff_chan(&self) -> chan::Receiver<Instant> {
+        match self {
+            Self::BackingOff { rx_done, .. } => rx_done.clone(),
+            _ => chan::never(),
+        }
+    }
+
+    /// Wait for `backoff` before the next build (if one is scheduled) starts.
+    fn back_off(&mut self, backoff: Duration) {
+        let scheduled = matches!(self, Self::RunningAndScheduled(_));
+        *self = Self::BackingOff {
+            rx_done: chan::after(backoff),
+            scheduled,
+        };
+    }
+
     fn display_status(&self) -> &str {
         match self {
             Self::NotRunning => "not running",
+            Self::Queued(_) => "queued",
             Self::Running(_) => "running",
             Self::RunningAndScheduled(_) => "running and scheduled",
+            Self::BackingOff { .. } => "backing off",
         }
     }
 }
@@ -164,74 +209,256 @@ impl<'a> BuildLoop<'a> {
     ///
     /// Will start by only watching the project’s nix file,
     /// and then add new files after each nix run.
+    ///
+    /// With a `poll_interval` (or if the project’s `.lorri.toml` sets
+    /// `poll_interval_secs`), the files are checked for changes in that interval,
+    /// for file systems which don’t report changes.
     pub fn new(
         project: &'a Project,
         extra_nix_options: NixOptions,
+        poll_interval: Option<Duration>,
+        user: project::Username,
+        logger: slog::Logger,
+    ) -> anyhow::Result<BuildLoop<'a>> {
+        Self::new_with_watcher(
+            project,
+            extra_nix_options,
+            poll_interval,
+            None,
+            user,
+            logger,
+        )
+    }
+
+    /// Like `new`, but unless they are polled, the files are watched with
+    /// `shared`, so that files the daemon’s projects have in common are
+    /// watched once.
+    pub fn new_with_watcher(
+        project: &'a Project,
+        extra_nix_options: NixOptions,
+        poll_interval: Option<Duration>,
+        shared: Option<&SharedWatcher>,
         user: project::Username,
         logger: slog::Logger,
     ) -> anyhow::Result<BuildLoop<'a>> {
-        let mut watch = Watch::try_new(logger.clone()).map_err(|err| anyhow!(err))?;
-        watch
-            .extend(vec![WatchPathBuf::Normal(
-                project.nix_file.as_absolute_path().to_owned(),
-            )])
-            .with_context(|| {
-                format!(
-                    "Failed to add nix path to watcher for nix file {}",
-                    project.nix_file.display()
-                )
-            })?;
+        let config = load_config(project);
+        let poll_interval = config
+            .poll_interval_secs
+            .map(Duration::from_secs)
+            .or(poll_interval);
+        let mut watch = match (poll_interval, shared) {
+            (None, Some(shared)) => Ok(Watch::new_shared(shared, logger.clone())),
+            (None, None) => Watch::try_new(logger.clone()),
+            (Some(interval), _) => Watch::try_new_polling(interval, logger.clone()),
+        }
+        .map_err(|err| anyhow!(err))?;
+        watch.set_excludes(project_dir(project), &config.exclude_patterns());
+        let nix_file_path = WatchPathBuf::Normal(project.nix_file.as_absolute_path().to_owned());
+        watch.extend(vec![nix_file_path.clone()]).with_context(|| {
+            format!(
+                "Failed to add nix path to watcher for nix file {}",
+                project.nix_file.display()
+            )
+        })?;
 
+        let build_log =
+            RotatingFile::open(project.build_log().as_path().to_owned(), BUILD_LOG_ROTATION)
+                .map_err(|e| debug!(logger, "could not open the build log"; "error" => %e))
+                .ok();
+        let (tx_log_lines, rx_log_lines) = chan::unbounded();
         Ok(BuildLoop {
             project,
             extra_nix_options,
             watch,
+            watched: std::iter::once((nix_file_path, WatchReason::NixFile)).collect(),
             user,
             logger,
+            cancel_builds: true,
+            build_queue: None,
+            local_build_fallback: false,
+            respect_ignore_files: true,
+            debounce: DEFAULT_DEBOUNCE,
+            poll_fallback: None,
+            passed_env: Arc::new(Mutex::new(BTreeMap::new())),
+            paused: false,
+            rx_pause: chan::never(),
+            use_eval_cache: std::cell::Cell::new(true),
+            input_hashes: None,
+            changed_while_building: vec![],
+            consecutive_timeouts: 0,
+            consecutive_failures: 0,
+            requested_reason: RefCell::new(None),
+            config: RefCell::new(config),
+            build_log: RefCell::new(build_log),
+            tx_log_lines,
+            rx_log_lines,
         })
     }
 
     /// Loop forever, watching the filesystem for changes. Blocks.
     /// Sends `Event`s over `Self.tx` once they happen.
     /// When new filesystem changes are detected while a build is
-    /// still running, it is finished first before starting a new build.
-    pub fn forever(
-        &mut self,
-        tx: chan::Sender<LoopHandlerEvent>,
-        rx_ping: chan::Receiver<()>,
-    ) -> crate::Never {
+    /// still running, it is cancelled and a new build is started
+    /// (unless `cancel_builds` is disabled, then it is finished first).
+    ///
+    /// Returns once `rx_ping` (or `rx_pause`) is disconnected,
+    /// after cancelling the running build.
+    pub fn forever(&mut self, tx: chan::Sender<LoopHandlerEvent>, rx_ping: chan::Receiver<()>) {
         let mut current_build = BuildState::NotRunning;
+        // whether we were told to stop
+        let mut stopped = false;
+        // file changes which wait for the debounce window to pass
+        let mut pending_changes: Option<(chan::Receiver<Instant>, Vec<PathBuf>)> = None;
+        // why a build was requested while the builds were paused
+        let mut deferred: Option<Reason> = None;
+        // file changes during a build which might have been made by the
+        // project’s `pre_eval` hook, decided on once the build is done
+        let mut held_back: Vec<PathBuf> = vec![];
+        // changed files whose contents are being compared to the last build’s inputs
+        let mut checking: Option<(Async<bool>, Vec<PathBuf>)> = None;
         let rx_watcher = self.watch.rx.clone();
+        let rx_pause = self.rx_pause.clone();
+        let rx_log_lines = self.rx_log_lines.clone();
+        self.send_watched_paths(&tx);
 
         loop {
             debug!(self.logger, "looping build_loop";
                    "current_build" => current_build.display_status(),
                    "project" => &self.project.nix_file);
             let rx_current_build = current_build.result_chan();
+            let rx_permit = current_build.permit_chan();
+            let rx_backoff = current_build.backoff_chan();
+            let rx_debounce = pending_changes
+                .as_ref()
+                .map_or_else(chan::never, |(rx_done, _)| rx_done.clone());
+            let rx_checked = checking
+                .as_ref()
+                .map_or_else(chan::never, |(check, _)| check.chan());
 
             let send = |msg| {
                 tx.send(LoopHandlerEvent::BuildEvent(msg))
                     .expect("Failed to send an event")
             };
+            // TODO: this is not a started, this is just a scheduled!
+            let request_build = |this: &Self,
+                                 current_build: &mut BuildState,
+                                 deferred: &mut Option<Reason>,
+                                 reason: Reason| {
+                if this.paused {
+                    debug!(this.logger, "deferring build while paused"; "project" => &this.project.nix_file);
+                    *deferred = Some(match (deferred.take(), reason) {
+                        (Some(Reason::FilesChanged(mut before)), Reason::FilesChanged(changed)) => {
+                            before.extend(changed);
+                            before.sort();
+                            before.dedup();
+                            Reason::FilesChanged(before)
+                        }
+                        (_, reason) => reason,
+                    });
+                } else {
+                    *this.requested_reason.borrow_mut() = Some(reason.clone());
+                    this.write_status(State::Building);
+                    send(Event::Started {
+                        nix_file: this.project.nix_file.clone(),
+                        attr: this.project.attr.clone(),
+                        reason,
+                    });
+                    this.schedule_build(current_build)
+                }
+            };
 
             chan::select! {
 
                 // build finished
                 recv(rx_current_build) -> msg => match msg {
+                    Ok(Err(BuildError::Timeout { timeout_secs })) => {
+                        if let Some(build) = current_build.running() {
+                            self.record_build(build, Outcome::Timeout, None);
+                        }
+                        self.notify_finished(Outcome::Timeout, true);
+                        self.run_post_build(Outcome::Timeout, None);
+                        self.write_status(State::Timeout);
+                        let backoff = self.timeout_backoff();
+                        current_build.back_off(backoff);
+                        send(Event::BuildTimeout {
+                            nix_file: self.project.nix_file.clone(),
+                            attr: self.project.attr.clone(),
+                            timeout_secs,
+                            backoff_secs: backoff.as_secs(),
+                        });
+                        // the timed out build hashed no inputs, so the files
+                        // held back meanwhile might have changed after it read them
+                        self.changed_while_building.clear();
+                        let changed = held_back_changes(std::mem::take(&mut held_back), &BTreeMap::new());
+                        if !changed.is_empty() {
+                            request_build(self, &mut current_build, &mut deferred, Reason::FilesChanged(changed))
+                        }
+                    }
                     Ok(run_result) => {
-                        self.start_if_scheduled_or_stop(&mut current_build);
+                        let hashes_before_eval = run_result
+                            .as_ref()
+                            .map(|run_result| run_result.hashes_before_eval.clone())
+                            .unwrap_or_default();
+                        let was_failing = self.consecutive_failures > 0 || self.consecutive_timeouts > 0;
+                        self.consecutive_timeouts = 0;
+                        // the environment before this build, to tell what changed
+                        let previous = std::fs::read_link(
+                            self.project.root_paths().shell_gc_root.0.as_path(),
+                        )
+                        .ok();
+                        let result = self.handle_run_result(run_result);
+                        let (outcome, out_path) = match &result {
+                            Ok(paths) => (
+                                Outcome::Success,
+                                std::fs::read_link(paths.shell_gc_root.0.as_path()).ok(),
+                            ),
+                            Err(_) => (Outcome::Failure, None),
+                        };
+                        if let Some(build) = current_build.running() {
+                            self.record_build(build, outcome, out_path.clone());
+                        }
+                        self.notify_finished(outcome, was_failing);
+                        self.run_post_build(outcome, out_path.as_deref());
+                        self.write_status(if result.is_ok() { State::Success } else { State::Failure });
+                        // the build might have hashed the files after they changed,
+                        // unless they were the same before the evaluation read them
+                        let changed = std::mem::take(&mut self.changed_while_building);
+                        if !unchanged_since(&hashes_before_eval, &changed) {
+                            self.input_hashes = None;
+                        }
+                        if result.is_ok() {
+                            self.consecutive_failures = 0;
+                            self.start_if_scheduled_or_stop(&mut current_build);
+                        } else {
+                            let backoff = self.failure_backoff();
+                            debug!(self.logger, "backing off after failed build"; "project" => &self.project.nix_file, "backoff" => ?backoff);
+                            current_build.back_off(backoff);
+                        }
+                        if !held_back.is_empty() {
+                            let changed = held_back_changes(std::mem::take(&mut held_back), &hashes_before_eval);
+                            if changed.is_empty() {
+                                debug!(self.logger, "skipping rebuild, the files changed before the evaluation read them"; "project" => &self.project.nix_file);
+                            } else {
+                                request_build(self, &mut current_build, &mut deferred, Reason::FilesChanged(changed))
+                            }
+                        }
 
-                        match self.handle_run_result(run_result) {
+                        match result {
                             Ok(rooted_output_paths) => {
+                                let changes = self.env_changes(previous, &rooted_output_paths);
+                                self.push(&rooted_output_paths, &tx);
                                 send(Event::Completed {
                                     nix_file: self.project.nix_file.clone(),
+                                    attr: self.project.attr.clone(),
                                     rooted_output_paths,
+                                    changes,
                                 });
                             }
                             Err(e) => {
                                 if e.is_actionable() {
                                     send(Event::Failure {
                                         nix_file: self.project.nix_file.clone(),
+                                        attr: self.project.attr.clone(),
                                         failure: e,
                                     })
                                 } else {
@@ -239,22 +466,60 @@ impl<'a> BuildLoop<'a> {
                                 }
                             }
                         }
+                        if self.watch.take_watch_limit_reached() {
+                            send(Event::WatchLimitReached {
+                                nix_file: self.project.nix_file.clone(),
+                                attr: self.project.attr.clone(),
+                                polling: self.poll_fallback.is_some(),
+                            });
+                        }
+                        self.send_watched_paths(&tx);
                     },
                     Err(chan::RecvError) =>
                         debug!(self.logger, "current build async chan was disconnected"; "project" => &self.project.nix_file)
                 },
 
+                // nix printed something
+                recv(rx_log_lines) -> msg => match msg {
+                    Ok(line) => {
+                        self.write_build_log(line.0.as_bytes());
+                        tx.send(LoopHandlerEvent::BuildLog {
+                            nix_file: self.project.nix_file.clone(),
+                            attr: self.project.attr.clone(),
+                            line,
+                        }).expect("Failed to send a log line")
+                    },
+                    Err(chan::RecvError) =>
+                        debug!(self.logger, "log chan was disconnected"; "project" => &self.project.nix_file)
+                },
+
+                // our turn in the build queue
+                recv(rx_permit) -> msg => match msg {
+                    Ok(permit) => current_build = BuildState::Running(self.start_build(Some(permit))),
+                    Err(chan::RecvError) =>
+                        debug!(self.logger, "build queue chan was disconnected"; "project" => &self.project.nix_file)
+                },
+
+                // the backoff after a timed out or failed build is over
+                recv(rx_backoff) -> _ => {
+                    current_build = match std::mem::replace(&mut current_build, BuildState::NotRunning) {
+                        BuildState::BackingOff { scheduled: true, .. } => self.queue_build(),
+                        _ => BuildState::NotRunning,
+                    }
+                },
+
                 // watcher found file change
                 recv(rx_watcher) -> msg => match msg {
                     Ok(msg) => {
                         match self.watch.process(msg) {
                             Some(changed) => {
-                                // TODO: this is not a started, this is just a scheduled!
-                                send(Event::Started {
-                                    nix_file: self.project.nix_file.clone(),
-                                    reason: Reason::FilesChanged(changed)
-                                });
-                                self.schedule_build(&mut current_build)
+                                if let BuildState::Running(_) | BuildState::RunningAndScheduled(_) = current_build {
+                                    self.changed_while_building.extend(changed.iter().cloned());
+                                }
+                                // wait for the files to settle, starting over with every change
+                                let mut paths = pending_changes.take().map_or_else(Vec::new, |(_, paths)| paths);
+                                paths.extend(changed);
+                                pending_changes = Some((chan::after(self.debounce()), paths));
                             },
                             // No relevant file events
                             None => {}
@@ -264,29 +529,115 @@ impl<'a> BuildLoop<'a> {
                         debug!(self.logger, "notify chan was disconnected"; "project" => &self.project.nix_file)
                 },
 
+                // no files changed for the debounce window
+                recv(rx_debounce) -> _ => {
+                    if let Some((_, mut changed)) = pending_changes.take() {
+                        // files whose check is still running are checked again
+                        if let Some((_, before)) = checking.take() {
+                            changed.extend(before);
+                        }
+                        changed.sort();
+                        changed.dedup();
+                        if let Some(check) = self.check_contents(&current_build, &changed) {
+                            checking = Some((check, changed));
+                        } else if current_build.running().is_some() && self.has_pre_eval() {
+                            // rebuilding for the files the hook writes would never stop
+                            debug!(self.logger, "deciding about the changed files after the build"; "project" => &self.project.nix_file, "changed" => changed.len());
+                            held_back.extend(changed);
+                        } else {
+                            request_build(self, &mut current_build, &mut deferred, Reason::FilesChanged(changed))
+                        }
+                    }
+                },
+
+                // the contents of the changed files were compared
+                recv(rx_checked) -> msg => {
+                    if let Some((_, changed)) = checking.take() {
+                        if matches!(msg, Ok(true)) {
+                            debug!(self.logger, "skipping rebuild, the contents of the changed files are the same"; "project" => &self.project.nix_file, "changed" => changed.len());
+                        } else if current_build.running().is_some() && self.has_pre_eval() {
+                            debug!(self.logger, "deciding about the changed files after the build"; "project" => &self.project.nix_file, "changed" => changed.len());
+                            held_back.extend(changed);
+                        } else {
+                            request_build(self, &mut current_build, &mut deferred, Reason::FilesChanged(changed))
+                        }
+                    }
+                },
+
                 // we were pinged
                 recv(rx_ping) -> msg => match msg {
                     Ok(()) => {
-                        // TODO: this is not a started, this is just a scheduled!
-                        send(Event::Started{
-                            nix_file: self.project.nix_file.clone(),
-                            reason: Reason::PingReceived
-                        });
-                        self.schedule_build(&mut current_build)
+                        // an explicit request doesn’t wait for the backoff
+                        self.consecutive_timeouts = 0;
+                        self.consecutive_failures = 0;
+                        if let BuildState::BackingOff { .. } = current_build {
+                            current_build = BuildState::NotRunning;
+                        }
+                        request_build(self, &mut current_build, &mut deferred, Reason::PingReceived)
                     },
-                    Err(chan::RecvError) =>
-                        debug!(self.logger, "ping chan was disconnected"; "project" => &self.project.nix_file)
+                    Err(chan::RecvError) => {
+                        debug!(self.logger, "ping chan was disconnected"; "project" => &self.project.nix_file);
+                        stopped = true;
+                    }
+                },
+
+                // we were paused or resumed
+                recv(rx_pause) -> msg => match msg {
+                    Ok(paused) => {
+                        debug!(self.logger, "pause state changed"; "project" => &self.project.nix_file, "paused" => paused);
+                        self.paused = paused;
+                        if let Some(reason) = deferred.take() {
+                            request_build(self, &mut current_build, &mut deferred, reason)
+                        }
+                    },
+                    Err(chan::RecvError) => {
+                        debug!(self.logger, "pause chan was disconnected"; "project" => &self.project.nix_file);
+                        stopped = true;
+                    }
                 }
             };
+
+            if stopped {
+                match current_build {
+                    BuildState::Running(build) | BuildState::RunningAndScheduled(build) => {
+                        debug!(self.logger, "cancelling build of stopped project"; "project" => &self.project.nix_file);
+                        build.cancellation.cancel();
+                        // wait for the killed nix processes
+                        drop(build.result);
+                    }
+                    BuildState::NotRunning
+                    | BuildState::Queued(_)
+                    | BuildState::BackingOff { .. } => {}
+                }
+                return;
+            }
         }
     }
 
     /// Schedule a build to be run as soon as possible.
     fn schedule_build(&self, current_build: &mut BuildState) {
         *current_build = match std::mem::replace(current_build, BuildState::NotRunning) {
-            BuildState::NotRunning => BuildState::Running(self.start_build()),
+            BuildState::NotRunning => self.queue_build(),
+            // the queued build has not started yet, so it will see the latest changes
+            BuildState::Queued(rx_permit) => BuildState::Queued(rx_permit),
+            BuildState::Running(build) | BuildState::RunningAndScheduled(build)
+                if self.cancel_builds =>
+            {
+                debug!(self.logger, "cancelling outdated build"; "project" => &self.project.nix_file);
+                self.record_build(&build, Outcome::Cancelled, None);
+                build.cancellation.cancel();
+                // wait for the killed nix processes, its result is thrown away
+                drop(build.result);
+                self.reload_config();
+                // the new build takes over the slot in the build queue
+                BuildState::Running(self.start_build(build.permit))
+            }
             BuildState::Running(build) => BuildState::RunningAndScheduled(build),
             BuildState::RunningAndScheduled(build) => BuildState::RunningAndScheduled(build),
+            BuildState::BackingOff { rx_done, .. } => BuildState::BackingOff {
+                rx_done,
+                scheduled: true,
+            },
         }
     }
 
@@ -294,34 +645,274 @@ impl<'a> BuildLoop<'a> {
     fn start_if_scheduled_or_stop(&self, current_build: &mut BuildState) {
         *current_build = match std::mem::replace(current_build, BuildState::NotRunning) {
             BuildState::NotRunning => BuildState::NotRunning,
+            BuildState::Queued(rx_permit) => BuildState::Queued(rx_permit),
             BuildState::Running(_) => BuildState::NotRunning,
-            BuildState::RunningAndScheduled(_) => BuildState::Running(self.start_build()),
+            BuildState::BackingOff { rx_done, scheduled } => {
+                BuildState::BackingOff { rx_done, scheduled }
+            }
+            BuildState::RunningAndScheduled(build) => {
+                // give up our slot first, so that other projects get their turn
+                drop(build);
+                self.queue_build()
+            }
+        }
+    }
+
+    /// How long to wait after another timed out build.
+    fn timeout_backoff(&mut self) -> Duration {
+        let backoff = TIMEOUT_BACKOFF * (1 << std::cmp::min(self.consecutive_timeouts, 6));
+        self.consecutive_timeouts = self.consecutive_timeouts.saturating_add(1);
+        std::cmp::min(backoff, MAX_TIMEOUT_BACKOFF)
+    }
+
+    /// How long to wait after another failed build.
+    fn failure_backoff(&mut self) -> Duration {
+        let backoff = FAILURE_BACKOFF * (1 << std::cmp::min(self.consecutive_failures, 6));
+        self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+        std::cmp::min(backoff, MAX_FAILURE_BACKOFF)
+    }
+
+    /// Find out in the background whether the `changed` files (e.g. touched
+    /// by a `git checkout`) still have the contents the last successful build read,
+    /// since hashing big inputs takes a while.
+    /// Files which are not inputs of that build always count as changed.
+    /// `None` if there is nothing to compare them to.
+    fn check_contents(
+        &self,
+        current_build: &BuildState,
+        changed: &[PathBuf],
+    ) -> Option<Async<bool>> {
+        match (current_build, &self.input_hashes) {
+            (BuildState::NotRunning, Some(input_hashes)) => {
+                let input_hashes = input_hashes.clone();
+                let changed = changed.to_vec();
+                // dropping an outdated check must not wait for it
+                Some(Async::run_and_linger(&self.logger, move || {
+                    unchanged_since(&input_hashes, &changed)
+                }))
+            }
+            _ => None,
+        }
+    }
+
+    /// Read the project’s `.lorri.toml` again for the build which starts next.
+    fn reload_config(&self) {
+        *self.config.borrow_mut() = load_config(self.project);
+    }
+
+    /// Whether the project’s `.lorri.toml` sets a `pre_eval` hook.
+    fn has_pre_eval(&self) -> bool {
+        self.config.borrow().pre_eval.is_some()
+    }
+
+    /// Start a build, or wait for our turn if there is a build queue
+    /// (unless the project is configured to skip it).
+    fn queue_build(&self) -> BuildState {
+        self.reload_config();
+        let config = self.config.borrow();
+        match build_queue_for(self.build_queue.as_ref(), &config) {
+            Some(queue) => BuildState::Queued(queue.enqueue(
+                (self.project.nix_file.clone(), self.project.attr.clone()),
+                self.dependencies(&config),
+            )),
+            None => BuildState::Running(self.start_build(None)),
+        }
+    }
+
+    /// How long no files may change before a build starts.
+    fn debounce(&self) -> Duration {
+        self.config
+            .borrow()
+            .debounce_ms
+            .map_or(self.debounce, Duration::from_millis)
+    }
+
+    /// The nix files of the projects `config` `depends_on`.
+    fn dependencies(&self, config: &ProjectConfig) -> Vec<NixFile> {
+        config
+            .dependencies(self.project.nix_file.as_absolute_path())
+            .into_iter()
+            .map(|path| NixFile::from(AbsPathBuf::new_unchecked(path)).canonical())
+            .collect()
+    }
+
+    /// Add the finished `build` to the project’s history.
+    fn record_build(&self, build: &RunningBuild, outcome: Outcome, out_path: Option<PathBuf>) {
+        let record = BuildRecord {
+            started_ms: build
+                .started
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            duration_ms: build.started.elapsed().unwrap_or_default().as_millis() as u64,
+            outcome,
+            reason: build.reason.clone(),
+            out_path,
+        };
+        if let Err(e) = history::record(self.project.history_file().as_path(), record) {
+            debug!(self.logger, "could not record the build"; "error" => %e);
+        }
+    }
+
+    /// Update the project’s status file.
+    fn write_status(&self, state: State) {
+        let status = Status::now(self.project.nix_file.as_absolute_path(), state);
+        if let Err(e) = status::write(self.project.status_file().as_path(), &status) {
+            debug!(self.logger, "could not write the status file"; "error" => %e);
+        }
+    }
+
+    /// Show a desktop notification about a finished build,
+    /// if the project’s `.lorri.toml` asks for one.
+    fn notify_finished(&self, outcome: Outcome, was_failing: bool) {
+        let title = match (self.config.borrow().notify, outcome) {
+            (Notify::Never, _) => return,
+            // a project which is fixed again is worth knowing about as well
+            (Notify::Failures, Outcome::Success) if !was_failing => return,
+            (_, Outcome::Success) => "lorri: build succeeded",
+            (_, Outcome::Failure) => "lorri: build failed",
+            (_, Outcome::Timeout) => "lorri: build timed out",
+            (_, Outcome::Cancelled) => return,
+        };
+        crate::daemon::notify::send(
+            title,
+            &self.project.nix_file.display().to_string(),
+            &self.logger,
+        );
+    }
+
+    /// Push the environment to the binary caches of the project’s
+    /// `[push]` table in the background, and report how it went.
+    fn push(
+        &self,
+        output_paths: &builder::OutputPath<project::RootPath>,
+        tx: &chan::Sender<LoopHandlerEvent>,
+    ) {
+        let targets = self.config.borrow().push.clone();
+        if targets.is_empty() {
+            return;
+        }
+        let out_path = match std::fs::read_link(output_paths.shell_gc_root.0.as_path()) {
+            Ok(path) => path,
+            Err(e) => {
+                debug!(self.logger, "cannot push, the environment is not rooted"; "error" => %e);
+                return;
+            }
+        };
+        let nix_file = self.project.nix_file.clone();
+        let attr = self.project.attr.clone();
+        let tx = tx.clone();
+        let logger = self.logger.clone();
+        // pushing can take a while, and the next build shouldn’t wait for it
+        std::thread::spawn(move || {
+            let paths = vec![out_path];
+            for (target, error) in push::run(&targets, &paths, &logger) {
+                let _ = tx.send(LoopHandlerEvent::BuildEvent(Event::Pushed {
+                    nix_file: nix_file.clone(),
+                    attr: attr.clone(),
+                    target,
+                    paths: paths.clone(),
+                    error,
+                }));
+            }
+        });
+    }
+
+    /// Run the project’s `post_build` command, if it has one.
+    fn run_post_build(&self, outcome: Outcome, out_path: Option<&Path>) {
+        let post_build = match &self.config.borrow().post_build {
+            Some(post_build) => post_build.clone(),
+            None => return,
+        };
+        let root_path = self.project.root_paths().shell_gc_root;
+        hook::run(
+            &post_build,
+            project_dir(self.project),
+            &hook::Finished {
+                outcome: outcome.name(),
+                nix_file: self.project.nix_file.as_absolute_path(),
+                root_path: root_path.0.as_path(),
+                out_path,
+            },
+            &self.logger,
+        );
+    }
+
+    /// Append `line` to the project’s build log.
+    fn write_build_log(&self, line: &[u8]) {
+        if let Some(build_log) = self.build_log.borrow_mut().as_mut() {
+            let mut line = line.to_vec();
+            line.push(b'\n');
+            if let Err(e) = build_log.write_all(&line) {
+                debug!(self.logger, "could not write to the build log"; "error" => %e);
+            }
         }
     }
 
     /// Start an actual build, asynchronously.
-    fn start_build(&self) -> Async<Result<builder::RunResult, BuildError>> {
+    fn start_build(&self, permit: Option<Permit>) -> RunningBuild {
+        self.write_build_log(
+            format!("--- build started at {} ---", crate::logging::timestamp()).as_bytes(),
+        );
         let nix_file = self.project.nix_file.clone();
+        let attr = self.project.attr.clone();
         let cas = self.project.cas.clone();
-        let extra_nix_options = self.extra_nix_options.clone();
+        let cancellation = builder::Cancellation::new();
+        let run_options = builder::RunOptions {
+            extra_nix_options: self.extra_nix_options.clone(),
+            cancellation: cancellation.clone(),
+            local_build_fallback: self.local_build_fallback,
+            log_lines: Some(self.tx_log_lines.clone()),
+            // later builds are requested because something changed,
+            // which might be something nix didn’t tell us it read
+            eval_cache: self.use_eval_cache.replace(false),
+            env: self
+                .passed_env
+                .lock()
+                .expect("passed_env lock poisoned")
+                .clone(),
+            // the daemon uses the project’s `build_timeout_secs`
+            build_timeout_secs: None,
+            previous_inputs: self.watched.keys().cloned().collect(),
+        };
         let logger2 = self.logger.clone();
-        crate::run_async::Async::run(&self.logger, move || {
-            builder::run(&nix_file, &cas, &extra_nix_options, &logger2)
-        })
+        RunningBuild {
+            result: crate::run_async::Async::run(&self.logger, move || {
+                builder::run_with(&nix_file, attr.as_deref(), &cas, &run_options, &logger2)
+            }),
+            cancellation,
+            permit,
+            started: SystemTime::now(),
+            reason: self
+                .requested_reason
+                .borrow()
+                .as_ref()
+                .map_or_else(|| String::from("unknown"), Reason::describe),
+        }
     }
 
     /// Execute a single build of the environment.
     ///
     /// This will create GC roots and expand the file watch list for
-    /// the evaluation.
-    pub fn once(&mut self) -> Result<builder::OutputPath<project::RootPath>, BuildError> {
+    /// the evaluation. If `build_timeout_secs` is set, it overrides
+    /// the project’s `build_timeout_secs`.
+    pub fn once(
+        &mut self,
+        build_timeout_secs: Option<u64>,
+    ) -> Result<builder::OutputPath<project::RootPath>, BuildError> {
+        self.reload_config();
         let nix_file = self.project.nix_file.clone();
+        let attr = self.project.attr.clone();
         let cas = self.project.cas.clone();
-        let extra_nix_options = self.extra_nix_options.clone();
+        let run_options = builder::RunOptions {
+            extra_nix_options: self.extra_nix_options.clone(),
+            build_timeout_secs,
+            ..builder::RunOptions::default()
+        };
         let logger2 = self.logger.clone();
         self.handle_run_result(
             crate::run_async::Async::run(&self.logger, move || {
-                builder::run(&nix_file, &cas, &extra_nix_options, &logger2)
+                builder::run_with(&nix_file, attr.as_deref(), &cas, &run_options, &logger2)
             })
             .block(),
         )
@@ -331,22 +922,97 @@ impl<'a> BuildLoop<'a> {
         &mut self,
         run_result: Result<builder::RunResult, BuildError>,
     ) -> Result<builder::OutputPath<project::RootPath>, BuildError> {
+        self.input_hashes = None;
         let run_result = run_result?;
-        self.register_paths(&run_result.referenced_paths)?;
-        self.root_result(run_result.result)
+        self.register_paths(&run_result.referenced_paths, &run_result.watch_reasons)
+            .map_err(BuildError::io)?;
+        // e.g. `lorri watch` might build the same project right now
+        let _lock = self.project.lock().map_err(BuildError::io)?;
+        let output_paths = self.root_result(run_result.result)?;
+        self.project
+            .root_build_inputs(&run_result.build_inputs, &self.user, &self.logger)
+            .map_err(BuildError::io)?;
+        self.input_hashes = run_result.input_hashes;
+        Ok(output_paths)
     }
 
-    fn register_paths(&mut self, paths: &[WatchPathBuf]) -> Result<(), notify::Error> {
+    fn register_paths(
+        &mut self,
+        paths: &[WatchPathBuf],
+        reasons: &BTreeMap<WatchPathBuf, WatchReason>,
+    ) -> Result<(), notify::Error> {
         let original_paths_len = paths.len();
-        let paths = reduce_paths(&paths);
+        self.watch.set_read_files(paths);
+        let paths = reduce_paths(paths);
         debug!(self.logger, "paths reduced"; "from" => original_paths_len, "to" => paths.len());
 
+        for path in &paths {
+            match reasons.get(path) {
+                Some(reason) => {
+                    self.watched.insert(path.clone(), *reason);
+                }
+                // the nix file is read by the evaluation as well
+                None => {
+                    self.watched
+                        .entry(path.clone())
+                        .or_insert(WatchReason::Evaluation);
+                }
+            }
+        }
+
         // add all new (reduced) nix sources to the input source watchlist
+        self.watch.respect_ignore_files = self.respect_ignore_files;
+        self.watch.poll_fallback = self.poll_fallback;
+        // the `exclude` list might have changed
+        let excludes = self.config.borrow().exclude_patterns();
+        self.watch
+            .set_excludes(project_dir(self.project), &excludes);
         self.watch.extend(paths.into_iter().collect::<Vec<_>>())?;
 
         Ok(())
     }
 
+    /// Tell the daemon what we watch now, see `lorri internal watched-paths`.
+    fn send_watched_paths(&self, tx: &chan::Sender<LoopHandlerEvent>) {
+        tx.send(LoopHandlerEvent::WatchedPaths {
+            nix_file: self.project.nix_file.clone(),
+            attr: self.project.attr.clone(),
+            paths: self
+                .watched
+                .iter()
+                .map(|(path, reason)| WatchedPath {
+                    path: path.clone(),
+                    reason: *reason,
+                })
+                .collect(),
+        })
+        .expect("Failed to send the watched paths")
+    }
+
+    /// What changed between the environment in `previous` and the new build’s.
+    fn env_changes(
+        &self,
+        previous: Option<PathBuf>,
+        output_paths: &builder::OutputPath<project::RootPath>,
+    ) -> Option<EnvDiff> {
+        let root = output_paths.shell_gc_root.0.as_path();
+        let previous = previous?;
+        if std::fs::read_link(root).ok().as_ref() == Some(&previous) {
+            return Some(EnvDiff::default());
+        }
+        let config = self.config.borrow();
+        let load = |root: &Path| {
+            environment::load(root, &config)
+                .map_err(|e| debug!(self.logger, "could not load the environment"; "root" => root.display(), "error" => %e))
+                .ok()
+        };
+        Some(EnvDiff::between(
+            &load(&previous)?,
+            &load(root)?,
+            crate::nix::store::store_dir(),
+        ))
+    }
+
     fn root_result(
         &mut self,
         build: builder::RootedPath,
@@ -356,3 +1022,172 @@ impl<'a> BuildLoop<'a> {
             .map_err(BuildError::io)
     }
 }
+
+/// Whether the `changed` files still have the contents they had when
+/// they were hashed into `hashes`. Files which are not covered by
+/// any of the hashed paths always count as changed.
+fn unchanged_since(hashes: &BTreeMap<WatchPathBuf, Option<String>>, changed: &[PathBuf]) -> bool {
+    // every input is hashed at most once
+    let mut rehashed: BTreeMap<&WatchPathBuf, bool> = BTreeMap::new();
+    changed.iter().all(|path| {
+        let mut covered = false;
+        for (input, hash) in hashes {
+            let covers = match input {
+                // a change inside a directory changes its listing
+                WatchPathBuf::Normal(input) => {
+                    input == path || path.parent() == Some(input.as_path())
+                }
+                WatchPathBuf::Recursive(input) => path.starts_with(input),
+            };
+            if !covers {
+                continue;
+            }
+            covered = true;
+            let same = *rehashed.entry(input).or_insert_with(
+                || matches!(builder::hash_input(input), Ok(ref now) if now == hash),
+            );
+            if !same {
+                return false;
+            }
+        }
+        covered
+    })
+}
+
+/// The files held back during a build (see `forever`) which need another one,
+/// sorted and deduplicated: none if they all had the contents hashed
+/// into `hashes_before_eval` when the evaluation read them, else all of them.
+fn held_back_changes(
+    mut held_back: Vec<PathBuf>,
+    hashes_before_eval: &BTreeMap<WatchPathBuf, Option<String>>,
+) -> Vec<PathBuf> {
+    if unchanged_since(hashes_before_eval, &held_back) {
+        return vec![];
+    }
+    held_back.sort();
+    held_back.dedup();
+    held_back
+}
+
+/// Read the project’s `.lorri.toml`.
+fn load_config(project: &Project) -> ProjectConfig {
+    // an invalid configuration is reported by the build itself
+    ProjectConfig::load(project.nix_file.as_absolute_path()).unwrap_or_default()
+}
+
+/// The build queue a build of the project configured with `config` waits in,
+/// unless it is configured to skip it.
+fn build_queue_for<'q>(
+    queue: Option<&'q BuildQueue>,
+    config: &ProjectConfig,
+) -> Option<&'q BuildQueue> {
+    queue.filter(|_| !config.build_immediately)
+}
+
+/// The directory of the project’s nix file, which the `exclude` patterns are relative to.
+fn project_dir(project: &Project) -> &Path {
+    project
+        .nix_file
+        .as_absolute_path()
+        .parent()
+        .unwrap_or_else(|| Path::new("/"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn files_the_pre_eval_hook_rewrote_need_no_rebuild() -> std::io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let generated = dir.path().join("Cargo.nix");
+        let other = dir.path().join("shell.nix");
+        std::fs::write(&generated, "{ }")?;
+        std::fs::write(&other, "{ }")?;
+        let input = WatchPathBuf::Normal(generated.clone());
+        // hashed after the hook ran, before the evaluation
+        let hashes: BTreeMap<_, _> = vec![(input.clone(), builder::hash_input(&input)?)]
+            .into_iter()
+            .collect();
+
+        // the hook’s write arrives while the build runs, with the same contents
+        std::fs::write(&generated, "{ }")?;
+        assert!(unchanged_since(&hashes, std::slice::from_ref(&generated)));
+        // files which are not inputs always need a rebuild
+        assert!(!unchanged_since(&hashes, &[other]));
+        assert!(!unchanged_since(
+            &BTreeMap::new(),
+            std::slice::from_ref(&generated)
+        ));
+        // someone changed it after the evaluation started
+        std::fs::write(&generated, "{ x = 1; }")?;
+        assert!(!unchanged_since(&hashes, &[generated]));
+        Ok(())
+    }
+
+    #[test]
+    fn files_changed_while_a_build_timed_out_are_rebuilt() -> std::io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let generated = dir.path().join("Cargo.nix");
+        let other = dir.path().join("shell.nix");
+        std::fs::write(&generated, "{ }")?;
+        let input = WatchPathBuf::Normal(generated.clone());
+        let hashes: BTreeMap<_, _> = vec![(input.clone(), builder::hash_input(&input)?)]
+            .into_iter()
+            .collect();
+
+        // the hook’s write lands while the build is held back
+        std::fs::write(&generated, "{ }")?;
+        let held_back = vec![generated.clone(), other.clone(), generated.clone()];
+        assert_eq!(
+            held_back_changes(vec![generated.clone()], &hashes),
+            Vec::<PathBuf>::new()
+        );
+        assert_eq!(
+            held_back_changes(held_back, &hashes),
+            vec![generated.clone(), other]
+        );
+        // a timed out build hashed nothing
+        assert_eq!(
+            held_back_changes(vec![generated.clone()], &BTreeMap::new()),
+            vec![generated]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn touched_sources_need_no_rebuild() -> std::io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let src = dir.path().join("src");
+        std::fs::create_dir(&src)?;
+        let main = src.join("main.rs");
+        std::fs::write(&main, "fn main() {}")?;
+        let input = WatchPathBuf::Recursive(src.clone());
+        let hashes: BTreeMap<_, _> = vec![(input.clone(), builder::hash_input(&input)?)]
+            .into_iter()
+            .collect();
+
+        // e.g. a `git checkout` and back
+        std::fs::remove_file(&main)?;
+        std::fs::write(&main, "fn main() {}")?;
+        assert!(unchanged_since(&hashes, &[main]));
+        // a new file in the source changes it
+        let lib = src.join("lib.rs");
+        std::fs::write(&lib, "")?;
+        assert!(!unchanged_since(&hashes, &[lib]));
+        Ok(())
+    }
+
+    #[test]
+    fn build_immediately_skips_the_build_queue() {
+        let (tx_events, _rx_events) = chan::unbounded();
+        let queue = BuildQueue::new(1, tx_events);
+
+        let config = ProjectConfig::parse("").unwrap();
+        assert!(build_queue_for(Some(&queue), &config).is_some());
+        assert!(build_queue_for(None, &config).is_none());
+
+        let config = ProjectConfig::parse("build_immediately = true").unwrap();
+        assert!(build_queue_for(Some(&queue), &config).is_none());
+    }
+}
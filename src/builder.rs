@@ -8,215 +8,154 @@
 //! `stderr`, like which source files are used by the evaluator.
 
 use crate::cas::ContentAddressable;
-use crate::nix::{options::NixOptions, StorePath};
+use crate::nix::version::{self, Feature};
+use crate::nix::{options::NixOptions, store, Backend, StorePath};
 use crate::osstrlines;
-use crate::watch::WatchPathBuf;
+use crate::project::config::{EnvBackend, ProjectConfig};
+use crate::project::{TemporaryRoot, Username};
+use crate::watch::{WatchPathBuf, WatchReason};
 use crate::{DrvFile, NixFile};
+use crossbeam_channel as chan;
 use regex::Regex;
-use slog::debug;
+use slog::{debug, info, warn};
+use std::collections::BTreeMap;
 use std::ffi::{OsStr, OsString};
 use std::io::BufReader;
-use std::os::unix::prelude::OsStrExt;
-use std::path::PathBuf;
-use std::process::{Command, ExitStatus, Stdio};
-use std::{fmt, thread};
-
-/// An error that can occur during a build.
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub enum BuildError {
-    /// A system-level IO error occurred during the build.
-    Io {
-        /// Error message of the underlying error. Stored as a string because we need `BuildError`
-        /// to implement `Copy`, but `io::Error` does not implement `Copy`.
-        msg: String,
-    },
-
-    /// An error occurred while spawning a Nix process.
-    ///
-    /// Usually this means that the relevant Nix executable was not on the $PATH.
-    Spawn {
-        /// The command that failed. Stored as a string because we need `BuildError` to implement
-        /// `Copy`, but `Command` does not implement `Copy`.
-        cmd: String,
-
-        /// Error message of the underlying error. Stored as a string because we need `BuildError`
-        /// to implement `Copy`, but `io::Error` does not implement `Copy`.
-        msg: String,
-    },
-
-    /// The Nix process returned with a non-zero exit code.
-    Exit {
-        /// The command that failed. Stored as a string because we need `BuildError` to implement
-        /// `Copy`, but `Command` does not implement `Copy`.
-        cmd: String,
-
-        /// The `ExitStatus` of the command. The smart constructor `BuildError::exit` asserts that
-        /// it is non-successful.
-        status: Option<i32>,
-
-        /// Error logs of the failed process.
-        logs: Vec<LogLine>,
-    },
-
-    /// There was something wrong with the output of the Nix command.
-    ///
-    /// This error may for example indicate that the wrong number of outputs was produced.
-    Output {
-        /// Error message explaining the nature of the output error.
-        msg: String,
-    },
-}
-
-impl From<std::io::Error> for BuildError {
-    fn from(e: std::io::Error) -> BuildError {
-        BuildError::io(e)
+use std::os::unix::prelude::{CommandExt, OsStrExt};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+mod eval_cache;
+mod prefetch;
+
+pub use lorri_client::build_error::{BuildError, LogLine};
+pub use lorri_client::event::OutputPath;
+
+impl From<version::Unsupported> for BuildError {
+    fn from(e: version::Unsupported) -> BuildError {
+        BuildError::Unsupported { msg: e.to_string() }
     }
 }
 
-impl From<notify::Error> for BuildError {
-    fn from(e: notify::Error) -> BuildError {
-        BuildError::io(e)
-    }
+struct RootedDrv {
+    _gc_handle: GcRootTempDir,
+    path: DrvFile,
+    /// The backend which evaluated the drv, and builds it.
+    backend: Backend,
 }
 
-impl From<serde_json::Error> for BuildError {
-    fn from(e: serde_json::Error) -> BuildError {
-        BuildError::io(e)
-    }
+/// Represents a path which is temporarily rooted in a temporary directory.
+/// Users are required to keep the gc_handle value alive for as long as
+/// StorePath is alive, _or_ re-root the StorePath using project::Roots
+/// before dropping gc_handle.
+#[derive(Debug)]
+pub struct RootedPath {
+    /// The handle to a temporary directory keeping `.path` alive.
+    pub gc_handle: crate::nix::GcRootTempDir,
+    /// The realized store path
+    pub path: StorePath,
 }
 
-impl fmt::Display for BuildError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            BuildError::Io { msg } => write!(f, "I/O error: {}", msg),
-            BuildError::Spawn { cmd, msg } => write!(
-                f,
-                "failed to spawn Nix process. Is Nix installed and on the $PATH?\n\
-                 $ {}\n\
-                 {}",
-                cmd, msg,
-            ),
-            BuildError::Exit { cmd, status, logs } => write!(
-                f,
-                "Nix process returned exit code {}.\n\
-                 $ {}\n\
-                 {}",
-                status.map_or("<unknown>".to_string(), |c| i32::to_string(&c)),
-                cmd,
-                LogLinesDisplay(logs)
-            ),
-            BuildError::Output { msg } => write!(f, "{}", msg),
-        }
-    }
+/// A handle to cancel a running build.
+///
+/// Every nix process of the build runs in its own process group,
+/// so that cancelling kills nix together with everything it spawned.
+#[derive(Clone, Debug, Default)]
+pub struct Cancellation(Arc<Mutex<CancellationState>>);
+
+#[derive(Debug, Default)]
+struct CancellationState {
+    cancelled: bool,
+    process_groups: Vec<::nix::unistd::Pid>,
+    /// Connections to the nix daemon, which stops working on
+    /// an operation when its connection is shut down.
+    #[cfg(feature = "nix-daemon")]
+    daemon_connections: Vec<std::os::unix::net::UnixStream>,
 }
 
-// TODO: rethink these constructors
-impl BuildError {
-    /// Smart constructor for `BuildError::Io`
-    pub fn io<D>(e: D) -> BuildError
-    where
-        D: fmt::Debug,
-    {
-        BuildError::Io {
-            msg: format!("{:?}", e),
-        }
+impl Cancellation {
+    /// A handle for a build which is not cancelled yet.
+    pub fn new() -> Cancellation {
+        Cancellation::default()
     }
 
-    /// Smart constructor for `BuildError::Spawn`
-    pub fn spawn<D>(cmd: &Command, e: D) -> BuildError
-    where
-        D: fmt::Display,
-    {
-        BuildError::Spawn {
-            cmd: format!("{:?}", cmd),
-            msg: format!("{}", e),
+    /// Kill all running nix processes of the build.
+    /// Processes registered afterwards are killed right away.
+    pub fn cancel(&self) {
+        let mut state = self.0.lock().expect("cancellation lock poisoned");
+        state.cancelled = true;
+        for pgid in state.process_groups.drain(..) {
+            // the process group is gone if nix already exited
+            let _ = ::nix::sys::signal::killpg(pgid, ::nix::sys::signal::Signal::SIGTERM);
         }
-    }
-
-    /// Smart constructor for `BuildError::Exit`
-    pub fn exit(cmd: &Command, status: ExitStatus, logs: Vec<OsString>) -> BuildError {
-        assert!(
-            !status.success(),
-            "cannot create an exit error from a successful status code"
-        );
-        BuildError::Exit {
-            cmd: format!("{:?}", cmd),
-            status: status.code(),
-            logs: logs.iter().map(|l| LogLine::from(l.clone())).collect(),
+        #[cfg(feature = "nix-daemon")]
+        for connection in state.daemon_connections.drain(..) {
+            let _ = connection.shutdown(std::net::Shutdown::Both);
         }
     }
 
-    /// Smart constructor for `BuildError::Output`
-    pub fn output(msg: String) -> BuildError {
-        BuildError::Output { msg }
+    /// Whether `cancel` was called.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.lock().expect("cancellation lock poisoned").cancelled
     }
 
-    /// Is there something the user can do about this error?
-    pub fn is_actionable(&self) -> bool {
-        match self {
-            BuildError::Io { .. } => false,
-            BuildError::Spawn { .. } => true, // install Nix or fix $PATH
-            BuildError::Exit { .. } => true,  // fix Nix expression
-            BuildError::Output { .. } => true, // fix Nix expression
+    /// Start `cmd` in a new process group, which `cancel` can kill as a whole.
+    pub(crate) fn own_process_group(cmd: &mut Command) {
+        // `setpgid` is async-signal-safe, so it may run between fork and exec.
+        unsafe {
+            cmd.pre_exec(|| {
+                ::nix::unistd::setpgid(
+                    ::nix::unistd::Pid::from_raw(0),
+                    ::nix::unistd::Pid::from_raw(0),
+                )
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+            });
         }
     }
-}
 
-/// A line from stderr log output.
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct LogLine(pub OsString);
-
-impl From<OsString> for LogLine {
-    fn from(oss: OsString) -> Self {
-        LogLine(oss)
-    }
-}
-
-impl From<String> for LogLine {
-    fn from(s: String) -> Self {
-        LogLine(OsString::from(s))
+    /// Kill the process group of `child` once the build is cancelled.
+    /// `child` must have been spawned with `own_process_group`.
+    pub(crate) fn register(&self, child: &Child) {
+        let pgid = ::nix::unistd::Pid::from_raw(child.id() as i32);
+        let mut state = self.0.lock().expect("cancellation lock poisoned");
+        if state.cancelled {
+            let _ = ::nix::sys::signal::killpg(pgid, ::nix::sys::signal::Signal::SIGTERM);
+        } else {
+            state.process_groups.push(pgid);
+        }
     }
-}
 
-struct LogLinesDisplay<'a>(&'a [LogLine]);
-
-impl<'a> fmt::Display for LogLinesDisplay<'a> {
-    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
-        for l in self.0 {
-            let mut s = String::from_utf8_lossy(l.0.as_bytes()).into_owned();
-            s.push('\n');
-            formatter.write_str(&s)?;
+    /// Shut the daemon connection `stream` down once the build is cancelled.
+    #[cfg(feature = "nix-daemon")]
+    pub(crate) fn register_daemon_connection(&self, stream: std::os::unix::net::UnixStream) {
+        let mut state = self.0.lock().expect("cancellation lock poisoned");
+        if state.cancelled {
+            let _ = stream.shutdown(std::net::Shutdown::Both);
+        } else {
+            state.daemon_connections.push(stream);
         }
-        Ok(())
     }
 }
 
-struct RootedDrv {
-    _gc_handle: GcRootTempDir,
-    path: DrvFile,
-}
-
-/// Represents a path which is temporarily rooted in a temporary directory.
-/// Users are required to keep the gc_handle value alive for as long as
-/// StorePath is alive, _or_ re-root the StorePath using project::Roots
-/// before dropping gc_handle.
-#[derive(Debug)]
-pub struct RootedPath {
-    /// The handle to a temporary directory keeping `.path` alive.
-    pub gc_handle: crate::nix::GcRootTempDir,
-    /// The realized store path
-    pub path: StorePath,
-}
-
 struct InstantiateOutput {
     referenced_paths: Vec<WatchPathBuf>,
+    watch_reasons: BTreeMap<WatchPathBuf, WatchReason>,
     output: RootedDrv,
+    /// Whether the shell uses `__structuredAttrs`
+    structured_attrs: bool,
 }
 
 fn instrumented_instantiation(
     nix_file: &NixFile,
+    attr: Option<&str>,
+    config: &ProjectConfig,
     cas: &ContentAddressable,
     extra_nix_options: &NixOptions,
+    run_options: &RunOptions,
     logger: &slog::Logger,
 ) -> Result<InstantiateOutput, BuildError> {
     // We're looking for log lines matching:
@@ -225,27 +164,59 @@ fn instrumented_instantiation(
     //     evaluating file '...'
     //
     // to determine which files we should setup watches on.
-    // Increasing verbosity by two levels via `-vv` satisfies that.
-
-    let mut cmd = Command::new("nix-instantiate");
+    // Increasing verbosity by two levels via `-vv` satisfies that
+    // (three levels for `nix`, which is less verbose by default).
+
+    // Fail before the evaluation if we couldn’t build its result afterwards:
+    // the `nix` command builds the shell derivation as `drv^*`.
+    let backend = config.nix_backend.unwrap_or_else(Backend::get);
+    if backend == Backend::NixCommand {
+        version::require(Feature::NixCommand)?;
+        version::require(Feature::PrintOutPaths)?;
+        version::require(Feature::AllOutputs)?;
+    }
+    if nix_file.is_flake() {
+        version::require(Feature::Flakes)?;
+    }
+    if config.env_backend == EnvBackend::PrintDevEnv {
+        version::require(Feature::PrintDevEnv)?;
+        version::require(Feature::AllOutputs)?;
+    }
+    let mut cmd = match backend {
+        Backend::Legacy => crate::nix::limits::command("nix-instantiate"),
+        Backend::NixCommand => {
+            let mut cmd = crate::nix::limits::command("nix");
+            cmd.args(["eval", "--raw", "--impure"]);
+            cmd.args(Backend::nix_command_arguments());
+            cmd
+        }
+    };
 
     let logged_evaluation_nix = cas.file_from_string(include_str!("./logged-evaluation.nix"))?;
 
     // TODO: see ::nix::CallOpts::paths for the problem with this
     let gc_root_dir = tempfile::TempDir::new()?;
 
-    cmd.args(&[
+    cmd.args([
         // verbose mode prints the files we track
-        OsStr::new("-vv"),
+        OsStr::new(match backend {
+            Backend::Legacy => "-vv",
+            Backend::NixCommand => "-vvv",
+        }),
     ]);
     // put the passed extra options at the front
     // to make them more visible in traces
     cmd.args(extra_nix_options.to_nix_arglist());
-    cmd.args(&[
+    if backend == Backend::Legacy {
         // we add a temporary indirect GC root
-        OsStr::new("--add-root"),
-        gc_root_dir.path().join("result").as_os_str(),
-        OsStr::new("--indirect"),
+        // (`nix eval` can’t, see `root_evaluated_drv`)
+        cmd.args([
+            OsStr::new("--add-root"),
+            gc_root_dir.path().join("result").as_os_str(),
+            OsStr::new("--indirect"),
+        ]);
+    }
+    cmd.args([
         OsStr::new("--argstr"),
         // runtime nix paths to needed dependencies that come with lorri
         OsStr::new("runTimeClosure"),
@@ -253,15 +224,65 @@ fn instrumented_instantiation(
         // the source file
         OsStr::new("--argstr"),
     ]);
-    cmd.args(&[OsStr::new("src"), nix_file.as_absolute_path().as_os_str()]);
-    cmd.args(&[
-        // instrumented by `./logged-evaluation.nix`
-        OsStr::new("--"),
-        &logged_evaluation_nix.as_path().as_os_str(),
-    ])
+    cmd.args([OsStr::new("src"), nix_file.as_absolute_path().as_os_str()]);
+    if config.env_backend == EnvBackend::PrintDevEnv {
+        // we only need the shell’s derivation, see `build`
+        cmd.args(["--arg", "keepEnvHack", "false"]);
+    }
+    let watch_reasons = watch_reasons(nix_file, config, run_options);
+    if nix_file.is_flake() {
+        cmd.args([
+            // flakes are still an experimental feature
+            OsStr::new("--option"),
+            OsStr::new("extra-experimental-features"),
+            OsStr::new("nix-command flakes"),
+            OsStr::new("--arg"),
+            OsStr::new("isFlake"),
+            OsStr::new("true"),
+        ]);
+        // an explicitly selected attribute wins over the configured one
+        if let Some(attr) = attr.or(config.flake_attr.as_deref()) {
+            cmd.args([
+                OsStr::new("--argstr"),
+                OsStr::new("flakeAttr"),
+                OsStr::new(attr),
+            ]);
+        }
+    } else {
+        if let Some(attr) = attr {
+            cmd.args([OsStr::new("--argstr"), OsStr::new("attr"), OsStr::new(attr)]);
+        }
+        if !config.nix.args.is_empty() || !config.nix.argstrs.is_empty() {
+            cmd.args([
+                OsStr::new("--arg"),
+                OsStr::new("shellArgs"),
+                OsStr::new(&shell_args_expression(
+                    &config.nix.args,
+                    &config.nix.argstrs,
+                )),
+            ]);
+        }
+    }
+    match backend {
+        Backend::Legacy => cmd.args([
+            // instrumented by `./logged-evaluation.nix`
+            OsStr::new("--"),
+            logged_evaluation_nix.as_path().as_os_str(),
+        ]),
+        Backend::NixCommand => cmd.args([
+            // instrumented by `./logged-evaluation.nix`
+            OsStr::new("--file"),
+            logged_evaluation_nix.as_path().as_os_str(),
+            // `nix eval` prints the derivation path, `nix-instantiate` does that by default
+            OsStr::new("drvPath"),
+        ]),
+    }
     .stdin(Stdio::null())
     .stdout(Stdio::piped())
     .stderr(Stdio::piped());
+    crate::nix::limit_ssh_connect_time(&mut cmd);
+    Cancellation::own_process_group(&mut cmd);
+    cmd.envs(passed_env(config, run_options));
 
     debug!(logger, "nix-instantiate"; "command" => ?cmd);
 
@@ -269,6 +290,7 @@ fn instrumented_instantiation(
         std::io::ErrorKind::NotFound => BuildError::spawn(&cmd, e),
         _ => BuildError::io(e),
     })?;
+    run_options.cancellation.register(&child);
 
     let stdout = child
         .stdout
@@ -279,9 +301,22 @@ fn instrumented_instantiation(
         .take()
         .expect("we must be able to access the stderr of nix-instantiate");
 
+    let log_lines = run_options.log_lines.clone();
     let stderr_results = thread::spawn(move || {
         osstrlines::Lines::from(BufReader::new(stderr))
-            .map(|line| line.map(parse_evaluation_line))
+            .map(|line| {
+                line.map(|line| {
+                    let datum = parse_evaluation_line(&line);
+                    // only pass on what nix itself would print without `-vv`
+                    match (&log_lines, &datum) {
+                        (Some(tx), LogDatum::Text(_)) | (Some(tx), LogDatum::NonUtf(_)) => {
+                            let _ = tx.send(LogLine::from(line));
+                        }
+                        _ => {}
+                    }
+                    datum
+                })
+            })
             .collect::<Result<Vec<LogDatum>, _>>()
     });
 
@@ -305,8 +340,9 @@ fn instrumented_instantiation(
     // meaning we don’t have to keep the outputs in memory (fold directly)
 
     // iterate over all lines, parsing out the ones we are interested in
-    let mut paths: Vec<WatchPathBuf> = vec![];
+    let mut paths: Vec<WatchPathBuf> = watch_reasons.keys().cloned().collect();
     let mut log_lines: Vec<OsString> = vec![];
+    let mut structured_attrs = false;
     for result in results {
         match result {
             LogDatum::CopiedSource(src) | LogDatum::ReadRecursively(src) => {
@@ -329,6 +365,7 @@ fn instrumented_instantiation(
                 }
                 paths.push(WatchPathBuf::Normal(src));
             }
+            LogDatum::StructuredAttrs => structured_attrs = true,
             LogDatum::Text(line) => log_lines.push(OsString::from(line)),
             LogDatum::NonUtf(line) => log_lines.push(line),
         };
@@ -349,40 +386,407 @@ fn instrumented_instantiation(
 
     Ok(InstantiateOutput {
         referenced_paths: paths,
+        watch_reasons,
         output: RootedDrv {
-            _gc_handle: GcRootTempDir(gc_root_dir),
+            _gc_handle: match backend {
+                Backend::Legacy => GcRootTempDir::Dir(gc_root_dir),
+                Backend::NixCommand => root_evaluated_drv(&shell_gc_root, logger),
+            },
             path: shell_gc_root,
+            backend,
         },
+        structured_attrs,
     })
 }
 
+/// The paths to watch which the evaluation itself doesn’t report.
+fn watch_reasons(
+    nix_file: &NixFile,
+    config: &ProjectConfig,
+    run_options: &RunOptions,
+) -> BTreeMap<WatchPathBuf, WatchReason> {
+    // changes to the configuration have to trigger a rebuild
+    let mut watch_reasons = BTreeMap::new();
+    watch_reasons.insert(
+        WatchPathBuf::Normal(ProjectConfig::file_for(nix_file.as_absolute_path())),
+        WatchReason::ProjectConfig,
+    );
+    // and so do the files the user wants us to watch
+    for path in config.watch_paths(nix_file.as_absolute_path()) {
+        watch_reasons.insert(path, WatchReason::ConfiguredWatch);
+    }
+    if nix_file.is_flake() {
+        // nix doesn’t tell us what it reads of the inputs which are local directories
+        if let Some(flake_dir) = nix_file.as_absolute_path().parent() {
+            for path in crate::nix::flake_lock::path_inputs(flake_dir) {
+                watch_reasons.insert(path, WatchReason::FlakeInput);
+            }
+        }
+    } else {
+        // and the channels `<…>` lookups resolve through (flakes can’t use them)
+        let nix_path = passed_env(config, run_options)
+            .get(&String::from("NIX_PATH"))
+            .map(|nix_path| nix_path.to_string())
+            .or_else(|| std::env::var("NIX_PATH").ok());
+        for path in crate::nix::search_path::watch_paths(nix_path.as_deref()) {
+            watch_reasons.insert(path, WatchReason::NixPath);
+        }
+    }
+    watch_reasons
+}
+
+/// Evaluate the shell of a flake with `nix eval <flake>#<attr>.drvPath`,
+/// which nix answers from its flake evaluation cache as long as
+/// the flake didn’t change, instead of evaluating e.g. nixpkgs again.
+///
+/// The cache holds the shell’s derivation without the keep-env-hack,
+/// so this is only possible with `EnvBackend::PrintDevEnv`
+/// and the `nix` command.
+/// `None` if it isn’t, or if nix can’t evaluate the attribute
+/// (e.g. a flake with only the old `devShell.<system>` output);
+/// the instrumented instantiation then does the evaluation instead.
+fn flake_instantiation(
+    nix_file: &NixFile,
+    attr: Option<&str>,
+    config: &ProjectConfig,
+    extra_nix_options: &NixOptions,
+    run_options: &RunOptions,
+    logger: &slog::Logger,
+) -> Option<InstantiateOutput> {
+    if !nix_file.is_flake()
+        || config.env_backend != EnvBackend::PrintDevEnv
+        || config.nix_backend.unwrap_or_else(Backend::get) != Backend::NixCommand
+    {
+        return None;
+    }
+    let flake_dir = nix_file.as_absolute_path().parent()?;
+    let nix_eval = |installable: &[&OsStr]| -> Option<OsString> {
+        let mut cmd = crate::nix::limits::command("nix");
+        cmd.args(["eval", "--raw"]);
+        cmd.args(Backend::nix_command_arguments());
+        cmd.args([
+            "--option",
+            "extra-experimental-features",
+            "nix-command flakes",
+        ]);
+        cmd.args(extra_nix_options.to_nix_arglist());
+        cmd.args(installable);
+        cmd.stdin(Stdio::null());
+        crate::nix::limit_ssh_connect_time(&mut cmd);
+        Cancellation::own_process_group(&mut cmd);
+        cmd.envs(passed_env(config, run_options));
+        debug!(logger, "nix eval"; "command" => ?cmd);
+        let child = cmd
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .ok()?;
+        run_options.cancellation.register(&child);
+        let output = child.wait_with_output().ok()?;
+        if output.status.success() {
+            Some(OsString::from(OsStr::from_bytes(&output.stdout)))
+        } else {
+            debug!(logger, "nix eval failed"; "stderr" => %String::from_utf8_lossy(&output.stderr));
+            None
+        }
+    };
+
+    // like `./logged-evaluation.nix`, an explicitly selected attribute
+    // wins over the configured one
+    let attr = match attr.or(config.flake_attr.as_deref()) {
+        Some(attr) => attr.to_string(),
+        None => {
+            let system = nix_eval(&[
+                OsStr::new("--impure"),
+                OsStr::new("--expr"),
+                OsStr::new("builtins.currentSystem"),
+            ])?;
+            format!("devShells.{}.default", system.to_string_lossy())
+        }
+    };
+    let mut installable = flake_dir.as_os_str().to_owned();
+    installable.push(format!("#{}.drvPath", attr));
+    let drv_path = DrvFile::from(PathBuf::from(nix_eval(&[installable.as_os_str()])?));
+
+    // the files `./logged-evaluation.nix` reports for flakes
+    let watch_reasons = watch_reasons(nix_file, config, run_options);
+    let mut referenced_paths: Vec<WatchPathBuf> = watch_reasons.keys().cloned().collect();
+    referenced_paths.push(WatchPathBuf::Recursive(
+        nix_file.as_absolute_path().to_owned(),
+    ));
+    referenced_paths.push(WatchPathBuf::Recursive(flake_dir.join("flake.lock")));
+    Some(InstantiateOutput {
+        referenced_paths,
+        watch_reasons,
+        output: RootedDrv {
+            _gc_handle: root_evaluated_drv(&drv_path, logger),
+            path: drv_path,
+            backend: Backend::NixCommand,
+        },
+        structured_attrs: false,
+    })
+}
+
+/// Run the project’s `pre_eval` command in the project directory,
+/// before the evaluation reads any files.
+/// Like the nix processes, it is resource limited and killed when the build is cancelled.
+/// Its output goes to the build log; if it fails, so does the build.
+fn run_pre_eval(
+    pre_eval: &str,
+    root_nix_file: &NixFile,
+    config: &ProjectConfig,
+    run_options: &RunOptions,
+    logger: &slog::Logger,
+) -> Result<(), BuildError> {
+    let mut cmd = crate::nix::limits::command("sh");
+    cmd.args(["-c", pre_eval])
+        .current_dir(
+            root_nix_file
+                .as_absolute_path()
+                .parent()
+                .unwrap_or_else(|| Path::new("/")),
+        )
+        .envs(passed_env(config, run_options))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    Cancellation::own_process_group(&mut cmd);
+    debug!(logger, "running pre_eval hook"; "command" => ?cmd);
+    let mut child = cmd.spawn().map_err(|e| BuildError::spawn(&cmd, e))?;
+    run_options.cancellation.register(&child);
+
+    let stdout = child
+        .stdout
+        .take()
+        .expect("we must be able to access the stdout of the pre_eval hook");
+    let stderr = child
+        .stderr
+        .take()
+        .expect("we must be able to access the stderr of the pre_eval hook");
+    let log_lines = run_options.log_lines.clone();
+    let stdout_lines = thread::spawn(move || {
+        for line in osstrlines::Lines::from(BufReader::new(stdout)) {
+            match (&log_lines, line) {
+                (Some(tx), Ok(line)) => {
+                    let _ = tx.send(LogLine::from(line));
+                }
+                (_, Err(_)) => break,
+                (None, Ok(_)) => {}
+            }
+        }
+    });
+    // only what it printed to stderr ends up in the error
+    let mut errors: Vec<OsString> = vec![];
+    for line in osstrlines::Lines::from(BufReader::new(stderr)) {
+        let line = line?;
+        if let Some(tx) = &run_options.log_lines {
+            let _ = tx.send(LogLine::from(line.clone()));
+        }
+        errors.push(line);
+    }
+    let status = child.wait()?;
+    stdout_lines
+        .join()
+        .expect("Failed to join stdout processing thread");
+    if status.success() {
+        Ok(())
+    } else {
+        Err(BuildError::exit(&cmd, status, errors))
+    }
+}
+
+/// The environment variables for the evaluation.
+/// Whoever sent them, only the ones the project allows are passed.
+fn passed_env<'a>(
+    config: &ProjectConfig,
+    run_options: &'a RunOptions,
+) -> BTreeMap<&'a String, &'a String> {
+    run_options
+        .env
+        .iter()
+        .filter(|(name, _)| config.pass_env.contains(name))
+        .collect()
+}
+
+/// A nix attribute set of the arguments to the project’s nix file.
+/// `args` are nix expressions, `argstrs` are strings.
+fn shell_args_expression(
+    args: &BTreeMap<String, String>,
+    argstrs: &BTreeMap<String, String>,
+) -> String {
+    let mut expr = String::from("{ ");
+    for (name, value) in args {
+        // the newline ends a trailing comment in `value`
+        expr.push_str(&format!("{} = ({}\n); ", nix_string(name), value));
+    }
+    for (name, value) in argstrs {
+        expr.push_str(&format!("{} = {}; ", nix_string(name), nix_string(value)));
+    }
+    expr.push('}');
+    expr
+}
+
+/// Quote `s` as a nix string literal.
+fn nix_string(s: &str) -> String {
+    format!(
+        "\"{}\"",
+        s.replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace("${", "\\${")
+    )
+}
+
 struct BuildOutput {
     output: RootedPath,
 }
 
+/// Hash the contents of a path the evaluation read, as they are stored
+/// in `RunResult.input_hashes`; `None` if it does not exist.
+pub fn hash_input(path: &WatchPathBuf) -> std::io::Result<Option<String>> {
+    eval_cache::hash(path)
+}
+
 /// Builds the Nix expression in `root_nix_file`.
 ///
 /// Instruments the nix file to gain extra information, which is valuable even if the build fails.
-fn build(drv_path: DrvFile, logger: &slog::Logger) -> Result<BuildOutput, BuildError> {
-    let (path, gc_handle) = crate::nix::CallOpts::file(drv_path.as_path()).path(logger)?;
+fn build(
+    drv: &RootedDrv,
+    root_nix_file: &NixFile,
+    env_backend: EnvBackend,
+    cas: &ContentAddressable,
+    nix_options: &NixOptions,
+    run_options: &RunOptions,
+    logger: &slog::Logger,
+) -> Result<BuildOutput, BuildError> {
+    fn call_opts<'a>(
+        file: &'a Path,
+        backend: Backend,
+        nix_options: &NixOptions,
+        run_options: &RunOptions,
+    ) -> crate::nix::CallOpts<'a> {
+        let mut nix = crate::nix::CallOpts::file(file);
+        nix.backend(backend);
+        nix.extra_options(nix_options.clone());
+        nix.cancellation(run_options.cancellation.clone());
+        if let Some(tx) = &run_options.log_lines {
+            nix.log_lines(tx.clone());
+        }
+        nix
+    }
+    let (path, gc_handle) = match env_backend {
+        EnvBackend::KeepEnvHack => {
+            call_opts(drv.path.as_path(), drv.backend, nix_options, run_options).path(logger)?
+        }
+        EnvBackend::PrintDevEnv => {
+            // `drv` is the shell itself, turn its environment
+            // into the same output the keep-env-hack has
+            let (dev_env, _dev_env_gc_handle) =
+                call_opts(drv.path.as_path(), drv.backend, nix_options, run_options)
+                    .dev_env(logger)?;
+            let dev_env_nix = cas.file_from_string(include_str!("./dev-env.nix"))?;
+            call_opts(dev_env_nix.as_path(), drv.backend, nix_options, run_options)
+                .argstr("devEnv", dev_env.as_path())
+                .argstr("src", root_nix_file.as_absolute_path())
+                .argstr("runTimeClosure", crate::RUN_TIME_CLOSURE)
+                .path(logger)?
+        }
+    };
     Ok(BuildOutput {
         output: RootedPath { gc_handle, path },
     })
 }
 
-/// Opaque type to keep a temporary GC root directory alive.
+/// The build-time dependencies of an environment.
+///
+/// Rooting these is the equivalent of setting `keep-derivations`
+/// and `keep-outputs` for the environment, so that a rebuild after
+/// `nix-collect-garbage` doesn’t have to download or build them again.
+#[derive(Debug)]
+pub struct BuildInputs {
+    /// Keeps `drv` alive until it is rooted by the project.
+    _gc_handle: GcRootTempDir,
+    /// The derivation of the environment.
+    pub drv: DrvFile,
+    /// The outputs of all derivations the environment is built from,
+    /// and the sources it directly references.
+    pub paths: Vec<StorePath>,
+}
+
+/// Find the build-time dependencies of `drv`.
+///
+/// Outputs of input derivations which are not realized
+/// (e.g. because they were substituted away) are skipped.
+fn build_inputs(drv: RootedDrv, logger: &slog::Logger) -> Result<BuildInputs, BuildError> {
+    let references = store::references(drv.path.as_path(), logger)?;
+    let (input_drvs, mut paths): (Vec<PathBuf>, Vec<PathBuf>) = references
+        .into_iter()
+        .partition(|path| path.extension() == Some(OsStr::new("drv")));
+    if !input_drvs.is_empty() {
+        let input_drvs: Vec<&std::path::Path> = input_drvs.iter().map(|p| p.as_path()).collect();
+        paths.extend(store::realised_outputs(&input_drvs, logger)?);
+    }
+    Ok(BuildInputs {
+        _gc_handle: drv._gc_handle,
+        drv: drv.path,
+        paths: paths
+            .into_iter()
+            .filter(|path| path.exists())
+            .map(|path| StorePath::from(path.into_os_string()))
+            .collect(),
+    })
+}
+
+/// Opaque type to keep a temporary GC root alive.
 /// Once it is dropped, the GC root is removed.
-/// Copied from `nix`, because the type should stay opaque.
+/// Like the one in `nix`, which can only hold a directory.
 #[derive(Debug)]
-struct GcRootTempDir(tempfile::TempDir);
+enum GcRootTempDir {
+    /// A directory with the indirect roots nix added.
+    Dir(#[allow(dead_code)] tempfile::TempDir),
+    /// A root we registered ourselves.
+    Registered(#[allow(dead_code)] TemporaryRoot),
+    /// Nothing keeps the paths alive.
+    Unrooted,
+}
+
+/// Keep the derivation `nix eval` printed alive until it is built,
+/// since `nix eval` can’t add a GC root like `nix-instantiate --add-root`.
+fn root_evaluated_drv(drv: &DrvFile, logger: &slog::Logger) -> GcRootTempDir {
+    let root = Username::current()
+        .map_err(|e| e.to_string())
+        .and_then(|user| TemporaryRoot::new(drv.as_path(), &user).map_err(|e| e.to_string()));
+    match root {
+        Ok(root) => GcRootTempDir::Registered(root),
+        Err(e) => {
+            warn!(
+                logger,
+                "not rooting the derivation, nix-collect-garbage may delete it before it is built";
+                "drv" => drv.as_path().display(),
+                "error" => %e
+            );
+            GcRootTempDir::Unrooted
+        }
+    }
+}
 
 /// The result of a single instantiation and build.
 #[derive(Debug)]
 pub struct RunResult {
     /// All the paths identified during the instantiation
     pub referenced_paths: Vec<WatchPathBuf>,
+    /// The hashes of the contents of the `referenced_paths` right after
+    /// the build (see `hash_input`), `None` if they could not be read.
+    pub input_hashes: Option<BTreeMap<WatchPathBuf, Option<String>>>,
+    /// The hashes of `RunOptions.previous_inputs` right after the
+    /// project’s `pre_eval` hook ran, before the evaluation.
+    /// Empty if the project has no hook.
+    pub hashes_before_eval: BTreeMap<WatchPathBuf, Option<String>>,
+    /// Why the `referenced_paths` which the evaluation didn’t read are watched.
+    pub watch_reasons: BTreeMap<WatchPathBuf, WatchReason>,
     /// The status of the build attempt
     pub result: RootedPath,
+    /// The build-time dependencies of the result
+    pub build_inputs: BuildInputs,
 }
 
 /// Builds the Nix expression in `root_nix_file`.
@@ -391,16 +795,251 @@ pub struct RunResult {
 /// which is valuable even if the build fails.
 pub fn run(
     root_nix_file: &NixFile,
+    attr: Option<&str>,
     cas: &ContentAddressable,
     extra_nix_options: &NixOptions,
     logger: &slog::Logger,
 ) -> Result<RunResult, BuildError> {
-    let inst_info = instrumented_instantiation(root_nix_file, cas, &extra_nix_options, logger)?;
-    let buildoutput = build(inst_info.output.path, logger)?;
-    Ok(RunResult {
+    run_with(
+        root_nix_file,
+        attr,
+        cas,
+        &RunOptions {
+            extra_nix_options: extra_nix_options.clone(),
+            ..RunOptions::default()
+        },
+        logger,
+    )
+}
+
+/// How `run_with` runs a build.
+#[derive(Clone)]
+pub struct RunOptions {
+    /// Extra options to pass to each nix invocation
+    pub extra_nix_options: NixOptions,
+    /// Kills the nix processes when cancelled, which fails the build
+    pub cancellation: Cancellation,
+    /// Retry a build which fails because of the remote builders without them
+    pub local_build_fallback: bool,
+    /// Receives the lines nix prints while it runs
+    pub log_lines: Option<chan::Sender<LogLine>>,
+    /// Reuse the result of the last build if none of its inputs changed
+    pub eval_cache: bool,
+    /// Environment variables for the evaluation; only the ones
+    /// in the project’s `pass_env` are set
+    pub env: BTreeMap<String, String>,
+    /// Kill the build after this many seconds, instead of after
+    /// the project’s `build_timeout_secs`
+    pub build_timeout_secs: Option<u64>,
+    /// The inputs of the last build, which are hashed after
+    /// the project’s `pre_eval` hook changed them
+    pub previous_inputs: Vec<WatchPathBuf>,
+}
+
+impl Default for RunOptions {
+    fn default() -> RunOptions {
+        RunOptions {
+            extra_nix_options: NixOptions::empty(),
+            cancellation: Cancellation::new(),
+            local_build_fallback: false,
+            log_lines: None,
+            eval_cache: false,
+            env: BTreeMap::new(),
+            build_timeout_secs: None,
+            previous_inputs: vec![],
+        }
+    }
+}
+
+/// Like `run`, with more control over the build.
+pub fn run_with(
+    root_nix_file: &NixFile,
+    attr: Option<&str>,
+    cas: &ContentAddressable,
+    run_options: &RunOptions,
+    logger: &slog::Logger,
+) -> Result<RunResult, BuildError> {
+    let config = ProjectConfig::load(root_nix_file.as_absolute_path())
+        .map_err(|e| BuildError::output(format!("{:#}", anyhow::Error::new(e))))?;
+    let watchdog = run_options
+        .build_timeout_secs
+        .or(config.build_timeout_secs)
+        .filter(|secs| *secs > 0)
+        .map(|secs| Watchdog::start(secs, run_options.cancellation.clone()));
+    let result = run_configured(root_nix_file, attr, cas, &config, run_options, logger);
+    match watchdog {
+        Some(watchdog) if watchdog.timed_out() => Err(BuildError::Timeout {
+            timeout_secs: watchdog.timeout_secs,
+        }),
+        _ => result,
+    }
+}
+
+/// Cancels a build which takes longer than its timeout.
+struct Watchdog {
+    timeout_secs: u64,
+    timed_out: Arc<AtomicBool>,
+    /// Stops the watchdog when it is dropped.
+    _tx_done: chan::Sender<()>,
+}
+
+impl Watchdog {
+    fn start(timeout_secs: u64, cancellation: Cancellation) -> Watchdog {
+        let (tx_done, rx_done) = chan::bounded::<()>(0);
+        let timed_out = Arc::new(AtomicBool::new(false));
+        let timed_out2 = timed_out.clone();
+        thread::spawn(move || {
+            if let Err(chan::RecvTimeoutError::Timeout) =
+                rx_done.recv_timeout(Duration::from_secs(timeout_secs))
+            {
+                timed_out2.store(true, Ordering::SeqCst);
+                cancellation.cancel();
+            }
+        });
+        Watchdog {
+            timeout_secs,
+            timed_out,
+            _tx_done: tx_done,
+        }
+    }
+
+    fn timed_out(&self) -> bool {
+        self.timed_out.load(Ordering::SeqCst)
+    }
+}
+
+fn run_configured(
+    root_nix_file: &NixFile,
+    attr: Option<&str>,
+    cas: &ContentAddressable,
+    config: &ProjectConfig,
+    run_options: &RunOptions,
+    logger: &slog::Logger,
+) -> Result<RunResult, BuildError> {
+    // before the cache lookup, since it might change the inputs
+    let hashes_before_eval = match &config.pre_eval {
+        Some(pre_eval) => {
+            run_pre_eval(pre_eval, root_nix_file, config, run_options, logger)?;
+            // to tell the files the hook wrote from the ones changed during the evaluation
+            eval_cache::hash_inputs(&run_options.previous_inputs, logger).unwrap_or_default()
+        }
+        None => BTreeMap::new(),
+    };
+    // the project’s settings win over the ones from the daemon
+    let mut nix_options = run_options.extra_nix_options.clone();
+    nix_options.append(NixOptions {
+        options: config.nix.options.clone(),
+        ..NixOptions::empty()
+    });
+    let env = passed_env(config, run_options);
+    let cache_key = eval_cache::key(root_nix_file, attr, &nix_options, &env);
+    let shared_key = eval_cache::shared_key(root_nix_file, attr, config, &nix_options, &env);
+    if run_options.eval_cache {
+        if let Some(result) = eval_cache::lookup(cas, &cache_key, logger) {
+            info!(logger, "inputs did not change, skipping evaluation"; "nix_file" => root_nix_file);
+            return Ok(RunResult {
+                hashes_before_eval,
+                ..result
+            });
+        }
+        let shared = shared_key.as_ref().and_then(|shared_key| {
+            eval_cache::lookup_shared(
+                cas,
+                shared_key,
+                root_nix_file,
+                watch_reasons(root_nix_file, config, run_options),
+                logger,
+            )
+        });
+        if let Some(result) = shared {
+            info!(logger, "another project evaluated the same shell, skipping evaluation"; "nix_file" => root_nix_file);
+            eval_cache::save(cas, &cache_key, &result, logger);
+            return Ok(RunResult {
+                hashes_before_eval,
+                ..result
+            });
+        }
+    }
+    let inst_info = match flake_instantiation(
+        root_nix_file,
+        attr,
+        config,
+        &nix_options,
+        run_options,
+        logger,
+    ) {
+        Some(inst_info) => inst_info,
+        None => instrumented_instantiation(
+            root_nix_file,
+            attr,
+            config,
+            cas,
+            &nix_options,
+            run_options,
+            logger,
+        )?,
+    };
+    // download the dependencies while the shell is built
+    let prefetch = if config.prefetch {
+        Some(prefetch::Prefetch::start(
+            &inst_info.output.path,
+            &nix_options,
+            logger,
+        ))
+    } else {
+        None
+    };
+    // the keep-env-hack can’t see the environment of these shells
+    let env_backend = if inst_info.structured_attrs {
+        debug!(logger, "shell uses structured attrs, recording it with nix print-dev-env"; "nix_file" => root_nix_file);
+        EnvBackend::PrintDevEnv
+    } else {
+        config.env_backend
+    };
+    let build_with = |nix_options: &NixOptions| {
+        build(
+            &inst_info.output,
+            root_nix_file,
+            env_backend,
+            cas,
+            nix_options,
+            run_options,
+            logger,
+        )
+    };
+    let buildoutput = match build_with(&nix_options) {
+        Err(BuildError::RemoteBuilder { reason, .. }) if run_options.local_build_fallback => {
+            warn!(logger, "remote builders failed, building locally"; "nix_file" => root_nix_file, "reason" => &reason);
+            let mut local_options = nix_options.clone();
+            local_options.builders = Some(vec![]);
+            local_options
+                .options
+                .insert(String::from("max-jobs"), String::from("auto"));
+            build_with(&local_options).map_err(BuildError::build)?
+        }
+        res => res.map_err(BuildError::build)?,
+    };
+    drop(prefetch);
+    let build_inputs = build_inputs(inst_info.output, logger)?;
+    let input_hashes = eval_cache::hash_inputs(&inst_info.referenced_paths, logger);
+    let result = RunResult {
         referenced_paths: inst_info.referenced_paths,
+        input_hashes,
+        hashes_before_eval,
+        watch_reasons: inst_info.watch_reasons,
         result: buildoutput.output,
-    })
+        build_inputs,
+    };
+    eval_cache::save(cas, &cache_key, &result, logger);
+    // unless the shell file changed since it was evaluated
+    if let Some(shared_key) = shared_key {
+        if eval_cache::shared_key(root_nix_file, attr, config, &nix_options, &env).as_ref()
+            == Some(&shared_key)
+        {
+            eval_cache::save_shared(cas, &shared_key, root_nix_file, &result, logger);
+        }
+    }
+    Ok(result)
 }
 
 /// Classifies the output of nix-instantiate -vv.
@@ -416,6 +1055,9 @@ enum LogDatum {
     /// A `builtins.readDir` invocation (at eval time).
     /// The subtree must not be recursively watched, only the file listing of the directory.
     ReadDir(PathBuf),
+    /// The shell uses `__structuredAttrs`, so its environment
+    /// has to be recorded with `nix print-dev-env`.
+    StructuredAttrs,
     /// Arbitrary text (which we couldn’t otherwise classify)
     Text(String),
     /// Text which we coudn’t decode from UTF-8
@@ -448,6 +1090,8 @@ where
         static ref LORRI_READDIR: Regex =
             Regex::new("^trace: lorri readdir: '(?P<source>.*)'$").expect("invalid regex!");
     }
+    // Printed by `./logged-evaluation.nix` for shells with `__structuredAttrs`.
+    const LORRI_STRUCTURED_ATTRS: &str = "trace: lorri structured-attrs";
 
     // see the regexes above for explanations of the nix outputs
     match line.as_ref().to_str() {
@@ -457,14 +1101,16 @@ where
         Some(linestr) => {
             // Lines about evaluating a file are much more common, so looking
             // for them first will reduce comparisons.
-            if let Some(matches) = EVAL_FILE.captures(&linestr) {
+            if let Some(matches) = EVAL_FILE.captures(linestr) {
                 LogDatum::NixSourceFile(PathBuf::from(&matches["source"]))
-            } else if let Some(matches) = COPIED_SOURCE.captures(&linestr) {
+            } else if let Some(matches) = COPIED_SOURCE.captures(linestr) {
                 LogDatum::CopiedSource(PathBuf::from(&matches["source"]))
-            } else if let Some(matches) = LORRI_READ.captures(&linestr) {
+            } else if let Some(matches) = LORRI_READ.captures(linestr) {
                 LogDatum::ReadRecursively(PathBuf::from(&matches["source"]))
-            } else if let Some(matches) = LORRI_READ.captures(&linestr) {
+            } else if let Some(matches) = LORRI_READ.captures(linestr) {
                 LogDatum::ReadDir(PathBuf::from(&matches["source"]))
+            } else if linestr == LORRI_STRUCTURED_ATTRS {
+                LogDatum::StructuredAttrs
             } else {
                 LogDatum::Text(linestr.to_owned())
             }
@@ -472,25 +1118,6 @@ where
     }
 }
 
-/// Output path generated by `logged-evaluation.nix`
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct OutputPath<T> {
-    /// Shell path modified to work as a gc root
-    pub shell_gc_root: T,
-}
-
-impl<T> OutputPath<T> {
-    /// map over the inner type.
-    pub fn map<F, T2>(self, f: F) -> OutputPath<T2>
-    where
-        F: Fn(T) -> T2,
-    {
-        OutputPath {
-            shell_gc_root: f(self.shell_gc_root),
-        }
-    }
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -521,6 +1148,11 @@ mod tests {
             ))
         );
 
+        assert_eq!(
+            parse_evaluation_line("trace: lorri structured-attrs"),
+            LogDatum::StructuredAttrs
+        );
+
         assert_eq!(
             parse_evaluation_line(
                 "downloading 'https://static.rust-lang.org/dist/channel-rust-stable.toml'..."
@@ -531,6 +1163,19 @@ mod tests {
         );
     }
 
+    /// Arguments from the project configuration are quoted for nix.
+    #[test]
+    fn shell_args_are_quoted() {
+        let mut args = BTreeMap::new();
+        args.insert(String::from("withDocs"), String::from("true # for now"));
+        let mut argstrs = BTreeMap::new();
+        argstrs.insert(String::from("name"), String::from("\"${x}\""));
+        assert_eq!(
+            shell_args_expression(&args, &argstrs),
+            "{ \"withDocs\" = (true # for now\n); \"name\" = \"\\\"\\${x}\\\"\"; }"
+        );
+    }
+
     /// Create a locally built base derivation expression.
     /// `args` is just interpolated into the derivation fields.
     fn drv(name: &str, args: &str) -> String {
@@ -584,6 +1229,7 @@ in {}
         // build, because instantiate doesn’t return the build output (obviously …)
         run(
             &crate::NixFile::from(cas.file_from_string(&nix_drv)?),
+            None,
             &cas,
             &NixOptions::empty(),
             &crate::logging::test_logger(),
@@ -605,15 +1251,13 @@ in {}
 
         if let Err(BuildError::Exit { .. }) = run(
             &d,
+            None,
             &cas,
             &NixOptions::empty(),
             &crate::logging::test_logger(),
         ) {
         } else {
-            assert!(
-                false,
-                "builder::run should have failed with BuildError::Exit"
-            );
+            panic!("builder::run should have failed with BuildError::Exit");
         }
         Ok(())
     }
@@ -657,19 +1301,22 @@ dir-as-source = ./dir;
         let dir = root.join("dir");
         std::fs::create_dir(&dir)?;
         let foo_default = &foo.join("default.nix");
-        std::fs::write(&foo_default, "import ./baz")?;
+        std::fs::write(foo_default, "import ./baz")?;
         let foo_bar = &foo.join("bar");
-        std::fs::write(&foo_bar, "This file should not be watched")?;
+        std::fs::write(foo_bar, "This file should not be watched")?;
         let foo_baz = &foo.join("baz");
-        std::fs::write(&foo_baz, "\"This file should be watched\"")?;
+        std::fs::write(foo_baz, "\"This file should be watched\"")?;
 
         let cas =
             ContentAddressable::new(crate::AbsPathBuf::new(cas_tmp.path().join("cas")).unwrap())?;
 
         let inst_info = instrumented_instantiation(
             &NixFile::from(AbsPathBuf::new(shell).unwrap()),
+            None,
+            &ProjectConfig::default(),
             &cas,
             &NixOptions::empty(),
+            &RunOptions::default(),
             &crate::logging::test_logger(),
         )
         .unwrap();
@@ -693,4 +1340,49 @@ dir-as-source = ./dir;
         );
         Ok(())
     }
+
+    /// Cancelling kills the whole process group, also processes registered late.
+    #[test]
+    fn cancellation_kills_process_group() -> std::io::Result<()> {
+        use std::os::unix::process::ExitStatusExt;
+        let spawn = || {
+            let mut cmd = Command::new("sh");
+            // the child of the shell has to be killed as well,
+            // otherwise it keeps stdout open
+            cmd.args(["-c", "sleep 60; echo done"])
+                .stdout(Stdio::piped());
+            Cancellation::own_process_group(&mut cmd);
+            cmd.spawn()
+        };
+        let cancellation = Cancellation::new();
+        let running = spawn()?;
+        cancellation.register(&running);
+        cancellation.cancel();
+        assert!(cancellation.is_cancelled());
+        let late = spawn()?;
+        cancellation.register(&late);
+        for child in [running, late] {
+            let output = child.wait_with_output()?;
+            assert_eq!(
+                output.status.signal(),
+                Some(::nix::sys::signal::Signal::SIGTERM as i32)
+            );
+            assert!(output.stdout.is_empty());
+        }
+        Ok(())
+    }
+
+    /// The watchdog cancels the build once the timeout is over,
+    /// unless the build finished (and dropped the watchdog) before.
+    #[test]
+    fn watchdog_cancels_after_timeout() {
+        let (slow, fast) = (Cancellation::new(), Cancellation::new());
+        let slow_watchdog = Watchdog::start(1, slow.clone());
+        drop(Watchdog::start(1, fast.clone()));
+        assert!(!slow_watchdog.timed_out());
+        thread::sleep(Duration::from_millis(1500));
+        assert!(slow_watchdog.timed_out());
+        assert!(slow.is_cancelled());
+        assert!(!fast.is_cancelled());
+    }
 }
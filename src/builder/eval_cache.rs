@@ -0,0 +1,564 @@
+//! Skip the evaluation of a project whose inputs did not change
+//! since it was last evaluated, e.g. before the daemon was restarted.
+//!
+//! After each successful build, the paths the evaluation read are
+//! saved to the CAS together with the hashes of their contents
+//! and the results of the build.
+//!
+//! Projects whose shell files have the same contents can also reuse
+//! each other’s evaluations, see `shared_key`.
+
+use super::{shell_args_expression, BuildInputs, GcRootTempDir, RootedPath, RunResult};
+use crate::cas::ContentAddressable;
+use crate::nix::options::NixOptions;
+use crate::nix::StorePath;
+use crate::project::config::ProjectConfig;
+use crate::watch::{WatchPathBuf, WatchReason};
+use crate::{DrvFile, NixFile};
+use slog::debug;
+use std::collections::{BTreeMap, HashMap};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+/// What is remembered about a successful build.
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    inputs: Vec<Input>,
+    drv: PathBuf,
+    output: PathBuf,
+    build_inputs: Vec<PathBuf>,
+    /// See `RunResult.watch_reasons`.
+    #[serde(default)]
+    watch_reasons: Vec<(WatchPathBuf, WatchReason)>,
+}
+
+/// A path read by the evaluation.
+#[derive(Serialize, Deserialize)]
+struct Input {
+    path: WatchPathBuf,
+    /// `None` if the path did not exist.
+    hash: Option<String>,
+}
+
+/// The cache key of an evaluation of `nix_file`.
+///
+/// Everything that influences the evaluation
+/// but is not an input file has to be part of it.
+pub fn key(
+    nix_file: &NixFile,
+    attr: Option<&str>,
+    nix_options: &NixOptions,
+    env: &BTreeMap<&String, &String>,
+) -> String {
+    format!(
+        "evaluation\0{}\0{}\0{}\0{:?}\0{}\0{:x}",
+        nix_file.display(),
+        attr.unwrap_or(""),
+        nix_options.to_nix_arglist().join("\0"),
+        env,
+        crate::RUN_TIME_CLOSURE,
+        md5::compute(include_str!("../logged-evaluation.nix"))
+    )
+}
+
+/// The cache key under which `nix_file` shares its evaluation with
+/// the other projects, or `None` if its evaluation can’t be shared.
+///
+/// Shell files with the same contents (like the ones `lorri init` writes)
+/// evaluate to the same shell in every project, unless the evaluation
+/// depends on where the file is: a relative path or a position in it,
+/// the directory of a flake, or a `pre_eval` hook changing the project.
+/// Everything else the evaluation reads is found at the same path
+/// in every project, and is checked like for `key`.
+pub fn shared_key(
+    nix_file: &NixFile,
+    attr: Option<&str>,
+    config: &ProjectConfig,
+    nix_options: &NixOptions,
+    env: &BTreeMap<&String, &String>,
+) -> Option<String> {
+    if nix_file.is_flake() || config.pre_eval.is_some() {
+        return None;
+    }
+    let contents = std::fs::read(nix_file.as_absolute_path()).ok()?;
+    let shell_args = shell_args_expression(&config.nix.args, &config.nix.argstrs);
+    if depends_on_location(&contents) || depends_on_location(shell_args.as_bytes()) {
+        return None;
+    }
+    Some(format!(
+        "shared evaluation\0{:x}\0{}\0{}\0{:?}\0{}\0{:?}\0{}\0{:x}",
+        md5::compute(&contents),
+        attr.unwrap_or(""),
+        shell_args,
+        config.env_backend,
+        nix_options.to_nix_arglist().join("\0"),
+        env,
+        crate::RUN_TIME_CLOSURE,
+        md5::compute(include_str!("../logged-evaluation.nix"))
+    ))
+}
+
+/// Whether evaluating `code` might depend on the location of its file.
+///
+/// Errs on the side of caution: anything that looks like a relative path
+/// counts, even in a string or a comment.
+fn depends_on_location(code: &[u8]) -> bool {
+    let contains = |needle: &[u8]| code.windows(needle.len()).any(|window| window == needle);
+    contains(b"./") || contains(b"__curPos") || contains(b"unsafeGetAttrPos")
+}
+
+/// The result of the last build with `key`,
+/// if none of the inputs of its evaluation changed since
+/// and its outputs are still in the nix store.
+pub fn lookup(cas: &ContentAddressable, key: &str, logger: &slog::Logger) -> Option<RunResult> {
+    restore(read_entry(cas, key, logger)?, logger)
+}
+
+/// Like `lookup`, for the result another project saved with `save_shared`.
+///
+/// The project’s own `nix_file` and `watch_reasons` are added to the inputs.
+pub fn lookup_shared(
+    cas: &ContentAddressable,
+    key: &str,
+    nix_file: &NixFile,
+    watch_reasons: BTreeMap<WatchPathBuf, WatchReason>,
+    logger: &slog::Logger,
+) -> Option<RunResult> {
+    let mut entry = read_entry(cas, key, logger)?;
+    let own_paths = watch_reasons
+        .keys()
+        .cloned()
+        .chain(std::iter::once(WatchPathBuf::Normal(
+            nix_file.as_absolute_path().to_owned(),
+        )));
+    for path in own_paths {
+        let hash = hash(&path).ok()?;
+        entry.inputs.push(Input { path, hash });
+    }
+    entry.watch_reasons = watch_reasons.into_iter().collect();
+    restore(entry, logger)
+}
+
+fn read_entry(cas: &ContentAddressable, key: &str, logger: &slog::Logger) -> Option<Entry> {
+    match cas.get_key(key) {
+        Ok(Some(content)) => match serde_json::from_str(&content) {
+            Ok(entry) => Some(entry),
+            Err(e) => {
+                debug!(logger, "could not parse evaluation cache entry"; "error" => %e);
+                None
+            }
+        },
+        Ok(None) => None,
+        Err(e) => {
+            debug!(logger, "could not read evaluation cache"; "error" => %e);
+            None
+        }
+    }
+}
+
+/// The `RunResult` of `entry`, if its inputs did not change
+/// and its outputs are still in the nix store.
+fn restore(entry: Entry, logger: &slog::Logger) -> Option<RunResult> {
+    if !entry.drv.exists() || !entry.output.exists() {
+        debug!(logger, "cached build result was garbage collected"; "output" => entry.output.display());
+        return None;
+    }
+    for input in &entry.inputs {
+        match hash(&input.path) {
+            Ok(hash) if hash == input.hash => {}
+            _ => {
+                debug!(logger, "evaluation input changed"; "path" => input.path.as_ref().display());
+                return None;
+            }
+        }
+    }
+
+    // the cached paths might be collected before the project roots them
+    let build_inputs: Vec<PathBuf> = entry
+        .build_inputs
+        .into_iter()
+        .filter(|path| path.exists())
+        .collect();
+    let rooted =
+        crate::nix::GcRootTempDir::rooting(&[entry.output.as_path(), entry.drv.as_path()], logger)
+            .and_then(|gc_handle| {
+                let dir = tempfile::TempDir::new()?;
+                let paths: Vec<&Path> = build_inputs.iter().map(|path| path.as_path()).collect();
+                crate::nix::store::add_temporary_roots(&paths, dir.path(), logger)?;
+                Ok((gc_handle, GcRootTempDir::Dir(dir)))
+            });
+    let (gc_handle, build_inputs_handle) = match rooted {
+        Ok(handles) => handles,
+        Err(e) => {
+            debug!(logger, "could not root cached build result"; "error" => %e);
+            return None;
+        }
+    };
+    // it might have been collected before we rooted it
+    if !entry.drv.exists() || !entry.output.exists() {
+        return None;
+    }
+    Some(RunResult {
+        referenced_paths: entry
+            .inputs
+            .iter()
+            .map(|input| input.path.clone())
+            .collect(),
+        input_hashes: Some(
+            entry
+                .inputs
+                .into_iter()
+                .map(|input| (input.path, input.hash))
+                .collect(),
+        ),
+        hashes_before_eval: BTreeMap::new(),
+        watch_reasons: entry.watch_reasons.into_iter().collect(),
+        result: RootedPath {
+            gc_handle,
+            path: StorePath::from(entry.output.into_os_string()),
+        },
+        build_inputs: BuildInputs {
+            _gc_handle: build_inputs_handle,
+            drv: DrvFile::from(entry.drv),
+            paths: build_inputs
+                .into_iter()
+                .map(|path| StorePath::from(path.into_os_string()))
+                .collect(),
+        },
+    })
+}
+
+/// Remember `result` as the result of the build with `key`.
+///
+/// Failing to save is not an error, the next build just has to evaluate.
+pub fn save(cas: &ContentAddressable, key: &str, result: &RunResult, logger: &slog::Logger) {
+    if let Some(entry) = Entry::from_result(result) {
+        write_entry(cas, key, &entry, logger);
+    }
+}
+
+/// Remember `result` for the projects whose evaluation has `key`,
+/// see `shared_key`.
+///
+/// The paths only the project of `nix_file` watches are left out,
+/// `lookup_shared` adds the ones of the project which looks it up.
+pub fn save_shared(
+    cas: &ContentAddressable,
+    key: &str,
+    nix_file: &NixFile,
+    result: &RunResult,
+    logger: &slog::Logger,
+) {
+    if let Some(mut entry) = Entry::from_result(result) {
+        entry.inputs.retain(|input| {
+            let path: &Path = input.path.as_ref();
+            path != nix_file.as_absolute_path() && !result.watch_reasons.contains_key(&input.path)
+        });
+        entry.watch_reasons = vec![];
+        write_entry(cas, key, &entry, logger);
+    }
+}
+
+impl Entry {
+    /// `None` if the inputs of `result` could not be hashed.
+    fn from_result(result: &RunResult) -> Option<Entry> {
+        let inputs = result
+            .input_hashes
+            .as_ref()?
+            .iter()
+            .map(|(path, hash)| Input {
+                path: path.clone(),
+                hash: hash.clone(),
+            })
+            .collect();
+        Some(Entry {
+            inputs,
+            drv: result.build_inputs.drv.as_path().to_owned(),
+            output: result.result.path.as_path().to_owned(),
+            build_inputs: result
+                .build_inputs
+                .paths
+                .iter()
+                .map(|path| path.as_path().to_owned())
+                .collect(),
+            watch_reasons: result
+                .watch_reasons
+                .iter()
+                .map(|(path, reason)| (path.clone(), *reason))
+                .collect(),
+        })
+    }
+}
+
+fn write_entry(cas: &ContentAddressable, key: &str, entry: &Entry, logger: &slog::Logger) {
+    let saved = serde_json::to_string(entry)
+        .map_err(std::io::Error::from)
+        .and_then(|content| cas.file_from_string(&content))
+        .and_then(|file| cas.set_key(key, &file));
+    if let Err(e) = saved {
+        debug!(logger, "could not save evaluation cache entry"; "error" => %e);
+    }
+}
+
+/// Hash the contents of all `paths`, see `hash`.
+pub fn hash_inputs(
+    paths: &[WatchPathBuf],
+    logger: &slog::Logger,
+) -> Option<BTreeMap<WatchPathBuf, Option<String>>> {
+    let hashes = paths
+        .iter()
+        .map(|path| Ok((path.clone(), hash(path)?)))
+        .collect::<std::io::Result<_>>();
+    match hashes {
+        Ok(hashes) => Some(hashes),
+        Err(e) => {
+            debug!(logger, "could not hash evaluation inputs"; "error" => %e);
+            None
+        }
+    }
+}
+
+/// Hash the contents of `path`, or `None` if it does not exist.
+///
+/// Only the file listing of a `Normal` directory is hashed,
+/// a `Recursive` one is hashed with all its contents.
+pub fn hash(path: &WatchPathBuf) -> std::io::Result<Option<String>> {
+    hash_in(path, crate::nix::store::store_dir())
+}
+
+/// `hash`, with the nix store in `store_dir`.
+fn hash_in(path: &WatchPathBuf, store_dir: &Path) -> std::io::Result<Option<String>> {
+    let mut context = md5::Context::new();
+    let res = match path {
+        // store paths never change, so it only matters whether they exist
+        WatchPathBuf::Normal(path) | WatchPathBuf::Recursive(path)
+            if path.starts_with(store_dir) =>
+        {
+            std::fs::symlink_metadata(path).map(|_| context.consume(path.as_os_str().as_bytes()))
+        }
+        WatchPathBuf::Normal(path) => hash_path(&mut context, path, false),
+        WatchPathBuf::Recursive(path) => hash_path(&mut context, path, true),
+    };
+    match res {
+        Ok(()) => Ok(Some(format!("{:x}", context.compute()))),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn hash_path(context: &mut md5::Context, path: &Path, recursive: bool) -> std::io::Result<()> {
+    let metadata = if recursive {
+        std::fs::symlink_metadata(path)?
+    } else {
+        std::fs::metadata(path)?
+    };
+    if metadata.file_type().is_symlink() {
+        // nix copies symlinks in a source as they are, so only the target matters
+        // (and following them could loop forever)
+        context.consume(std::fs::read_link(path)?.as_os_str().as_bytes());
+    } else if metadata.is_dir() {
+        let mut entries = std::fs::read_dir(path)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<std::io::Result<Vec<PathBuf>>>()?;
+        entries.sort();
+        for entry in entries {
+            if let Some(name) = entry.file_name() {
+                context.consume(name.as_bytes());
+                context.consume(b"\0");
+            }
+            if recursive {
+                hash_path(context, &entry, true)?;
+            }
+        }
+    } else if metadata.is_file() {
+        context.consume(hash_file(path, &metadata)?.0);
+    } else {
+        // reading a fifo or a device could block forever, and nix can’t read them either
+        context.consume(format!("special file {:o}", metadata.mode()));
+    }
+    Ok(())
+}
+
+/// What tells one version of a regular file from another without reading it.
+#[derive(PartialEq, Eq)]
+struct Stamp {
+    len: u64,
+    modified: SystemTime,
+    inode: u64,
+}
+
+/// A file changed less than this ago could change again
+/// without changing its modification time.
+const RACY_MODIFICATION: Duration = Duration::from_secs(2);
+
+lazy_static::lazy_static! {
+    /// The hashes of the regular files read so far, by path.
+    static ref FILE_HASHES: Mutex<HashMap<PathBuf, (Stamp, md5::Digest)>> =
+        Mutex::new(HashMap::new());
+}
+
+/// The hash of the contents of the regular file `path`,
+/// which is only read again if its `Stamp` changed.
+fn hash_file(path: &Path, metadata: &std::fs::Metadata) -> std::io::Result<md5::Digest> {
+    let stamp = Stamp {
+        len: metadata.len(),
+        modified: metadata.modified()?,
+        inode: metadata.ino(),
+    };
+    match FILE_HASHES
+        .lock()
+        .expect("file hashes lock poisoned")
+        .get(path)
+    {
+        Some((known, digest)) if *known == stamp => return Ok(*digest),
+        _ => {}
+    }
+    let digest = md5::compute(std::fs::read(path)?);
+    let settled = stamp
+        .modified
+        .checked_add(RACY_MODIFICATION)
+        .map_or(false, |settled| settled < SystemTime::now());
+    let mut hashes = FILE_HASHES.lock().expect("file hashes lock poisoned");
+    if settled {
+        hashes.insert(path.to_owned(), (stamp, digest));
+    } else {
+        hashes.remove(path);
+    }
+    Ok(digest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Changing an input file changes its hash, a missing file has none.
+    #[test]
+    fn hashes_follow_contents() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let file = tmp.path().join("shell.nix");
+        let dir = WatchPathBuf::Recursive(tmp.path().to_owned());
+        let listing = WatchPathBuf::Normal(tmp.path().to_owned());
+
+        let store = Path::new("/nix/store");
+        let hash = |path: &WatchPathBuf| hash_in(path, store);
+
+        assert_eq!(hash(&WatchPathBuf::Normal(file.clone()))?, None);
+        std::fs::write(&file, "{}")?;
+        let file_hash = hash(&WatchPathBuf::Normal(file.clone()))?;
+        let dir_hash = hash(&dir)?;
+        let listing_hash = hash(&listing)?;
+        assert!(file_hash.is_some());
+
+        std::fs::write(&file, "{ x = 1; }")?;
+        assert_ne!(hash(&WatchPathBuf::Normal(file))?, file_hash);
+        assert_ne!(hash(&dir)?, dir_hash);
+        // the directory listing is still the same
+        assert_eq!(hash(&listing)?, listing_hash);
+        Ok(())
+    }
+
+    /// Shell files with the same contents share their evaluation,
+    /// unless it might depend on where the file is.
+    #[test]
+    fn identical_shells_share_evaluations() -> std::io::Result<()> {
+        let (a, b) = (tempfile::tempdir()?, tempfile::tempdir()?);
+        let shell = |dir: &tempfile::TempDir, contents: &str| -> std::io::Result<NixFile> {
+            let path = dir.path().join("shell.nix");
+            std::fs::write(&path, contents)?;
+            Ok(NixFile::from(crate::AbsPathBuf::new(path).unwrap()))
+        };
+        let key = |nix_file: &NixFile, config: &ProjectConfig| {
+            shared_key(
+                nix_file,
+                None,
+                config,
+                &NixOptions::empty(),
+                &BTreeMap::new(),
+            )
+        };
+        let config = ProjectConfig::default();
+
+        let pinned = "with import <nixpkgs> {}; mkShell {}";
+        let (shell_a, shell_b) = (shell(&a, pinned)?, shell(&b, pinned)?);
+        assert!(key(&shell_a, &config).is_some());
+        assert_eq!(key(&shell_a, &config), key(&shell_b, &config));
+
+        let shell_b = shell(&b, "with import <nixpkgs> {}; mkShell { name = \"b\"; }")?;
+        assert!(key(&shell_b, &config).is_some());
+        assert_ne!(key(&shell_a, &config), key(&shell_b, &config));
+
+        // `./.` is a different directory in every project
+        let shell_b = shell(&b, "with import <nixpkgs> {}; mkShell { src = ./.; }")?;
+        assert_eq!(key(&shell_b, &config), None);
+
+        let with_hook = ProjectConfig {
+            pre_eval: Some(String::from("make")),
+            ..ProjectConfig::default()
+        };
+        assert_eq!(key(&shell_a, &with_hook), None);
+        Ok(())
+    }
+
+    /// Files in the store are not read, they can’t change.
+    #[test]
+    fn store_paths_are_not_read() -> std::io::Result<()> {
+        let store = tempfile::tempdir()?;
+        let source = store.path().join("abc-source");
+        std::fs::create_dir(&source)?;
+        std::fs::write(source.join("default.nix"), "{}")?;
+        let input = WatchPathBuf::Recursive(source.clone());
+
+        let before = hash_in(&input, store.path())?;
+        assert!(before.is_some());
+        std::fs::write(source.join("default.nix"), "{ x = 1; }")?;
+        assert_eq!(hash_in(&input, store.path())?, before);
+        std::fs::remove_dir_all(&source)?;
+        assert_eq!(hash_in(&input, store.path())?, None);
+        Ok(())
+    }
+
+    /// Fifos are hashed without reading them, which would block.
+    #[test]
+    fn special_files_are_not_read() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let fifo = tmp.path().join("fifo");
+        let status = std::process::Command::new("mkfifo").arg(&fifo).status()?;
+        assert!(status.success());
+        let store = Path::new("/nix/store");
+        assert!(hash_in(&WatchPathBuf::Normal(fifo), store)?.is_some());
+        assert!(hash_in(&WatchPathBuf::Recursive(tmp.path().to_owned()), store)?.is_some());
+        Ok(())
+    }
+
+    /// Files which did not change since they were hashed are not read again.
+    #[test]
+    fn settled_files_are_hashed_once() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let file = tmp.path().join("shell.nix");
+        let touch = |date: &str| -> std::io::Result<()> {
+            let status = std::process::Command::new("touch")
+                .args(["-m", "-d", date])
+                .arg(&file)
+                .status()?;
+            assert!(status.success());
+            Ok(())
+        };
+        std::fs::write(&file, "{}")?;
+        touch("@1600000000")?;
+        let input = WatchPathBuf::Normal(file.clone());
+        let store = Path::new("/nix/store");
+        let before = hash_in(&input, store)?;
+
+        // same size and modification time, so the contents aren’t read
+        std::fs::write(&file, "[]")?;
+        touch("@1600000000")?;
+        assert_eq!(hash_in(&input, store)?, before);
+        // with any other modification time they are
+        touch("@1600000001")?;
+        assert_ne!(hash_in(&input, store)?, before);
+        Ok(())
+    }
+}
@@ -0,0 +1,118 @@
+//! Download the dependencies of a shell from the substituters
+//! while lorri builds it, as soon as its derivation is known.
+//!
+//! The build substitutes them as well, but only once it needs them;
+//! with `nix print-dev-env` that is after the shell was evaluated again.
+
+use super::Cancellation;
+use crate::nix::options::NixOptions;
+use crate::nix::store;
+use crate::DrvFile;
+use slog::debug;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+
+/// A running prefetch. Dropping it stops the download.
+pub struct Prefetch {
+    cancellation: Cancellation,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Prefetch {
+    /// Start substituting the outputs of the input derivations of `drv`
+    /// which are not in the store yet.
+    pub fn start(drv: &DrvFile, nix_options: &NixOptions, logger: &slog::Logger) -> Prefetch {
+        let cancellation = Cancellation::new();
+        let drv = drv.as_path().to_owned();
+        let nix_options = nix_options.clone();
+        let logger = logger.clone();
+        let cancellation2 = cancellation.clone();
+        let thread = thread::spawn(move || {
+            if let Err(e) = prefetch(&drv, &nix_options, &cancellation2, &logger) {
+                debug!(logger, "prefetching failed"; "drv" => drv.display(), "error" => %e);
+            }
+        });
+        Prefetch {
+            cancellation,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for Prefetch {
+    fn drop(&mut self) {
+        // whatever the build still needed, it substituted itself
+        self.cancellation.cancel();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn prefetch(
+    drv: &Path,
+    nix_options: &NixOptions,
+    cancellation: &Cancellation,
+    logger: &slog::Logger,
+) -> Result<(), super::BuildError> {
+    let input_drvs: Vec<PathBuf> = store::references(drv, logger)?
+        .into_iter()
+        .filter(|path| path.extension() == Some(OsStr::new("drv")))
+        .collect();
+    let input_drvs: Vec<&Path> = input_drvs.iter().map(|p| p.as_path()).collect();
+    let missing: Vec<PathBuf> = store::realised_outputs(&input_drvs, logger)?
+        .into_iter()
+        .filter(|path| !path.exists())
+        .collect();
+    if missing.is_empty() || cancellation.is_cancelled() {
+        return Ok(());
+    }
+
+    let mut cmd = prefetch_command(&missing, nix_options);
+    cmd.stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    crate::nix::limit_ssh_connect_time(&mut cmd);
+    Cancellation::own_process_group(&mut cmd);
+    debug!(logger, "prefetching"; "paths" => missing.len(), "command" => ?cmd);
+    let mut child = cmd.spawn()?;
+    cancellation.register(&child);
+    child.wait()?;
+    Ok(())
+}
+
+/// The command which substitutes the output paths `missing`.
+fn prefetch_command(missing: &[PathBuf], nix_options: &NixOptions) -> Command {
+    // realising an output path only substitutes it, it never builds;
+    // the ones no substituter has are left to the build
+    let mut cmd = crate::nix::limits::command("nix-store");
+    cmd.args(["--realise", "--ignore-unknown"])
+        .args(nix_options.to_nix_arglist())
+        .args(missing);
+    cmd
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefetching_only_substitutes() {
+        let missing = vec![
+            PathBuf::from("/nix/store/aaaa-hello-2.10"),
+            PathBuf::from("/nix/store/bbbb-git-2.30"),
+        ];
+        let nix_options = NixOptions {
+            substituters: Some(vec![String::from("https://cache.example.org")]),
+            ..NixOptions::empty()
+        };
+        assert_eq!(
+            format!("{:?}", prefetch_command(&missing, &nix_options)),
+            "\"nix-store\" \"--realise\" \"--ignore-unknown\" \
+             \"--substituters\" \"https://cache.example.org\" \
+             \"/nix/store/aaaa-hello-2.10\" \"/nix/store/bbbb-git-2.30\""
+        );
+    }
+}
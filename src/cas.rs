@@ -78,8 +78,43 @@ impl ContentAddressable {
 
         Ok(file_name)
     }
+
+    /// Make `key` point to `file`, which must be a file of this store.
+    ///
+    /// This allows finding contents by something other than their hash.
+    /// An existing `key` is replaced.
+    pub fn set_key(&self, key: &str, file: &AbsPathBuf) -> std::io::Result<()> {
+        let keys_dir = self.store_dir.join(KEYS_DIR);
+        std::fs::create_dir_all(keys_dir.as_path())?;
+        // create the link in a temporary directory first,
+        // so that readers never see a half-written key
+        let tmp_dir = tempfile::Builder::new()
+            .prefix(".tmp")
+            .tempdir_in(keys_dir.as_path())?;
+        let tmp_link = tmp_dir.path().join("link");
+        std::os::unix::fs::symlink(file.as_path(), &tmp_link)?;
+        std::fs::rename(&tmp_link, self.key_path(key).as_path())
+    }
+
+    /// The contents `key` points to, if it was set with `set_key`.
+    pub fn get_key(&self, key: &str) -> std::io::Result<Option<String>> {
+        match std::fs::read_to_string(self.key_path(key).as_path()) {
+            Ok(content) => Ok(Some(content)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn key_path(&self, key: &str) -> AbsPathBuf {
+        self.store_dir
+            .join(KEYS_DIR)
+            .join(format!("{:x}", md5::compute(key.as_bytes())))
+    }
 }
 
+/// Subdirectory of the store which contains the keys (see `set_key`).
+const KEYS_DIR: &str = "keys";
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -106,10 +141,11 @@ mod tests {
             cas_file.as_path().parent().unwrap()
         );
         // the content should be the same in the file that was written
-        Ok(assert_eq!(
+        assert_eq!(
             content,
             std::str::from_utf8(&std::fs::read(cas_file)?).unwrap()
-        ))
+        );
+        Ok(())
     }
 
     /// Ensures that adding the same content twice does not write
@@ -132,4 +168,20 @@ mod tests {
         assert_eq!(first_mtime, second_mtime);
         Ok(())
     }
+
+    /// Keys point to the contents they were last set to.
+    #[test]
+    fn keys_point_to_content() -> std::io::Result<()> {
+        let store_dir = tempfile::tempdir()?;
+        let cas = ContentAddressable::new(abs_path(&store_dir)).unwrap();
+        assert_eq!(cas.get_key("key")?, None);
+
+        cas.set_key("key", &cas.file_from_string("first")?)?;
+        assert_eq!(cas.get_key("key")?, Some(String::from("first")));
+
+        cas.set_key("key", &cas.file_from_string("second")?)?;
+        assert_eq!(cas.get_key("key")?, Some(String::from("second")));
+        assert_eq!(cas.get_key("other key")?, None);
+        Ok(())
+    }
 }
@@ -20,6 +20,16 @@ pub struct Arguments {
     #[structopt(short = "v", long = "verbose", parse(from_occurrences))]
     pub verbosity: u8,
 
+    /// How log messages are formatted: "full", "compact", or "json" (one object per line).
+    /// Overrides `log_format` in the configuration file.
+    #[structopt(long = "log-format")]
+    pub log_format: Option<crate::logging::LogFormat>,
+
+    /// Log levels per module, like "watch=warn,builder=debug", and optionally for all
+    /// other modules, like "info,watch=warn". Overrides `log_filter` in the configuration file.
+    #[structopt(long = "log-filter")]
+    pub log_filter: Option<crate::logging::LogFilter>,
+
     /// Sub-command to execute
     #[structopt(subcommand)]
     pub command: Command,
@@ -58,13 +68,92 @@ pub enum Command {
     #[structopt(name = "daemon")]
     Daemon(DaemonOptions),
 
+    /// Build the project once and print the path of its environment
+    #[structopt(name = "build")]
+    Build(BuildOptions),
+
+    /// Print the environment of the project’s last successful build,
+    /// e.g. for a Makefile, a container entrypoint or a CI step
+    #[structopt(name = "env")]
+    Env(EnvOptions),
+
     /// Upgrade Lorri
     #[structopt(name = "self-upgrade", alias = "self-update")]
     Upgrade(UpgradeTo),
 
     /// Write bootstrap files to current directory to create a new lorri project
     #[structopt(name = "init")]
-    Init,
+    Init(InitOptions),
+
+    /// Switch the project environment back to a previous generation
+    #[structopt(name = "rollback")]
+    Rollback(RollbackOptions),
+
+    /// Show which variables and store paths changed between two builds of a project
+    #[structopt(name = "diff")]
+    Diff(DiffOptions),
+
+    /// Remove GC roots of projects whose nix file does not exist anymore
+    #[structopt(name = "gc")]
+    Gc(GcOptions),
+
+    /// Show how much disk space the environments of all projects use
+    #[structopt(name = "du")]
+    Du,
+
+    /// Check whether nix, direnv and the daemon are set up correctly,
+    /// and explain how to fix any problems
+    #[structopt(name = "doctor")]
+    Doctor,
+
+    /// Show the output of the daemon’s current build of a project
+    #[structopt(name = "logs")]
+    Logs(LogsOptions),
+
+    /// List the projects the daemon builds, and the state of their builds
+    #[structopt(name = "ps")]
+    Ps(PsOptions),
+
+    /// Print the state of the project’s builds in one word, quickly enough for shell prompts
+    #[structopt(name = "status")]
+    Status(StatusOptions),
+
+    /// List the daemon’s recent builds of a project, with how long they took
+    #[structopt(name = "history")]
+    History(HistoryOptions),
+
+    /// Stop the daemon from building a project (or all projects) until it is resumed
+    #[structopt(name = "pause")]
+    Pause(PauseOptions),
+
+    /// Let the daemon build a paused project (or all projects) again
+    #[structopt(name = "resume")]
+    Resume(PauseOptions),
+
+    /// Stop the daemon from watching and building a project
+    #[structopt(name = "untrack")]
+    Untrack(UntrackOptions),
+
+    /// Name a project, so that commands accept the name instead of its nix file
+    #[structopt(name = "name")]
+    Name(NameOptions),
+
+    /// Make the daemon watch and build all projects of the workspace the current directory is in
+    #[structopt(name = "workspace")]
+    Workspace(WorkspaceOptions),
+
+    /// Show, rebuild, pause or resume all projects with a tag from their `.lorri.toml`
+    #[structopt(name = "group")]
+    Group(GroupOptions),
+
+    /// Install the `use lorri` function into direnv’s library
+    /// (`~/.config/direnv/lib/lorri.sh`), or update or remove it
+    #[structopt(name = "direnv-install")]
+    DirenvInstall(DirenvInstallOptions),
+
+    /// Print a script which completes lorri’s commands and options in a shell
+    #[structopt(name = "completions")]
+    Completions(CompletionsOptions),
 
     /// Internal commands, only use to experiment with unstable features
     #[structopt(name = "internal")]
@@ -81,6 +170,213 @@ pub struct DirenvOptions {
     /// The .nix file in the current directory to use
     #[structopt(long = "shell-file", parse(from_os_str), default_value = "shell.nix")]
     pub nix_file: PathBuf,
+    /// Build only this attribute of the nix file’s expression, e.g. `shells.dev`
+    #[structopt(long = "attr", short = "A")]
+    pub attr: Option<String>,
+    /// Start the daemon in the background if it is not running,
+    /// through its systemd user unit if there is one
+    #[structopt(long = "spawn-daemon")]
+    pub spawn_daemon: bool,
+    /// If the daemon is not running, build the environment right away,
+    /// which blocks until the build is done
+    #[structopt(long = "fallback-build")]
+    pub fallback_build: bool,
+    /// The shell to print the environment for (bash|fish|elvish|tcsh);
+    /// anything but bash only gets the variables, for direnv-like tools
+    #[structopt(long = "shell", default_value = "bash")]
+    pub shell: crate::ops::DirenvShell,
+}
+
+/// Options for the `build` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct BuildOptions {
+    /// The .nix file in the current directory to use
+    #[structopt(long = "shell-file", parse(from_os_str), default_value = "shell.nix")]
+    pub nix_file: PathBuf,
+    /// Build only this attribute of the nix file’s expression, e.g. `shells.dev`
+    #[structopt(long = "attr", short = "A")]
+    pub attr: Option<String>,
+    /// Print the result as JSON, for use in scripts. An object with the fields
+    /// `nix_file`, `success`, `out_path`, `gc_root`, `duration_ms` and `error`
+    /// (with `kind`, `message` and `diagnostics`) is printed, even if the build fails.
+    #[structopt(long = "json")]
+    pub json: bool,
+    /// Abort the build after this many seconds, overriding the project’s `build_timeout_secs`
+    #[structopt(long = "timeout")]
+    pub timeout: Option<u64>,
+}
+
+/// Options for the `logs` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct LogsOptions {
+    /// The .nix file of the project
+    #[structopt(long = "shell-file", parse(from_os_str), default_value = "shell.nix")]
+    pub nix_file: PathBuf,
+    /// The name of the project (see `lorri name`), instead of `--shell-file`
+    #[structopt(name = "project")]
+    pub project: Option<String>,
+    /// Keep printing the output of this and all following builds as it happens
+    #[structopt(long = "follow")]
+    pub follow: bool,
+    /// Print the output of the earlier builds as well, from the log file
+    /// the daemon keeps for the project (this works without the daemon)
+    #[structopt(long = "history")]
+    pub history: bool,
+    /// The attribute of the nix file’s expression the project builds
+    #[structopt(long = "attr", short = "A")]
+    pub attr: Option<String>,
+}
+
+/// Options for the `env` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct EnvOptions {
+    /// The .nix file in the current directory to use
+    #[structopt(long = "shell-file", parse(from_os_str), default_value = "shell.nix")]
+    pub nix_file: PathBuf,
+    /// Build only this attribute of the nix file’s expression, e.g. `shells.dev`
+    #[structopt(long = "attr", short = "A")]
+    pub attr: Option<String>,
+    /// How to print the variables: `bash` (`export` statements), `fish` (`set -gx`
+    /// statements), `json` (an object) or `dotenv` (`NAME="value"` lines)
+    #[structopt(long = "format", default_value = "bash")]
+    pub format: crate::ops::EnvFormat,
+}
+
+/// Options for the `init` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct InitOptions {
+    /// The kind of project to create: `shell` (a shell.nix using the system’s nixpkgs),
+    /// `flake` (a flake.nix), `niv` or `npins` (a shell.nix with nixpkgs pinned by niv
+    /// or npins) or `channel` (a shell.nix with nixpkgs from a release channel)
+    #[structopt(long = "template", default_value = "shell")]
+    pub template: crate::ops::InitTemplate,
+}
+
+/// Options for the `completions` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct CompletionsOptions {
+    /// The shell to print the completion script for
+    #[structopt(raw(possible_values = "&structopt::clap::Shell::variants()"))]
+    pub shell: structopt::clap::Shell,
+}
+
+/// Options for the `ps` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct PsOptions {
+    /// Print the projects as JSON, for use in scripts
+    #[structopt(long = "json")]
+    pub json: bool,
+}
+
+/// Options for the `history` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct HistoryOptions {
+    /// The .nix file of the project
+    #[structopt(long = "shell-file", parse(from_os_str), default_value = "shell.nix")]
+    pub nix_file: PathBuf,
+    /// The attribute of the nix file’s expression the project builds
+    #[structopt(long = "attr", short = "A")]
+    pub attr: Option<String>,
+    /// How many of the most recent builds to list
+    #[structopt(long = "limit", default_value = "20")]
+    pub limit: usize,
+    /// Print the builds and statistics as JSON, for use in scripts
+    #[structopt(long = "json")]
+    pub json: bool,
+}
+
+/// Options for the `status` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct StatusOptions {
+    /// The .nix file of the project
+    #[structopt(long = "shell-file", parse(from_os_str), default_value = "shell.nix")]
+    pub nix_file: PathBuf,
+    /// The attribute of the nix file’s expression the project builds
+    #[structopt(long = "attr", short = "A")]
+    pub attr: Option<String>,
+}
+
+/// Options for the `name` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct NameOptions {
+    /// The .nix file of the project
+    #[structopt(long = "shell-file", parse(from_os_str), default_value = "shell.nix")]
+    pub nix_file: PathBuf,
+    /// The attribute of the nix file’s expression the project builds
+    #[structopt(long = "attr", short = "A")]
+    pub attr: Option<String>,
+    /// The new name; without it, print the project’s name
+    #[structopt(name = "name")]
+    pub name: Option<String>,
+}
+
+/// Options for the `workspace` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct WorkspaceOptions {
+    /// Only list the nix files of the workspace’s projects
+    #[structopt(long = "list")]
+    pub list: bool,
+}
+
+/// Options for the `group` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct GroupOptions {
+    /// The tag of the projects
+    #[structopt(name = "tag")]
+    pub tag: String,
+    /// What to do with the projects
+    #[structopt(
+        name = "action",
+        default_value = "status",
+        raw(possible_values = r#"&["status", "rebuild", "pause", "resume"]"#)
+    )]
+    pub action: String,
+    /// Print the projects as JSON, for use in scripts
+    #[structopt(long = "json")]
+    pub json: bool,
+}
+
+/// Options for the `pause` and `resume` subcommands.
+#[derive(StructOpt, Debug)]
+pub struct PauseOptions {
+    /// The .nix file of the project
+    #[structopt(long = "shell-file", parse(from_os_str), default_value = "shell.nix")]
+    pub nix_file: PathBuf,
+    /// The attribute of the nix file’s expression the project builds
+    #[structopt(long = "attr", short = "A")]
+    pub attr: Option<String>,
+    /// Pause or resume all projects instead
+    #[structopt(long = "all")]
+    pub all: bool,
+}
+
+/// Options for the `untrack` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct UntrackOptions {
+    /// The .nix file of the project
+    #[structopt(long = "shell-file", parse(from_os_str), default_value = "shell.nix")]
+    pub nix_file: PathBuf,
+    /// The attribute of the nix file’s expression the project builds
+    #[structopt(long = "attr", short = "A")]
+    pub attr: Option<String>,
+    /// The name of the project (see `lorri name`), instead of `--shell-file`
+    #[structopt(name = "project")]
+    pub project: Option<String>,
+    /// Also delete the project’s GC roots (so that nix can collect
+    /// its environment) and the state lorri keeps for it
+    #[structopt(long = "remove-roots")]
+    pub remove_roots: bool,
+}
+
+/// Options for the `direnv-install` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct DirenvInstallOptions {
+    /// Remove the integration instead of installing it
+    #[structopt(long = "uninstall")]
+    pub uninstall: bool,
+    /// Replace or remove the file even if it was not written by lorri
+    #[structopt(long = "force")]
+    pub force: bool,
 }
 
 /// Options for the `info` subcommand.
@@ -92,6 +388,12 @@ pub struct InfoOptions {
     // file was causing problems when they submit a bug report.
     #[structopt(long = "shell-file", parse(from_os_str))]
     pub nix_file: PathBuf,
+    /// The attribute of the nix file’s expression the project builds
+    #[structopt(long = "attr", short = "A")]
+    pub attr: Option<String>,
+    /// Print the information as JSON, for use in scripts
+    #[structopt(long = "json")]
+    pub json: bool,
 }
 
 /// Options for the `shell` subcommand.
@@ -100,6 +402,9 @@ pub struct ShellOptions {
     /// The .nix file in the current directory to use
     #[structopt(long = "shell-file", parse(from_os_str), default_value = "shell.nix")]
     pub nix_file: PathBuf,
+    /// Build only this attribute of the nix file’s expression, e.g. `shells.dev`
+    #[structopt(long = "attr", short = "A")]
+    pub attr: Option<String>,
     /// If true, load environment from cache
     #[structopt(long = "cached")]
     pub cached: bool,
@@ -114,6 +419,9 @@ pub struct StartUserShellOptions_ {
     /// The .nix file in the current directory to use to instantiate the project
     #[structopt(long = "shell-file", parse(from_os_str))]
     pub nix_file: PathBuf,
+    /// The attribute of the nix file’s expression the project builds
+    #[structopt(long = "attr", short = "A")]
+    pub attr: Option<String>,
 }
 
 /// Options for the `watch` subcommand.
@@ -122,9 +430,62 @@ pub struct WatchOptions {
     /// The .nix file in the current directory to use
     #[structopt(long = "shell-file", parse(from_os_str), default_value = "shell.nix")]
     pub nix_file: PathBuf,
-    /// Exit after a the first build
+    /// Build only this attribute of the nix file’s expression, e.g. `shells.dev`
+    #[structopt(long = "attr", short = "A")]
+    pub attr: Option<String>,
+    /// Exit after a the first build: with 0 if it succeeded, 1 if the evaluation failed
+    /// or timed out and 2 if a derivation failed to build
     #[structopt(long = "once")]
     pub once: bool,
+    /// With `--once`, abort the build after this many seconds,
+    /// overriding the project’s `build_timeout_secs`
+    #[structopt(long = "timeout", requires = "once")]
+    pub timeout: Option<u64>,
+}
+
+/// Options for the `rollback` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct RollbackOptions {
+    /// The .nix file in the current directory to use
+    #[structopt(long = "shell-file", parse(from_os_str), default_value = "shell.nix")]
+    pub nix_file: PathBuf,
+    /// The attribute of the nix file’s expression the project builds
+    #[structopt(long = "attr", short = "A")]
+    pub attr: Option<String>,
+    /// The generation to switch to. Defaults to the one before the current generation
+    #[structopt(long = "generation")]
+    pub generation: Option<u64>,
+    /// Only list the available generations
+    #[structopt(long = "list")]
+    pub list: bool,
+}
+
+/// Options for the `diff` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct DiffOptions {
+    /// The .nix file in the current directory to use
+    #[structopt(long = "shell-file", parse(from_os_str), default_value = "shell.nix")]
+    pub nix_file: PathBuf,
+    /// The attribute of the nix file’s expression the project builds
+    #[structopt(long = "attr", short = "A")]
+    pub attr: Option<String>,
+    /// The generation to compare from. Defaults to the one before `--to`
+    #[structopt(long = "from")]
+    pub from: Option<u64>,
+    /// The generation to compare to. Defaults to the current generation
+    #[structopt(long = "to")]
+    pub to: Option<u64>,
+    /// Print the changes, with the old and new values, as JSON
+    #[structopt(long = "json")]
+    pub json: bool,
+}
+
+/// Options for the `gc` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct GcOptions {
+    /// Only list the GC roots and what would be removed, don’t remove anything
+    #[structopt(long = "dry-run")]
+    pub dry_run: bool,
 }
 
 /// Options for the `daemon` subcommand
@@ -134,17 +495,70 @@ pub struct DaemonOptions {
         long = "extra-nix-options",
         parse(try_from_str = "serde_json::from_str")
     )]
-    /// JSON value of nix config options to add.
+    /// JSON value of nix config options to add,
+    /// instead of `extra_nix_options` in the configuration file.
     /// Only a subset is supported:
     /// {
     ///   "builders": <optional list of string>,
     ///   "substituters": <optional list of string>
     /// }
     pub extra_nix_options: Option<NixOptions>,
+    /// How many generations of each project environment to keep as GC roots
+    #[structopt(long = "keep-generations", default_value = "5")]
+    pub keep_generations: usize,
+    /// Remove the GC roots of projects which have not been built or used for this many days,
+    /// so that `nix-collect-garbage` can reclaim them.
+    /// Projects can override this with `gc_root_ttl_days` in their `.lorri.toml`.
+    /// Without it, no GC roots expire
+    #[structopt(long = "gc-root-ttl-days")]
+    pub gc_root_ttl_days: Option<u64>,
+    /// Finish running builds when their project changes,
+    /// instead of killing them and starting over with the latest changes
+    #[structopt(long = "no-cancel-builds")]
+    pub no_cancel_builds: bool,
+    /// How many projects to build at the same time (default: 1).
+    /// Further builds wait in a queue, where projects used recently
+    /// (e.g. through `lorri direnv`) go first
+    #[structopt(long = "max-concurrent-builds")]
+    pub max_concurrent_builds: Option<usize>,
+    /// When a build fails because the remote builders (see `builders` in
+    /// `--extra-nix-options`) can’t be used, build it again on this machine
+    #[structopt(long = "local-build-fallback")]
+    pub local_build_fallback: bool,
+    /// Also rebuild when files change which the `.gitignore` or `.ignore` files
+    /// of a watched directory ignore (e.g. editor caches and build artifacts)
+    #[structopt(long = "no-ignore-files")]
+    pub no_ignore_files: bool,
+    /// How many milliseconds no files may change before a project is rebuilt (default: 100).
+    /// Projects can override this with `debounce_ms` in their `.lorri.toml`
+    #[structopt(long = "debounce-ms")]
+    pub debounce_ms: Option<u64>,
+    /// Check watched files for changes every this many seconds,
+    /// for file systems which don’t report changes (e.g. NFS or Docker bind mounts).
+    /// Projects can override this with `poll_interval_secs` in their `.lorri.toml`
+    #[structopt(long = "poll-interval")]
+    pub poll_interval: Option<u64>,
+    /// When the file system can’t watch any more files
+    /// (e.g. inotify’s `fs.inotify.max_user_watches` is reached),
+    /// poll the remaining ones for changes every this many seconds
+    #[structopt(long = "poll-fallback")]
+    pub poll_fallback: Option<u64>,
+    /// Don’t remember the watched projects when the daemon is stopped,
+    /// so they are only watched again once a client pings them
+    #[structopt(long = "no-persist-projects")]
+    pub no_persist_projects: bool,
+    /// Serve an HTTP API with the status of the projects and their
+    /// build events on this port of the loopback interface
+    #[structopt(long = "http-port")]
+    pub http_port: Option<u16>,
+    /// Log to this file instead of stdout, rotating it as `log_rotation`
+    /// in the configuration file says (by default once it is larger than 10 MB)
+    #[structopt(long = "log-file", parse(from_os_str))]
+    pub log_file: Option<PathBuf>,
 }
 
 /// The nix options we can parse as json string
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone)]
 // ATTN: If you modify this,
 // adjust the help text in DaemonOptions.extra_nix_options
 pub struct NixOptions {
@@ -162,6 +576,11 @@ pub enum Internal_ {
     StartUserShell_(StartUserShellOptions_),
 
     /// (plumbing) Tell the lorri daemon to care about the current directory's project
+    ///
+    /// Prints the state of the daemon: its protocol version, uptime,
+    /// and how many projects it watches, builds and has queued.
+    /// With `--json`, print a JSON object with the fields `protocol_version`,
+    /// `uptime_secs`, `projects`, `building` and `queued`.
     #[structopt(name = "ping")]
     Ping_(Ping_),
 
@@ -171,8 +590,39 @@ pub enum Internal_ {
     /// so if you want to use it in your scripts make sure you follow our changes.
     /// Once it stabilizes a bit more we will start mentioning changes in the changelog,
     /// and eventually ensure backwards compat.
+    ///
+    /// Every event has a `seq` number, which increases with every event the daemon
+    /// sends out, and a `cursor`. Pass the `cursor` of the last one you saw to `--since`
+    /// to get the events you missed.
     #[structopt(name = "stream-events")]
     StreamEvents_(StreamEvents_),
+
+    /// (experimental) Report where in the nix files the daemon’s builds fail, for editors.
+    ///
+    /// After every finished build, print a JSON object per line for each file
+    /// the build’s error points into, with the fields `nix_file` and `attr`
+    /// (the project), `file` and `diagnostics` (a list of objects with `file`,
+    /// `line`, `column` and `message`). Files whose errors are gone get an empty
+    /// list, so that an editor can clear them, like LSP’s
+    /// `textDocument/publishDiagnostics`; each project clears only its own.
+    #[structopt(name = "stream-diagnostics")]
+    StreamDiagnostics_,
+
+    /// (plumbing) List the GC roots of all projects.
+    ///
+    /// With `--json`, print a JSON list of objects with the fields
+    /// `id`, `nix_file`, `project_dir`, `shell_gc_root`, `store_path`,
+    /// `created`, `last_used` (seconds since the epoch) and `reverse_root_exists`.
+    /// Fields which are unknown are `null`.
+    #[structopt(name = "gc-roots")]
+    GcRoots_(GcRoots_),
+
+    /// (plumbing) List the paths the daemon watches for the project, and why.
+    ///
+    /// With `--json`, print a JSON list of objects with the fields
+    /// `path`, `recursive` and `reason`.
+    #[structopt(name = "watched-paths")]
+    WatchedPaths_(WatchedPaths_),
 }
 
 /// Send a message with a lorri project.
@@ -185,6 +635,12 @@ pub struct Ping_ {
     /// The .nix file to watch and build on changes.
     #[structopt(parse(from_os_str))]
     pub nix_file: PathBuf,
+    /// Build only this attribute of the nix file’s expression
+    #[structopt(long = "attr", short = "A")]
+    pub attr: Option<String>,
+    /// Print the state of the daemon as JSON, for use in scripts
+    #[structopt(long = "json")]
+    pub json: bool,
 }
 
 /// Stream events from the daemon.
@@ -193,6 +649,34 @@ pub struct StreamEvents_ {
     #[structopt(long, default_value = "all")]
     /// The kind of events to report
     pub kind: crate::ops::EventKind,
+    /// Instead of the latest event of each project, start with the events
+    /// after the one with this `cursor`, e.g. after reconnecting.
+    /// Falls back to the latest events if the daemon doesn’t remember them all,
+    /// or was restarted since.
+    #[structopt(long = "since")]
+    pub since: Option<crate::daemon::EventCursor>,
+}
+
+/// List the GC roots of all projects.
+#[derive(StructOpt, Debug)]
+pub struct GcRoots_ {
+    /// Print the roots as JSON, for use in scripts
+    #[structopt(long = "json")]
+    pub json: bool,
+}
+
+/// List the paths the daemon watches for a project.
+#[derive(StructOpt, Debug)]
+pub struct WatchedPaths_ {
+    /// The .nix file of the project
+    #[structopt(long = "shell-file", parse(from_os_str), default_value = "shell.nix")]
+    pub nix_file: PathBuf,
+    /// The attribute of the nix file’s expression the project builds
+    #[structopt(long = "attr", short = "A")]
+    pub attr: Option<String>,
+    /// Print the paths as JSON, for use in scripts
+    #[structopt(long = "json")]
+    pub json: bool,
 }
 
 /// A stub struct to represent how what we want to upgrade to.
@@ -0,0 +1,335 @@
+//! Global configuration, read from `config.toml` in the user’s
+//! configuration directory (`~/.config/lorri` on Linux)
+//! by the daemon and the clients.
+//!
+//! All fields are optional, a missing file is the same as an empty one.
+//! Every field can be overridden with an environment variable,
+//! and command line flags override both.
+
+use crate::cli;
+use crate::logging::rotate::LogRotation;
+use crate::logging::{LogFilter, LogFormat};
+use crate::nix::limits::{IoClass, ResourceLimits};
+use crate::nix::Backend;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+use thiserror::Error;
+
+/// Overrides the path of the configuration file.
+pub const CONFIG_PATH_VAR: &str = "LORRI_CONFIG";
+
+/// Name of the configuration file in the configuration directory.
+pub const CONFIG_FILE_NAME: &str = "config.toml";
+
+/// How many milliseconds no files may change before a project is rebuilt,
+/// unless configured otherwise.
+pub const DEFAULT_DEBOUNCE_MS: u64 = 100;
+
+/// How many projects the daemon builds at the same time,
+/// unless configured otherwise.
+pub const DEFAULT_MAX_CONCURRENT_BUILDS: usize = 1;
+
+/// How often the `poll` watcher checks the files for changes,
+/// unless `poll_interval_secs` is set.
+pub const DEFAULT_POLL_INTERVAL_SECS: u64 = 5;
+
+/// Defaults for the daemon and the clients.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Config {
+    /// Wait until no files changed for this many milliseconds before building.
+    /// `LORRI_DEBOUNCE_MS`, overridden by the daemon’s `--debounce-ms`.
+    pub debounce_ms: Option<u64>,
+    /// How many projects the daemon builds at the same time.
+    /// `LORRI_MAX_CONCURRENT_BUILDS`, overridden by `--max-concurrent-builds`.
+    pub max_concurrent_builds: Option<usize>,
+    /// How changes to the watched files are noticed.
+    /// `LORRI_WATCHER`, overridden by `--poll-interval`.
+    pub watcher: Watcher,
+    /// With the `poll` watcher, check the files every this many seconds.
+    /// `LORRI_POLL_INTERVAL_SECS`.
+    pub poll_interval_secs: Option<u64>,
+    /// Nix options for all builds, like the daemon’s `--extra-nix-options`.
+    /// `LORRI_EXTRA_NIX_OPTIONS`, as JSON, overridden by `--extra-nix-options`.
+    pub extra_nix_options: Option<cli::NixOptions>,
+    /// How log messages are formatted.
+    /// `LORRI_LOG_FORMAT`, overridden by `--log-format`.
+    pub log_format: LogFormat,
+    /// Log levels per module, like `watch=warn,builder=debug`.
+    /// `LORRI_LOG_FILTER`, overridden by `--log-filter`.
+    pub log_filter: Option<LogFilter>,
+    /// When the daemon rotates its `--log-file`,
+    /// from the `[log_rotation]` table.
+    pub log_rotation: LogRotation,
+    /// Limits for the nix processes the daemon runs,
+    /// from the `[nix_limits]` table.
+    pub nix_limits: ResourceLimits,
+    /// Which nix commands evaluate and build, `legacy` or `nix-command`,
+    /// unless a project’s `.lorri.toml` sets it. Detected if unset.
+    /// `LORRI_NIX_BACKEND`.
+    pub nix_backend: Option<Backend>,
+}
+
+/// The ways lorri can notice changes to the watched files.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Watcher {
+    /// The file system reports changes (inotify on Linux, FSEvents on macOS).
+    Notify,
+    /// Check the files for changes in an interval, for file systems
+    /// which don’t report them (e.g. NFS or Docker bind mounts).
+    Poll,
+}
+
+impl Default for Watcher {
+    fn default() -> Watcher {
+        Watcher::Notify
+    }
+}
+
+impl FromStr for Watcher {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "notify" => Ok(Watcher::Notify),
+            "poll" => Ok(Watcher::Poll),
+            _ => Err(format!("{} not in notify,poll", s)),
+        }
+    }
+}
+
+/// Error conditions encountered when reading the global configuration.
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    /// The file exists, but could not be read.
+    #[error("Could not read the configuration {0}")]
+    Io(PathBuf, #[source] std::io::Error),
+    /// The file is not a valid configuration.
+    #[error("Invalid configuration in {0}")]
+    Parse(PathBuf, #[source] toml::de::Error),
+    /// An environment variable which overrides a setting has an invalid value.
+    #[error("Invalid value {value:?} of {var}: {message}")]
+    #[allow(missing_docs)]
+    Env {
+        var: &'static str,
+        value: String,
+        message: String,
+    },
+}
+
+impl Config {
+    /// Read the configuration file and apply the overrides
+    /// from the environment.
+    pub fn load() -> Result<Config, ConfigError> {
+        let mut config = match Self::file() {
+            None => Config::default(),
+            Some(file) => match std::fs::read_to_string(&file) {
+                Ok(contents) => Self::parse(&contents).map_err(|e| ConfigError::Parse(file, e))?,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Config::default(),
+                Err(e) => return Err(ConfigError::Io(file, e)),
+            },
+        };
+        config.override_with(|var| std::env::var(var).ok())?;
+        Ok(config)
+    }
+
+    /// The path of the configuration file: `LORRI_CONFIG`,
+    /// or `config.toml` in the user’s configuration directory.
+    pub fn file() -> Option<PathBuf> {
+        match std::env::var_os(CONFIG_PATH_VAR) {
+            Some(file) => Some(PathBuf::from(file)),
+            None => {
+                crate::constants::project_dirs().map(|pd| pd.config_dir().join(CONFIG_FILE_NAME))
+            }
+        }
+    }
+
+    /// Parse the contents of a configuration file.
+    pub fn parse(contents: &str) -> Result<Config, toml::de::Error> {
+        toml::from_str(contents)
+    }
+
+    /// Override the settings whose environment variable `get_var` returns.
+    fn override_with<F>(&mut self, get_var: F) -> Result<(), ConfigError>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        fn parse<T, E: ToString>(
+            var: &'static str,
+            value: String,
+            parse: impl FnOnce(&str) -> Result<T, E>,
+        ) -> Result<T, ConfigError> {
+            parse(&value).map_err(|e| ConfigError::Env {
+                var,
+                message: e.to_string(),
+                value,
+            })
+        }
+
+        if let Some(v) = get_var("LORRI_DEBOUNCE_MS") {
+            self.debounce_ms = Some(parse("LORRI_DEBOUNCE_MS", v, u64::from_str)?);
+        }
+        if let Some(v) = get_var("LORRI_MAX_CONCURRENT_BUILDS") {
+            self.max_concurrent_builds =
+                Some(parse("LORRI_MAX_CONCURRENT_BUILDS", v, usize::from_str)?);
+        }
+        if let Some(v) = get_var("LORRI_WATCHER") {
+            self.watcher = parse("LORRI_WATCHER", v, Watcher::from_str)?;
+        }
+        if let Some(v) = get_var("LORRI_POLL_INTERVAL_SECS") {
+            self.poll_interval_secs = Some(parse("LORRI_POLL_INTERVAL_SECS", v, u64::from_str)?);
+        }
+        if let Some(v) = get_var("LORRI_EXTRA_NIX_OPTIONS") {
+            self.extra_nix_options = Some(parse("LORRI_EXTRA_NIX_OPTIONS", v, |s| {
+                serde_json::from_str(s)
+            })?);
+        }
+        if let Some(v) = get_var("LORRI_LOG_FORMAT") {
+            self.log_format = parse("LORRI_LOG_FORMAT", v, LogFormat::from_str)?;
+        }
+        if let Some(v) = get_var("LORRI_LOG_FILTER") {
+            self.log_filter = Some(parse("LORRI_LOG_FILTER", v, LogFilter::from_str)?);
+        }
+        if let Some(v) = get_var("LORRI_NIX_NICE") {
+            self.nix_limits.nice = Some(parse("LORRI_NIX_NICE", v, i32::from_str)?);
+        }
+        if let Some(v) = get_var("LORRI_NIX_IONICE") {
+            self.nix_limits.ionice = Some(parse("LORRI_NIX_IONICE", v, IoClass::from_str)?);
+        }
+        if let Some(v) = get_var("LORRI_NIX_CPU_QUOTA") {
+            self.nix_limits.cpu_quota = Some(v);
+        }
+        if let Some(v) = get_var("LORRI_NIX_MEMORY_MAX") {
+            self.nix_limits.memory_max = Some(v);
+        }
+        if let Some(v) = get_var("LORRI_NIX_BACKEND") {
+            self.nix_backend = Some(parse("LORRI_NIX_BACKEND", v, Backend::from_str)?);
+        }
+        Ok(())
+    }
+
+    /// The debounce interval, unless a flag sets it.
+    pub fn debounce(&self) -> Duration {
+        Duration::from_millis(self.debounce_ms.unwrap_or(DEFAULT_DEBOUNCE_MS))
+    }
+
+    /// The polling interval, if the files are polled for changes.
+    pub fn poll_interval(&self) -> Option<Duration> {
+        match self.watcher {
+            Watcher::Notify => None,
+            Watcher::Poll => Some(Duration::from_secs(
+                self.poll_interval_secs
+                    .unwrap_or(DEFAULT_POLL_INTERVAL_SECS),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn empty_config_is_default() {
+        let config = Config::parse("").unwrap();
+        assert_eq!(
+            config.debounce(),
+            Duration::from_millis(DEFAULT_DEBOUNCE_MS)
+        );
+        assert_eq!(config.max_concurrent_builds, None);
+        assert_eq!(config.poll_interval(), None);
+        assert_eq!(config.log_format, LogFormat::Full);
+        assert_eq!(config.nix_backend, None);
+    }
+
+    #[test]
+    fn parse_config() {
+        let config = Config::parse(
+            r#"
+            debounce_ms = 500
+            max_concurrent_builds = 4
+            watcher = "poll"
+            log_format = "compact"
+            log_filter = "debug,watch=warn"
+            nix_backend = "nix-command"
+
+            [extra_nix_options]
+            substituters = ["https://cache.nixos.org"]
+
+            [nix_limits]
+            nice = 10
+            ionice = "idle"
+
+            [log_rotation]
+            max_age_days = 7
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.debounce(), Duration::from_millis(500));
+        assert_eq!(config.max_concurrent_builds, Some(4));
+        assert_eq!(
+            config.poll_interval(),
+            Some(Duration::from_secs(DEFAULT_POLL_INTERVAL_SECS))
+        );
+        assert_eq!(config.log_format, LogFormat::Compact);
+        let filter = config.log_filter.unwrap();
+        assert_eq!(filter.level("lorri::watch"), slog::Level::Warning);
+        assert_eq!(filter.level("lorri::builder"), slog::Level::Debug);
+        assert!(Config::parse("log_filter = \"watch=loud\"").is_err());
+        assert_eq!(
+            config.extra_nix_options.unwrap().substituters,
+            Some(vec![String::from("https://cache.nixos.org")])
+        );
+        assert_eq!(config.nix_limits.nice, Some(10));
+        assert_eq!(config.nix_limits.ionice, Some(IoClass::Idle));
+        assert_eq!(config.log_rotation.max_age_days, Some(7));
+        assert_eq!(config.nix_backend, Some(Backend::NixCommand));
+        // the other settings keep their defaults
+        assert_eq!(config.log_rotation.max_size_mb, Some(10));
+        assert!(Config::parse("watcher = \"fanotify\"").is_err());
+        assert!(Config::parse("nix_backend = \"nix-env\"").is_err());
+    }
+
+    #[test]
+    fn environment_overrides_file() {
+        let env: HashMap<&str, &str> = [
+            ("LORRI_DEBOUNCE_MS", "250"),
+            ("LORRI_WATCHER", "poll"),
+            ("LORRI_POLL_INTERVAL_SECS", "30"),
+            ("LORRI_LOG_FORMAT", "json"),
+            ("LORRI_NIX_BACKEND", "legacy"),
+        ]
+        .iter()
+        .cloned()
+        .collect();
+        let mut config = Config::parse(
+            "debounce_ms = 500\nmax_concurrent_builds = 2\nnix_backend = \"nix-command\"",
+        )
+        .unwrap();
+        config
+            .override_with(|var| env.get(var).map(|v| v.to_string()))
+            .unwrap();
+        assert_eq!(config.debounce(), Duration::from_millis(250));
+        assert_eq!(config.max_concurrent_builds, Some(2));
+        assert_eq!(config.poll_interval(), Some(Duration::from_secs(30)));
+        assert_eq!(config.log_format, LogFormat::Json);
+        assert_eq!(config.nix_backend, Some(Backend::Legacy));
+
+        let err = config
+            .override_with(|var| match var {
+                "LORRI_MAX_CONCURRENT_BUILDS" => Some(String::from("many")),
+                _ => None,
+            })
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            ConfigError::Env {
+                var: "LORRI_MAX_CONCURRENT_BUILDS",
+                ..
+            }
+        ));
+    }
+}
@@ -1,16 +1,21 @@
 //! Global project constants.
 
 use crate::cas::ContentAddressable;
+use crate::socket::path::{SocketPath, SocketPathError};
 use crate::AbsPathBuf;
-use directories::ProjectDirs;
 use thiserror::Error;
 
+pub use crate::socket::path::{project_dirs, SOCKET_PATH_VAR};
+
 /// Path constants like the GC root directory.
 pub struct Paths {
     gc_root_dir: AbsPathBuf,
     // TODO: make SocketPath
     daemon_socket_file: AbsPathBuf,
     cas_store: ContentAddressable,
+    tracked_projects_file: AbsPathBuf,
+    aliases_file: AbsPathBuf,
+    http_token_file: AbsPathBuf,
 }
 
 /// Everything that can happen when creating `Paths`.
@@ -33,6 +38,9 @@ pub enum PathsInitError {
         #[source]
         err: std::io::Error,
     },
+    /// `LORRI_DAEMON_SOCKET` is not an absolute path.
+    #[error("LORRI_DAEMON_SOCKET must be an absolute path, but is {0}")]
+    SocketPathNotAbsolute(String),
     /// The CAS creation failed.
     #[error("Could not create the CAS directory in {cas_dir}")]
     #[allow(missing_docs)]
@@ -46,7 +54,7 @@ pub enum PathsInitError {
 impl Paths {
     /// Set up project paths, creating directories if necessary.
     pub fn initialize() -> Result<Paths, PathsInitError> {
-        let pd = ProjectDirs::from("com.github.nix-community.lorri", "lorri", "lorri")
+        let pd = project_dirs()
             .expect("Could not determine lorri project/cache directories, please set $HOME");
         let create_dir = |dir: AbsPathBuf| -> std::io::Result<AbsPathBuf> {
             std::fs::create_dir_all(&dir).and(Ok(dir))
@@ -74,6 +82,18 @@ impl Paths {
                 rd.display()
             )
         });
+        let daemon_socket_file = match SocketPath::of_daemon() {
+            Ok(socket) => AbsPathBuf::new_unchecked(socket.as_absolute_path().to_owned()),
+            Err(SocketPathError::NotAbsolute(path)) => {
+                return Err(PathsInitError::SocketPathNotAbsolute(path))
+            }
+            Err(SocketPathError::NoRuntimeDir) => abs_runtime_dir.join("daemon.socket"),
+        };
+        let socket_dir = daemon_socket_file.as_path().parent().map_or_else(
+            || abs_runtime_dir.clone(),
+            |dir| AbsPathBuf::new_unchecked(dir.to_owned()),
+        );
+        let http_token_file = socket_dir.join("http-token");
 
         Ok(Paths {
             gc_root_dir: create_dir(gc_root_dir.clone()).map_err(|err| {
@@ -82,18 +102,21 @@ impl Paths {
                     err,
                 }
             })?,
-            daemon_socket_file: create_dir(abs_runtime_dir.clone())
+            daemon_socket_file: create_dir(socket_dir.clone())
                 .map_err(|err| PathsInitError::SocketDirCantBeCreated {
-                    socket_dir: abs_runtime_dir.display().to_string(),
+                    socket_dir: socket_dir.display().to_string(),
                     err,
-                })?
-                .join("daemon.socket"),
+                })
+                .map(|_| daemon_socket_file)?,
             cas_store: ContentAddressable::new(cas_dir.clone()).map_err(|err| {
                 PathsInitError::CasCantBeCreated {
                     cas_dir: cas_dir.display().to_string(),
                     err,
                 }
             })?,
+            tracked_projects_file: abs_cache_dir.join("tracked_projects.json"),
+            aliases_file: abs_cache_dir.join("aliases.json"),
+            http_token_file,
         })
     }
 
@@ -117,4 +140,20 @@ impl Paths {
     pub fn cas_store(&self) -> &ContentAddressable {
         &self.cas_store
     }
+
+    /// The projects the daemon watches, so it can watch them again
+    /// after it is restarted.
+    pub fn tracked_projects_file(&self) -> &AbsPathBuf {
+        &self.tracked_projects_file
+    }
+
+    /// The names of projects set with `lorri name`, see `project::alias`.
+    pub fn aliases_file(&self) -> &AbsPathBuf {
+        &self.aliases_file
+    }
+
+    /// The token clients of the HTTP API have to send, see `daemon::http`.
+    pub fn http_token_file(&self) -> &AbsPathBuf {
+        &self.http_token_file
+    }
 }
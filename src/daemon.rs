@@ -1,25 +1,113 @@
 //! The lorri daemon, watches multiple projects in the background.
 
+pub mod build_queue;
 pub mod client;
+pub mod hook;
+pub mod http;
+pub mod metrics;
+pub mod notify;
+pub mod push;
+pub mod restart;
 pub mod server;
+pub mod tracked;
 
-use crate::build_loop::{BuildLoop, Event};
+use crate::build_loop::{BuildLoop, Event, LogEvent, ReasonI};
+use crate::builder::LogLine;
 use crate::nix::options::NixOptions;
 use crate::ops::error::ExitError;
+use crate::project::config::ProjectConfig;
 use crate::socket::communicate;
-use crate::socket::path::SocketPath;
+use crate::socket::path::{BindError, SocketPath};
+use crate::watch::{self, WatchedPath};
 use crate::{project, AbsPathBuf, NixFile};
 use crossbeam_channel as chan;
-use slog::debug;
-use std::collections::HashMap;
+use slog::{debug, info, warn};
+use std::collections::{vec_deque, BTreeMap, HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+pub use lorri_client::event::{EventCursor, SequencedEvent};
+pub use lorri_client::status::{BuildStatus, DaemonStatus, ProjectStatus};
 
 #[derive(Debug, Clone)]
 /// Union of build_loop::Event and NewListener for internal use.
 pub enum LoopHandlerEvent {
-    /// A new listener has joined for event streaming
-    NewListener(chan::Sender<Event>),
+    /// A new listener has joined for event streaming.
+    /// It gets the events after the given sequence number if they are
+    /// still buffered, otherwise the latest event of each project.
+    NewListener(Option<EventCursor>, chan::Sender<SequencedEvent>),
     /// Events from a BuildLoop
     BuildEvent(Event),
+    /// A new listener has joined for the build logs of a project
+    NewLogListener(build_queue::ProjectKey, chan::Sender<LogEvent>),
+    /// A line of build output from a BuildLoop
+    BuildLog {
+        /// The shell.nix file of the building project
+        nix_file: NixFile,
+        /// The attribute of the nix file the project builds, if not the whole file
+        attr: Option<String>,
+        /// What nix printed
+        line: LogLine,
+    },
+    /// The paths a BuildLoop watches changed
+    WatchedPaths {
+        /// The shell.nix file of the project
+        nix_file: NixFile,
+        /// The attribute of the nix file the project builds, if not the whole file
+        attr: Option<String>,
+        /// Everything that is watched for the project
+        paths: Vec<WatchedPath>,
+    },
+    /// A client wants to know what is watched for a project,
+    /// `None` if the daemon doesn’t watch it
+    GetWatchedPaths(
+        build_queue::ProjectKey,
+        chan::Sender<Option<Vec<WatchedPath>>>,
+    ),
+    /// A client wants to know the status of all projects the daemon builds
+    GetProjects(chan::Sender<Vec<ProjectStatus>>),
+    /// A client wants to know the metrics of all projects
+    GetMetrics(chan::Sender<metrics::Metrics>),
+    /// A client wants to know the state of the daemon
+    GetStatus(chan::Sender<DaemonStatus>),
+}
+
+/// How many lines of a project’s current build a new log listener gets.
+const MAX_BUFFERED_LOG_LINES: usize = 1000;
+
+/// How many of the latest events can be replayed to a reconnecting listener.
+const MAX_BUFFERED_EVENTS: usize = 1000;
+
+/// Remember `event` as one of the `recent` events, forgetting the oldest ones.
+fn remember_event(recent: &mut VecDeque<SequencedEvent>, event: SequencedEvent) {
+    if recent.len() == MAX_BUFFERED_EVENTS {
+        recent.pop_front();
+    }
+    recent.push_back(event);
+}
+
+/// The events a listener which saw the event at `since` missed,
+/// if they are all still `recent`.
+///
+/// `None` if it needs the latest event of each project instead:
+/// when the cursor is from another daemon run (whose `seq` started over),
+/// from an event we never sent, or from before the oldest one we remember.
+fn missed_events(
+    recent: &VecDeque<SequencedEvent>,
+    epoch: u64,
+    last_seq: u64,
+    since: EventCursor,
+) -> Option<vec_deque::Iter<'_, SequencedEvent>> {
+    if since.epoch != epoch || since.seq > last_seq {
+        return None;
+    }
+    match recent.front() {
+        _ if since.seq == last_seq => Some(recent.range(0..0)),
+        Some(first) if since.seq + 1 >= first.seq => {
+            Some(recent.range((since.seq + 1 - first.seq) as usize..))
+        }
+        _ => None,
+    }
 }
 
 /// Indicate that the user is interested in a specific nix file.
@@ -32,10 +120,93 @@ pub enum LoopHandlerEvent {
 pub struct IndicateActivity {
     /// This nix file should be build/watched by the daemon.
     pub nix_file: NixFile,
+    /// Only this attribute of the nix file should be built.
+    pub attr: Option<String>,
     /// Determines when this activity will cause a rebuild.
     pub rebuild: communicate::Rebuild,
+    /// The values of the project’s `pass_env` variables, see `communicate::Ping`.
+    /// `None` keeps the values the project already has.
+    pub env: Option<BTreeMap<String, String>>,
+}
+
+/// Pause or resume the builds of a project, or of all projects.
+///
+/// `lorri pause` and `lorri resume` trigger this signal.
+pub struct SetPaused {
+    /// The project to pause or resume, all projects if `None`.
+    pub nix_file: Option<NixFile>,
+    /// The attribute of the nix file the project builds, if not the whole file.
+    pub attr: Option<String>,
+    /// Whether to pause or resume.
+    pub paused: bool,
+}
+
+/// Stop watching and building a project.
+///
+/// `lorri untrack` triggers this signal.
+pub struct Untrack {
+    /// The project to stop watching.
+    pub nix_file: NixFile,
+    /// The attribute of the nix file the project builds, if not the whole file.
+    pub attr: Option<String>,
+    /// Also delete the project’s GC roots and state directory.
+    pub remove_roots: bool,
+}
+
+/// Stop all build loops, before the daemon restarts.
+/// Replies with the projects that were watched, and the environment they were built with.
+struct StopAll(chan::Sender<Vec<(build_queue::ProjectKey, BTreeMap<String, String>)>>);
+
+/// A `BuildLoop` running in its own thread.
+struct ProjectThread {
+    /// Triggers a build. The loop stops once this is dropped.
+    tx_ping: chan::Sender<()>,
+    /// The environment its builds use.
+    passed_env: Arc<Mutex<BTreeMap<String, String>>>,
+    /// Pauses and resumes its builds.
+    tx_pause: chan::Sender<bool>,
+    thread: std::thread::JoinHandle<()>,
+}
+
+/// Settings of the daemon, which apply to all projects it watches.
+#[derive(Clone)]
+pub struct Settings {
+    /// Extra options to pass to each nix invocation
+    pub extra_nix_options: NixOptions,
+    /// How many generations to keep for each project
+    pub keep_generations: usize,
+    /// Remove the GC roots of projects which were not built or used
+    /// for this long. Can be overridden per project, if it is set.
+    pub gc_root_ttl: Option<Duration>,
+    /// Kill running builds when their project changes again
+    pub cancel_builds: bool,
+    /// How many projects can be built at the same time
+    pub max_concurrent_builds: usize,
+    /// Build locally when the remote builders fail
+    pub local_build_fallback: bool,
+    /// Skip changes to paths ignored by `.gitignore` and `.ignore` files
+    pub respect_ignore_files: bool,
+    /// How long no files may change before a project is rebuilt.
+    /// Can be overridden per project.
+    pub debounce: Duration,
+    /// Poll for file changes in this interval instead of relying on
+    /// the file system to report them. Can be overridden per project.
+    pub poll_interval: Option<Duration>,
+    /// Poll for changes of files which can’t be watched because
+    /// the file system’s watch limit is reached in this interval
+    pub poll_fallback: Option<Duration>,
+    /// Save the watched projects to this file and watch them again
+    /// when the daemon starts, `None` to forget them
+    pub tracked_projects_file: Option<AbsPathBuf>,
+    /// Serve the HTTP API on this port of the loopback interface
+    pub http_port: Option<u16>,
+    /// Where the token the HTTP API requires is written
+    pub http_token_file: AbsPathBuf,
 }
 
+/// How often the daemon checks for expired GC roots.
+const GC_ROOT_EXPIRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
 /// Keeps all state of the running `lorri daemon` service, watches nix files and runs builds.
 pub struct Daemon {
     /// Sending end that we pass to every `BuildLoop` the daemon controls.
@@ -43,15 +214,15 @@ pub struct Daemon {
     tx_build_events: chan::Sender<LoopHandlerEvent>,
     rx_build_events: chan::Receiver<LoopHandlerEvent>,
     mon_tx: chan::Sender<LoopHandlerEvent>,
-    /// Extra options to pass to each nix invocation
-    extra_nix_options: NixOptions,
+    /// Settings that apply to all projects
+    settings: Settings,
 }
 
 impl Daemon {
     /// Create a new daemon. Also return an `chan::Receiver` that
     /// receives `LoopHandlerEvent`s for all builders this daemon
     /// supervises.
-    pub fn new(extra_nix_options: NixOptions) -> (Daemon, chan::Receiver<LoopHandlerEvent>) {
+    pub fn new(settings: Settings) -> (Daemon, chan::Receiver<LoopHandlerEvent>) {
         let (tx_build_events, rx_build_events) = chan::unbounded();
         let (mon_tx, mon_rx) = chan::unbounded();
         (
@@ -59,7 +230,7 @@ impl Daemon {
                 tx_build_events,
                 rx_build_events,
                 mon_tx,
-                extra_nix_options,
+                settings,
             },
             mon_rx,
         )
@@ -78,16 +249,77 @@ impl Daemon {
             chan::Sender<IndicateActivity>,
             chan::Receiver<IndicateActivity>,
         ) = chan::unbounded();
+        let (tx_pause, rx_pause) = chan::unbounded();
+        let (tx_untrack, rx_untrack) = chan::unbounded();
+        let (tx_stop, rx_stop) = chan::unbounded();
+
+        // Nix might have lost track of our roots while the daemon wasn’t running
+        repair_gc_roots(gc_root_dir, &user, logger);
+
+        if let Some(projects) = restart::take_handover(logger) {
+            info!(logger, "watching the projects of the previous daemon"; "projects" => projects.len());
+            watch_again(&tx_activity, projects);
+        } else if let Some(file) = &self.settings.tracked_projects_file {
+            match tracked::load(file.as_path()) {
+                Ok(projects) => {
+                    info!(logger, "watching projects again"; "projects" => projects.len());
+                    for (nix_file, attr) in projects {
+                        tx_activity
+                            .send(IndicateActivity {
+                                nix_file,
+                                attr,
+                                rebuild: communicate::Rebuild::OnlyIfNotYetWatching,
+                                // the clients send their environment once they enter the project
+                                env: None,
+                            })
+                            .expect("rx_activity hung up");
+                    }
+                }
+                Err(err) => {
+                    warn!(logger, "could not load the watched projects"; "file" => file.display(), "error" => %err)
+                }
+            }
+        }
 
         let mut pool = crate::thread::Pool::new(logger.clone());
         let tx_build_events = self.tx_build_events.clone();
 
-        let server = server::Server::new(tx_activity, tx_build_events);
+        if let Some(port) = self.settings.http_port {
+            let api = http::Api::new(
+                tx_activity.clone(),
+                tx_untrack.clone(),
+                tx_build_events.clone(),
+            );
+            let logger = logger.clone();
+            let token_file = self.settings.http_token_file.clone();
+            pool.spawn("http-api", move || {
+                api.listen(port, &token_file, &logger)
+                    .map(|n| n.never())
+                    .map_err(BindError::from)
+            })?;
+        }
+
+        {
+            let tx_activity = tx_activity.clone();
+            let tx_build_events = tx_build_events.clone();
+            let logger = logger.clone();
+            pool.spawn("restart-handler", move || loop {
+                if let Err(err) = restart::wait_for_signal() {
+                    warn!(logger, "cannot wait for restart requests"; "error" => %err);
+                    return Ok(());
+                }
+                restart_daemon(&tx_stop, &tx_build_events, &tx_activity, &logger);
+            })?;
+        }
+
+        let server =
+            server::Server::new(tx_activity.clone(), tx_pause, tx_untrack, tx_build_events);
 
         let socket_path = socket_path.clone();
         let logger = logger.clone();
         let logger2 = logger.clone();
         let logger3 = logger.clone();
+        let logger4 = logger.clone();
 
         pool.spawn("accept-loop", move || {
             server.listen(&socket_path, &logger).map(|n| n.never())
@@ -96,26 +328,39 @@ impl Daemon {
         let rx_build_events = self.rx_build_events.clone();
         let mon_tx = self.mon_tx.clone();
         pool.spawn("build-loop", move || {
-            Self::build_loop(rx_build_events, mon_tx, &logger2);
+            Self::build_loop(rx_build_events, mon_tx, tx_activity, &logger2);
             Ok(())
         })?;
 
         let tx_build_events = self.tx_build_events.clone();
-        let extra_nix_options = self.extra_nix_options.clone();
-        let gc_root_dir = gc_root_dir.clone();
+        let settings = self.settings.clone();
+        let gc_root_dir2 = gc_root_dir.clone();
+        let user2 = user.clone();
         pool.spawn("build-instruction-handler", move || {
             Self::build_instruction_handler(
                 tx_build_events,
-                extra_nix_options,
+                settings,
                 rx_activity,
-                &gc_root_dir,
+                rx_pause,
+                rx_untrack,
+                rx_stop,
+                &gc_root_dir2,
                 cas,
-                user,
+                user2,
                 &logger3,
             );
             Ok(())
         })?;
 
+        // without a TTL, no roots expire
+        if let Some(gc_root_ttl) = self.settings.gc_root_ttl {
+            let gc_root_dir = gc_root_dir.clone();
+            pool.spawn("gc-root-expiry", move || loop {
+                expire_gc_roots(&gc_root_dir, gc_root_ttl, &user, &logger4);
+                std::thread::sleep(GC_ROOT_EXPIRY_INTERVAL);
+            })?;
+        }
+
         pool.join_all_or_panic()?;
 
         Ok(())
@@ -124,92 +369,405 @@ impl Daemon {
     fn build_loop(
         rx_build_events: chan::Receiver<LoopHandlerEvent>,
         mon_tx: chan::Sender<LoopHandlerEvent>,
+        tx_activity: chan::Sender<IndicateActivity>,
         logger: &slog::Logger,
     ) {
-        let mut project_states: HashMap<NixFile, Event> = HashMap::new();
-        let mut event_listeners: Vec<chan::Sender<Event>> = Vec::new();
+        let started = Instant::now();
+        // tells the events of this run from the ones before a restart
+        let epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64);
+        // the state of each project, i.e. each attribute of a nix file
+        let mut project_states: HashMap<build_queue::ProjectKey, SequencedEvent> = HashMap::new();
+        let mut event_listeners: Vec<chan::Sender<SequencedEvent>> = Vec::new();
+        // the latest events, for listeners which reconnect
+        let mut recent_events: VecDeque<SequencedEvent> = VecDeque::new();
+        let mut last_seq: u64 = 0;
+        // the output of the current build of each project
+        let mut project_logs: HashMap<build_queue::ProjectKey, VecDeque<LogLine>> = HashMap::new();
+        let mut log_listeners: Vec<(build_queue::ProjectKey, chan::Sender<LogEvent>)> = Vec::new();
+        let mut project_watches: HashMap<build_queue::ProjectKey, Vec<WatchedPath>> =
+            HashMap::new();
+        let mut project_status: HashMap<build_queue::ProjectKey, ProjectStatus> = HashMap::new();
+        // what each project’s `.lorri.toml` `depends_on`, read again when it changes
+        let mut project_dependencies: HashMap<build_queue::ProjectKey, Vec<NixFile>> =
+            HashMap::new();
+        let mut build_metrics = metrics::Metrics::default();
+        fn send_log(
+            listeners: &mut Vec<(build_queue::ProjectKey, chan::Sender<LogEvent>)>,
+            key: &build_queue::ProjectKey,
+            ev: LogEvent,
+        ) {
+            listeners.retain(|(project, tx)| project != key || tx.send(ev.clone()).is_ok())
+        }
 
         for msg in rx_build_events {
+            // build output is too noisy for the monitor
+            if let LoopHandlerEvent::BuildLog {
+                nix_file,
+                attr,
+                line,
+            } = msg
+            {
+                let key = (nix_file, attr);
+                let lines = project_logs.entry(key.clone()).or_default();
+                if lines.len() == MAX_BUFFERED_LOG_LINES {
+                    lines.pop_front();
+                }
+                lines.push_back(line.clone());
+                send_log(&mut log_listeners, &key, LogEvent::Line(line));
+                continue;
+            }
             mon_tx
                 .send(msg.clone())
                 .expect("listener still to be there");
             match &msg {
-                LoopHandlerEvent::BuildEvent(ev) => match ev {
-                    Event::SectionEnd => (),
-                    Event::Started { nix_file, .. }
-                    | Event::Completed { nix_file, .. }
-                    | Event::Failure { nix_file, .. } => {
-                        project_states.insert(nix_file.clone(), ev.clone());
-                        event_listeners.retain(|tx| {
-                            let keep = tx.send(ev.clone()).is_ok();
-                            debug!(logger,"Sent"; "event" => ?ev, "keep" => keep);
-                            keep
-                        })
+                LoopHandlerEvent::BuildEvent(Event::SectionEnd) => (),
+                LoopHandlerEvent::BuildEvent(ev) => {
+                    last_seq += 1;
+                    let sequenced = SequencedEvent {
+                        epoch,
+                        seq: last_seq,
+                        event: ev.clone(),
+                    };
+                    remember_event(&mut recent_events, sequenced.clone());
+                    match ev {
+                        // handled above
+                        Event::SectionEnd => (),
+                        Event::Started { nix_file, attr, .. }
+                        | Event::Queued { nix_file, attr, .. }
+                        | Event::Completed { nix_file, attr, .. }
+                        | Event::Failure { nix_file, attr, .. }
+                        | Event::BuildTimeout { nix_file, attr, .. } => {
+                            let key = (nix_file.clone(), attr.clone());
+                            if let Event::Started { reason, .. } = ev {
+                                project_logs.remove(&key);
+                                send_log(&mut log_listeners, &key, LogEvent::BuildStarted);
+                                let config_changed = match reason {
+                                    ReasonI::FilesChanged(changed) => changed.contains(
+                                        &ProjectConfig::file_for(nix_file.as_absolute_path()),
+                                    ),
+                                    _ => false,
+                                };
+                                if config_changed || !project_dependencies.contains_key(&key) {
+                                    project_dependencies
+                                        .insert(key.clone(), dependencies_of(nix_file));
+                                }
+                                if let ReasonI::FilesChanged(_) = reason {
+                                    rebuild_dependents(
+                                        nix_file,
+                                        &project_dependencies,
+                                        &tx_activity,
+                                        logger,
+                                    );
+                                }
+                            }
+                            project_states.insert(key.clone(), sequenced.clone());
+                            if let Some(status) = ProjectStatus::update(
+                                project_status.get(&key),
+                                ev,
+                                SystemTime::now(),
+                            ) {
+                                build_metrics.record(ev, status.last_build_duration);
+                                project_status.insert(key, status);
+                            }
+                            event_listeners.retain(|tx| {
+                                let keep = tx.send(sequenced.clone()).is_ok();
+                                debug!(logger,"Sent"; "event" => ?ev, "keep" => keep);
+                                keep
+                            })
+                        }
+                        Event::Removed { nix_file, attr } => {
+                            let kept = |key: &build_queue::ProjectKey| {
+                                key.0 != *nix_file || key.1 != *attr
+                            };
+                            project_states.retain(|key, _| kept(key));
+                            project_status.retain(|key, _| kept(key));
+                            build_metrics.projects.retain(|key, _| kept(key));
+                            project_logs.retain(|key, _| kept(key));
+                            project_watches.retain(|key, _| kept(key));
+                            project_dependencies.retain(|key, _| kept(key));
+                            event_listeners.retain(|tx| {
+                                let keep = tx.send(sequenced.clone()).is_ok();
+                                debug!(logger,"Sent"; "event" => ?ev, "keep" => keep);
+                                keep
+                            })
+                        }
+                        // not a state of the project’s build, so only passed on
+                        Event::WatchLimitReached { .. } | Event::Pushed { .. } => event_listeners
+                            .retain(|tx| {
+                                let keep = tx.send(sequenced.clone()).is_ok();
+                                debug!(logger,"Sent"; "event" => ?ev, "keep" => keep);
+                                keep
+                            }),
                     }
-                },
-                LoopHandlerEvent::NewListener(tx) => {
-                    debug!(logger, "adding listener");
-                    let keep = project_states.values().all(|event| {
-                        let keeping = tx.send(event.clone()).is_ok();
-                        debug!(logger, "Sent snapshot"; "event" => ?&event, "keep" => keeping);
-                        keeping
-                    });
+                }
+                LoopHandlerEvent::NewListener(since, tx) => {
+                    debug!(logger, "adding listener"; "since" => ?since);
+                    // replay the missed events if we still have all of them
+                    let missed = since
+                        .and_then(|since| missed_events(&recent_events, epoch, last_seq, since));
+                    let keep = match missed {
+                        Some(mut missed) => missed.all(|event| {
+                            let keeping = tx.send(event.clone()).is_ok();
+                            debug!(logger, "Sent missed event"; "event" => ?&event, "keep" => keeping);
+                            keeping
+                        }),
+                        None => project_states.values().all(|event| {
+                            let keeping = tx.send(event.clone()).is_ok();
+                            debug!(logger, "Sent snapshot"; "event" => ?&event, "keep" => keeping);
+                            keeping
+                        }),
+                    };
                     debug!(logger,"Finished snapshot"; "keep" => keep);
                     if keep {
                         event_listeners.push(tx.clone());
                     }
                     event_listeners.retain(|tx| {
-                        let keep = tx.send(Event::SectionEnd).is_ok();
+                        let keep = tx
+                            .send(SequencedEvent {
+                                epoch,
+                                seq: last_seq,
+                                event: Event::SectionEnd,
+                            })
+                            .is_ok();
                         debug!(logger, "Sent new listener sectionend"; "keep" => keep);
                         keep
                     })
                 }
+                LoopHandlerEvent::NewLogListener(key, tx) => {
+                    debug!(logger, "adding log listener"; "project" => &key.0, "attr" => ?&key.1);
+                    let keep = project_logs
+                        .get(key)
+                        .into_iter()
+                        .flatten()
+                        .all(|line| tx.send(LogEvent::Line(line.clone())).is_ok())
+                        && tx.send(LogEvent::SectionEnd).is_ok();
+                    if keep {
+                        log_listeners.push((key.clone(), tx.clone()));
+                    }
+                }
+                LoopHandlerEvent::BuildLog { .. } => {}
+                LoopHandlerEvent::WatchedPaths {
+                    nix_file,
+                    attr,
+                    paths,
+                } => {
+                    project_watches.insert((nix_file.clone(), attr.clone()), paths.clone());
+                }
+                LoopHandlerEvent::GetWatchedPaths(key, tx) => {
+                    // the client might have given up already
+                    let _ = tx.send(project_watches.get(key).cloned());
+                }
+                LoopHandlerEvent::GetProjects(tx) => {
+                    // the client might have given up already
+                    let _ = tx.send(project_status.values().cloned().collect());
+                }
+                LoopHandlerEvent::GetMetrics(tx) => {
+                    let mut current = build_metrics.clone();
+                    for (key, paths) in &project_watches {
+                        current
+                            .projects
+                            .entry(key.clone())
+                            .or_default()
+                            .watched_paths = paths.len();
+                    }
+                    current.queued = project_states
+                        .values()
+                        .filter(|ev| matches!(ev.event, Event::Queued { .. }))
+                        .count();
+                    // the client might have given up already
+                    let _ = tx.send(current);
+                }
+                LoopHandlerEvent::GetStatus(tx) => {
+                    let count = |is: fn(&Event) -> bool| {
+                        project_states.values().filter(|ev| is(&ev.event)).count()
+                    };
+                    // the client might have given up already
+                    let _ = tx.send(DaemonStatus {
+                        protocol_version: crate::socket::communicate::PROTOCOL_VERSION,
+                        uptime: started.elapsed(),
+                        projects: project_states.len(),
+                        building: count(|ev| matches!(ev, Event::Started { .. })),
+                        queued: count(|ev| matches!(ev, Event::Queued { .. })),
+                    });
+                }
             }
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn build_instruction_handler(
         // TODO: use the pool here
         // pool: &mut crate::thread::Pool,
         tx_build_events: chan::Sender<LoopHandlerEvent>,
-        extra_nix_options: NixOptions,
+        settings: Settings,
         rx_activity: chan::Receiver<IndicateActivity>,
+        rx_pause: chan::Receiver<SetPaused>,
+        rx_untrack: chan::Receiver<Untrack>,
+        rx_stop: chan::Receiver<StopAll>,
         gc_root_dir: &AbsPathBuf,
         cas: crate::cas::ContentAddressable,
         user: project::Username,
         logger: &slog::Logger,
     ) {
-        // A thread for each `BuildLoop`, keyed by the nix files listened on.
-        let mut handler_threads: HashMap<NixFile, chan::Sender<()>> = HashMap::new();
+        // A thread for each `BuildLoop`, keyed by the nix files (and attributes) listened on.
+        let mut handler_threads: HashMap<build_queue::ProjectKey, ProjectThread> = HashMap::new();
+        let queue =
+            build_queue::BuildQueue::new(settings.max_concurrent_builds, tx_build_events.clone());
+        // Whether all projects are paused, and which projects are paused on their own.
+        let mut paused_all = false;
+        let mut paused: HashSet<build_queue::ProjectKey> = HashSet::new();
+        // Watches the files of all projects, so that the files they
+        // have in common (e.g. in a workspace) are watched once.
+        let shared_watcher = match watch::SharedWatcher::try_new() {
+            Ok(shared) => Some(shared),
+            Err(err) => {
+                warn!(logger, "could not start the shared file watcher, every project watches its files on its own"; "error" => %err);
+                None
+            }
+        };
 
         // For each build instruction, add the corresponding file
         // to the watch list.
-        for IndicateActivity { nix_file, rebuild } in rx_activity {
-            let project = crate::project::Project::new(nix_file, gc_root_dir, cas.clone())
-                // TODO: the project needs to create its gc root dir
-                .unwrap();
+        loop {
+            // `None` if the server hung up, `Some(None)` after a pause or untrack
+            let instruction = chan::select! {
+                recv(rx_activity) -> msg => msg.ok().map(Some),
+                recv(rx_pause) -> msg => msg.ok().map(|SetPaused { nix_file, attr, paused: pause }| {
+                    match nix_file.map(|nix_file| (nix_file.canonical(), attr)) {
+                        None => {
+                            paused_all = pause;
+                            paused.clear();
+                        }
+                        Some(key) if pause => {
+                            paused.insert(key);
+                        }
+                        Some(key) => {
+                            paused.remove(&key);
+                        }
+                    }
+                    info!(logger, "changed paused projects"; "all" => paused_all, "projects" => paused.len());
+                    for (key, project_thread) in &handler_threads {
+                        // the build loop might have failed to start
+                        let _ = project_thread
+                            .tx_pause
+                            .send(paused_all || paused.contains(key));
+                    }
+                    None
+                }),
+                recv(rx_untrack) -> msg => msg.ok().map(|Untrack { nix_file, attr, remove_roots }| {
+                    let key = (nix_file.canonical(), attr);
+                    if let Some(project_thread) = handler_threads.remove(&key) {
+                        stop_build_loop(&key, project_thread, logger);
+                    }
+                    queue.remove(&key);
+                    paused.remove(&key);
+                    if remove_roots {
+                        remove_project_roots(gc_root_dir, &key, &user, logger);
+                    }
+                    info!(logger, "untracked project"; "project" => &key.0, "attr" => ?&key.1, "removed_roots" => remove_roots);
+                    save_tracked(&settings, handler_threads.keys(), logger);
+                    let (nix_file, attr) = key;
+                    tx_build_events
+                        .send(LoopHandlerEvent::BuildEvent(Event::Removed { nix_file, attr }))
+                        .expect("rx_build_events hung up");
+                    None
+                }),
+                recv(rx_stop) -> msg => msg.ok().map(|StopAll(tx_projects)| {
+                    let projects = handler_threads
+                        .drain()
+                        .map(|(key, project_thread)| {
+                            let env = project_thread
+                                .passed_env
+                                .lock()
+                                .expect("passed_env lock poisoned")
+                                .clone();
+                            queue.remove(&key);
+                            stop_build_loop(&key, project_thread, logger);
+                            (key, env)
+                        })
+                        .collect();
+                    info!(logger, "stopped all build loops");
+                    // the restart might have failed already
+                    let _ = tx_projects.send(projects);
+                    None
+                }),
+            };
+            let IndicateActivity {
+                nix_file,
+                attr,
+                rebuild,
+                env,
+            } = match instruction {
+                None => break,
+                Some(None) => continue,
+                Some(Some(activity)) => activity,
+            };
+            // clients might have reached the project through a symlink
+            let nix_file = nix_file.canonical();
+            let mut project =
+                crate::project::Project::new_with_attr(nix_file, attr, gc_root_dir, cas.clone())
+                    // TODO: the project needs to create its gc root dir
+                    .unwrap();
+            project.keep_generations = settings.keep_generations;
+            if let Err(err) = project.mark_used() {
+                debug!(logger, "could not record project activity"; "project" => &project.nix_file, "error" => %err);
+            }
 
-            let key = project.nix_file.clone();
+            let key = (project.nix_file.clone(), project.attr.clone());
+            queue.mark_active(key.clone());
             let project_is_watched = handler_threads.get(&key);
 
             let send_ping =
                 |to: &chan::Sender<()>| to.send(()).expect("could not ping the build loop");
 
             match (project_is_watched, rebuild) {
-                (Some(builder), communicate::Rebuild::Always) => {
-                    debug!(logger, "triggering rebuild"; "project" => key, "cause" => "unconditional ping");
-                    send_ping(builder)
+                (Some(project_thread), communicate::Rebuild::Always) => {
+                    debug!(logger, "triggering rebuild"; "project" => &key.0, "attr" => ?&key.1, "cause" => "unconditional ping");
+                    if let Some(env) = env {
+                        *project_thread
+                            .passed_env
+                            .lock()
+                            .expect("passed_env lock poisoned") = env;
+                    }
+                    send_ping(&project_thread.tx_ping)
                 }
-                (Some(_), communicate::Rebuild::OnlyIfNotYetWatching) => {
-                    debug!(logger, "skipping rebuild"; "project" => key, "cause" => "already watching");
+                (Some(project_thread), communicate::Rebuild::OnlyIfNotYetWatching) => {
+                    let mut passed_env = project_thread
+                        .passed_env
+                        .lock()
+                        .expect("passed_env lock poisoned");
+                    match env {
+                        Some(env) if *passed_env != env => {
+                            debug!(logger, "triggering rebuild"; "project" => &key.0, "attr" => ?&key.1, "cause" => "passed environment changed");
+                            *passed_env = env;
+                            send_ping(&project_thread.tx_ping)
+                        }
+                        _ => {
+                            debug!(logger, "skipping rebuild"; "project" => &key.0, "attr" => ?&key.1, "cause" => "already watching");
+                        }
+                    }
                 }
                 // only add if there is no no build_loop for this file yet.
                 (None, _) => {
                     let (tx_ping, rx_ping) = chan::unbounded();
+                    let (tx_pause, rx_pause) = chan::unbounded();
+                    let start_paused = paused_all || paused.contains(&key);
+                    let passed_env = Arc::new(Mutex::new(env.unwrap_or_default()));
+                    let passed_env2 = passed_env.clone();
                     // cloning the tx means the daemon’s rx gets all
                     // messages from all builders.
                     let tx_build_events = tx_build_events.clone();
-                    let extra_nix_options = extra_nix_options.clone();
+                    let extra_nix_options = settings.extra_nix_options.clone();
+                    let cancel_builds = settings.cancel_builds;
+                    let local_build_fallback = settings.local_build_fallback;
+                    let respect_ignore_files = settings.respect_ignore_files;
+                    let debounce = settings.debounce;
+                    let poll_interval = settings.poll_interval;
+                    let poll_fallback = settings.poll_fallback;
+                    let queue = queue.clone();
+                    let shared_watcher = shared_watcher.clone();
                     let user = user.clone();
                     let logger = logger.clone();
                     let logger2 = logger.clone();
@@ -221,10 +779,26 @@ impl Daemon {
                     // If we can get the pool to “wait for join but also spawn new
                     // thread when you get a message” that could work!
                     // pool.spawn(format!("build_loop for {}", nix_file.display()),
-                    let _ = std::thread::spawn(move || {
-                        match BuildLoop::new(&project, extra_nix_options, user, logger) {
+                    let thread = std::thread::spawn(move || {
+                        match BuildLoop::new_with_watcher(
+                            &project,
+                            extra_nix_options,
+                            poll_interval,
+                            shared_watcher.as_ref(),
+                            user,
+                            logger,
+                        ) {
                             Ok(mut build_loop) => {
-                                build_loop.forever(tx_build_events, rx_ping).never()
+                                build_loop.cancel_builds = cancel_builds;
+                                build_loop.build_queue = Some(queue);
+                                build_loop.local_build_fallback = local_build_fallback;
+                                build_loop.respect_ignore_files = respect_ignore_files;
+                                build_loop.debounce = debounce;
+                                build_loop.poll_fallback = poll_fallback;
+                                build_loop.passed_env = passed_env2;
+                                build_loop.paused = start_paused;
+                                build_loop.rx_pause = rx_pause;
+                                build_loop.forever(tx_build_events, rx_ping)
                             }
                             Err(err) =>
                             // TODO: omg this is so bad, too many layers of wrapping
@@ -232,6 +806,7 @@ impl Daemon {
                                 tx_build_events
                                     .send(LoopHandlerEvent::BuildEvent(Event::Failure {
                                         nix_file: project.nix_file.clone(),
+                                        attr: project.attr.clone(),
                                         failure: crate::builder::BuildError::Io {
                                             msg: err
                                                 .context(format!(
@@ -246,17 +821,540 @@ impl Daemon {
                         }
                     });
 
-                    let e = handler_threads.insert(key.clone(), tx_ping.clone());
+                    let e = handler_threads.insert(
+                        key.clone(),
+                        ProjectThread {
+                            tx_ping: tx_ping.clone(),
+                            passed_env,
+                            tx_pause,
+                            thread,
+                        },
+                    );
                     match e {
                         None => {}
                         Some(_) => {
                             panic!("handler_threads had the key, but we already checked before")
                         }
                     }
-                    debug!(logger2, "triggering rebuild"; "project" => key, "cause" => "new project");
+                    save_tracked(&settings, handler_threads.keys(), &logger2);
+                    debug!(logger2, "triggering rebuild"; "project" => &key.0, "attr" => ?&key.1, "cause" => "new project");
                     send_ping(&tx_ping);
                 }
             }
         }
     }
 }
+
+/// The nix files of the projects the `.lorri.toml` of `nix_file` `depends_on`.
+fn dependencies_of(nix_file: &NixFile) -> Vec<NixFile> {
+    // an invalid configuration is reported by the project’s own build
+    ProjectConfig::load(nix_file.as_absolute_path())
+        .map(|config| config.dependencies(nix_file.as_absolute_path()))
+        .unwrap_or_default()
+        .into_iter()
+        .map(|dependency| NixFile::from(AbsPathBuf::new_unchecked(dependency)).canonical())
+        .collect()
+}
+
+/// Rebuild the projects whose `dependencies` contain the project
+/// of `nix_file`, whose files changed, and the projects that depend on
+/// those in turn. Their builds wait for their dependencies’ builds
+/// in the build queue.
+fn rebuild_dependents(
+    nix_file: &NixFile,
+    dependencies: &HashMap<build_queue::ProjectKey, Vec<NixFile>>,
+    tx_activity: &chan::Sender<IndicateActivity>,
+    logger: &slog::Logger,
+) {
+    // the dependents’ builds don’t trigger their own dependents,
+    // so walk the whole graph once, rebuilding every project at most once
+    let mut rebuilt: HashSet<&build_queue::ProjectKey> = HashSet::new();
+    let mut changed = vec![nix_file.clone()];
+    while let Some(dependency) = changed.pop() {
+        for (key, depends_on) in dependencies {
+            let (project, attr) = key;
+            if project == nix_file || !depends_on.contains(&dependency) || !rebuilt.insert(key) {
+                continue;
+            }
+            debug!(logger, "triggering rebuild"; "project" => project, "attr" => ?attr, "cause" => "dependency changed", "dependency" => &dependency);
+            tx_activity
+                .send(IndicateActivity {
+                    nix_file: project.clone(),
+                    attr: attr.clone(),
+                    rebuild: communicate::Rebuild::Always,
+                    env: None,
+                })
+                .expect("rx_activity hung up");
+            changed.push(project.clone());
+        }
+    }
+}
+
+/// Stop a build loop and wait for it, which cancels its running build.
+fn stop_build_loop(
+    key: &build_queue::ProjectKey,
+    project_thread: ProjectThread,
+    logger: &slog::Logger,
+) {
+    drop(project_thread.tx_ping);
+    drop(project_thread.tx_pause);
+    // the build loop cancels its build before it stops
+    if project_thread.thread.join().is_err() {
+        warn!(logger, "build loop panicked"; "project" => &key.0, "attr" => ?&key.1);
+    }
+}
+
+/// Stop all build loops and replace the daemon with a new one,
+/// which watches the same projects.
+///
+/// If that fails, the projects are watched again by this daemon.
+fn restart_daemon(
+    tx_stop: &chan::Sender<StopAll>,
+    tx_build_events: &chan::Sender<LoopHandlerEvent>,
+    tx_activity: &chan::Sender<IndicateActivity>,
+    logger: &slog::Logger,
+) {
+    let (tx_status, rx_status) = chan::bounded(1);
+    tx_build_events
+        .send(LoopHandlerEvent::GetProjects(tx_status))
+        .expect("rx_build_events hung up");
+    let building: HashSet<build_queue::ProjectKey> = rx_status
+        .recv()
+        .expect("build_loop did not reply with the projects")
+        .into_iter()
+        .filter(|project| project.status == BuildStatus::Building)
+        .map(|project| (project.nix_file, project.attr))
+        .collect();
+
+    let (tx_projects, rx_projects) = chan::bounded(1);
+    tx_stop
+        .send(StopAll(tx_projects))
+        .expect("build_instruction_handler hung up");
+    let mut projects: Vec<restart::HandoverProject> = rx_projects
+        .recv()
+        .expect("build_instruction_handler did not reply with the projects")
+        .into_iter()
+        .map(|(key, env)| restart::HandoverProject {
+            building: building.contains(&key),
+            nix_file: key.0,
+            attr: key.1,
+            env,
+        })
+        .collect();
+    // the builds which were interrupted are started first
+    projects.sort_by_key(|project| !project.building);
+
+    info!(logger, "restarting the daemon"; "projects" => projects.len());
+    let err = restart::exec(&projects);
+    warn!(logger, "could not restart the daemon, watching the projects again"; "error" => %err);
+    watch_again(tx_activity, projects);
+}
+
+/// Watch and build `projects` again, in order.
+fn watch_again(
+    tx_activity: &chan::Sender<IndicateActivity>,
+    projects: Vec<restart::HandoverProject>,
+) {
+    for project in projects {
+        tx_activity
+            .send(IndicateActivity {
+                nix_file: project.nix_file,
+                attr: project.attr,
+                rebuild: communicate::Rebuild::OnlyIfNotYetWatching,
+                env: Some(project.env),
+            })
+            .expect("rx_activity hung up");
+    }
+}
+
+/// Save the watched projects, if the daemon remembers them.
+fn save_tracked<'a>(
+    settings: &Settings,
+    projects: impl Iterator<Item = &'a build_queue::ProjectKey>,
+    logger: &slog::Logger,
+) {
+    if let Some(file) = &settings.tracked_projects_file {
+        if let Err(err) = tracked::save(file.as_path(), projects) {
+            warn!(logger, "could not save the watched projects"; "file" => file.display(), "error" => %err);
+        }
+    }
+}
+
+/// Re-register missing GC roots and remove dangling ones for all projects.
+fn repair_gc_roots(gc_root_dir: &AbsPathBuf, user: &project::Username, logger: &slog::Logger) {
+    let roots = match project::list_roots(gc_root_dir) {
+        Ok(roots) => roots,
+        Err(err) => {
+            warn!(logger, "could not list GC roots"; "gc_root_dir" => gc_root_dir.display(), "error" => %err);
+            return;
+        }
+    };
+    for root in roots {
+        match root.repair(user, logger) {
+            Ok(repaired) if repaired == project::Repaired::default() => {}
+            Ok(repaired) => {
+                info!(logger, "repaired GC roots"; "nix_file" => ?root.nix_file,
+                      "reregistered" => repaired.reregistered,
+                      "removed_dangling" => repaired.removed_dangling)
+            }
+            Err(err) => {
+                warn!(logger, "could not repair GC roots"; "hash" => &root.hash, "error" => %err)
+            }
+        }
+    }
+}
+
+/// Delete the GC roots and state directory of the project `key`.
+fn remove_project_roots(
+    gc_root_dir: &AbsPathBuf,
+    key: &build_queue::ProjectKey,
+    user: &project::Username,
+    logger: &slog::Logger,
+) {
+    let roots = match project::list_roots(gc_root_dir) {
+        Ok(roots) => roots,
+        Err(err) => {
+            warn!(logger, "could not list GC roots"; "gc_root_dir" => gc_root_dir.display(), "error" => %err);
+            return;
+        }
+    };
+    let hash = project::project_hash(&key.0, key.1.as_deref());
+    for root in roots.iter().filter(|r| r.hash == hash) {
+        if let Err(err) = root.remove(user) {
+            warn!(logger, "could not remove GC roots"; "hash" => &root.hash, "error" => %err);
+        }
+    }
+}
+
+/// Remove the GC roots of all projects which have not been built or used
+/// for longer than their time-to-live, so that nix can collect their environments.
+///
+/// The default time-to-live can be overridden in the project configuration.
+fn expire_gc_roots(
+    gc_root_dir: &AbsPathBuf,
+    default_ttl: Duration,
+    user: &project::Username,
+    logger: &slog::Logger,
+) {
+    let roots = match project::list_roots(gc_root_dir) {
+        Ok(roots) => roots,
+        Err(err) => {
+            warn!(logger, "could not list GC roots"; "gc_root_dir" => gc_root_dir.display(), "error" => %err);
+            return;
+        }
+    };
+    let now = SystemTime::now();
+    for root in roots.iter().filter(|r| r.has_roots()) {
+        let config_ttl = match &root.nix_file {
+            Some(nix_file) => match ProjectConfig::load(nix_file) {
+                Ok(config) => config.gc_root_ttl_days,
+                Err(err) => {
+                    warn!(logger, "ignoring invalid project configuration"; "error" => %err);
+                    None
+                }
+            },
+            None => None,
+        };
+        let ttl = match config_ttl {
+            // 0 disables expiry for the project
+            Some(0) => None,
+            Some(days) => Some(Duration::from_secs(days * 24 * 60 * 60)),
+            None => Some(default_ttl),
+        };
+        let expired = match (ttl, root.last_used()) {
+            (Some(ttl), Some(last_used)) => now
+                .duration_since(last_used)
+                .map(|age| age > ttl)
+                .unwrap_or(false),
+            _ => false,
+        };
+        if expired {
+            info!(logger, "removing expired GC roots"; "hash" => &root.hash, "nix_file" => ?root.nix_file);
+            if let Err(err) = root.remove_roots(user) {
+                warn!(logger, "could not remove expired GC roots"; "hash" => &root.hash, "error" => %err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// Two attributes of one nix file are separate projects,
+    /// with their own status and build logs.
+    #[test]
+    fn attributes_of_one_file_are_separate_projects() {
+        let nix_file = NixFile::from(AbsPathBuf::new_unchecked(PathBuf::from(
+            "/project/shell.nix",
+        )));
+        let attr = |name: &str| Some(String::from(name));
+        let (tx_events, rx_events) = chan::unbounded();
+        let (mon_tx, _mon_rx) = chan::unbounded();
+        let (tx_activity, _rx_activity) = chan::unbounded();
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let handler =
+            std::thread::spawn(move || Daemon::build_loop(rx_events, mon_tx, tx_activity, &logger));
+
+        let send = |ev| tx_events.send(ev).expect("build_loop hung up");
+        send(LoopHandlerEvent::BuildEvent(Event::Started {
+            nix_file: nix_file.clone(),
+            attr: attr("shells.dev"),
+            reason: ReasonI::PingReceived,
+        }));
+        send(LoopHandlerEvent::BuildLog {
+            nix_file: nix_file.clone(),
+            attr: attr("shells.dev"),
+            line: LogLine::from(String::from("building dev")),
+        });
+        send(LoopHandlerEvent::BuildEvent(Event::Failure {
+            nix_file: nix_file.clone(),
+            attr: attr("shells.ci"),
+            failure: crate::builder::BuildError::Io {
+                msg: String::from("boom"),
+            },
+        }));
+
+        let (tx_projects, rx_projects) = chan::bounded(1);
+        send(LoopHandlerEvent::GetProjects(tx_projects));
+        let mut projects: Vec<(Option<String>, BuildStatus)> = rx_projects
+            .recv()
+            .expect("no projects")
+            .into_iter()
+            .map(|project| (project.attr, project.status))
+            .collect();
+        projects.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            projects,
+            vec![
+                (attr("shells.ci"), BuildStatus::Failure),
+                (attr("shells.dev"), BuildStatus::Building),
+            ]
+        );
+
+        let logs = |name: &str| {
+            let (tx_log, rx_log) = chan::unbounded();
+            send(LoopHandlerEvent::NewLogListener(
+                (nix_file.clone(), attr(name)),
+                tx_log,
+            ));
+            let mut lines = vec![];
+            for ev in rx_log.iter() {
+                match ev {
+                    LogEvent::Line(line) => lines.push(line.0),
+                    LogEvent::SectionEnd => break,
+                    LogEvent::BuildStarted => {}
+                }
+            }
+            lines
+        };
+        assert_eq!(logs("shells.dev"), vec!["building dev"]);
+        assert!(logs("shells.ci").is_empty());
+
+        // untracking one attribute keeps the other
+        send(LoopHandlerEvent::BuildEvent(Event::Removed {
+            nix_file: nix_file.clone(),
+            attr: attr("shells.ci"),
+        }));
+        let (tx_projects, rx_projects) = chan::bounded(1);
+        send(LoopHandlerEvent::GetProjects(tx_projects));
+        let projects: Vec<Option<String>> = rx_projects
+            .recv()
+            .expect("no projects")
+            .into_iter()
+            .map(|project| project.attr)
+            .collect();
+        assert_eq!(projects, vec![attr("shells.dev")]);
+
+        drop(tx_events);
+        handler.join().expect("build_loop panicked");
+    }
+
+    /// A change to a project rebuilds the projects that depend on it,
+    /// and the projects that depend on those.
+    #[test]
+    fn dependents_of_dependents_are_rebuilt() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let tmp = std::fs::canonicalize(tmp.path())?;
+        let project = |name: &str, config: &str| -> std::io::Result<NixFile> {
+            let dir = tmp.join(name);
+            std::fs::create_dir_all(&dir)?;
+            std::fs::write(dir.join("shell.nix"), "{}")?;
+            std::fs::write(dir.join(".lorri.toml"), config)?;
+            Ok(NixFile::from(AbsPathBuf::new_unchecked(
+                dir.join("shell.nix"),
+            )))
+        };
+        let lib = project("lib", "")?;
+        let api = project("api", "depends_on = [\"../lib/shell.nix\"]")?;
+        let web = project("web", "depends_on = [\"../api/shell.nix\"]")?;
+        let docs = project("docs", "")?;
+
+        let (tx_events, rx_events) = chan::unbounded();
+        let (mon_tx, _mon_rx) = chan::unbounded();
+        let (tx_activity, rx_activity) = chan::unbounded();
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let handler =
+            std::thread::spawn(move || Daemon::build_loop(rx_events, mon_tx, tx_activity, &logger));
+
+        let send = |ev| tx_events.send(ev).expect("build_loop hung up");
+        for nix_file in &[&lib, &api, &web, &docs] {
+            send(LoopHandlerEvent::BuildEvent(Event::Started {
+                nix_file: (*nix_file).clone(),
+                attr: None,
+                reason: ReasonI::PingReceived,
+            }));
+        }
+
+        send(LoopHandlerEvent::BuildEvent(Event::Started {
+            nix_file: lib.clone(),
+            attr: None,
+            reason: ReasonI::FilesChanged(vec![tmp.join("lib/shell.nix")]),
+        }));
+        drop(tx_events);
+        handler.join().expect("build_loop panicked");
+
+        // the dependents’ builds are not started in the test,
+        // so everything was triggered by the change to lib
+        let mut rebuilt: Vec<PathBuf> = rx_activity
+            .try_iter()
+            .map(|activity| activity.nix_file.as_absolute_path().to_path_buf())
+            .collect();
+        rebuilt.sort();
+        assert_eq!(
+            rebuilt,
+            vec![
+                api.as_absolute_path().to_path_buf(),
+                web.as_absolute_path().to_path_buf()
+            ]
+        );
+        Ok(())
+    }
+
+    /// A project which starts to depend on another one once its
+    /// `.lorri.toml` changes is rebuilt with it from then on.
+    #[test]
+    fn dependents_follow_changes_to_their_configuration() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let tmp = std::fs::canonicalize(tmp.path())?;
+        let project = |name: &str| -> std::io::Result<NixFile> {
+            let dir = tmp.join(name);
+            std::fs::create_dir_all(&dir)?;
+            std::fs::write(dir.join("shell.nix"), "{}")?;
+            Ok(NixFile::from(AbsPathBuf::new_unchecked(
+                dir.join("shell.nix"),
+            )))
+        };
+        let lib = project("lib")?;
+        let api = project("api")?;
+
+        let (tx_events, rx_events) = chan::unbounded();
+        let (mon_tx, _mon_rx) = chan::unbounded();
+        let (tx_activity, rx_activity) = chan::unbounded();
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let handler =
+            std::thread::spawn(move || Daemon::build_loop(rx_events, mon_tx, tx_activity, &logger));
+
+        let send = |ev| tx_events.send(ev).expect("build_loop hung up");
+        let files_changed = |nix_file: &NixFile, changed: PathBuf| {
+            LoopHandlerEvent::BuildEvent(Event::Started {
+                nix_file: nix_file.clone(),
+                attr: None,
+                reason: ReasonI::FilesChanged(vec![changed]),
+            })
+        };
+        for nix_file in &[&lib, &api] {
+            send(LoopHandlerEvent::BuildEvent(Event::Started {
+                nix_file: (*nix_file).clone(),
+                attr: None,
+                reason: ReasonI::PingReceived,
+            }));
+        }
+        send(files_changed(&lib, tmp.join("lib/shell.nix")));
+        // the daemon answers a new listener once it handled the events before
+        let (tx_listener, rx_listener) = chan::unbounded();
+        send(LoopHandlerEvent::NewListener(None, tx_listener));
+        rx_listener
+            .iter()
+            .find(|sequenced| matches!(sequenced.event, Event::SectionEnd));
+        drop(rx_listener);
+
+        let api_config = tmp.join("api/.lorri.toml");
+        std::fs::write(&api_config, "depends_on = [\"../lib/shell.nix\"]")?;
+        send(files_changed(&api, api_config));
+        send(files_changed(&lib, tmp.join("lib/shell.nix")));
+        drop(tx_events);
+        handler.join().expect("build_loop panicked");
+
+        // only the change to lib after api’s configuration changed rebuilt it
+        let rebuilt: Vec<PathBuf> = rx_activity
+            .try_iter()
+            .map(|activity| activity.nix_file.as_absolute_path().to_path_buf())
+            .collect();
+        assert_eq!(rebuilt, vec![api.as_absolute_path().to_path_buf()]);
+        Ok(())
+    }
+
+    /// Listeners get the events after their cursor
+    /// as long as the daemon remembers all of them.
+    #[test]
+    fn reconnecting_listeners_get_the_events_they_missed() {
+        let epoch = 1_600_000_000_000;
+        let mut recent = VecDeque::new();
+        let total = MAX_BUFFERED_EVENTS as u64 + 3;
+        for seq in 1..=total {
+            remember_event(
+                &mut recent,
+                SequencedEvent {
+                    epoch,
+                    seq,
+                    event: Event::SectionEnd,
+                },
+            );
+        }
+        // the first three were forgotten
+        assert_eq!(recent.len(), MAX_BUFFERED_EVENTS);
+        assert_eq!(recent.front().map(|ev| ev.seq), Some(4));
+
+        let missed = |since_epoch, since_seq| {
+            missed_events(
+                &recent,
+                epoch,
+                total,
+                EventCursor {
+                    epoch: since_epoch,
+                    seq: since_seq,
+                },
+            )
+            .map(|events| events.map(|ev| ev.seq).collect::<Vec<u64>>())
+        };
+        assert_eq!(missed(epoch, total), Some(vec![]));
+        assert_eq!(missed(epoch, total - 2), Some(vec![total - 1, total]));
+        // the oldest event we remember is the first one after the cursor
+        assert_eq!(
+            missed(epoch, 3).map(|seqs| seqs.len()),
+            Some(MAX_BUFFERED_EVENTS)
+        );
+        assert_eq!(
+            missed(epoch, 3).and_then(|seqs| seqs.first().cloned()),
+            Some(4)
+        );
+        // we don’t have event 3 anymore
+        assert_eq!(missed(epoch, 2), None);
+        // a cursor of a daemon from before a restart, or one we never sent
+        assert_eq!(missed(epoch - 1, total - 2), None);
+        assert_eq!(missed(epoch, total + 1), None);
+    }
+
+    #[test]
+    fn event_cursors_round_trip() {
+        let cursor = EventCursor {
+            epoch: 1_600_000_000_000,
+            seq: 42,
+        };
+        assert_eq!(cursor.to_string(), "1600000000000-42");
+        assert_eq!("1600000000000-42".parse(), Ok(cursor));
+        assert!("42".parse::<EventCursor>().is_err());
+    }
+}
@@ -0,0 +1,342 @@
+//! Decides in which order the daemon builds its projects,
+//! and how many builds run at the same time.
+
+use crate::build_loop::Event;
+use crate::daemon::LoopHandlerEvent;
+use crate::NixFile;
+use crossbeam_channel as chan;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Projects a client asked for in this time window
+/// (e.g. through `lorri direnv`) are built before all others.
+const ACTIVE_PROJECT_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+/// A project in the queue: its nix file and the attribute that is built.
+pub type ProjectKey = (NixFile, Option<String>);
+
+/// The queue of projects waiting for a build.
+///
+/// Projects somebody is working with right now go first,
+/// otherwise projects are built in the order they were queued.
+/// Requests for a project which is already waiting are coalesced
+/// into one build, which keeps the project’s place in the queue.
+///
+/// A project waits while one of the projects it depends on is waiting
+/// or building, unless that one (indirectly) depends on it as well.
+///
+/// Every waiting project is told its position through a `Queued` event.
+#[derive(Clone)]
+pub struct BuildQueue(Arc<Mutex<QueueState>>);
+
+struct QueueState {
+    /// How many builds can run at the same time.
+    max_running: usize,
+    /// The projects which hold a `Permit`.
+    running: Vec<NixFile>,
+    waiting: Vec<Waiting>,
+    last_activity: HashMap<ProjectKey, Instant>,
+    /// To keep the queue order stable.
+    next_seq: u64,
+    tx_events: chan::Sender<LoopHandlerEvent>,
+}
+
+struct Waiting {
+    key: ProjectKey,
+    seq: u64,
+    /// The nix files of the projects which are built first.
+    depends_on: Vec<NixFile>,
+    /// The position the last `Queued` event reported.
+    reported_position: Option<usize>,
+    tx_permit: chan::Sender<Permit>,
+}
+
+/// Allows a single build to run.
+/// The build slot is given to the next project once this is dropped.
+pub struct Permit {
+    queue: Option<BuildQueue>,
+    nix_file: NixFile,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some(queue) = self.queue.take() {
+            let mut state = queue.lock();
+            if let Some(i) = state.running.iter().position(|r| *r == self.nix_file) {
+                state.running.remove(i);
+            }
+            state.dispatch(&queue);
+        }
+    }
+}
+
+impl BuildQueue {
+    /// Create a queue that runs at most `max_running` builds (but at least one) at once.
+    /// Position updates are sent to `tx_events`.
+    pub fn new(max_running: usize, tx_events: chan::Sender<LoopHandlerEvent>) -> BuildQueue {
+        BuildQueue(Arc::new(Mutex::new(QueueState {
+            max_running: std::cmp::max(max_running, 1),
+            running: vec![],
+            waiting: vec![],
+            last_activity: HashMap::new(),
+            next_seq: 0,
+            tx_events,
+        })))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, QueueState> {
+        self.0.lock().expect("build queue lock poisoned")
+    }
+
+    /// Queue a build of `key`, after the builds of the projects of `depends_on`.
+    ///
+    /// The returned channel receives a `Permit` once it is the project’s turn.
+    /// If the project is already waiting, only the channel of the newest
+    /// request receives the permit.
+    pub fn enqueue(&self, key: ProjectKey, depends_on: Vec<NixFile>) -> chan::Receiver<Permit> {
+        let (tx_permit, rx_permit) = chan::bounded(1);
+        let mut state = self.lock();
+        match state.waiting.iter_mut().find(|waiting| waiting.key == key) {
+            Some(waiting) => {
+                waiting.tx_permit = tx_permit;
+                waiting.depends_on = depends_on;
+            }
+            None => {
+                let seq = state.next_seq;
+                state.next_seq += 1;
+                state.waiting.push(Waiting {
+                    key,
+                    seq,
+                    depends_on,
+                    reported_position: None,
+                    tx_permit,
+                })
+            }
+        }
+        state.dispatch(self);
+        rx_permit
+    }
+
+    /// Forget all builds of the project `key`, e.g. because it is not watched anymore.
+    pub fn remove(&self, key: &ProjectKey) {
+        let mut state = self.lock();
+        state.waiting.retain(|waiting| waiting.key != *key);
+        state.last_activity.retain(|active, _| active != key);
+        state.dispatch(self);
+    }
+
+    /// Record that a client asked for `key`,
+    /// which moves it ahead of projects nobody is working with.
+    pub fn mark_active(&self, key: ProjectKey) {
+        let mut state = self.lock();
+        state.last_activity.insert(key, Instant::now());
+        state.dispatch(self);
+    }
+}
+
+impl QueueState {
+    fn is_active(&self, key: &ProjectKey, now: Instant) -> bool {
+        self.last_activity
+            .get(key)
+            .map_or(false, |t| now.duration_since(*t) < ACTIVE_PROJECT_WINDOW)
+    }
+
+    /// Whether the waiting project of `from` has to wait for the one of `to`,
+    /// directly or through other waiting projects.
+    fn waits_for(&self, from: &NixFile, to: &NixFile) -> bool {
+        let mut visited: Vec<&NixFile> = vec![];
+        let mut next = vec![from];
+        while let Some(nix_file) = next.pop() {
+            if visited.contains(&nix_file) {
+                continue;
+            }
+            visited.push(nix_file);
+            for waiting in self.waiting.iter().filter(|w| w.key.0 == *nix_file) {
+                if waiting.depends_on.contains(to) {
+                    return true;
+                }
+                next.extend(&waiting.depends_on);
+            }
+        }
+        false
+    }
+
+    /// Whether `waiting` has to wait for a project it depends on.
+    /// Dependencies which go in a circle are ignored.
+    fn is_held(&self, waiting: &Waiting) -> bool {
+        waiting.depends_on.iter().any(|dependency| {
+            self.running.contains(dependency)
+                || (self.waiting.iter().any(|w| w.key.0 == *dependency)
+                    && !self.waits_for(dependency, &waiting.key.0))
+        })
+    }
+
+    /// Hand out permits while there are free slots,
+    /// then tell the projects which are still waiting their position.
+    fn dispatch(&mut self, queue: &BuildQueue) {
+        let now = Instant::now();
+        let mut waiting = std::mem::take(&mut self.waiting);
+        waiting.sort_by_key(|w| (!self.is_active(&w.key, now), w.seq));
+        self.waiting = waiting;
+
+        while self.running.len() < self.max_running {
+            let next = match self.waiting.iter().position(|w| !self.is_held(w)) {
+                Some(i) => self.waiting.remove(i),
+                None => break,
+            };
+            let nix_file = next.key.0.clone();
+            self.running.push(nix_file.clone());
+            let permit = Permit {
+                queue: Some(queue.clone()),
+                nix_file,
+            };
+            if let Err(err) = next.tx_permit.try_send(permit) {
+                // The build loop is gone. Dropping the permit normally
+                // would try to take the lock we are holding.
+                let mut permit = err.into_inner();
+                permit.queue = None;
+                self.running.pop();
+            }
+        }
+
+        for (position, waiting) in self.waiting.iter_mut().enumerate() {
+            if waiting.reported_position != Some(position) {
+                waiting.reported_position = Some(position);
+                // the daemon is shutting down if nobody listens
+                let _ = self
+                    .tx_events
+                    .send(LoopHandlerEvent::BuildEvent(Event::Queued {
+                        nix_file: waiting.key.0.clone(),
+                        attr: waiting.key.1.clone(),
+                        position,
+                    }));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AbsPathBuf;
+    use std::path::PathBuf;
+
+    fn key(name: &str) -> ProjectKey {
+        (
+            NixFile::from(AbsPathBuf::new_unchecked(PathBuf::from(format!(
+                "/{}/shell.nix",
+                name
+            )))),
+            None,
+        )
+    }
+
+    fn positions(rx: &chan::Receiver<LoopHandlerEvent>) -> Vec<(NixFile, usize)> {
+        rx.try_iter()
+            .filter_map(|ev| match ev {
+                LoopHandlerEvent::BuildEvent(Event::Queued {
+                    nix_file, position, ..
+                }) => Some((nix_file, position)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Builds wait for a free slot; active projects go first.
+    #[test]
+    fn active_projects_go_first() {
+        let (tx, rx) = chan::unbounded();
+        let queue = BuildQueue::new(1, tx);
+
+        let first = queue
+            .enqueue(key("a"), vec![])
+            .try_recv()
+            .expect("a slot is free");
+        let b = queue.enqueue(key("b"), vec![]);
+        let c = queue.enqueue(key("c"), vec![]);
+        assert!(b.try_recv().is_err());
+        assert_eq!(positions(&rx), vec![(key("b").0, 0), (key("c").0, 1)]);
+
+        queue.mark_active(key("c"));
+        assert_eq!(positions(&rx), vec![(key("c").0, 0), (key("b").0, 1)]);
+
+        drop(first);
+        let second = c.try_recv().expect("c is next");
+        assert!(b.try_recv().is_err());
+        assert_eq!(positions(&rx), vec![(key("b").0, 0)]);
+
+        drop(second);
+        assert!(b.try_recv().is_ok());
+    }
+
+    /// Queueing a project twice keeps one entry at the original place.
+    #[test]
+    fn requests_are_coalesced() {
+        let (tx, rx) = chan::unbounded();
+        let queue = BuildQueue::new(1, tx);
+
+        let running = queue
+            .enqueue(key("a"), vec![])
+            .try_recv()
+            .expect("a slot is free");
+        let _b = queue.enqueue(key("b"), vec![]);
+        let c = queue.enqueue(key("c"), vec![]);
+        let b = queue.enqueue(key("b"), vec![]);
+        assert_eq!(positions(&rx), vec![(key("b").0, 0), (key("c").0, 1)]);
+
+        drop(running);
+        let _running = b.try_recv().expect("b is next");
+        assert!(c.try_recv().is_err());
+    }
+
+    /// Removed projects give up their place in the queue.
+    #[test]
+    fn removed_projects_leave_the_queue() {
+        let (tx, rx) = chan::unbounded();
+        let queue = BuildQueue::new(1, tx);
+
+        let running = queue
+            .enqueue(key("a"), vec![])
+            .try_recv()
+            .expect("a slot is free");
+        let b = queue.enqueue(key("b"), vec![]);
+        let c = queue.enqueue(key("c"), vec![]);
+        assert_eq!(positions(&rx), vec![(key("b").0, 0), (key("c").0, 1)]);
+
+        queue.remove(&key("b"));
+        assert_eq!(positions(&rx), vec![(key("c").0, 0)]);
+
+        drop(running);
+        assert!(b.try_recv().is_err());
+        assert!(c.try_recv().is_ok());
+    }
+
+    /// Projects wait for the projects they depend on, unless they go in a circle.
+    #[test]
+    fn dependencies_are_built_first() {
+        let (tx, _rx) = chan::unbounded();
+        let queue = BuildQueue::new(2, tx);
+
+        let a = queue
+            .enqueue(key("a"), vec![])
+            .try_recv()
+            .expect("a slot is free");
+        let b = queue.enqueue(key("b"), vec![key("a").0]);
+        let c = queue.enqueue(key("c"), vec![key("b").0]);
+        // waits for a to finish building, c for b to be built
+        assert!(b.try_recv().is_err());
+        assert!(c.try_recv().is_err());
+
+        drop(a);
+        let b = b.try_recv().expect("a is built");
+        assert!(c.try_recv().is_err());
+        drop(b);
+        assert!(c.try_recv().is_ok());
+
+        let d = queue.enqueue(key("d"), vec![key("e").0]);
+        let e = queue.enqueue(key("e"), vec![key("d").0]);
+        assert!(d.try_recv().is_ok());
+        assert!(e.try_recv().is_ok());
+    }
+}
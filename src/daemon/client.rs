@@ -5,7 +5,10 @@ use crate::socket::communicate::{client::Client, Handler};
 use crate::socket::path::SocketPath;
 use slog::debug;
 
-pub use crate::socket::communicate::{Ping, Rebuild, StreamEvents};
+pub use crate::socket::communicate::{
+    Group, GroupAction, Pause, Ping, Projects, Rebuild, StreamEvents, StreamLogs, Untrack,
+    WatchedPaths,
+};
 pub use crate::socket::read_writer::Timeout;
 
 /// Create a connected client or exit.
@@ -0,0 +1,83 @@
+//! Run the command a project’s `.lorri.toml` sets as `post_build`
+//! after each of its builds.
+
+use slog::{debug, info};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// What the hook is told about the build, in its environment.
+pub struct Finished<'a> {
+    /// `success`, `failure` or `timeout` (`LORRI_OUTCOME`)
+    pub outcome: &'a str,
+    /// The project’s nix file (`LORRI_NIX_FILE`)
+    pub nix_file: &'a Path,
+    /// The GC root of the environment (`LORRI_ROOT_PATH`)
+    pub root_path: &'a Path,
+    /// The built environment, if the build succeeded (`LORRI_OUT_PATH`)
+    pub out_path: Option<&'a Path>,
+}
+
+/// Start `hook` with `sh -c` in `dir`, without waiting for it to finish.
+///
+/// Its output is discarded; whether it failed is only logged.
+pub fn run(hook: &str, dir: &Path, finished: &Finished, logger: &slog::Logger) {
+    let mut cmd = command(hook, dir, finished);
+    cmd.stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    debug!(logger, "running post_build hook"; "command" => ?cmd);
+    match cmd.spawn() {
+        // reaped in the background, the build loop has better things to do
+        Ok(mut child) => {
+            let logger = logger.clone();
+            std::thread::spawn(move || match child.wait() {
+                Ok(status) if status.success() => {}
+                Ok(status) => info!(logger, "post_build hook failed"; "status" => %status),
+                Err(e) => debug!(logger, "could not wait for the post_build hook"; "error" => %e),
+            });
+        }
+        Err(e) => info!(logger, "could not run the post_build hook"; "error" => %e),
+    }
+}
+
+fn command(hook: &str, dir: &Path, finished: &Finished) -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.args(["-c", hook])
+        .current_dir(dir)
+        .env("LORRI_OUTCOME", finished.outcome)
+        .env("LORRI_NIX_FILE", finished.nix_file)
+        .env("LORRI_ROOT_PATH", finished.root_path);
+    match finished.out_path {
+        Some(out_path) => cmd.env("LORRI_OUT_PATH", out_path),
+        None => cmd.env_remove("LORRI_OUT_PATH"),
+    };
+    cmd
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hook_gets_the_build_in_its_environment() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let finished = Finished {
+            outcome: "failure",
+            nix_file: Path::new("/project/shell.nix"),
+            root_path: Path::new("/gc_root/shell_gc_root"),
+            out_path: None,
+        };
+        let status = command(
+            "echo \"$LORRI_OUTCOME $LORRI_NIX_FILE ${LORRI_OUT_PATH-none}\" > out",
+            tmp.path(),
+            &finished,
+        )
+        .status()?;
+        assert!(status.success());
+        assert_eq!(
+            std::fs::read_to_string(tmp.path().join("out"))?,
+            "failure /project/shell.nix none\n"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,620 @@
+//! An HTTP API for the daemon, for tools which can’t speak the socket protocol.
+//!
+//! It is only served on the loopback interface, and only if the daemon
+//! is started with `--http-port`. All bodies except the metrics are JSON:
+//!
+//! - `GET /projects`: the status of all projects, as in `lorri ps --json`
+//! - `GET /events`: the build events, as in `lorri internal stream-events`,
+//!   as server-sent events. Their ids are the sequence numbers, so clients
+//!   which reconnect with `Last-Event-ID` get the events they missed
+//! - `GET /metrics`: build counts, build times, watched paths and the length
+//!   of the build queue, in the Prometheus text format
+//! - `POST /trigger`: build a project,
+//!   e.g. `{"shell_file": "/home/me/project/shell.nix", "attr": null}`
+//! - `POST /untrack`: stop watching a project,
+//!   e.g. `{"shell_file": "/home/me/project/shell.nix", "attr": null, "remove_roots": false}`
+//!
+//! Other users can connect to the loopback interface as well, so every
+//! request has to send the token the daemon writes to the `http-token` file
+//! next to its socket (which only the user can read)
+//! as `Authorization: Bearer <token>`.
+//!
+//! Websites must not be able to use the API through the user’s browser,
+//! so requests need to name `localhost` as their host, and `POST` requests
+//! need to be sent as `application/json` (which browsers only do for other
+//! sites after asking us, and we never agree).
+
+use crate::build_loop::Event;
+use crate::daemon::{self, IndicateActivity, LoopHandlerEvent, SequencedEvent};
+use crate::ops::{ProjectJson, StreamEvent};
+use crate::socket::communicate;
+use crate::{AbsPathBuf, Never, NixFile};
+use crossbeam_channel as chan;
+use slog::{debug, info};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How long a client may take to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Requests with larger bodies are rejected.
+const MAX_BODY_LENGTH: usize = 64 * 1024;
+
+/// More connections at the same time (e.g. event streams) are turned away.
+const MAX_CONNECTIONS: usize = 32;
+
+/// Serves the HTTP API, passing requests on to the daemon.
+#[derive(Clone)]
+pub struct Api {
+    tx_activity: chan::Sender<IndicateActivity>,
+    tx_untrack: chan::Sender<daemon::Untrack>,
+    tx_build: chan::Sender<LoopHandlerEvent>,
+}
+
+/// A parsed HTTP request.
+struct Request {
+    method: String,
+    path: String,
+    host: Option<String>,
+    authorization: Option<String>,
+    content_type: Option<String>,
+    last_event_id: Option<String>,
+    body: Vec<u8>,
+}
+
+/// The body of a `POST /trigger` request.
+#[derive(Deserialize)]
+struct TriggerRequest {
+    shell_file: PathBuf,
+    #[serde(default)]
+    attr: Option<String>,
+}
+
+/// The body of a `POST /untrack` request.
+#[derive(Deserialize)]
+struct UntrackRequest {
+    shell_file: PathBuf,
+    #[serde(default)]
+    attr: Option<String>,
+    #[serde(default)]
+    remove_roots: bool,
+}
+
+/// An HTTP status and the message explaining it.
+struct ErrorResponse(&'static str, String);
+
+impl ErrorResponse {
+    /// The daemon is shutting down, so nobody handles the request.
+    fn shutting_down() -> ErrorResponse {
+        ErrorResponse(
+            "503 Service Unavailable",
+            "the daemon is shutting down".to_string(),
+        )
+    }
+}
+
+/// Counts the open connections, see `MAX_CONNECTIONS`.
+struct Connection(Arc<AtomicUsize>);
+
+impl Connection {
+    /// `None` if there are too many connections already.
+    fn open(open: &Arc<AtomicUsize>) -> Option<Connection> {
+        if open.fetch_add(1, Ordering::SeqCst) < MAX_CONNECTIONS {
+            Some(Connection(open.clone()))
+        } else {
+            open.fetch_sub(1, Ordering::SeqCst);
+            None
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Api {
+    /// Create a new API.
+    pub fn new(
+        tx_activity: chan::Sender<IndicateActivity>,
+        tx_untrack: chan::Sender<daemon::Untrack>,
+        tx_build: chan::Sender<LoopHandlerEvent>,
+    ) -> Api {
+        Api {
+            tx_activity,
+            tx_untrack,
+            tx_build,
+        }
+    }
+
+    /// Listen on `port` of the loopback interface, writing a new token
+    /// to `token_file`. Goes into an accept() loop, thus blocks.
+    pub fn listen(
+        &self,
+        port: u16,
+        token_file: &AbsPathBuf,
+        logger: &slog::Logger,
+    ) -> std::io::Result<Never> {
+        let token = Arc::new(write_token(token_file.as_path())?);
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+        info!(logger, "serving the HTTP API"; "address" => %listener.local_addr()?, "token_file" => token_file.display());
+        let open = Arc::new(AtomicUsize::new(0));
+        loop {
+            match listener.accept() {
+                Ok((mut stream, _)) => {
+                    let connection = match Connection::open(&open) {
+                        Some(connection) => connection,
+                        None => {
+                            debug!(logger, "too many HTTP connections");
+                            let _ = stream.set_write_timeout(Some(REQUEST_TIMEOUT));
+                            let _ = respond_error(
+                                &mut stream,
+                                ErrorResponse(
+                                    "503 Service Unavailable",
+                                    "too many connections".to_string(),
+                                ),
+                            );
+                            continue;
+                        }
+                    };
+                    let api = self.clone();
+                    let token = token.clone();
+                    let logger = logger.clone();
+                    // requests don’t share any state, and events are streamed
+                    // for as long as the client listens, so each gets a thread
+                    std::thread::spawn(move || {
+                        let _connection = connection;
+                        if let Err(err) = api.handle(stream, &token, &logger) {
+                            debug!(logger, "HTTP client vanished"; "error" => %err);
+                        }
+                    });
+                }
+                Err(err) => {
+                    info!(logger, "Failed accepting an HTTP connection"; "error" => %err);
+                    // avoid a busy loop on errors like `too many open file descriptors`
+                    std::thread::sleep(Duration::from_millis(100));
+                }
+            }
+        }
+    }
+
+    fn handle(
+        &self,
+        mut stream: TcpStream,
+        token: &str,
+        logger: &slog::Logger,
+    ) -> std::io::Result<()> {
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        let request = match read_request(&stream) {
+            Ok(request) => request,
+            Err(err) => {
+                return respond_error(&mut stream, ErrorResponse("400 Bad Request", err));
+            }
+        };
+        debug!(logger, "HTTP request"; "method" => &request.method, "path" => &request.path);
+
+        let is_local = request.host.as_ref().map_or(false, |host| {
+            let name = host.rsplitn(2, ':').last().unwrap_or("");
+            name == "localhost" || name == "127.0.0.1"
+        });
+        if !is_local {
+            return respond_error(
+                &mut stream,
+                ErrorResponse("403 Forbidden", "the host must be localhost".to_string()),
+            );
+        }
+
+        if !is_authorized(&request, token) {
+            return respond_error(
+                &mut stream,
+                ErrorResponse(
+                    "401 Unauthorized",
+                    "send the token of the http-token file as `Authorization: Bearer <token>`"
+                        .to_string(),
+                ),
+            );
+        }
+
+        match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/projects") => {
+                let (tx_projects, rx_projects) = chan::bounded(1);
+                let projects = self
+                    .tx_build
+                    .send(LoopHandlerEvent::GetProjects(tx_projects))
+                    .ok()
+                    .and_then(|()| rx_projects.recv().ok());
+                let mut projects = match projects {
+                    Some(projects) => projects,
+                    None => return respond_error(&mut stream, ErrorResponse::shutting_down()),
+                };
+                projects.sort_by(|a, b| {
+                    a.nix_file
+                        .as_absolute_path()
+                        .cmp(b.nix_file.as_absolute_path())
+                });
+                let projects: Vec<ProjectJson> = projects.iter().map(ProjectJson::from).collect();
+                respond_json(&mut stream, "200 OK", &projects)
+            }
+            ("GET", "/events") => {
+                let since = match request.last_event_id.as_deref().map(str::parse) {
+                    None => None,
+                    Some(Ok(cursor)) => Some(cursor),
+                    Some(Err(e)) => {
+                        return respond_error(&mut stream, ErrorResponse("400 Bad Request", e))
+                    }
+                };
+                self.stream_events(stream, since)
+            }
+            ("GET", "/metrics") => {
+                let (tx_metrics, rx_metrics) = chan::bounded(1);
+                let metrics = self
+                    .tx_build
+                    .send(LoopHandlerEvent::GetMetrics(tx_metrics))
+                    .ok()
+                    .and_then(|()| rx_metrics.recv().ok());
+                let metrics = match metrics {
+                    Some(metrics) => metrics,
+                    None => return respond_error(&mut stream, ErrorResponse::shutting_down()),
+                };
+                respond(
+                    &mut stream,
+                    "200 OK",
+                    "text/plain; version=0.0.4",
+                    metrics.to_prometheus().as_bytes(),
+                )
+            }
+            ("POST", "/trigger") => match parse_body::<TriggerRequest>(&request) {
+                Ok(TriggerRequest { shell_file, attr }) => match nix_file(shell_file) {
+                    Ok(nix_file) if !nix_file.as_absolute_path().is_file() => respond_error(
+                        &mut stream,
+                        ErrorResponse(
+                            "422 Unprocessable Entity",
+                            format!("{} does not exist", nix_file.display()),
+                        ),
+                    ),
+                    Ok(nix_file) => {
+                        let sent = self.tx_activity.send(IndicateActivity {
+                            nix_file,
+                            attr,
+                            rebuild: communicate::Rebuild::Always,
+                            // keep the environment the project’s clients sent
+                            env: None,
+                        });
+                        match sent {
+                            Ok(()) => {
+                                respond_json(&mut stream, "202 Accepted", &serde_json::json!({}))
+                            }
+                            Err(_) => respond_error(&mut stream, ErrorResponse::shutting_down()),
+                        }
+                    }
+                    Err(err) => respond_error(&mut stream, err),
+                },
+                Err(err) => respond_error(&mut stream, err),
+            },
+            ("POST", "/untrack") => match parse_body::<UntrackRequest>(&request) {
+                Ok(UntrackRequest {
+                    shell_file,
+                    attr,
+                    remove_roots,
+                }) => match nix_file(shell_file) {
+                    Ok(nix_file) => {
+                        let sent = self.tx_untrack.send(daemon::Untrack {
+                            nix_file,
+                            attr,
+                            remove_roots,
+                        });
+                        match sent {
+                            Ok(()) => {
+                                respond_json(&mut stream, "202 Accepted", &serde_json::json!({}))
+                            }
+                            Err(_) => respond_error(&mut stream, ErrorResponse::shutting_down()),
+                        }
+                    }
+                    Err(err) => respond_error(&mut stream, err),
+                },
+                Err(err) => respond_error(&mut stream, err),
+            },
+            (_, "/projects")
+            | (_, "/events")
+            | (_, "/metrics")
+            | (_, "/trigger")
+            | (_, "/untrack") => respond_error(
+                &mut stream,
+                ErrorResponse(
+                    "405 Method Not Allowed",
+                    format!("{} is not supported here", request.method),
+                ),
+            ),
+            (_, path) => respond_error(
+                &mut stream,
+                ErrorResponse("404 Not Found", format!("{} does not exist", path)),
+            ),
+        }
+    }
+
+    /// Send the build events as server-sent events until the client hangs up,
+    /// starting after the event `since` if the client reconnected.
+    fn stream_events(
+        &self,
+        mut stream: TcpStream,
+        since: Option<daemon::EventCursor>,
+    ) -> std::io::Result<()> {
+        let (tx_event, rx_event) = chan::unbounded();
+        if self
+            .tx_build
+            .send(LoopHandlerEvent::NewListener(since, tx_event))
+            .is_err()
+        {
+            return respond_error(&mut stream, ErrorResponse::shutting_down());
+        }
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\n\
+             Content-Type: text/event-stream\r\n\
+             Cache-Control: no-cache\r\n\
+             Connection: close\r\n\r\n"
+        )?;
+        stream.flush()?;
+        for sequenced in rx_event {
+            let cursor = sequenced.cursor();
+            let SequencedEvent { event, .. } = sequenced;
+            // clients can’t tell the snapshot from the live events anyway
+            if let Event::SectionEnd = event {
+                continue;
+            }
+            let data =
+                serde_json::to_string(&StreamEvent::from(event)).expect("couldn't serialize event");
+            write!(stream, "id: {}\ndata: {}\n\n", cursor, data)?;
+            stream.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Read the request line, the headers we care about and the body.
+fn read_request(stream: &TcpStream) -> Result<Request, String> {
+    let mut reader = BufReader::new(stream);
+    let mut read_line = || -> Result<String, String> {
+        let mut line = String::new();
+        match reader.read_line(&mut line) {
+            Ok(0) => Err("the request ended early".to_string()),
+            Ok(_) => Ok(line.trim_end_matches(&['\r', '\n'][..]).to_string()),
+            Err(err) => Err(format!("could not read the request: {}", err)),
+        }
+    };
+
+    let request_line = read_line()?;
+    let mut parts = request_line.split(' ');
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method.to_string(), target),
+        _ => return Err(format!("invalid request line: {}", request_line)),
+    };
+    let path = target.split('?').next().unwrap_or(target).to_string();
+
+    let mut host = None;
+    let mut authorization = None;
+    let mut content_type = None;
+    let mut last_event_id = None;
+    let mut content_length = 0;
+    loop {
+        let line = read_line()?;
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = match line.find(':') {
+            Some(i) => (line[..i].trim().to_lowercase(), line[i + 1..].trim()),
+            None => return Err(format!("invalid header: {}", line)),
+        };
+        match name.as_str() {
+            "host" => host = Some(value.to_string()),
+            "authorization" => authorization = Some(value.to_string()),
+            "content-type" => content_type = Some(value.to_string()),
+            "last-event-id" => last_event_id = Some(value.to_string()),
+            "content-length" => {
+                content_length = value
+                    .parse()
+                    .map_err(|_| format!("invalid content length: {}", value))?
+            }
+            _ => {}
+        }
+    }
+    if content_length > MAX_BODY_LENGTH {
+        return Err(format!("the body is longer than {} bytes", MAX_BODY_LENGTH));
+    }
+
+    let mut body = vec![0; content_length];
+    reader
+        .read_exact(&mut body)
+        .map_err(|err| format!("could not read the body: {}", err))?;
+    Ok(Request {
+        method,
+        path,
+        host,
+        authorization,
+        content_type,
+        last_event_id,
+        body,
+    })
+}
+
+/// Write a new random token to `token_file`, readable only by the user.
+fn write_token(token_file: &Path) -> std::io::Result<String> {
+    let mut bytes = [0u8; 16];
+    std::fs::File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    let token: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    // a file left over from an earlier daemon might be readable by others
+    match std::fs::remove_file(token_file) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+        _ => {}
+    }
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(token_file)?;
+    writeln!(file, "{}", token)?;
+    Ok(token)
+}
+
+/// Whether the request sends `token`.
+fn is_authorized(request: &Request, token: &str) -> bool {
+    let sent = match request
+        .authorization
+        .as_deref()
+        .and_then(|value| value.strip_prefix("Bearer "))
+    {
+        Some(sent) => sent.trim().as_bytes(),
+        None => return false,
+    };
+    // compare all bytes, so the time it takes doesn’t tell how many were right
+    sent.len() == token.len()
+        && sent
+            .iter()
+            .zip(token.as_bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Parse the JSON body of a `POST` request.
+fn parse_body<T: serde::de::DeserializeOwned>(request: &Request) -> Result<T, ErrorResponse> {
+    let is_json = request.content_type.as_ref().map_or(false, |content_type| {
+        content_type.split(';').next().map(str::trim) == Some("application/json")
+    });
+    if !is_json {
+        return Err(ErrorResponse(
+            "415 Unsupported Media Type",
+            "the body must be application/json".to_string(),
+        ));
+    }
+    serde_json::from_slice(&request.body)
+        .map_err(|err| ErrorResponse("400 Bad Request", format!("invalid body: {}", err)))
+}
+
+/// The nix file of a request.
+fn nix_file(shell_file: PathBuf) -> Result<NixFile, ErrorResponse> {
+    AbsPathBuf::new(shell_file)
+        .map(NixFile::from)
+        .map_err(|path| {
+            ErrorResponse(
+                "422 Unprocessable Entity",
+                format!("{} is not an absolute path", path.display()),
+            )
+        })
+}
+
+fn respond(
+    stream: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &[u8],
+) -> std::io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\n\
+         Content-Type: {}\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    )?;
+    stream.write_all(body)?;
+    stream.flush()
+}
+
+fn respond_json<T: serde::Serialize>(
+    stream: &mut TcpStream,
+    status: &str,
+    body: &T,
+) -> std::io::Result<()> {
+    let body = serde_json::to_vec(body).expect("couldn't serialize response");
+    respond(stream, status, "application/json", &body)
+}
+
+fn respond_error(
+    stream: &mut TcpStream,
+    ErrorResponse(status, message): ErrorResponse,
+) -> std::io::Result<()> {
+    respond_json(stream, status, &serde_json::json!({ "error": message }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_post_request() -> std::io::Result<()> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let mut client = TcpStream::connect(listener.local_addr()?)?;
+        let body = r#"{"shell_file": "/project/shell.nix"}"#;
+        write!(
+            client,
+            "POST /trigger?verbose HTTP/1.1\r\n\
+             Host: localhost:8080\r\n\
+             content-type: application/json; charset=utf-8\r\n\
+             Content-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )?;
+        let (server, _) = listener.accept()?;
+        let request = read_request(&server).expect("request should parse");
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/trigger");
+        assert_eq!(request.host.as_deref(), Some("localhost:8080"));
+        let trigger: TriggerRequest = parse_body(&request).unwrap_or_else(|_| panic!());
+        assert_eq!(trigger.shell_file, PathBuf::from("/project/shell.nix"));
+        assert_eq!(trigger.attr, None);
+        Ok(())
+    }
+
+    #[test]
+    fn requests_need_the_token() -> std::io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let token_file = dir.path().join("http-token");
+        std::fs::write(&token_file, "stale")?;
+        let token = write_token(&token_file)?;
+        assert_eq!(std::fs::read_to_string(&token_file)?.trim(), token);
+        let mode =
+            std::os::unix::fs::PermissionsExt::mode(&std::fs::metadata(&token_file)?.permissions());
+        assert_eq!(mode & 0o777, 0o600);
+
+        let request = |authorization: Option<String>| Request {
+            method: "POST".to_string(),
+            path: "/untrack".to_string(),
+            host: Some("localhost".to_string()),
+            authorization,
+            content_type: None,
+            last_event_id: None,
+            body: vec![],
+        };
+        assert!(is_authorized(
+            &request(Some(format!("Bearer {}", token))),
+            &token
+        ));
+        assert!(!is_authorized(&request(None), &token));
+        assert!(!is_authorized(&request(Some(token.clone())), &token));
+        assert!(!is_authorized(
+            &request(Some("Bearer 00".to_string())),
+            &token
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn connections_are_bounded() {
+        let open = Arc::new(AtomicUsize::new(0));
+        let connections: Vec<Connection> = (0..MAX_CONNECTIONS)
+            .map(|_| Connection::open(&open).expect("below the limit"))
+            .collect();
+        assert!(Connection::open(&open).is_none());
+        drop(connections);
+        assert_eq!(open.load(Ordering::SeqCst), 0);
+        assert!(Connection::open(&open).is_some());
+    }
+}
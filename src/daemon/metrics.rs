@@ -0,0 +1,183 @@
+//! Counters about the daemon’s builds, served as Prometheus metrics
+//! by the HTTP API.
+
+use super::build_queue::ProjectKey;
+use crate::build_loop::Event;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::time::Duration;
+
+/// What happened to a project’s builds since the daemon started.
+#[derive(Debug, Clone, Default)]
+pub struct ProjectMetrics {
+    /// Finished builds, successful or not
+    pub builds: u64,
+    /// Builds which failed or timed out
+    pub failures: u64,
+    /// How long all finished builds took together
+    pub build_time: Duration,
+    /// How many paths are watched for the project
+    pub watched_paths: usize,
+}
+
+/// The metrics of all projects.
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    /// The metrics of each project, i.e. each attribute of a nix file
+    pub projects: HashMap<ProjectKey, ProjectMetrics>,
+    /// How many projects wait for their build to start
+    pub queued: usize,
+}
+
+impl Metrics {
+    /// Count a finished build, which took `duration`.
+    /// Other events are ignored.
+    pub fn record(&mut self, ev: &Event, duration: Option<Duration>) {
+        let (nix_file, attr, failed) = match ev {
+            Event::Completed { nix_file, attr, .. } => (nix_file, attr, false),
+            Event::Failure { nix_file, attr, .. } | Event::BuildTimeout { nix_file, attr, .. } => {
+                (nix_file, attr, true)
+            }
+            _ => return,
+        };
+        let project = self
+            .projects
+            .entry((nix_file.clone(), attr.clone()))
+            .or_default();
+        project.builds += 1;
+        if failed {
+            project.failures += 1;
+        }
+        project.build_time += duration.unwrap_or_default();
+    }
+
+    /// The metrics in the Prometheus text format.
+    pub fn to_prometheus(&self) -> String {
+        let mut projects: Vec<(&ProjectKey, &ProjectMetrics)> = self.projects.iter().collect();
+        projects.sort_by(|((a, a_attr), _), ((b, b_attr), _)| {
+            (a.as_absolute_path(), a_attr).cmp(&(b.as_absolute_path(), b_attr))
+        });
+
+        let mut out = String::new();
+        write_per_project(
+            &mut out,
+            &projects,
+            ("lorri_builds_total", "counter"),
+            "Finished builds of the project.",
+            |m| m.builds.to_string(),
+        );
+        write_per_project(
+            &mut out,
+            &projects,
+            ("lorri_build_failures_total", "counter"),
+            "Failed or timed out builds of the project.",
+            |m| m.failures.to_string(),
+        );
+        write_per_project(
+            &mut out,
+            &projects,
+            ("lorri_build_duration_seconds_total", "counter"),
+            "How long the finished builds of the project took.",
+            |m| m.build_time.as_secs_f64().to_string(),
+        );
+        write_per_project(
+            &mut out,
+            &projects,
+            ("lorri_watched_paths", "gauge"),
+            "Paths watched for changes of the project.",
+            |m| m.watched_paths.to_string(),
+        );
+        let _ = writeln!(
+            out,
+            "# HELP lorri_build_queue_length Projects waiting for their build to start."
+        );
+        let _ = writeln!(out, "# TYPE lorri_build_queue_length gauge");
+        let _ = writeln!(out, "lorri_build_queue_length {}", self.queued);
+        out
+    }
+}
+
+/// Write the metric `name` of type `kind` for each project.
+fn write_per_project<F>(
+    out: &mut String,
+    projects: &[(&ProjectKey, &ProjectMetrics)],
+    (name, kind): (&str, &str),
+    help: &str,
+    value: F,
+) where
+    F: Fn(&ProjectMetrics) -> String,
+{
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for ((nix_file, attr), metrics) in projects {
+        let attr = match attr {
+            Some(attr) => format!(",attr=\"{}\"", escape_label(attr)),
+            None => String::new(),
+        };
+        let _ = writeln!(
+            out,
+            "{}{{nix_file=\"{}\"{}}} {}",
+            name,
+            escape_label(&nix_file.display().to_string()),
+            attr,
+            value(metrics)
+        );
+    }
+}
+
+/// Escape a label value, see
+/// <https://prometheus.io/docs/instrumenting/exposition_formats/>.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AbsPathBuf, NixFile};
+    use std::path::PathBuf;
+
+    #[test]
+    fn prometheus_format() {
+        let nix_file = NixFile::from(AbsPathBuf::new_unchecked(PathBuf::from(
+            "/my \"project\"/shell.nix",
+        )));
+        let mut metrics = Metrics::default();
+        metrics.record(
+            &Event::BuildTimeout {
+                nix_file: nix_file.clone(),
+                attr: None,
+                timeout_secs: 60,
+                backoff_secs: 60,
+            },
+            Some(Duration::from_secs(60)),
+        );
+        metrics.record(
+            &Event::Failure {
+                nix_file,
+                attr: Some(String::from("shells.dev")),
+                failure: crate::builder::BuildError::Io {
+                    msg: String::from("boom"),
+                },
+            },
+            Some(Duration::from_secs(1)),
+        );
+        metrics.queued = 2;
+        let out = metrics.to_prometheus();
+        assert!(out.contains("lorri_builds_total{nix_file=\"/my \\\"project\\\"/shell.nix\"} 1\n"));
+        assert!(out.contains(
+            "lorri_build_failures_total{nix_file=\"/my \\\"project\\\"/shell.nix\"} 1\n"
+        ));
+        assert!(out.contains(
+            "lorri_build_duration_seconds_total{nix_file=\"/my \\\"project\\\"/shell.nix\"} 60\n"
+        ));
+        // each attribute of the file is a project of its own
+        assert!(out.contains(
+            "lorri_builds_total{nix_file=\"/my \\\"project\\\"/shell.nix\",attr=\"shells.dev\"} 1\n"
+        ));
+        assert!(out.contains("\nlorri_build_queue_length 2\n"));
+    }
+}
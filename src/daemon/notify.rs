@@ -0,0 +1,76 @@
+//! Desktop notifications about finished builds, for projects
+//! whose `.lorri.toml` sets `notify`.
+
+use crate::nix::is_on_path;
+use slog::debug;
+use std::process::{Command, Stdio};
+
+/// Show a desktop notification, without waiting for it to be shown.
+///
+/// Uses `notify-send` on Linux, and `terminal-notifier` (or `osascript`)
+/// on macOS. Failures are only logged, a notification is not important
+/// enough to bother anybody about.
+pub fn send(title: &str, body: &str, logger: &slog::Logger) {
+    let mut cmd = match command(title, body) {
+        Some(cmd) => cmd,
+        None => {
+            debug!(
+                logger,
+                "cannot show notifications, notify-send is not installed"
+            );
+            return;
+        }
+    };
+    cmd.stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    match cmd.spawn() {
+        // reaped in the background, the build loop has better things to do
+        Ok(mut child) => {
+            std::thread::spawn(move || child.wait());
+        }
+        Err(e) => debug!(logger, "could not show a notification"; "error" => %e),
+    }
+}
+
+fn command(title: &str, body: &str) -> Option<Command> {
+    if cfg!(target_os = "macos") {
+        if is_on_path("terminal-notifier") {
+            let mut cmd = Command::new("terminal-notifier");
+            cmd.args(["-group", "lorri", "-title", title, "-message", body]);
+            Some(cmd)
+        } else {
+            let mut cmd = Command::new("osascript");
+            cmd.arg("-e").arg(format!(
+                "display notification {} with title {}",
+                applescript_string(body),
+                applescript_string(title)
+            ));
+            Some(cmd)
+        }
+    } else if is_on_path("notify-send") {
+        let mut cmd = Command::new("notify-send");
+        cmd.args(["--app-name=lorri", "--", title, body]);
+        Some(cmd)
+    } else {
+        None
+    }
+}
+
+/// Quote `s` as an AppleScript string.
+fn applescript_string(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quotes_applescript_strings() {
+        assert_eq!(
+            applescript_string(r#"/home/a "b"\c/shell.nix"#),
+            r#""/home/a \"b\"\\c/shell.nix""#
+        );
+    }
+}
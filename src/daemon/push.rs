@@ -0,0 +1,108 @@
+//! Push a project’s environment to binary caches after it was built,
+//! for projects whose `.lorri.toml` has a `[push]` table.
+
+use crate::nix::is_on_path;
+use crate::nix::Backend;
+use crate::project::config::Push;
+use slog::{debug, info};
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+/// Push `paths` (and their closure) to every cache of `push`, one after the other.
+///
+/// Returns where each push went and, if it failed, why.
+pub fn run(push: &Push, paths: &[PathBuf], logger: &slog::Logger) -> Vec<(String, Option<String>)> {
+    command_lines(push, paths)
+        .into_iter()
+        .map(|(target, args)| {
+            let error = match execute(args, logger) {
+                Ok(()) => {
+                    info!(logger, "pushed the environment"; "target" => &target);
+                    None
+                }
+                Err(e) => {
+                    info!(logger, "could not push the environment"; "target" => &target, "error" => &e);
+                    Some(e)
+                }
+            };
+            (target, error)
+        })
+        .collect()
+}
+
+fn execute(args: Vec<OsString>, logger: &slog::Logger) -> Result<(), String> {
+    let (program, args) = args.split_first().expect("push command is empty");
+    let program = program.to_string_lossy();
+    let mut cmd = if program == "nix" {
+        crate::nix::limits::command("nix")
+    } else if is_on_path(&program) {
+        Command::new(&*program)
+    } else {
+        return Err(format!("{} is not installed", program));
+    };
+    cmd.args(args).stdin(Stdio::null());
+    debug!(logger, "pushing"; "command" => ?cmd);
+    let output = cmd
+        .output()
+        .map_err(|e| format!("could not run {}: {}", program, e))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        // the last line usually says what went wrong
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        Err(stderr
+            .lines()
+            .rev()
+            .find(|line| !line.trim().is_empty())
+            .map_or_else(|| format!("{} {}", program, output.status), String::from))
+    }
+}
+
+/// The command line of each push, with a description of its target.
+fn command_lines(push: &Push, paths: &[PathBuf]) -> Vec<(String, Vec<OsString>)> {
+    let mut commands = vec![];
+    if let Some(cache) = &push.cachix {
+        let mut args: Vec<OsString> = vec!["cachix".into(), "push".into(), cache.into()];
+        args.extend(paths.iter().map(OsString::from));
+        commands.push((format!("cachix:{}", cache), args));
+    }
+    if let Some(to) = &push.to {
+        let mut args: Vec<OsString> = vec!["nix".into()];
+        args.extend(
+            Backend::nix_command_arguments()
+                .into_iter()
+                .map(OsString::from),
+        );
+        args.extend(vec!["copy".into(), "--to".into(), to.into()]);
+        args.extend(paths.iter().map(OsString::from));
+        commands.push((to.clone(), args));
+    }
+    commands
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn push_command_lines() {
+        let push = Push {
+            cachix: Some(String::from("team")),
+            to: Some(String::from("s3://team-cache")),
+        };
+        let commands = command_lines(&push, &[PathBuf::from("/nix/store/abc-shell")]);
+        assert_eq!(commands.len(), 2);
+        assert_eq!(commands[0].0, "cachix:team");
+        assert_eq!(
+            commands[0].1,
+            vec!["cachix", "push", "team", "/nix/store/abc-shell"]
+        );
+        assert_eq!(commands[1].0, "s3://team-cache");
+        assert_eq!(
+            &commands[1].1[commands[1].1.len() - 4..],
+            &["copy", "--to", "s3://team-cache", "/nix/store/abc-shell"]
+        );
+        assert!(command_lines(&Push::default(), &[]).is_empty());
+    }
+}
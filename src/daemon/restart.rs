@@ -0,0 +1,91 @@
+//! Restart the daemon in place on `SIGHUP`, e.g. after lorri was upgraded.
+//!
+//! The running builds are cancelled, and the new daemon gets the watched
+//! projects (with the environment their clients sent) through an environment
+//! variable, so that it can watch and build them right away.
+//! Clients only notice that the socket is gone for a moment, or, if systemd
+//! passed the socket, that the daemon takes a moment to answer.
+
+use crate::socket::path;
+use crate::NixFile;
+use nix::sys::signal::{SigSet, Signal};
+use slog::warn;
+use std::collections::BTreeMap;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+
+/// The environment variable the projects are handed over in.
+const HANDOVER_VAR: &str = "LORRI_DAEMON_HANDOVER";
+
+/// A watched project, as handed over to the new daemon.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HandoverProject {
+    /// The shell.nix file of the project
+    pub nix_file: NixFile,
+    /// The attribute which is built, if not the whole file
+    pub attr: Option<String>,
+    /// The values of the project’s `pass_env` variables
+    pub env: BTreeMap<String, String>,
+    /// Whether a build was queued or running, so that it is started first
+    pub building: bool,
+}
+
+fn restart_signal() -> SigSet {
+    let mut set = SigSet::empty();
+    set.add(Signal::SIGHUP);
+    set
+}
+
+/// Block `SIGHUP` in this thread and all threads it starts afterwards,
+/// so that only `wait_for_signal` receives it.
+///
+/// Has to be called before any other thread is started, otherwise they
+/// might get the signal and terminate the process.
+pub fn block_signal() -> nix::Result<()> {
+    restart_signal().thread_block()
+}
+
+/// Wait until the daemon is asked to restart.
+pub fn wait_for_signal() -> nix::Result<()> {
+    restart_signal().wait().map(|_| ())
+}
+
+/// The projects the previous daemon handed over, if it restarted into this one.
+pub fn take_handover(logger: &slog::Logger) -> Option<Vec<HandoverProject>> {
+    let handover = std::env::var(HANDOVER_VAR).ok()?;
+    // nix and the shells we start don’t need to see it
+    std::env::remove_var(HANDOVER_VAR);
+    match serde_json::from_str(&handover) {
+        Ok(projects) => Some(projects),
+        Err(err) => {
+            warn!(logger, "could not read the projects of the previous daemon"; "error" => %err);
+            None
+        }
+    }
+}
+
+/// Replace this process with a new daemon started in the same way,
+/// handing over `projects`. Only returns if that fails.
+///
+/// The binary is looked up by the name it was started with,
+/// so that an upgraded lorri is picked up.
+/// If systemd passed the socket, the new daemon takes it over.
+pub fn exec(projects: &[HandoverProject]) -> std::io::Error {
+    let handover = serde_json::to_string(projects).expect("couldn't serialize the projects");
+    let mut args = std::env::args_os();
+    let program = args.next().unwrap_or_else(|| "lorri".into());
+    // the pid stays the same, so `LISTEN_PID` and `LISTEN_FDS` stay valid
+    let inherited = match path::keep_inherited_listener_on_exec(true) {
+        Ok(inherited) => inherited,
+        Err(err) => return std::io::Error::new(std::io::ErrorKind::Other, err),
+    };
+    let err = Command::new(program)
+        .args(args)
+        .env(HANDOVER_VAR, handover)
+        .exec();
+    if inherited {
+        // the nix processes we start should still not see the socket
+        let _ = path::keep_inherited_listener_on_exec(false);
+    }
+    err
+}
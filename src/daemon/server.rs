@@ -1,19 +1,25 @@
 //! Serve the lorri daemon on a unix socket.
-use crate::daemon::{IndicateActivity, LoopHandlerEvent};
+use crate::daemon::{self, IndicateActivity, LoopHandlerEvent, SetPaused};
+use crate::project::config::ProjectConfig;
 use crate::run_async::Async;
 use crate::socket::communicate;
-use crate::socket::communicate::listener::{Connection, Listener};
-use crate::socket::communicate::{CommunicationType, Ping, StreamEvents};
+use crate::socket::communicate::listener::{AcceptError, Connection, Listener};
+use crate::socket::communicate::{
+    CommunicationType, Group, GroupAction, Pause, Ping, Projects, Rebuild, StreamEvents,
+    StreamLogs, Untrack, WatchedPaths,
+};
 use crate::socket::path::{BindError, SocketPath};
-use crate::Never;
+use crate::{Never, NixFile};
 use crossbeam_channel as chan;
-use slog::{debug, info};
+use slog::{debug, info, warn};
 use std::collections::HashMap;
 use std::thread;
 
 /// Native backend Server
 pub struct Server {
     tx_activity: chan::Sender<IndicateActivity>,
+    tx_pause: chan::Sender<SetPaused>,
+    tx_untrack: chan::Sender<daemon::Untrack>,
     tx_build: chan::Sender<LoopHandlerEvent>,
 }
 
@@ -21,10 +27,14 @@ impl Server {
     /// Create a new server.
     pub fn new(
         tx_activity: chan::Sender<IndicateActivity>,
+        tx_pause: chan::Sender<SetPaused>,
+        tx_untrack: chan::Sender<daemon::Untrack>,
         tx_build: chan::Sender<LoopHandlerEvent>,
     ) -> Self {
         Server {
             tx_activity,
+            tx_pause,
+            tx_untrack,
             tx_build,
         }
     }
@@ -42,7 +52,7 @@ impl Server {
         let (tx_new_thread, rx_new_thread) = chan::unbounded();
         let (tx_done_thread, rx_done_thread) = chan::unbounded();
         let logger2 = logger.clone();
-        let _joiner = Async::run(&logger, move || {
+        let _joiner = Async::run(logger, move || {
             join_continuously(rx_new_thread, rx_done_thread, &logger2)
         });
 
@@ -50,7 +60,18 @@ impl Server {
             let tx_done_thread = tx_done_thread.clone();
             match listener.accept() {
                 Ok(connection) => {
-                    self.handle_client(connection, tx_new_thread.clone(), tx_done_thread, &logger)
+                    self.handle_client(connection, tx_new_thread.clone(), tx_done_thread, logger)
+                }
+                Err(AcceptError::OutdatedClient) => {
+                    warn!(
+                        logger,
+                        "A client from an older lorri version connected, please upgrade it"
+                    )
+                }
+                Err(AcceptError::IncompatibleClient { protocol_version }) => {
+                    warn!(logger, "A client speaking a different protocol connected, please use the same lorri version for clients and daemon";
+                          "client_protocol_version" => protocol_version,
+                          "daemon_protocol_version" => communicate::PROTOCOL_VERSION)
                 }
                 Err(accept_err) => {
                     info!(logger, "Failed accepting a client connection"; "accept_error" => format!("{:?}", accept_err));
@@ -81,6 +102,8 @@ impl Server {
         let display_id_copy = display_id.clone();
 
         let tx_activity = self.tx_activity.clone();
+        let tx_pause = self.tx_pause.clone();
+        let tx_untrack = self.tx_untrack.clone();
         let tx_build = self.tx_build.clone();
         let logger = logger.clone();
 
@@ -96,20 +119,123 @@ impl Server {
                 // TODO: it would be good if we didn’t have to match on the communication type here, but I don’t see a way to do that.
                 match communication_type {
                     CommunicationType::Ping => {
-                        match handlers.ping().read(communicate::DEFAULT_READ_TIMEOUT) {
-                            Ok(Ping { nix_file, rebuild }) => tx_activity
-                                .send(IndicateActivity { nix_file, rebuild })
-                                .expect("Unable to send a ping from listener"),
+                        let mut rw = handlers.ping();
+                        match rw.read(communicate::DEFAULT_READ_TIMEOUT) {
+                            Ok(Ping {
+                                nix_file,
+                                attr,
+                                rebuild,
+                                env,
+                            }) => {
+                                tx_activity
+                                    .send(IndicateActivity {
+                                        nix_file,
+                                        attr,
+                                        rebuild,
+                                        env: Some(env),
+                                    })
+                                    .expect("Unable to send a ping from listener");
+                                let (tx_status, rx_status) = chan::bounded(1);
+                                tx_build
+                                    .send(LoopHandlerEvent::GetStatus(tx_status))
+                                    .expect("Unable to ask the build_loop for the daemon status");
+                                let status = rx_status
+                                    .recv()
+                                    .expect("build_loop did not reply with the daemon status");
+                                if let Err(e) = rw.write(communicate::DEFAULT_READ_TIMEOUT, &status)
+                                {
+                                    debug!(logger, "client vanished, closing socket"; "communication_type" => format!("{:?}", communication_type), "error" => format!("{:?}", e));
+                                }
+                            }
+                            Err(e) => err(communication_type, e),
+                        }
+                    }
+                    CommunicationType::Pause => {
+                        match handlers.pause().read(communicate::DEFAULT_READ_TIMEOUT) {
+                            Ok(Pause {
+                                nix_file,
+                                attr,
+                                paused,
+                            }) => tx_pause
+                                .send(SetPaused {
+                                    nix_file,
+                                    attr,
+                                    paused,
+                                })
+                                .expect("Unable to send a pause from listener"),
+                            Err(e) => err(communication_type, e),
+                        }
+                    }
+                    CommunicationType::Untrack => {
+                        match handlers.untrack().read(communicate::DEFAULT_READ_TIMEOUT) {
+                            Ok(Untrack {
+                                nix_file,
+                                attr,
+                                remove_roots,
+                            }) => tx_untrack
+                                .send(daemon::Untrack {
+                                    nix_file,
+                                    attr,
+                                    remove_roots,
+                                })
+                                .expect("Unable to send an untrack from listener"),
+                            Err(e) => err(communication_type, e),
+                        }
+                    }
+                    CommunicationType::Group => {
+                        let mut rw = handlers.group();
+                        match rw.read(communicate::DEFAULT_READ_TIMEOUT) {
+                            Ok(Group { tag, action }) => {
+                                let (tx_projects, rx_projects) = chan::bounded(1);
+                                tx_build
+                                    .send(LoopHandlerEvent::GetProjects(tx_projects))
+                                    .expect("Unable to ask the build_loop for the projects");
+                                let mut projects = vec![];
+                                for project in rx_projects
+                                    .recv()
+                                    .expect("build_loop did not reply with the projects")
+                                {
+                                    if tagged_config(&project.nix_file, &tag).is_none() {
+                                        continue;
+                                    }
+                                    let nix_file = project.nix_file.clone();
+                                    match action {
+                                        GroupAction::Status => {}
+                                        GroupAction::Rebuild => tx_activity
+                                            .send(IndicateActivity {
+                                                nix_file,
+                                                attr: project.attr.clone(),
+                                                rebuild: Rebuild::Always,
+                                                env: None,
+                                            })
+                                            .expect("Unable to send a ping from listener"),
+                                        GroupAction::Pause | GroupAction::Resume => tx_pause
+                                            .send(SetPaused {
+                                                nix_file: Some(nix_file),
+                                                attr: project.attr.clone(),
+                                                paused: action == GroupAction::Pause,
+                                            })
+                                            .expect("Unable to send a pause from listener"),
+                                    }
+                                    projects.push(project);
+                                }
+                                debug!(logger, "acted on group"; "tag" => &tag, "action" => ?action, "projects" => projects.len());
+                                if let Err(e) =
+                                    rw.write(communicate::DEFAULT_READ_TIMEOUT, &projects)
+                                {
+                                    debug!(logger, "client vanished, closing socket"; "communication_type" => format!("{:?}", communication_type), "error" => format!("{:?}", e));
+                                }
+                            }
                             Err(e) => err(communication_type, e),
                         }
                     }
                     CommunicationType::StreamEvents => {
                         let mut rw = handlers.stream_events();
                         match rw.read(communicate::DEFAULT_READ_TIMEOUT) {
-                            Ok(StreamEvents {}) => {
+                            Ok(StreamEvents { since }) => {
                                 let (tx_event, rx_event) = chan::unbounded();
                                 tx_build
-                                    .send(LoopHandlerEvent::NewListener(tx_event))
+                                    .send(LoopHandlerEvent::NewListener(since, tx_event))
                                     .expect("Unable to send a new listener to the build_loop");
                                 for event in rx_event {
                                     match rw.write(communicate::DEFAULT_READ_TIMEOUT, &event) {
@@ -125,6 +251,71 @@ impl Server {
                             Err(e) => err(communication_type, e),
                         }
                     }
+                    CommunicationType::StreamLogs => {
+                        let mut rw = handlers.stream_logs();
+                        match rw.read(communicate::DEFAULT_READ_TIMEOUT) {
+                            Ok(StreamLogs { nix_file, attr }) => {
+                                let (tx_log, rx_log) = chan::unbounded();
+                                tx_build
+                                    .send(LoopHandlerEvent::NewLogListener(
+                                        (nix_file, attr),
+                                        tx_log,
+                                    ))
+                                    .expect("Unable to send a new log listener to the build_loop");
+                                for event in rx_log {
+                                    if let Err(err) =
+                                        rw.write(communicate::DEFAULT_READ_TIMEOUT, &event)
+                                    {
+                                        debug!(logger, "client vanished, closing socket"; "communication_type" => format!("{:?}", communication_type), "error" => format!("{:?}", err));
+                                        break;
+                                    }
+                                }
+                            }
+                            Err(e) => err(communication_type, e),
+                        }
+                    }
+                    CommunicationType::WatchedPaths => {
+                        let mut rw = handlers.watched_paths();
+                        match rw.read(communicate::DEFAULT_READ_TIMEOUT) {
+                            Ok(WatchedPaths { nix_file, attr }) => {
+                                let (tx_paths, rx_paths) = chan::bounded(1);
+                                tx_build
+                                    .send(LoopHandlerEvent::GetWatchedPaths(
+                                        (nix_file, attr),
+                                        tx_paths,
+                                    ))
+                                    .expect("Unable to ask the build_loop for watched paths");
+                                let paths = rx_paths
+                                    .recv()
+                                    .expect("build_loop did not reply with the watched paths");
+                                if let Err(e) = rw.write(communicate::DEFAULT_READ_TIMEOUT, &paths)
+                                {
+                                    debug!(logger, "client vanished, closing socket"; "communication_type" => format!("{:?}", communication_type), "error" => format!("{:?}", e));
+                                }
+                            }
+                            Err(e) => err(communication_type, e),
+                        }
+                    }
+                    CommunicationType::Projects => {
+                        let mut rw = handlers.projects();
+                        match rw.read(communicate::DEFAULT_READ_TIMEOUT) {
+                            Ok(Projects {}) => {
+                                let (tx_projects, rx_projects) = chan::bounded(1);
+                                tx_build
+                                    .send(LoopHandlerEvent::GetProjects(tx_projects))
+                                    .expect("Unable to ask the build_loop for the projects");
+                                let projects = rx_projects
+                                    .recv()
+                                    .expect("build_loop did not reply with the projects");
+                                if let Err(e) =
+                                    rw.write(communicate::DEFAULT_READ_TIMEOUT, &projects)
+                                {
+                                    debug!(logger, "client vanished, closing socket"; "communication_type" => format!("{:?}", communication_type), "error" => format!("{:?}", e));
+                                }
+                            }
+                            Err(e) => err(communication_type, e),
+                        }
+                    }
                 }
             });
 
@@ -147,6 +338,14 @@ impl Server {
     }
 }
 
+/// The configuration of the project of `nix_file`, if its `tags` include `tag`.
+fn tagged_config(nix_file: &NixFile, tag: &str) -> Option<ProjectConfig> {
+    // an invalid configuration is reported by the project’s build
+    ProjectConfig::load(nix_file.as_absolute_path())
+        .ok()
+        .filter(|config| config.tags.iter().any(|t| t == tag))
+}
+
 /// Join threads continuously, so that we don’t generate too many zombies.
 /// Every new thread it signalled by `rx_new`, while every thread that is finishe
 /// sends its id to `rx_done`.
@@ -185,3 +384,97 @@ fn join_continuously(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::daemon::{BuildStatus, ProjectStatus};
+    use crate::project::config::CONFIG_FILE_NAME;
+    use crate::AbsPathBuf;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn groups_only_contain_the_projects_with_the_tag() -> std::io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let project = |name: &str, config: &str| -> std::io::Result<ProjectStatus> {
+            let project_dir = dir.path().join(name);
+            std::fs::create_dir(&project_dir)?;
+            std::fs::write(project_dir.join("shell.nix"), "{ }")?;
+            std::fs::write(project_dir.join(CONFIG_FILE_NAME), config)?;
+            Ok(ProjectStatus {
+                nix_file: NixFile::from(AbsPathBuf::new(project_dir.join("shell.nix")).unwrap()),
+                attr: None,
+                status: BuildStatus::Success,
+                build_started: SystemTime::now(),
+                last_build_finished: None,
+                last_build_duration: None,
+            })
+        };
+        let mut projects = vec![
+            project("api", "tags = [\"work\"]")?,
+            project("blog", "tags = [\"oss\"]")?,
+            project("web", "tags = [\"oss\", \"work\"]")?,
+            project("scratch", "")?,
+        ];
+        // only this attribute of web’s nix file is paused
+        projects[2].attr = Some(String::from("shells.web"));
+
+        let (tx_activity, _rx_activity) = chan::unbounded();
+        let (tx_pause, rx_pause) = chan::unbounded();
+        let (tx_untrack, _rx_untrack) = chan::unbounded();
+        let (tx_build, rx_build) = chan::unbounded();
+        let server = Server::new(tx_activity, tx_pause, tx_untrack, tx_build);
+        let socket = SocketPath::from(AbsPathBuf::new(dir.path().join("socket")).unwrap());
+        let socket2 = socket.clone();
+        let logger = crate::logging::test_logger();
+        thread::spawn(move || server.listen(&socket2, &logger));
+        // reply like the daemon does
+        let all = projects.clone();
+        thread::spawn(move || {
+            for event in rx_build {
+                if let LoopHandlerEvent::GetProjects(tx) = event {
+                    tx.send(all.clone()).unwrap();
+                }
+            }
+        });
+
+        // the server might not listen yet
+        let client = (0..50)
+            .find_map(|_| {
+                communicate::client::new::<Group>(communicate::DEFAULT_READ_TIMEOUT)
+                    .connect(&socket)
+                    .map_err(|_| thread::sleep(Duration::from_millis(20)))
+                    .ok()
+            })
+            .expect("the server did not start listening");
+        client
+            .write(&Group {
+                tag: String::from("work"),
+                action: GroupAction::Pause,
+            })
+            .unwrap();
+        let tagged: Vec<NixFile> = client
+            .read()
+            .unwrap()
+            .into_iter()
+            .map(|project| project.nix_file)
+            .collect();
+        let work = vec![projects[0].nix_file.clone(), projects[2].nix_file.clone()];
+        assert_eq!(tagged, work);
+        let paused: Vec<(NixFile, Option<String>)> = rx_pause
+            .try_iter()
+            .filter_map(|pause| Some((pause.nix_file?, pause.attr)))
+            .collect();
+        assert_eq!(
+            paused,
+            vec![
+                (projects[0].nix_file.clone(), None),
+                (
+                    projects[2].nix_file.clone(),
+                    Some(String::from("shells.web"))
+                ),
+            ]
+        );
+        Ok(())
+    }
+}
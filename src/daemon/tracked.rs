@@ -0,0 +1,85 @@
+//! Remember which projects the daemon watches,
+//! so that it can watch them again after it is restarted.
+//!
+//! The values of the projects’ `pass_env` variables are not saved,
+//! the evaluation gets them again once a client enters the project.
+
+use crate::daemon::build_queue::ProjectKey;
+use crate::NixFile;
+use std::path::Path;
+
+/// A watched project, as saved in the file.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+struct TrackedProject {
+    nix_file: NixFile,
+    attr: Option<String>,
+}
+
+/// The projects saved in `file` whose nix files still exist.
+///
+/// A missing or unreadable file means there are none.
+pub fn load(file: &Path) -> std::io::Result<Vec<ProjectKey>> {
+    let contents = match std::fs::read_to_string(file) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    let projects: Vec<TrackedProject> = serde_json::from_str(&contents)?;
+    Ok(projects
+        .into_iter()
+        .filter(|project| project.nix_file.as_absolute_path().is_file())
+        .map(|project| (project.nix_file, project.attr))
+        .collect())
+}
+
+/// Save `projects` to `file`, replacing what it contained.
+pub fn save<'a>(
+    file: &Path,
+    projects: impl Iterator<Item = &'a ProjectKey>,
+) -> std::io::Result<()> {
+    let mut projects: Vec<TrackedProject> = projects
+        .map(|(nix_file, attr)| TrackedProject {
+            nix_file: nix_file.clone(),
+            attr: attr.clone(),
+        })
+        .collect();
+    projects.sort_by(|a, b| {
+        (a.nix_file.as_absolute_path(), &a.attr).cmp(&(b.nix_file.as_absolute_path(), &b.attr))
+    });
+    // write to a temporary file first, so that a crash can’t leave half a file
+    let dir = file.parent().unwrap_or_else(|| Path::new("/"));
+    let tmp = tempfile::NamedTempFile::new_in(dir)?;
+    serde_json::to_writer(&tmp, &projects)?;
+    tmp.persist(file).map_err(|e| e.error)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AbsPathBuf;
+
+    #[test]
+    fn saved_projects_are_loaded() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let file = tmp.path().join("tracked_projects.json");
+        assert_eq!(load(&file)?, vec![]);
+
+        let nix_file = |name: &str| NixFile::from(AbsPathBuf::new_unchecked(tmp.path().join(name)));
+        std::fs::write(tmp.path().join("shell.nix"), "{}")?;
+        let projects = [
+            (nix_file("shell.nix"), Some(String::from("dev"))),
+            (nix_file("shell.nix"), None),
+            (nix_file("deleted.nix"), None),
+        ];
+        save(&file, projects.iter())?;
+        assert_eq!(
+            load(&file)?,
+            vec![
+                (nix_file("shell.nix"), None),
+                (nix_file("shell.nix"), Some(String::from("dev"))),
+            ]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,89 @@
+# Turns the environment which `nix print-dev-env --json` recorded for a shell
+# into the same output as the keep-env-hack of ./logged-evaluation.nix:
+# a `bash-export` file with the environment after the shellHook ran,
+# and an (empty) `varmap-v1` file.
+{ devEnv, src, runTimeClosure }:
+let
+  runtimeCfg = import runTimeClosure;
+
+  # the recorded environment references all dependencies of the shell,
+  # so rooting the output keeps them alive
+  devEnvPath = builtins.storePath devEnv;
+  env = builtins.fromJSON (builtins.readFile devEnvPath);
+
+  # Taken from https://github.com/NixOS/nixpkgs/blob/master/lib/strings.nix
+  escapeShellArg = arg: "'${builtins.replaceStrings [ "'" ] [ "'\\''" ] (toString arg)}'";
+
+  declareVariable = name:
+    let
+      var = env.variables.${name};
+    in
+      if var.type == "exported" then "export ${name}=${escapeShellArg var.value}"
+      else if var.type == "var" then "${name}=${escapeShellArg var.value}"
+      else if var.type == "array" then "declare -a ${name}=(${toString (map escapeShellArg var.value)})"
+      # associative arrays are only used by the builder itself
+      else "";
+
+  declareFunction = name: "${name} ()\n{\n${env.bashFunctions.${name}}\n}";
+
+  # shells with `__structuredAttrs` read their attributes from these files,
+  # which `nix print-dev-env` records next to the variables
+  structuredAttrs = env.structuredAttrs or {};
+  attrsJSON = structuredAttrs.".attrs.json" or "";
+  attrsSh = structuredAttrs.".attrs.sh" or "";
+
+  setup = builtins.concatStringsSep "\n" (
+    map declareVariable (builtins.attrNames env.variables)
+    ++ map declareFunction (builtins.attrNames (env.bashFunctions or {}))
+  );
+
+in
+derivation {
+  name = "lorri-dev-env";
+  system = builtins.currentSystem;
+  builder = runtimeCfg.builder;
+  PATH = runtimeCfg.path;
+
+  inherit setup attrsJSON attrsSh;
+  passAsFile = [ "setup" "attrsJSON" "attrsSh" ];
+  devEnv = devEnvPath;
+
+  args = [
+    "-e"
+    (
+      builtins.toFile "lorri-dev-env" ''
+        # the recorded environment overrides `out`, `PATH` and our own files
+        lorriOut="$out"
+        mkdir -p "$lorriOut"
+        touch "$lorriOut/varmap-v1"
+        ln -s "$devEnv" "$lorriOut/dev-env"
+
+        lorriAttrsJSON="$attrsJSONPath"
+        lorriAttrsSh="$attrsShPath"
+
+        source "$setupPath"
+
+        # point to copies of the structured attributes which outlive the build
+        if [ -s "$lorriAttrsJSON" ]; then
+          cp "$lorriAttrsJSON" "$lorriOut/.attrs.json"
+          cp "$lorriAttrsSh" "$lorriOut/.attrs.sh"
+          export NIX_ATTRS_JSON_FILE="$lorriOut/.attrs.json"
+          export NIX_ATTRS_SH_FILE="$lorriOut/.attrs.sh"
+        fi
+
+        # like the keep-env-hack, see ./logged-evaluation.nix
+        export IN_NIX_SHELL=impure
+        export IN_LORRI_SHELL=${escapeShellArg src}
+        if [ "$(type -t runHook)" = function ]; then
+          runHook shellHook;
+        fi;
+
+        export > "$lorriOut/bash-export"
+      ''
+    )
+  ];
+
+  # Because it’s a trivially lightweight drv, we should never substitute.
+  preferLocalBuild = true;
+  allowSubstitutes = false;
+}
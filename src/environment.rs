@@ -0,0 +1,122 @@
+//! The environment a project’s build records, and what changed
+//! in it between two builds.
+
+use crate::project::config::ProjectConfig;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::process::Command;
+
+pub use lorri_client::environment::EnvDiff;
+
+/// Variables bash sets for itself, which are not part of the project’s environment.
+pub const BASH_OWN_VARIABLES: &[&str] = &["BASH_ENV", "SHLVL", "PWD", "OLDPWD", "_"];
+
+/// The variables of an environment, by name.
+pub type Variables = BTreeMap<String, String>;
+
+/// Load the variables of the environment in the GC root `evaluation_root`,
+/// as `lorri direnv` sets them in an otherwise empty environment,
+/// filtered like `config` says.
+///
+/// Bytes which aren’t UTF-8 are replaced, see `load_exact`.
+pub fn load(evaluation_root: &Path, config: &ProjectConfig) -> std::io::Result<Variables> {
+    let output = exported(evaluation_root, config)?;
+    Ok(variables(
+        output
+            .split(|b| *b == 0)
+            .map(|var| String::from_utf8_lossy(var).into_owned()),
+    ))
+}
+
+/// Like `load`, but fails if a variable is not UTF-8 instead of changing it.
+pub fn load_exact(evaluation_root: &Path, config: &ProjectConfig) -> std::io::Result<Variables> {
+    let vars = exported(evaluation_root, config)?
+        .split(|b| *b == 0)
+        .map(|var| {
+            String::from_utf8(var.to_vec()).map_err(|_| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "the variable {} is not UTF-8",
+                        String::from_utf8_lossy(var).split('=').next().unwrap_or("")
+                    ),
+                )
+            })
+        })
+        .collect::<std::io::Result<Vec<String>>>()?;
+    Ok(variables(vars.into_iter()))
+}
+
+/// The exported variables of the environment in `evaluation_root`
+/// as `name=value`, each followed by a NUL byte.
+fn exported(evaluation_root: &Path, config: &ProjectConfig) -> std::io::Result<Vec<u8>> {
+    // print all exported variables, separated by \0
+    let script = format!(
+        r#"
+export PATH=
+export -n EVALUATION_ROOT
+
+{}
+{}
+
+for __lorri_var in $(compgen -e); do
+    printf '%s=%s\0' "$__lorri_var" "${{!__lorri_var}}"
+done
+"#,
+        config.envrc_prelude(),
+        include_str!("./ops/direnv/envrc.bash")
+    );
+    // in an empty environment, so that only the project’s variables are set;
+    // the empty PATH in the script replaces the default one bash sets
+    let output = Command::new("bash")
+        .args(["-c", &script])
+        .env_clear()
+        .env("EVALUATION_ROOT", evaluation_root)
+        .output()?;
+    if !output.status.success() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!(
+                "loading the environment failed: {}",
+                String::from_utf8_lossy(&output.stderr)
+            ),
+        ));
+    }
+    Ok(output.stdout)
+}
+
+/// The project’s variables among the `name=value` pairs `vars`.
+fn variables(vars: impl Iterator<Item = String>) -> Variables {
+    let mut variables = Variables::new();
+    for var in vars {
+        let (name, value) = match var.split_once('=') {
+            Some((name, value)) => (name, value),
+            None => continue,
+        };
+        if BASH_OWN_VARIABLES.contains(&name) || (name == "PATH" && value.is_empty()) {
+            continue;
+        }
+        variables.insert(name.to_string(), value.to_string());
+    }
+    variables
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_environment() -> std::io::Result<()> {
+        let root = tempfile::tempdir()?;
+        std::fs::write(
+            root.path().join("bash-export"),
+            "declare -x FOO=\"foo bar\"\ndeclare -x HOME=\"/homeless-shelter\"\ndeclare -x NIX_BUILD_TOP=\"/build\"\n",
+        )?;
+        let vars = load(root.path(), &ProjectConfig::default())?;
+        assert_eq!(vars.get("FOO").map(|s| s.as_str()), Some("foo bar"));
+        assert!(!vars.contains_key("HOME"));
+        assert!(!vars.contains_key("NIX_BUILD_TOP"));
+        assert!(!vars.contains_key("EVALUATION_ROOT"));
+        Ok(())
+    }
+}
@@ -8,7 +8,9 @@
 #![allow(clippy::single_match)]
 // I don’t think return, .into() is clearer than ?, sorry
 #![allow(clippy::try_err)]
-// triggered by select (TODO: fixed in crossbeam_channel 0.5)
+// triggered by select (TODO: fixed in crossbeam_channel 0.5);
+// newer toolchains know clippy::drop_copy as rustc's dropping_copy_types
+#![allow(renamed_and_removed_lints)]
 #![allow(clippy::drop_copy, clippy::zero_ptr)]
 // Remove clippy checks for stuff that is not even in stable yet (ugh)
 #![allow(clippy::match_like_matches_macro)]
@@ -23,8 +25,10 @@ pub mod builder;
 pub mod cas;
 pub mod changelog;
 pub mod cli;
+pub mod config;
 pub mod constants;
 pub mod daemon;
+pub mod environment;
 pub mod logging;
 pub mod nix;
 pub mod ops;
@@ -36,103 +40,12 @@ pub mod socket;
 pub mod thread;
 pub mod watch;
 
-use std::ffi::OsStr;
 use std::path::{Path, PathBuf};
 
 // OUT_DIR and build_rev.rs are generated by cargo, see ../build.rs
 include!(concat!(env!("OUT_DIR"), "/build_rev.rs"));
 
-/// Path guaranteed to be absolute by construction.
-#[derive(Hash, PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
-pub struct AbsPathBuf(PathBuf);
-
-impl AbsPathBuf {
-    /// Convert from a path to an absolute path.
-    ///
-    /// If the path is not absolute, the original `PathBuf`
-    /// is returned (similar to `OsString.into_string()`)
-    pub fn new(path: PathBuf) -> Result<Self, PathBuf> {
-        if path.is_absolute() {
-            Ok(Self::new_unchecked(path))
-        } else {
-            Err(path)
-        }
-    }
-
-    /// Convert from a known absolute path.
-    ///
-    /// Passing a relative path is a programming bug (unchecked).
-    pub fn new_unchecked(path: PathBuf) -> Self {
-        AbsPathBuf(path)
-    }
-
-    /// The absolute path, as `&Path`.
-    pub fn as_path(&self) -> &Path {
-        &self.0
-    }
-
-    /// Proxy through the `Display` class for `PathBuf`.
-    pub fn display(&self) -> std::path::Display {
-        self.0.display()
-    }
-
-    /// Joins a path to the end of this absolute path.
-    /// If the path is absolute, it will replace this absolute path.
-    pub fn join<P: AsRef<Path>>(&self, pb: P) -> Self {
-        let mut new = self.0.to_owned();
-        new.push(pb);
-        Self::new_unchecked(new)
-    }
-
-    /// Proxy through `with_file_name` for `PathBuf`
-    pub fn with_file_name<S: AsRef<OsStr>>(&self, file_name: S) -> Self {
-        // replacing the file name will never make the path relative
-        Self::new_unchecked(self.0.with_file_name(file_name))
-    }
-}
-
-impl AsRef<Path> for AbsPathBuf {
-    fn as_ref(&self) -> &Path {
-        self.as_path()
-    }
-}
-
-/// A .nix file.
-///
-/// Is guaranteed to have an absolute path by construction.
-#[derive(Hash, PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
-pub struct NixFile(AbsPathBuf);
-
-impl NixFile {
-    /// Absolute path of this file.
-    pub fn as_absolute_path(&self) -> &Path {
-        &self.0.as_path()
-    }
-}
-
-impl NixFile {
-    /// `display` the path.
-    pub fn display(&self) -> std::path::Display {
-        self.0.display()
-    }
-}
-
-impl From<AbsPathBuf> for NixFile {
-    fn from(abs_path: AbsPathBuf) -> Self {
-        NixFile(abs_path)
-    }
-}
-
-impl slog::Value for NixFile {
-    fn serialize(
-        &self,
-        _record: &slog::Record,
-        key: slog::Key,
-        serializer: &mut dyn slog::Serializer,
-    ) -> slog::Result {
-        serializer.emit_arguments(key, &format_args!("{}", self.as_absolute_path().display()))
-    }
-}
+pub use lorri_client::{AbsPathBuf, NixFile, FLAKE_FILE_NAME};
 
 /// A .drv file (generated by `nix-instantiate`).
 #[derive(Hash, PartialEq, Eq, Clone, Debug)]
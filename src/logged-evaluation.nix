@@ -1,4 +1,4 @@
-{ src, runTimeClosure }:
+{ src, runTimeClosure, attr ? null, isFlake ? false, flakeAttr ? null, shellArgs ? {}, keepEnvHack ? true }:
 let
   runtimeCfg = import runTimeClosure;
 
@@ -34,14 +34,47 @@ let
     };
   };
 
+  # Select a dotted attribute path like `shells.dev` from `value`.
+  getAttrPath = path: value:
+    builtins.foldl' (acc: name: acc.${name}) value
+      (builtins.filter builtins.isString (builtins.split "\\." path));
+
+  # For a flake, build its dev shell instead of importing `src`.
+  # Flakes are evaluated by nix itself, so we can’t log the files it reads;
+  # watch the flake’s nix file and lock file instead.
+  flakeShell =
+    let
+      flakeDir = dirOf src;
+      flake =
+        builtins.trace "lorri read: '${toString src}'"
+          (builtins.trace "lorri read: '${toString flakeDir}/flake.lock'"
+            (builtins.getFlake (toString flakeDir)));
+      system = builtins.currentSystem;
+    in
+      if flakeAttr != null
+      then getAttrPath flakeAttr flake
+      else if flake ? devShells.${system}.default
+      then flake.devShells.${system}.default
+      else if flake ? devShell.${system}
+      then flake.devShell.${system}
+      else throw "lorri: ${toString src} has neither devShells.${system}.default nor devShell.${system}, set flake_attr in .lorri.toml";
+
   imported =
     let
       raw = overrides.scopedImport overrides src;
     in
-      if (builtins.isFunction raw)
-      then raw {}
+      if isFlake
+      then flakeShell
+      # like nix-shell, only pass the arguments the function takes
+      else if (builtins.isFunction raw)
+      then raw (builtins.intersectAttrs (builtins.functionArgs raw) shellArgs)
       else raw;
 
+  selected =
+    if attr == null || isFlake
+    then imported
+    else getAttrPath attr imported;
+
   # If you add a .drv to a gc-root, the `.drv` itself is protected
   # from GC, and the parent `drv`s up the tree are also protected.
   # However, the output paths referenced in any of the drvs are NOT
@@ -169,7 +202,17 @@ let
     }
   );
 
-  gc-root = keep-env-hack imported;
+  # With `__structuredAttrs`, the attributes are passed to the builder
+  # in `.attrs.json`/`.attrs.sh` instead of the environment, which the
+  # keep-env-hack can’t dump; tell lorri to use `nix print-dev-env`.
+  structuredAttrs = selected.__structuredAttrs or false;
+
+  # without the keep-env-hack, lorri records the environment
+  # with `nix print-dev-env` (see ./dev-env.nix)
+  gc-root =
+    if structuredAttrs
+    then builtins.trace "lorri structured-attrs" selected
+    else if keepEnvHack then keep-env-hack selected else selected;
 
 in
 gc-root
@@ -1,10 +1,133 @@
 //! Helps instantiate a root slog logger
 
+pub mod rotate;
+
 use crate::cli::{Command, Verbosity};
+use rotate::{LogRotation, RotatingFile};
+use serde_json::{Map, Value};
 use slog::Drain;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How log messages are formatted.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogFormat {
+    /// A timestamp, the level, the message and all its key-value pairs
+    Full,
+    /// Like `Full`, but key-value pairs shared by consecutive messages are grouped
+    Compact,
+    /// One JSON object per line, for log collectors like journald
+    Json,
+}
+
+impl Default for LogFormat {
+    fn default() -> LogFormat {
+        LogFormat::Full
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "full" => Ok(LogFormat::Full),
+            "compact" => Ok(LogFormat::Compact),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("{} not in full,compact,json", s)),
+        }
+    }
+}
+
+/// Log levels for some modules, like `watch=warn,builder=debug`,
+/// and optionally for all others, like `info,watch=warn`.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct LogFilter {
+    /// The level of the modules which are not listed.
+    default: Option<slog::Level>,
+    /// Module paths without the `lorri::` prefix, the most specific first.
+    modules: Vec<(String, slog::Level)>,
+}
+
+impl FromStr for LogFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut filter = LogFilter::default();
+        for directive in s.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            match directive.split_once('=') {
+                None => filter.default = Some(parse_level(directive)?),
+                Some((module, level)) => {
+                    let module = module.trim();
+                    let module = module.strip_prefix("lorri::").unwrap_or(module);
+                    filter
+                        .modules
+                        .push((module.to_string(), parse_level(level.trim())?));
+                }
+            }
+        }
+        filter
+            .modules
+            .sort_by_key(|(module, _)| std::cmp::Reverse(module.len()));
+        Ok(filter)
+    }
+}
+
+impl TryFrom<String> for LogFilter {
+    type Error = String;
 
-/// Instantiate a root logger appropriate for the subcommand
-pub fn root(verbosity: Verbosity, command: &Command) -> slog::Logger {
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl LogFilter {
+    /// The level messages of `module` (e.g. `lorri::watch`) need at least.
+    /// Modules the filter doesn’t list log `info` and up, unless it sets another level.
+    pub fn level(&self, module: &str) -> slog::Level {
+        let module = module.strip_prefix("lorri::").unwrap_or(module);
+        self.modules
+            .iter()
+            .find(|(prefix, _)| {
+                module
+                    .strip_prefix(prefix.as_str())
+                    .map_or(false, |rest| rest.is_empty() || rest.starts_with("::"))
+            })
+            .map(|(_, level)| *level)
+            .or(self.default)
+            .unwrap_or(slog::Level::Info)
+    }
+}
+
+fn parse_level(s: &str) -> Result<slog::Level, String> {
+    match s {
+        "critical" => Ok(slog::Level::Critical),
+        "error" => Ok(slog::Level::Error),
+        "warn" | "warning" => Ok(slog::Level::Warning),
+        "info" => Ok(slog::Level::Info),
+        "debug" => Ok(slog::Level::Debug),
+        "trace" => Ok(slog::Level::Trace),
+        _ => Err(format!("{} not in critical,error,warn,info,debug,trace", s)),
+    }
+}
+
+/// Instantiate a root logger appropriate for the subcommand.
+/// Modules `filter` doesn’t list log at the level of `verbosity`,
+/// unless it sets another one.
+/// A daemon with a `--log-file` rotates it according to `rotation`.
+pub fn root(
+    verbosity: Verbosity,
+    mut filter: LogFilter,
+    format: LogFormat,
+    rotation: LogRotation,
+    command: &Command,
+) -> slog::Logger {
     let level = match verbosity {
         // log only up to info
         Verbosity::DefaultInfo => slog::Level::Info,
@@ -15,33 +138,237 @@ pub fn root(verbosity: Verbosity, command: &Command) -> slog::Logger {
     let log_to = match command {
         // direnv swallows stdout, so we must log to stderr
         Command::Direnv(_) => LogTo::Stderr,
+        // and the shell evaluates the completion script
+        Command::Completions(_) => LogTo::Stderr,
+        // scripts read the result of the build from stdout
+        Command::Build(_) => LogTo::Stderr,
+        // and the environment, e.g. with `eval "$(lorri env)"`
+        Command::Env(_) => LogTo::Stderr,
+        Command::Daemon(opts) => match &opts.log_file {
+            None => LogTo::Stdout,
+            Some(path) => match RotatingFile::open(path.clone(), rotation) {
+                Ok(file) => LogTo::File(file),
+                // there is no logger to report it to yet
+                Err(e) => {
+                    eprintln!("lorri: cannot log to {}: {}", path.display(), e);
+                    LogTo::Stdout
+                }
+            },
+        },
         _ => LogTo::Stdout,
     };
-    lorri_logger(level, log_to)
+    if filter.default.is_none() {
+        filter.default = Some(level);
+    }
+    lorri_logger(filter, log_to, format)
 }
 
 /// Logger that can be used in tests
 pub fn test_logger() -> slog::Logger {
-    lorri_logger(slog::Level::Trace, LogTo::Stderr)
+    lorri_logger(
+        LogFilter {
+            default: Some(slog::Level::Trace),
+            modules: vec![],
+        },
+        LogTo::Stderr,
+        LogFormat::Full,
+    )
 }
 
 /// output to log to
 enum LogTo {
     Stdout,
     Stderr,
+    File(RotatingFile),
 }
 
-fn lorri_logger(level: slog::Level, log_to: LogTo) -> slog::Logger {
-    let decorator = match log_to {
-        LogTo::Stderr => slog_term::TermDecorator::new().stderr().build(),
-        LogTo::Stdout => slog_term::TermDecorator::new().stdout().build(),
-    };
-    let drain = slog_term::FullFormat::new(decorator)
-        .build()
-        .filter_level(level)
+fn lorri_logger(filter: LogFilter, log_to: LogTo, format: LogFormat) -> slog::Logger {
+    match (log_to, format) {
+        (LogTo::Stdout, LogFormat::Json) => logger_with(JsonDrain::new(io::stdout()), filter),
+        (LogTo::Stderr, LogFormat::Json) => logger_with(JsonDrain::new(io::stderr()), filter),
+        (LogTo::File(file), LogFormat::Json) => logger_with(JsonDrain::new(file), filter),
+        (LogTo::Stdout, format) => term_logger(
+            slog_term::TermDecorator::new().stdout().build(),
+            format,
+            filter,
+        ),
+        (LogTo::Stderr, format) => term_logger(
+            slog_term::TermDecorator::new().stderr().build(),
+            format,
+            filter,
+        ),
+        // no colors in files
+        (LogTo::File(file), format) => {
+            term_logger(slog_term::PlainSyncDecorator::new(file), format, filter)
+        }
+    }
+}
+
+fn term_logger<D>(decorator: D, format: LogFormat, filter: LogFilter) -> slog::Logger
+where
+    D: slog_term::Decorator + Send + 'static,
+{
+    match format {
+        LogFormat::Compact => logger_with(slog_term::CompactFormat::new(decorator).build(), filter),
+        LogFormat::Full | LogFormat::Json => {
+            logger_with(slog_term::FullFormat::new(decorator).build(), filter)
+        }
+    }
+}
+
+fn logger_with<D>(drain: D, filter: LogFilter) -> slog::Logger
+where
+    D: Drain<Ok = (), Err = std::io::Error> + Send + 'static,
+{
+    let drain = drain
+        .filter(move |record| record.level().is_at_least(filter.level(record.module())))
         .fuse();
     // This makes all logging go through a mutex. Should logging ever become a bottleneck, consider
     // using slog_async instead.
     let drain = std::sync::Mutex::new(drain).fuse();
     slog::Logger::root(drain, slog::o!())
 }
+
+/// Writes every record as a JSON object on its own line, with the keys
+/// `timestamp`, `level`, `module`, `project` (if the record has one),
+/// `message` and `fields` (all other key-value pairs).
+struct JsonDrain<W> {
+    out: Mutex<W>,
+}
+
+impl<W: Write> JsonDrain<W> {
+    fn new(out: W) -> JsonDrain<W> {
+        JsonDrain {
+            out: Mutex::new(out),
+        }
+    }
+}
+
+impl<W: Write> Drain for JsonDrain<W> {
+    type Ok = ();
+    type Err = io::Error;
+
+    fn log(&self, record: &slog::Record, values: &slog::OwnedKVList) -> io::Result<()> {
+        let mut fields = JsonFields(Map::new());
+        // the record’s own values take precedence over the logger’s
+        slog::KV::serialize(values, record, &mut fields)?;
+        slog::KV::serialize(&record.kv(), record, &mut fields)?;
+        let mut fields = fields.0;
+
+        let mut line = Map::new();
+        line.insert(String::from("timestamp"), Value::from(timestamp()));
+        line.insert(
+            String::from("level"),
+            Value::from(record.level().as_str().to_lowercase()),
+        );
+        line.insert(String::from("module"), Value::from(record.module()));
+        if let Some(project) = fields.remove("project") {
+            line.insert(String::from("project"), project);
+        }
+        line.insert(
+            String::from("message"),
+            Value::from(record.msg().to_string()),
+        );
+        line.insert(String::from("fields"), Value::Object(fields));
+
+        let mut line = serde_json::to_vec(&line)?;
+        line.push(b'\n');
+        // a single write, so that lines of concurrent records don’t mix
+        self.out
+            .lock()
+            .expect("log output lock poisoned")
+            .write_all(&line)
+    }
+}
+
+/// Collects the key-value pairs of a record, keeping numbers and booleans.
+struct JsonFields(Map<String, Value>);
+
+impl JsonFields {
+    fn insert(&mut self, key: slog::Key, value: Value) -> slog::Result {
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+impl slog::Serializer for JsonFields {
+    fn emit_arguments(&mut self, key: slog::Key, val: &fmt::Arguments) -> slog::Result {
+        self.insert(key, Value::from(val.to_string()))
+    }
+
+    fn emit_str(&mut self, key: slog::Key, val: &str) -> slog::Result {
+        self.insert(key, Value::from(val))
+    }
+
+    fn emit_bool(&mut self, key: slog::Key, val: bool) -> slog::Result {
+        self.insert(key, Value::from(val))
+    }
+
+    fn emit_usize(&mut self, key: slog::Key, val: usize) -> slog::Result {
+        self.insert(key, Value::from(val))
+    }
+
+    fn emit_u32(&mut self, key: slog::Key, val: u32) -> slog::Result {
+        self.insert(key, Value::from(val))
+    }
+
+    fn emit_u64(&mut self, key: slog::Key, val: u64) -> slog::Result {
+        self.insert(key, Value::from(val))
+    }
+
+    fn emit_i32(&mut self, key: slog::Key, val: i32) -> slog::Result {
+        self.insert(key, Value::from(val))
+    }
+
+    fn emit_i64(&mut self, key: slog::Key, val: i64) -> slog::Result {
+        self.insert(key, Value::from(val))
+    }
+
+    fn emit_f64(&mut self, key: slog::Key, val: f64) -> slog::Result {
+        self.insert(key, Value::from(val))
+    }
+
+    fn emit_unit(&mut self, key: slog::Key) -> slog::Result {
+        self.insert(key, Value::Null)
+    }
+
+    fn emit_none(&mut self, key: slog::Key) -> slog::Result {
+        self.insert(key, Value::Null)
+    }
+}
+
+/// The current time, like `2020-07-20T12:34:56.789Z`.
+pub fn timestamp() -> String {
+    rfc3339(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default(),
+    )
+}
+
+/// Format a point in time, given as the time since the epoch,
+/// like `2020-07-20T12:34:56.789Z`.
+fn rfc3339(since_epoch: Duration) -> String {
+    let secs = since_epoch.as_secs();
+    // the days since the epoch as a date, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = secs / 86_400 + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs % 86_400 / 3600,
+        secs % 3600 / 60,
+        secs % 60,
+        since_epoch.subsec_millis()
+    )
+}
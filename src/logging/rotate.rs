@@ -0,0 +1,156 @@
+//! A log file which is rotated once it grows too large or too old,
+//! so that a long-running daemon doesn’t fill up the disk.
+//!
+//! `daemon.log` is renamed to `daemon.log.1`, `daemon.log.1` to
+//! `daemon.log.2`, and so on; the oldest file beyond `keep` is removed.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// When to rotate the log file, from the `[log_rotation]` table
+/// of the configuration file.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct LogRotation {
+    /// Rotate the file once it is larger than this many megabytes.
+    pub max_size_mb: Option<u64>,
+    /// Rotate the file once its first message is older than this many days.
+    pub max_age_days: Option<u64>,
+    /// How many rotated files to keep.
+    pub keep: usize,
+}
+
+impl Default for LogRotation {
+    fn default() -> LogRotation {
+        LogRotation {
+            max_size_mb: Some(10),
+            max_age_days: None,
+            keep: 5,
+        }
+    }
+}
+
+/// A log file which rotates itself before a write, see the module docs.
+pub struct RotatingFile {
+    path: PathBuf,
+    rotation: LogRotation,
+    file: File,
+    size: u64,
+    /// When the current file was started.
+    started: SystemTime,
+    /// Whether the last write ended a line, so that the file can be
+    /// rotated without splitting a message.
+    at_line_start: bool,
+}
+
+impl RotatingFile {
+    /// Append to the log file at `path`.
+    pub fn open(path: PathBuf, rotation: LogRotation) -> io::Result<RotatingFile> {
+        let file = append(&path)?;
+        let metadata = file.metadata()?;
+        Ok(RotatingFile {
+            path,
+            rotation,
+            file,
+            size: metadata.len(),
+            started: metadata.created().unwrap_or_else(|_| SystemTime::now()),
+            at_line_start: true,
+        })
+    }
+
+    fn needs_rotation(&self, len: usize) -> bool {
+        let too_large = self.rotation.max_size_mb.map_or(false, |mb| {
+            self.size > 0 && self.size + len as u64 > mb * 1024 * 1024
+        });
+        let too_old = self.rotation.max_age_days.map_or(false, |days| {
+            self.started
+                .elapsed()
+                .map_or(false, |age| age >= Duration::from_secs(days * 24 * 60 * 60))
+        });
+        self.at_line_start && (too_large || too_old)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let rotated = |n: usize| {
+            let mut name = self.path.clone().into_os_string();
+            name.push(format!(".{}", n));
+            PathBuf::from(name)
+        };
+        if self.rotation.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.rotation.keep).rev() {
+                match fs::rename(rotated(n), rotated(n + 1)) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+            fs::rename(&self.path, rotated(1))?;
+        }
+        self.file = append(&self.path)?;
+        self.size = 0;
+        self.started = SystemTime::now();
+        Ok(())
+    }
+}
+
+fn append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.needs_rotation(buf.len()) {
+            // keep logging to the old file rather than losing the message
+            if let Err(e) = self.rotate() {
+                eprintln!("lorri: could not rotate {}: {}", self.path.display(), e);
+                self.started = SystemTime::now();
+            }
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        self.at_line_start = buf[..written]
+            .last()
+            .map_or(self.at_line_start, |b| *b == b'\n');
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Large files are rotated between lines, and only `keep` old files are kept.
+    #[test]
+    fn rotates_by_size() -> io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let path = tmp.path().join("daemon.log");
+        let mut log = RotatingFile::open(
+            path.clone(),
+            LogRotation {
+                max_size_mb: Some(1),
+                max_age_days: None,
+                keep: 2,
+            },
+        )?;
+        let line = vec![b'x'; 600 * 1024];
+        for _ in 0..4 {
+            log.write_all(&line)?;
+            log.write_all(b"\n")?;
+        }
+        assert_eq!(fs::metadata(&path)?.len(), line.len() as u64 + 1);
+        assert_eq!(
+            fs::metadata(tmp.path().join("daemon.log.1"))?.len(),
+            line.len() as u64 + 1
+        );
+        assert!(tmp.path().join("daemon.log.2").exists());
+        assert!(!tmp.path().join("daemon.log.3").exists());
+        Ok(())
+    }
+}
@@ -1,7 +1,10 @@
 use lorri::cli::{Arguments, Command, Internal_, Verbosity};
+use lorri::config::Config;
 use lorri::logging;
 use lorri::ops;
 use lorri::ops::error::ExitError;
+use lorri::project::alias;
+use lorri::project::config::{self, ProjectConfig};
 use lorri::project::Project;
 use lorri::NixFile;
 use lorri::{constants, AbsPathBuf};
@@ -26,13 +29,49 @@ fn main() {
             _n => Verbosity::Debug,
         };
 
+        // the logger is set up with the default format if the configuration is invalid,
+        // so that it can report the problem
+        let config = Config::load();
+        let log_format = opts.log_format.unwrap_or_else(|| {
+            config
+                .as_ref()
+                .map(|config| config.log_format)
+                .unwrap_or_default()
+        });
+        let log_filter = opts.log_filter.clone().unwrap_or_else(|| {
+            config
+                .as_ref()
+                .ok()
+                .and_then(|config| config.log_filter.clone())
+                .unwrap_or_default()
+        });
+        let log_rotation = config
+            .as_ref()
+            .map(|config| config.log_rotation.clone())
+            .unwrap_or_default();
+
         // This logger is asynchronous. It is guaranteed to be flushed upon destruction. By tying
         // its lifetime to this smaller scope, we ensure that it is destroyed before
         // 'std::process::exit' gets called.
-        let logger = logging::root(verbosity, &opts.command);
+        let logger = logging::root(
+            verbosity,
+            log_filter,
+            log_format,
+            log_rotation,
+            &opts.command,
+        );
         debug!(logger, "input options"; "options" => ?opts);
 
-        match run_command(&logger, opts) {
+        let result = config
+            .map_err(|err| ExitError::user_error(anyhow::Error::new(err)))
+            .and_then(|config| {
+                debug!(logger, "configuration"; "config" => ?config);
+                if let Some(backend) = config.nix_backend {
+                    lorri::nix::Backend::configure(backend);
+                }
+                run_command(&logger, opts, &config)
+            });
+        match result {
             Err(err) => {
                 error!(logger, "{}", err.message());
                 err.exitcode()
@@ -48,6 +87,8 @@ fn main() {
     std::process::exit(exit_code);
 }
 
+// human-panic still uses `PanicInfo`, its replacement is newer than our rustc
+#[allow(deprecated)]
 fn install_panic_handler() {
     if let Err(env::VarError::NotPresent) = env::var("LORRI_NO_INSTALL_PANIC_HANDLER") {
         // This returns 101 on panics, see also `ExitError::panic`.
@@ -64,78 +105,272 @@ fn install_signal_handler() {
 }
 
 /// Reads a nix filename given by the user and either returns
-/// the `NixFile` type or exists with a helpful error message
+/// the `NixFile` type (and the attribute the project stands for,
+/// if `shellfile` is its name) or exists with a helpful error message
 /// that instructs the user how to write a minimal `shell.nix`.
-fn find_nix_file(shellfile: &Path) -> Result<NixFile, ExitError> {
-    // use shell.nix from cwd
-    match is_file_in_current_directory(shellfile) {
+fn find_nix_file(shellfile: &Path) -> Result<(NixFile, Option<String>), ExitError> {
+    // without `--shell-file`, the `.lorri.toml` in cwd can declare the nix file
+    let configured = match shellfile == Path::new("shell.nix") {
+        true => {
+            ProjectConfig::load_dir(Path::new("."))
+                .map_err(|err| ExitError::user_error(anyhow::Error::new(err)))?
+                .shell_file
+        }
+        false => None,
+    };
+    let shellfile = configured.as_deref().unwrap_or(shellfile);
+
+    // use shell.nix from cwd, or the flake if the project has no shell.nix,
+    // or in a workspace the project closest to cwd
+    let found = is_file_in_current_directory(shellfile).and_then(|found| match found {
+        None if shellfile == Path::new("shell.nix") => {
+            match is_file_in_current_directory(Path::new(lorri::FLAKE_FILE_NAME))? {
+                None => nearest_workspace_member(),
+                found => Ok(found),
+            }
+        }
+        found => Ok(found),
+    });
+    match found {
         Err(err) => Err(ExitError::temporary(err)),
-        Ok(None) => Err(ExitError::user_error(anyhow::anyhow!(
-            "`{}` does not exist\n\
+        Ok(None) => match named_project(shellfile)? {
+            Some(project) => Ok(project),
+            None => Err(ExitError::user_error(anyhow::anyhow!(
+                "`{}` does not exist\n\
                  You can use the following minimal `shell.nix` to get started:\n\n\
                  {}",
-            shellfile.display(),
-            TRIVIAL_SHELL_SRC
-        ))),
-        Ok(Some(file)) => Ok(NixFile::from(file)),
+                shellfile.display(),
+                TRIVIAL_SHELL_SRC
+            ))),
+        },
+        // one project, however its directory was reached
+        Ok(Some(file)) => Ok((NixFile::from(file).canonical(), None)),
     }
 }
 
-fn create_project(paths: &constants::Paths, shell_nix: NixFile) -> Result<Project, ExitError> {
-    Project::new(shell_nix, &paths.gc_root_dir(), paths.cas_store().clone()).map_err(|err| {
+/// The nix file and attribute of the project named `name` (see `lorri name`),
+/// if it is a name.
+fn named_project(name: &Path) -> Result<Option<(NixFile, Option<String>)>, ExitError> {
+    let name = match name.to_str() {
+        Some(name) if alias::is_valid(name) => name,
+        _ => return Ok(None),
+    };
+    let paths = lorri::ops::get_paths()?;
+    Ok(
+        alias::resolve(name, paths.aliases_file().as_path(), paths.gc_root_dir()).and_then(
+            |(nix_file, attr)| {
+                let nix_file = AbsPathBuf::new(nix_file).ok()?;
+                Some((NixFile::from(nix_file).canonical(), attr))
+            },
+        ),
+    )
+}
+
+/// The nix file of the project `shellfile` stands for, and the attribute
+/// the project builds: `--attr`, or the one its name stands for,
+/// or the one its `.lorri.toml` declares.
+///
+/// The daemon knows projects by both, so commands which tell it
+/// about a project have to agree with the ones which built it.
+fn find_project(
+    shellfile: &Path,
+    attr: &Option<String>,
+) -> Result<(NixFile, Option<String>), ExitError> {
+    let (nix_file, named_attr) = find_nix_file(shellfile)?;
+    let attr = match attr.clone().or(named_attr) {
+        Some(attr) => Some(attr),
+        None => {
+            ProjectConfig::load(nix_file.as_absolute_path())
+                .map_err(|err| ExitError::user_error(anyhow::Error::new(err)))?
+                .attr
+        }
+    };
+    Ok((nix_file, attr))
+}
+
+fn create_project(
+    paths: &constants::Paths,
+    shell_nix: NixFile,
+    attr: Option<String>,
+) -> Result<Project, ExitError> {
+    Project::new_with_attr(
+        shell_nix,
+        attr,
+        paths.gc_root_dir(),
+        paths.cas_store().clone(),
+    )
+    .map_err(|err| {
         ExitError::temporary(anyhow::anyhow!(err).context("Could not set up project paths"))
     })
 }
 
 /// Run the main function of the relevant command.
-fn run_command(logger: &slog::Logger, opts: Arguments) -> Result<(), ExitError> {
+fn run_command(logger: &slog::Logger, opts: Arguments, config: &Config) -> Result<(), ExitError> {
+    // completions are generated while lorri is packaged, where we have no cache directory
+    if let Command::Completions(opts) = &opts.command {
+        return ops::completions(opts.shell);
+    }
+
     let paths = lorri::ops::get_paths()?;
 
-    let with_project = |nix_file| -> std::result::Result<(Project, slog::Logger), ExitError> {
-        let project = create_project(&lorri::ops::get_paths()?, find_nix_file(nix_file)?)?;
+    let with_project = |nix_file,
+                        attr: &Option<String>|
+     -> std::result::Result<(Project, slog::Logger), ExitError> {
+        let (nix_file, attr) = find_project(nix_file, attr)?;
+        let project = create_project(&lorri::ops::get_paths()?, nix_file, attr)?;
         let logger = logger.new(o!("nix_file" => project.nix_file.clone()));
         Ok((project, logger))
     };
 
     match opts.command {
         Command::Info(opts) => {
-            let (project, _logger) = with_project(&opts.nix_file)?;
-            ops::info(project)
+            let (project, logger) = with_project(&opts.nix_file, &opts.attr)?;
+            ops::info(project, opts, &logger)
         }
         Command::Direnv(opts) => {
-            let (project, logger) = with_project(&opts.nix_file)?;
-            ops::direnv(project, /* shell_output */ std::io::stdout(), &logger)
+            let (project, logger) = with_project(&opts.nix_file, &opts.attr)?;
+            ops::direnv(
+                project,
+                /* shell_output */ std::io::stdout(),
+                opts.shell,
+                opts.spawn_daemon,
+                opts.fallback_build,
+                config,
+                &logger,
+            )
         }
         Command::Shell(opts) => {
-            let (project, logger) = with_project(&opts.nix_file)?;
+            let (project, logger) = with_project(&opts.nix_file, &opts.attr)?;
             ops::shell(project, opts, &logger)
         }
 
+        Command::Build(opts) => {
+            let (project, logger) = with_project(&opts.nix_file, &opts.attr)?;
+            ops::build(project, opts, config, &logger)
+        }
+        Command::Env(opts) => {
+            let (project, logger) = with_project(&opts.nix_file, &opts.attr)?;
+            ops::env(project, opts.format, &logger)
+        }
         Command::Watch(opts) => {
-            let (project, logger) = with_project(&opts.nix_file)?;
-            ops::watch(project, opts, &logger)
+            let (project, logger) = with_project(&opts.nix_file, &opts.attr)?;
+            ops::watch(project, opts, config, &logger)
         }
         Command::Daemon(opts) => {
+            // before the signal handler starts its thread, see `block_signal`
+            lorri::daemon::restart::block_signal().expect("Error blocking SIGHUP");
             install_signal_handler();
-            ops::daemon(opts, logger)
+            ops::daemon(opts, config, logger)
         }
         Command::Upgrade(opts) => ops::upgrade(opts, paths.cas_store(), logger),
-        Command::Init => ops::init(TRIVIAL_SHELL_SRC, DEFAULT_ENVRC, logger),
+        Command::Init(opts) => ops::init(opts.template, TRIVIAL_SHELL_SRC, DEFAULT_ENVRC, logger),
+        Command::Rollback(opts) => {
+            let (project, logger) = with_project(&opts.nix_file, &opts.attr)?;
+            ops::rollback(project, opts, &logger)
+        }
+        Command::Diff(opts) => {
+            let (project, _logger) = with_project(&opts.nix_file, &opts.attr)?;
+            ops::diff(project, opts)
+        }
+        Command::Gc(opts) => ops::gc(opts, paths.gc_root_dir(), logger),
+        Command::Du => ops::du(paths.gc_root_dir(), logger),
+        Command::Doctor => ops::doctor(&paths, logger),
+        Command::Completions(_) => unreachable!("completions are printed above"),
+        Command::Logs(mut opts) => {
+            if let Some(project) = opts.project.take() {
+                opts.nix_file = project.into();
+            }
+            let (project, logger) = with_project(&opts.nix_file, &opts.attr)?;
+            if opts.history {
+                ops::build_log(project, opts.follow)
+            } else {
+                ops::logs(project.nix_file, project.attr, opts.follow, &logger)
+            }
+        }
+        Command::Ps(opts) => ops::ps(opts, logger),
+        Command::Status(opts) => {
+            // outside of projects, prompts show nothing
+            let (nix_file, attr) = match find_project(&opts.nix_file, &opts.attr) {
+                Ok(project) => project,
+                Err(_) => return Ok(()),
+            };
+            ops::status(
+                &nix_file,
+                attr.as_deref(),
+                lorri::ops::get_paths()?.gc_root_dir(),
+            )
+        }
+        Command::History(opts) => {
+            let (project, _logger) = with_project(&opts.nix_file, &opts.attr)?;
+            ops::history(project, opts)
+        }
+        Command::Pause(opts) => {
+            let project = match opts.all {
+                true => None,
+                false => Some(find_project(&opts.nix_file, &opts.attr)?),
+            };
+            ops::pause(project, true, logger)
+        }
+        Command::Resume(opts) => {
+            let project = match opts.all {
+                true => None,
+                false => Some(find_project(&opts.nix_file, &opts.attr)?),
+            };
+            ops::pause(project, false, logger)
+        }
+        Command::Untrack(opts) => {
+            let (nix_file, attr) = find_project(
+                opts.project
+                    .as_deref()
+                    .map_or(opts.nix_file.as_path(), Path::new),
+                &opts.attr,
+            )?;
+            ops::untrack(nix_file, attr, opts.remove_roots, logger)
+        }
+        Command::DirenvInstall(opts) => ops::direnv_install(opts, logger),
+        Command::Workspace(opts) => ops::workspace(opts, logger),
+        Command::Group(opts) => ops::group(opts, logger),
+        Command::Name(opts) => {
+            let (nix_file, attr) = find_project(&opts.nix_file, &opts.attr)?;
+            ops::name(
+                &nix_file,
+                attr.as_deref(),
+                opts.name,
+                &lorri::ops::get_paths()?,
+            )
+        }
 
         Command::Internal { command } => match command {
             Internal_::Ping_(opts) => {
-                let nix_file = find_nix_file(&opts.nix_file)?;
-                ops::ping(nix_file, logger)
+                let (nix_file, named_attr) = find_nix_file(&opts.nix_file)?;
+                ops::ping(nix_file, opts.attr.or(named_attr), opts.json, logger)
             }
             Internal_::StartUserShell_(opts) => {
-                let (project, _logger) = with_project(&opts.nix_file)?;
+                let (project, _logger) = with_project(&opts.nix_file, &opts.attr)?;
                 ops::start_user_shell(project, opts)
             }
-            Internal_::StreamEvents_(se) => ops::stream_events(se.kind, logger),
+            Internal_::StreamEvents_(se) => ops::stream_events(se.kind, se.since, logger),
+            Internal_::StreamDiagnostics_ => ops::stream_diagnostics(logger),
+            Internal_::GcRoots_(opts) => ops::gc_roots(opts, paths.gc_root_dir()),
+            Internal_::WatchedPaths_(mut opts) => {
+                let (project, _logger) = with_project(&opts.nix_file, &opts.attr)?;
+                opts.attr = project.attr;
+                ops::watched_paths(opts, project.nix_file, logger)
+            }
         },
     }
 }
 
+/// The member of the workspace the current directory is in which is closest to it.
+fn nearest_workspace_member() -> anyhow::Result<Option<AbsPathBuf>> {
+    let cwd = env::current_dir()?;
+    Ok(config::find_workspace(&cwd).and_then(|(root, config)| {
+        let members = config.workspace_members(&root);
+        config::nearest_member(&members, &cwd)
+            .and_then(|member| AbsPathBuf::new(member.clone()).ok())
+    }))
+}
+
 /// Search for `name` in the current directory.
 /// If `name` is an absolute path and a file, it returns the file.
 /// If it doesn’t exist, returns `None`.
@@ -176,8 +411,8 @@ mod tests {
 
         let out = std::process::Command::new("nix-instantiate")
             // we can’t assume to have a <nixpkgs>, so use bogus-nixpkgs
-            .args(&["-I", &format!("nixpkgs={}", nixpkgs)])
-            .args(&["--expr", TRIVIAL_SHELL_SRC])
+            .args(["-I", &format!("nixpkgs={}", nixpkgs)])
+            .args(["--expr", TRIVIAL_SHELL_SRC])
             .output()?;
         assert!(
             out.status.success(),
@@ -219,4 +454,29 @@ mod tests {
         path.push("this-lorri-specific-file-probably-does-not-exist");
         assert_eq!(None, is_file_in_current_directory(&path).unwrap());
     }
+
+    /// A project reached through a symlinked directory is the same project,
+    /// but a symlinked nix file stays where it is linked.
+    #[test]
+    fn canonical_nix_file() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let tmp = std::fs::canonicalize(tmp.path())?;
+        std::fs::create_dir_all(tmp.join("data/work/project"))?;
+        std::fs::write(tmp.join("data/work/common.nix"), "{}")?;
+        std::os::unix::fs::symlink(tmp.join("data/work"), tmp.join("work"))?;
+        std::os::unix::fs::symlink("../common.nix", tmp.join("data/work/project/shell.nix"))?;
+        let nix_file = |path: PathBuf| NixFile::from(AbsPathBuf::new(path).unwrap()).canonical();
+
+        let expected = nix_file(tmp.join("data/work/project/shell.nix"));
+        assert_eq!(
+            expected.as_absolute_path(),
+            tmp.join("data/work/project/shell.nix")
+        );
+        assert_eq!(nix_file(tmp.join("work/project/shell.nix")), expected);
+        assert_eq!(
+            nix_file(tmp.join("work/project/../project/shell.nix")),
+            expected
+        );
+        Ok(())
+    }
 }
@@ -30,7 +30,7 @@
 //! );
 //! ```
 
-use crate::builder::BuildError;
+use crate::builder::{BuildError, Cancellation, LogLine};
 use crate::osstrlines;
 use crossbeam_channel as chan;
 use slog::debug;
@@ -38,12 +38,116 @@ use std::collections::HashMap;
 use std::ffi::{OsStr, OsString};
 use std::path::{Path, PathBuf};
 use std::process::{ChildStderr, ChildStdout, Command, ExitStatus, Stdio};
+use std::str::FromStr;
+use std::sync::RwLock;
 use std::thread;
 use vec1::Vec1;
 
 /// Construct and combine nix options to pass to nix executables.
 pub mod options;
 
+/// Talk to the nix daemon without running nix commands.
+#[cfg(feature = "nix-daemon")]
+pub mod daemon;
+pub mod diagnostics;
+pub mod flake_lock;
+/// Run nix with limited resources.
+pub mod limits;
+pub mod search_path;
+/// Detect the kind and location of the nix store.
+pub mod store;
+pub mod version;
+
+/// Which nix command line interface lorri uses to evaluate and build.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Backend {
+    /// The traditional `nix-instantiate` and `nix-build` commands.
+    Legacy,
+    /// The `nix` command of nix 2.13 and newer (`nix eval`, `nix build`).
+    NixCommand,
+}
+
+lazy_static::lazy_static! {
+    /// The backend set with `Backend::configure`, detected if there is none.
+    static ref BACKEND: RwLock<Option<Backend>> = RwLock::new(None);
+}
+
+impl Backend {
+    /// The backend to use for this process,
+    /// unless a project’s `nix_backend` setting overrides it.
+    pub fn get() -> Backend {
+        if let Some(backend) = *BACKEND.read().expect("nix backend lock poisoned") {
+            return backend;
+        }
+        *BACKEND
+            .write()
+            .expect("nix backend lock poisoned")
+            .get_or_insert_with(Backend::detect)
+    }
+
+    /// Use `backend` for this process from now on, from the `nix_backend`
+    /// setting of the configuration file (or `LORRI_NIX_BACKEND`).
+    pub fn configure(backend: Backend) {
+        *BACKEND.write().expect("nix backend lock poisoned") = Some(backend);
+    }
+
+    /// Use the legacy commands unless only `nix` is installed.
+    fn detect() -> Backend {
+        if !is_on_path("nix-build") && is_on_path("nix") {
+            Backend::NixCommand
+        } else {
+            Backend::Legacy
+        }
+    }
+
+    /// Arguments every `nix` command needs, because it is still experimental.
+    /// Both flags are as old as `Feature::NixCommand`, which callers require first.
+    pub fn nix_command_arguments() -> Vec<&'static str> {
+        vec![
+            "--extra-experimental-features",
+            "nix-command",
+            // no progress bar, and messages the same way the legacy commands print them
+            "--log-format",
+            "raw",
+        ]
+    }
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "legacy" => Ok(Backend::Legacy),
+            "nix-command" => Ok(Backend::NixCommand),
+            _ => Err(format!("{} not in legacy,nix-command", s)),
+        }
+    }
+}
+
+/// Whether an executable `name` is in one of the directories in `PATH`.
+pub(crate) fn is_on_path(name: &str) -> bool {
+    std::env::var_os("PATH")
+        .map(|path| std::env::split_paths(&path).any(|dir| dir.join(name).is_file()))
+        .unwrap_or(false)
+}
+
+/// How long ssh may take to connect to a remote builder.
+const SSH_CONNECT_TIMEOUT_SECS: u32 = 15;
+
+/// Let unreachable remote builders fail quickly, instead of hanging until
+/// the TCP connection times out. Only applies if `NIX_SSHOPTS` isn’t set already,
+/// and only to builds nix starts itself (the nix daemon uses its own environment).
+pub(crate) fn limit_ssh_connect_time(cmd: &mut Command) {
+    if std::env::var_os("NIX_SSHOPTS").is_none() {
+        cmd.env(
+            "NIX_SSHOPTS",
+            format!("-o ConnectTimeout={}", SSH_CONNECT_TIMEOUT_SECS),
+        );
+    }
+}
+
 /// Execute Nix commands using a builder-pattern abstraction.
 #[derive(Clone)]
 pub struct CallOpts<'a> {
@@ -51,6 +155,9 @@ pub struct CallOpts<'a> {
     attribute: Option<String>,
     argstrs: HashMap<OsString, OsString>,
     extra_options: options::NixOptions,
+    cancellation: Option<Cancellation>,
+    log_lines: Option<chan::Sender<LogLine>>,
+    backend: Backend,
 }
 
 /// Which input to give nix.
@@ -89,7 +196,20 @@ impl From<std::ffi::OsString> for StorePath {
 /// Opaque type to keep a temporary GC root directory alive.
 /// Once it is dropped, the GC root is removed.
 #[derive(Debug)]
-pub struct GcRootTempDir(tempfile::TempDir);
+pub struct GcRootTempDir(#[allow(dead_code)] tempfile::TempDir);
+
+impl GcRootTempDir {
+    /// A handle which keeps the valid store `paths` alive,
+    /// see `store::add_temporary_roots`.
+    pub(crate) fn rooting(
+        paths: &[&Path],
+        logger: &slog::Logger,
+    ) -> Result<GcRootTempDir, BuildError> {
+        let dir = tempfile::TempDir::new()?;
+        store::add_temporary_roots(paths, dir.path(), logger)?;
+        Ok(GcRootTempDir(dir))
+    }
+}
 
 impl<'a> CallOpts<'a> {
     /// Create a CallOpts with the Nix expression `expr`.
@@ -103,22 +223,28 @@ impl<'a> CallOpts<'a> {
     ///   output.unwrap(), 5
     /// );
     /// ```
-    pub fn expression(expr: &str) -> CallOpts {
+    pub fn expression(expr: &str) -> CallOpts<'_> {
         CallOpts {
             input: Input::Expression(expr),
             attribute: None,
             argstrs: HashMap::new(),
             extra_options: options::NixOptions::empty(),
+            cancellation: None,
+            log_lines: None,
+            backend: Backend::get(),
         }
     }
 
     /// Create a CallOpts with the Nix file `nix_file`.
-    pub fn file(nix_file: &Path) -> CallOpts {
+    pub fn file(nix_file: &Path) -> CallOpts<'_> {
         CallOpts {
             input: Input::File(nix_file),
             attribute: None,
             argstrs: HashMap::new(),
             extra_options: options::NixOptions::empty(),
+            cancellation: None,
+            log_lines: None,
+            backend: Backend::get(),
         }
     }
 
@@ -131,6 +257,24 @@ impl<'a> CallOpts<'a> {
         self.extra_options.append(opts)
     }
 
+    /// Kill the nix process when `cancellation` is cancelled.
+    pub fn cancellation(&mut self, cancellation: Cancellation) -> &mut Self {
+        self.cancellation = Some(cancellation);
+        self
+    }
+
+    /// Send every line nix prints on stderr to `tx`, as soon as it is printed.
+    pub fn log_lines(&mut self, tx: chan::Sender<LogLine>) -> &mut Self {
+        self.log_lines = Some(tx);
+        self
+    }
+
+    /// Run nix with `backend` instead of the process’s `Backend::get()`.
+    pub fn backend(&mut self, backend: Backend) -> &mut Self {
+        self.backend = backend;
+        self
+    }
+
     /// Evaluate a sub attribute of the expression. Only supports one:
     /// calling attribute() multiple times is supported, but overwrites
     /// the previous attribute.
@@ -213,13 +357,26 @@ impl<'a> CallOpts<'a> {
     ///     ]
     /// );
     /// ```
-    pub fn value<T: 'static>(&self) -> Result<T, BuildError>
+    pub fn value<T>(&self) -> Result<T, BuildError>
     where
-        T: Send + serde::de::DeserializeOwned,
+        T: 'static + Send + serde::de::DeserializeOwned,
     {
-        let mut cmd = Command::new("nix-instantiate");
-        cmd.args(&["--eval", "--json", "--strict"]);
-        cmd.args(self.command_arguments());
+        let cmd = match self.backend {
+            Backend::Legacy => {
+                let mut cmd = limits::command("nix-instantiate");
+                cmd.args(["--eval", "--json", "--strict"]);
+                cmd.args(self.command_arguments());
+                cmd
+            }
+            Backend::NixCommand => {
+                version::require(version::Feature::NixCommand)?;
+                let mut cmd = limits::command("nix");
+                cmd.args(["eval", "--json", "--impure"]);
+                cmd.args(Backend::nix_command_arguments());
+                cmd.args(self.nix_command_arguments());
+                cmd
+            }
+        };
         self.execute(cmd, move |stdout_handle| {
             serde_json::from_reader::<_, T>(stdout_handle)
         })?
@@ -327,17 +484,40 @@ impl<'a> CallOpts<'a> {
         // which is per-user and (on systemd systems) a tmpfs.
         let gc_root_dir = tempfile::TempDir::new()?;
 
-        let mut cmd = Command::new("nix-build");
+        #[cfg(feature = "nix-daemon")]
+        {
+            if let Some(paths) = self.daemon_build(&gc_root_dir, logger) {
+                return paths.map(|paths| (paths, GcRootTempDir(gc_root_dir)));
+            }
+        }
+
+        let mut cmd = match self.backend {
+            Backend::Legacy => limits::command("nix-build"),
+            Backend::NixCommand => {
+                version::require(version::Feature::NixCommand)?;
+                version::require(version::Feature::PrintOutPaths)?;
+                if self.is_derivation_file() {
+                    version::require(version::Feature::AllOutputs)?;
+                }
+                let mut cmd = limits::command("nix");
+                cmd.args(["build", "--impure", "--print-out-paths"]);
+                cmd.args(Backend::nix_command_arguments());
+                cmd
+            }
+        };
 
         // Create a gc root to the build output
-        cmd.args(&[
+        cmd.args([
             OsStr::new("--out-link"),
             gc_root_dir.path().join(Path::new("result")).as_os_str(),
         ]);
 
-        cmd.args(self.command_arguments());
+        match self.backend {
+            Backend::Legacy => cmd.args(self.command_arguments()),
+            Backend::NixCommand => cmd.args(self.nix_command_arguments()),
+        };
 
-        debug!(logger, "nix-build"; "command" => ?cmd);
+        debug!(logger, "nix build"; "command" => ?cmd);
 
         let paths: Vec<StorePath> = self.execute(cmd, move |stdout_handle| {
             osstrlines::Lines::from(stdout_handle)
@@ -345,6 +525,18 @@ impl<'a> CallOpts<'a> {
                 .collect::<Result<Vec<StorePath>, _>>()
         })??;
 
+        // The out link always points to the realised output, even if nix printed
+        // a path which doesn’t exist (like the output of a content-addressed
+        // derivation before it was resolved); we root what the link points to.
+        let paths = if paths.len() == 1 && !paths[0].as_path().exists() {
+            match std::fs::read_link(gc_root_dir.path().join("result")) {
+                Ok(realised) => vec![StorePath::from(realised.into_os_string())],
+                Err(_) => paths,
+            }
+        } else {
+            paths
+        };
+
         if let Ok(vec1) = Vec1::try_from_vec(paths) {
             Ok((vec1, GcRootTempDir(gc_root_dir)))
         } else {
@@ -354,36 +546,157 @@ impl<'a> CallOpts<'a> {
         }
     }
 
+    /// Build a derivation file with the nix daemon, rooted in `gc_root_dir`
+    /// like `nix-build` roots it, without running `nix-build`.
+    ///
+    /// `None` if the input is not a derivation file, there are options
+    /// the daemon couldn’t apply for us, or the daemon can’t do it.
+    #[cfg(feature = "nix-daemon")]
+    fn daemon_build(
+        &self,
+        gc_root_dir: &tempfile::TempDir,
+        logger: &slog::Logger,
+    ) -> Option<Result<Vec1<StorePath>, BuildError>> {
+        let drv = match self.input {
+            Input::File(path) if path.extension() == Some(OsStr::new("drv")) => path,
+            _ => return None,
+        };
+        if self.attribute.is_some()
+            || !self.argstrs.is_empty()
+            || !self.extra_options.to_nix_arglist().is_empty()
+        {
+            return None;
+        }
+        let mut daemon = daemon::connect(logger)?;
+        if let Some(cancellation) = &self.cancellation {
+            cancellation.register_daemon_connection(daemon.try_clone_stream().ok()?);
+        }
+
+        debug!(logger, "nix daemon build"; "drv" => drv.display());
+        let log_lines = self.log_lines.clone();
+        let built = daemon.build_derivations(&[drv], &mut |line| {
+            if let Some(tx) = &log_lines {
+                let _ = tx.send(line);
+            }
+        });
+        match built {
+            Ok(()) => {}
+            Err(daemon::Error::Nix { msg, mut logs }) => {
+                logs.push(LogLine::from(msg));
+                let cmd = format!("nix daemon: build {}", drv.display());
+                return Some(Err(BuildError::failed(cmd, None, logs)));
+            }
+            // a cancelled build’s connection was shut down
+            Err(e)
+                if self
+                    .cancellation
+                    .as_ref()
+                    .map_or(false, Cancellation::is_cancelled) =>
+            {
+                return Some(Err(BuildError::io(e)))
+            }
+            Err(e) => {
+                debug!(logger, "nix daemon build failed, running nix-build instead"; "error" => %e);
+                return None;
+            }
+        }
+
+        let outputs = match daemon.derivation_outputs(drv) {
+            Ok(outputs) => outputs,
+            Err(e) => {
+                debug!(logger, "nix daemon did not tell the outputs, running nix-build instead"; "error" => %e);
+                return None;
+            }
+        };
+        let mut paths = vec![];
+        for (name, path) in outputs {
+            let path = path?;
+            // named like the out links of `nix-build`
+            let link = match name.as_str() {
+                "out" => gc_root_dir.path().join("result"),
+                _ => gc_root_dir.path().join(format!("result-{}", name)),
+            };
+            let rooted = std::os::unix::fs::symlink(&path, &link)
+                .map_err(daemon::Error::from)
+                .and_then(|()| daemon.add_indirect_root(&link));
+            if let Err(e) = rooted {
+                return Some(Err(BuildError::io(e)));
+            }
+            paths.push(StorePath::from(path.into_os_string()));
+        }
+        Some(Vec1::try_from_vec(paths).map_err(|_| {
+            BuildError::output("expected exactly one Nix output, got zero".to_string())
+        }))
+    }
+
+    /// Record the build environment of the derivation given as file, like `nix develop`.
+    ///
+    /// Returns the store path of the recorded environment (a JSON file in the format
+    /// of `nix print-dev-env --json`), which references all of its dependencies.
+    /// Always uses the `nix` command, whatever the `Backend`.
+    pub fn dev_env(&self, logger: &slog::Logger) -> Result<(StorePath, GcRootTempDir), BuildError> {
+        version::require(version::Feature::PrintDevEnv)?;
+        if self.is_derivation_file() {
+            version::require(version::Feature::AllOutputs)?;
+        }
+        let gc_root_dir = tempfile::TempDir::new()?;
+        let profile = gc_root_dir.path().join("dev-env");
+
+        let mut cmd = limits::command("nix");
+        cmd.args(["print-dev-env", "--json", "--impure"]);
+        cmd.args(Backend::nix_command_arguments());
+        cmd.arg("--profile").arg(&profile);
+        cmd.args(self.nix_command_arguments());
+
+        debug!(logger, "nix print-dev-env"; "command" => ?cmd);
+
+        // the environment is read from the profile, which points to its store path
+        self.execute(cmd, |mut stdout_handle| {
+            std::io::copy(&mut stdout_handle, &mut std::io::sink())
+        })??;
+        let path = std::fs::canonicalize(&profile)?;
+        Ok((
+            StorePath::from(path.into_os_string()),
+            GcRootTempDir(gc_root_dir),
+        ))
+    }
+
     /// Execute a command (presumably a Nix command :)). stderr output
     /// is passed line-based to the CallOpts' stderr_line_tx receiver.
     /// Stdout is passed as a BufReader to `stdout_fn`.
-    fn execute<T: 'static, S: 'static>(
-        &self,
-        mut cmd: Command,
-        stdout_fn: S,
-    ) -> Result<T, BuildError>
+    fn execute<T, S>(&self, mut cmd: Command, stdout_fn: S) -> Result<T, BuildError>
     where
-        S: Send + Fn(std::io::BufReader<ChildStdout>) -> T,
-        T: Send,
+        S: 'static + Send + Fn(std::io::BufReader<ChildStdout>) -> T,
+        T: 'static + Send,
     {
         cmd.stderr(Stdio::piped());
         cmd.stdout(Stdio::piped());
+        limit_ssh_connect_time(&mut cmd);
+        if self.cancellation.is_some() {
+            Cancellation::own_process_group(&mut cmd);
+        }
 
         // 0. spawn the process
         let mut nix_proc = cmd.spawn().map_err(|e| match e.kind() {
             std::io::ErrorKind::NotFound => BuildError::spawn(&cmd, e),
             _ => BuildError::io(e),
         })?;
+        if let Some(cancellation) = &self.cancellation {
+            cancellation.register(&nix_proc);
+        }
 
         // 1. spawn a stderr handling thread
         let (stderr_tx, stderr_rx) = chan::unbounded();
         let stderr_handle: ChildStderr = nix_proc.stderr.take().expect("failed to take stderr");
+        let log_lines = self.log_lines.clone();
         let stderr_thread = thread::spawn(move || {
             let reader = osstrlines::Lines::from(std::io::BufReader::new(stderr_handle));
             for line in reader {
-                stderr_tx
-                    .send(line.unwrap())
-                    .expect("Receiver for nix.rs hung up");
+                let line = line.unwrap();
+                if let Some(tx) = &log_lines {
+                    let _ = tx.send(LogLine::from(line.clone()));
+                }
+                stderr_tx.send(line).expect("Receiver for nix.rs hung up");
             }
         });
 
@@ -455,6 +768,55 @@ impl<'a> CallOpts<'a> {
 
         ret
     }
+
+    /// Whether the input is a derivation file, which `nix build` needs as `drv^*`.
+    fn is_derivation_file(&self) -> bool {
+        match self.input {
+            Input::File(fp) => fp.extension() == Some(OsStr::new("drv")),
+            Input::Expression(_) => false,
+        }
+    }
+
+    /// Like `command_arguments`, but for the `nix` command,
+    /// which takes the attribute as installable after the input.
+    fn nix_command_arguments(&self) -> Vec<OsString> {
+        let mut ret: Vec<OsString> = self
+            .extra_options
+            .to_nix_arglist()
+            .into_iter()
+            .map(OsString::from)
+            .collect();
+
+        for (name, value) in self.argstrs.iter() {
+            ret.push(OsString::from("--argstr"));
+            ret.push(name.clone());
+            ret.push(value.clone());
+        }
+
+        match self.input {
+            Input::Expression(ref exp) => {
+                ret.push(OsString::from("--expr"));
+                ret.push(OsString::from(exp));
+            }
+            // derivations are built directly, with all their outputs
+            Input::File(ref fp) if self.is_derivation_file() => {
+                let mut installable = OsString::from(fp);
+                installable.push("^*");
+                ret.push(installable);
+                return ret;
+            }
+            Input::File(ref fp) => {
+                ret.push(OsString::from("--file"));
+                ret.push(OsString::from(fp));
+            }
+        }
+
+        if let Some(ref attr) = self.attribute {
+            ret.push(OsString::from(attr));
+        }
+
+        ret
+    }
 }
 
 /// Possible error conditions encountered when executing Nix evaluation commands.
@@ -529,7 +891,8 @@ mod tests {
         nix.argstr("foo", "bar");
         nix.extra_options(super::options::NixOptions {
             builders: Some(vec!["user@aarch64.nixos.community aarch64-linux /root/aarch64-build-box/ssh-key 64 1 big-parallel".to_owned(), "sub2".to_owned()]),
-            substituters: None
+            substituters: None,
+            ..super::options::NixOptions::empty()
                 });
         let exp: Vec<&OsStr> = [
             "--builders",
@@ -560,6 +923,7 @@ mod tests {
                 "mysubstituter".to_owned(),
                 "cache.nixos.org".to_owned(),
             ]),
+            ..super::options::NixOptions::empty()
         });
         let exp2: Vec<&OsStr> = [
             "--builders",
@@ -579,4 +943,32 @@ mod tests {
         .collect();
         assert_eq!(exp2, nix2.command_arguments());
     }
+
+    #[test]
+    fn nix_command_arguments_file() {
+        let mut nix = CallOpts::file(Path::new("/my-cool-file.nix"));
+        nix.attribute("hello");
+        nix.argstr("foo", "bar");
+        let exp: Vec<&OsStr> = [
+            "--argstr",
+            "foo",
+            "bar",
+            "--file",
+            "/my-cool-file.nix",
+            "hello",
+        ]
+        .iter()
+        .map(OsStr::new)
+        .collect();
+        assert_eq!(exp, nix.nix_command_arguments());
+    }
+
+    #[test]
+    fn nix_command_arguments_drv() {
+        let nix = CallOpts::file(Path::new("/nix/store/abc-shell.drv"));
+        assert_eq!(
+            vec![OsStr::new("/nix/store/abc-shell.drv^*")],
+            nix.nix_command_arguments()
+        );
+    }
 }
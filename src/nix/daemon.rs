@@ -0,0 +1,574 @@
+//! Talk to the nix daemon with its worker protocol,
+//! instead of running a nix command for every store query.
+//!
+//! Only the few operations lorri needs are implemented.
+//! Callers fall back to the nix commands if the daemon
+//! can’t be reached (e.g. on a single-user installation)
+//! or is too old for an operation.
+
+use super::store::{self, StoreKind};
+use crate::builder::LogLine;
+use slog::debug;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+const WORKER_MAGIC_1: u64 = 0x6e69_7863;
+const WORKER_MAGIC_2: u64 = 0x6478_696f;
+/// The version of the protocol we speak, 1.24 (nix 2.4).
+/// Newer daemons talk to us in this version, older ones in theirs.
+const PROTOCOL_VERSION: u64 = 1 << 8 | 24;
+
+const OP_BUILD_PATHS: u64 = 9;
+const OP_ADD_INDIRECT_ROOT: u64 = 12;
+const OP_QUERY_PATH_INFO: u64 = 26;
+const OP_QUERY_DERIVATION_OUTPUT_MAP: u64 = 41;
+
+const STDERR_NEXT: u64 = 0x6f6c_6d67;
+const STDERR_WRITE: u64 = 0x6461_7416;
+const STDERR_LAST: u64 = 0x616c_7473;
+const STDERR_ERROR: u64 = 0x6378_7470;
+const STDERR_START_ACTIVITY: u64 = 0x5354_5254;
+const STDERR_STOP_ACTIVITY: u64 = 0x5354_4f50;
+const STDERR_RESULT: u64 = 0x5253_4c54;
+
+/// The type of the results which carry a line of a build log.
+const RESULT_BUILD_LOG_LINE: u64 = 101;
+
+/// An error talking to the nix daemon.
+#[derive(Debug, Error)]
+pub enum Error {
+    /// Reading from or writing to the daemon socket failed.
+    #[error("could not talk to the nix daemon: {0}")]
+    Io(#[from] io::Error),
+    /// The daemon sent something we didn’t expect.
+    #[error("unexpected message from the nix daemon: {0:#x}")]
+    Protocol(u64),
+    /// The daemon doesn’t know the operation yet.
+    #[error("the nix daemon speaks protocol 1.{daemon_minor}, {operation} needs 1.{needed_minor}")]
+    Unsupported {
+        /// The operation we tried.
+        operation: &'static str,
+        /// The minor protocol version the daemon speaks.
+        daemon_minor: u64,
+        /// The minor protocol version the operation needs.
+        needed_minor: u64,
+    },
+    /// The daemon tried the operation, but nix failed.
+    #[error("{msg}")]
+    Nix {
+        /// The error nix reported.
+        msg: String,
+        /// What the daemon logged until then.
+        logs: Vec<LogLine>,
+    },
+}
+
+/// What the daemon knows about a valid store path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathInfo {
+    /// The derivation which built the path, if it is known.
+    pub deriver: Option<PathBuf>,
+    /// The store paths the path refers to.
+    pub references: Vec<PathBuf>,
+    /// The size of the path’s NAR serialization in bytes.
+    pub nar_size: u64,
+}
+
+/// A connection to the nix daemon.
+pub struct Connection {
+    reader: BufReader<UnixStream>,
+    writer: BufWriter<UnixStream>,
+    /// The protocol version both sides speak.
+    version: u64,
+}
+
+/// Connect to the daemon of the store `NIX_REMOTE` points to.
+///
+/// `None` if the store is not accessed through a local daemon,
+/// or the daemon can’t be reached.
+pub fn connect(logger: &slog::Logger) -> Option<Connection> {
+    let kind = StoreKind::detect();
+    match kind {
+        // a relocated store has no daemon
+        StoreKind::Local { root: Some(_) } | StoreKind::Remote(_) => return None,
+        StoreKind::Local { root: None } | StoreKind::Daemon => {}
+    }
+    let socket = std::env::var_os("NIX_DAEMON_SOCKET_PATH")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| {
+            store::state_dir(&kind)
+                .as_path()
+                .join("daemon-socket/socket")
+        });
+    match Connection::open(&socket) {
+        Ok(connection) => Some(connection),
+        Err(e) => {
+            debug!(logger, "not using the nix daemon"; "socket" => socket.display(), "error" => %e);
+            None
+        }
+    }
+}
+
+impl Connection {
+    /// Connect to the daemon listening on `socket`.
+    pub fn open(socket: &Path) -> Result<Connection, Error> {
+        Connection::handshake(UnixStream::connect(socket)?)
+    }
+
+    fn handshake(stream: UnixStream) -> Result<Connection, Error> {
+        let mut connection = Connection {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+            version: PROTOCOL_VERSION,
+        };
+        connection.write_u64(WORKER_MAGIC_1)?;
+        connection.flush()?;
+        let magic = connection.read_u64()?;
+        if magic != WORKER_MAGIC_2 {
+            return Err(Error::Protocol(magic));
+        }
+        let daemon_version = connection.read_u64()?;
+        if daemon_version >> 8 != PROTOCOL_VERSION >> 8 {
+            return Err(Error::Protocol(daemon_version));
+        }
+        connection.version = daemon_version.min(PROTOCOL_VERSION);
+        connection.write_u64(PROTOCOL_VERSION)?;
+        if connection.minor() >= 14 {
+            // no CPU affinity
+            connection.write_u64(0)?;
+        }
+        if connection.minor() >= 11 {
+            // don’t reserve space for the garbage collector
+            connection.write_u64(0)?;
+        }
+        connection.flush()?;
+        connection.process_stderr(&mut |_| {})?;
+        Ok(connection)
+    }
+
+    /// A handle to the socket, which can shut the connection down
+    /// while an operation is running.
+    pub fn try_clone_stream(&self) -> io::Result<UnixStream> {
+        self.writer.get_ref().try_clone()
+    }
+
+    /// What the daemon knows about `path`, `None` if it is not a valid path.
+    pub fn query_path_info(&mut self, path: &Path) -> Result<Option<PathInfo>, Error> {
+        self.require("QueryPathInfo", 17)?;
+        self.write_u64(OP_QUERY_PATH_INFO)?;
+        self.write_path(path)?;
+        self.flush()?;
+        self.process_stderr(&mut |_| {})?;
+        if self.read_u64()? == 0 {
+            return Ok(None);
+        }
+        let deriver = self.read_string()?;
+        let _nar_hash = self.read_string()?;
+        let references = self.read_paths()?;
+        let _registration_time = self.read_u64()?;
+        let nar_size = self.read_u64()?;
+        let _ultimate = self.read_u64()?;
+        let _signatures = self.read_paths()?;
+        let _content_address = self.read_string()?;
+        Ok(Some(PathInfo {
+            deriver: if deriver.is_empty() {
+                None
+            } else {
+                Some(PathBuf::from(OsString::from_vec(deriver)))
+            },
+            references,
+            nar_size,
+        }))
+    }
+
+    /// The outputs of the derivation `drv` by name,
+    /// `None` for the ones whose path is not known yet
+    /// (outputs of content-addressed derivations which were not built).
+    pub fn derivation_outputs(
+        &mut self,
+        drv: &Path,
+    ) -> Result<BTreeMap<String, Option<PathBuf>>, Error> {
+        self.require("QueryDerivationOutputMap", 22)?;
+        self.write_u64(OP_QUERY_DERIVATION_OUTPUT_MAP)?;
+        self.write_path(drv)?;
+        self.flush()?;
+        self.process_stderr(&mut |_| {})?;
+        let mut outputs = BTreeMap::new();
+        for _ in 0..self.read_u64()? {
+            let name = String::from_utf8_lossy(&self.read_string()?).into_owned();
+            let path = self.read_string()?;
+            let path = if path.is_empty() {
+                None
+            } else {
+                Some(PathBuf::from(OsString::from_vec(path)))
+            };
+            outputs.insert(name, path);
+        }
+        Ok(outputs)
+    }
+
+    /// Build all outputs of the derivations `drvs`, or substitute them.
+    /// `log` receives the messages and build logs of the daemon.
+    pub fn build_derivations(
+        &mut self,
+        drvs: &[&Path],
+        log: &mut dyn FnMut(LogLine),
+    ) -> Result<(), Error> {
+        self.require("BuildPaths", 15)?;
+        self.write_u64(OP_BUILD_PATHS)?;
+        // a derivation without `!outputs` means all of its outputs
+        self.write_u64(drvs.len() as u64)?;
+        for drv in drvs {
+            self.write_path(drv)?;
+        }
+        // normal build mode
+        self.write_u64(0)?;
+        self.flush()?;
+        self.process_stderr(log)?;
+        self.read_u64()?;
+        Ok(())
+    }
+
+    /// Make the symlink `link` a GC root for the store path it points to,
+    /// like `nix-build --out-link` does.
+    pub fn add_indirect_root(&mut self, link: &Path) -> Result<(), Error> {
+        self.write_u64(OP_ADD_INDIRECT_ROOT)?;
+        self.write_path(link)?;
+        self.flush()?;
+        self.process_stderr(&mut |_| {})?;
+        self.read_u64()?;
+        Ok(())
+    }
+
+    fn minor(&self) -> u64 {
+        self.version & 0xff
+    }
+
+    fn require(&self, operation: &'static str, needed_minor: u64) -> Result<(), Error> {
+        if self.minor() < needed_minor {
+            Err(Error::Unsupported {
+                operation,
+                daemon_minor: self.minor(),
+                needed_minor,
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Read the messages the daemon sends while it works on an operation,
+    /// until it is done.
+    fn process_stderr(&mut self, log: &mut dyn FnMut(LogLine)) -> Result<(), Error> {
+        let mut logs = vec![];
+        let mut emit = |mut line: Vec<u8>, logs: &mut Vec<LogLine>| {
+            if line.last() == Some(&b'\n') {
+                line.pop();
+            }
+            let line = LogLine::from(OsString::from_vec(line));
+            logs.push(line.clone());
+            log(line);
+        };
+        loop {
+            match self.read_u64()? {
+                STDERR_LAST => return Ok(()),
+                STDERR_NEXT => {
+                    let line = self.read_string()?;
+                    emit(line, &mut logs);
+                }
+                STDERR_WRITE => {
+                    self.read_string()?;
+                }
+                STDERR_START_ACTIVITY => {
+                    let _id = self.read_u64()?;
+                    let _level = self.read_u64()?;
+                    let _type = self.read_u64()?;
+                    let text = self.read_string()?;
+                    self.read_fields()?;
+                    let _parent = self.read_u64()?;
+                    if !text.is_empty() {
+                        emit(text, &mut logs);
+                    }
+                }
+                STDERR_STOP_ACTIVITY => {
+                    let _id = self.read_u64()?;
+                }
+                STDERR_RESULT => {
+                    let _id = self.read_u64()?;
+                    let result_type = self.read_u64()?;
+                    let fields = self.read_fields()?;
+                    if result_type == RESULT_BUILD_LOG_LINE {
+                        if let Some(Field::String(line)) = fields.into_iter().next() {
+                            emit(line, &mut logs);
+                        }
+                    }
+                }
+                STDERR_ERROR => {
+                    let msg = self.read_error()?;
+                    return Err(Error::Nix {
+                        msg: String::from_utf8_lossy(&msg).into_owned(),
+                        logs,
+                    });
+                }
+                // including `STDERR_READ`, we never send data the daemon could ask for
+                other => return Err(Error::Protocol(other)),
+            }
+        }
+    }
+
+    /// Read the message of an error, in the format of the protocol version.
+    fn read_error(&mut self) -> Result<Vec<u8>, Error> {
+        if self.minor() < 26 {
+            let msg = self.read_string()?;
+            let _status = self.read_u64()?;
+            return Ok(msg);
+        }
+        let _type = self.read_string()?;
+        let _level = self.read_u64()?;
+        let _name = self.read_string()?;
+        let msg = self.read_string()?;
+        let _have_position = self.read_u64()?;
+        for _ in 0..self.read_u64()? {
+            let _have_position = self.read_u64()?;
+            let _hint = self.read_string()?;
+        }
+        Ok(msg)
+    }
+
+    fn read_fields(&mut self) -> Result<Vec<Field>, Error> {
+        let mut fields = vec![];
+        for _ in 0..self.read_u64()? {
+            match self.read_u64()? {
+                0 => {
+                    self.read_u64()?;
+                    fields.push(Field::Int);
+                }
+                1 => fields.push(Field::String(self.read_string()?)),
+                other => return Err(Error::Protocol(other)),
+            }
+        }
+        Ok(fields)
+    }
+
+    fn read_u64(&mut self) -> io::Result<u64> {
+        let mut buf = [0; 8];
+        self.reader.read_exact(&mut buf)?;
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    /// Strings are their length, followed by their bytes,
+    /// padded with zeroes to a multiple of 8 bytes.
+    fn read_string(&mut self) -> io::Result<Vec<u8>> {
+        let len = self.read_u64()? as usize;
+        let mut buf = vec![0; len + padding(len)];
+        self.reader.read_exact(&mut buf)?;
+        buf.truncate(len);
+        Ok(buf)
+    }
+
+    fn read_paths(&mut self) -> io::Result<Vec<PathBuf>> {
+        (0..self.read_u64()?)
+            .map(|_| {
+                self.read_string()
+                    .map(|s| PathBuf::from(OsString::from_vec(s)))
+            })
+            .collect()
+    }
+
+    fn write_u64(&mut self, n: u64) -> io::Result<()> {
+        self.writer.write_all(&n.to_le_bytes())
+    }
+
+    fn write_path(&mut self, path: &Path) -> io::Result<()> {
+        let bytes = path.as_os_str().as_bytes();
+        self.write_u64(bytes.len() as u64)?;
+        self.writer.write_all(bytes)?;
+        self.writer.write_all(&[0; 8][..padding(bytes.len())])
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// A field of an activity or result.
+enum Field {
+    /// We don’t need the value of any number.
+    Int,
+    String(Vec<u8>),
+}
+
+fn padding(len: usize) -> usize {
+    (8 - len % 8) % 8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The daemon side of a connection.
+    struct Daemon(UnixStream);
+
+    impl Daemon {
+        fn u64(&mut self, n: u64) -> &mut Self {
+            self.0.write_all(&n.to_le_bytes()).unwrap();
+            self
+        }
+
+        fn string(&mut self, s: &str) -> &mut Self {
+            self.u64(s.len() as u64);
+            self.0.write_all(s.as_bytes()).unwrap();
+            self.0.write_all(&[0; 8][..padding(s.len())]).unwrap();
+            self
+        }
+
+        fn read_u64(&mut self) -> u64 {
+            let mut buf = [0; 8];
+            self.0.read_exact(&mut buf).unwrap();
+            u64::from_le_bytes(buf)
+        }
+
+        fn read_string(&mut self) -> String {
+            let len = self.read_u64() as usize;
+            let mut buf = vec![0; len + padding(len)];
+            self.0.read_exact(&mut buf).unwrap();
+            buf.truncate(len);
+            String::from_utf8(buf).unwrap()
+        }
+    }
+
+    /// Connect to a fake daemon speaking protocol 1.`minor`,
+    /// which runs `serve` after the handshake.
+    fn fake_daemon<F>(minor: u64, serve: F) -> (Connection, std::thread::JoinHandle<()>)
+    where
+        F: FnOnce(&mut Daemon) + Send + 'static,
+    {
+        let (client, daemon) = UnixStream::pair().unwrap();
+        let handle = std::thread::spawn(move || {
+            let mut daemon = Daemon(daemon);
+            assert_eq!(daemon.read_u64(), WORKER_MAGIC_1);
+            daemon.u64(WORKER_MAGIC_2).u64(1 << 8 | minor);
+            assert_eq!(daemon.read_u64(), PROTOCOL_VERSION);
+            // CPU affinity and reserve space
+            assert_eq!(daemon.read_u64(), 0);
+            assert_eq!(daemon.read_u64(), 0);
+            daemon.u64(STDERR_LAST);
+            serve(&mut daemon);
+        });
+        (Connection::handshake(client).unwrap(), handle)
+    }
+
+    #[test]
+    fn query_path_info() {
+        let (mut connection, daemon) = fake_daemon(24, |daemon| {
+            assert_eq!(daemon.read_u64(), OP_QUERY_PATH_INFO);
+            assert_eq!(daemon.read_string(), "/nix/store/abc-hello");
+            daemon
+                .u64(STDERR_LAST)
+                .u64(1)
+                .string("/nix/store/def-hello.drv")
+                .string("sha256:0000")
+                .u64(2)
+                .string("/nix/store/abc-hello")
+                .string("/nix/store/ghi-glibc")
+                .u64(1234)
+                .u64(5678)
+                .u64(0)
+                .u64(0)
+                .string("");
+            assert_eq!(daemon.read_u64(), OP_QUERY_PATH_INFO);
+            assert_eq!(daemon.read_string(), "/nix/store/xyz-gone");
+            daemon.u64(STDERR_LAST).u64(0);
+        });
+        assert_eq!(
+            connection
+                .query_path_info(Path::new("/nix/store/abc-hello"))
+                .unwrap(),
+            Some(PathInfo {
+                deriver: Some(PathBuf::from("/nix/store/def-hello.drv")),
+                references: vec![
+                    PathBuf::from("/nix/store/abc-hello"),
+                    PathBuf::from("/nix/store/ghi-glibc")
+                ],
+                nar_size: 5678,
+            })
+        );
+        assert_eq!(
+            connection
+                .query_path_info(Path::new("/nix/store/xyz-gone"))
+                .unwrap(),
+            None
+        );
+        daemon.join().unwrap();
+    }
+
+    #[test]
+    fn build_logs_and_errors() {
+        let (mut connection, daemon) = fake_daemon(24, |daemon| {
+            assert_eq!(daemon.read_u64(), OP_BUILD_PATHS);
+            assert_eq!(daemon.read_u64(), 1);
+            assert_eq!(daemon.read_string(), "/nix/store/abc-shell.drv");
+            assert_eq!(daemon.read_u64(), 0);
+            daemon
+                .u64(STDERR_NEXT)
+                .string("these derivations will be built:\n")
+                .u64(STDERR_START_ACTIVITY)
+                .u64(1)
+                .u64(3)
+                .u64(105)
+                .string("building '/nix/store/abc-shell.drv'")
+                .u64(0)
+                .u64(0)
+                .u64(STDERR_RESULT)
+                .u64(1)
+                .u64(RESULT_BUILD_LOG_LINE)
+                .u64(1)
+                .u64(1)
+                .string("compiling")
+                .u64(STDERR_STOP_ACTIVITY)
+                .u64(1)
+                .u64(STDERR_ERROR)
+                .string("builder failed")
+                .u64(1);
+        });
+        let mut forwarded = vec![];
+        let res = connection
+            .build_derivations(&[Path::new("/nix/store/abc-shell.drv")], &mut |l| {
+                forwarded.push(l.0.into_string().unwrap())
+            });
+        assert_eq!(
+            forwarded,
+            vec![
+                "these derivations will be built:",
+                "building '/nix/store/abc-shell.drv'",
+                "compiling"
+            ]
+        );
+        match res {
+            Err(Error::Nix { msg, logs }) => {
+                assert_eq!(msg, "builder failed");
+                assert_eq!(logs.len(), 3);
+            }
+            other => panic!("{:?}", other),
+        }
+        daemon.join().unwrap();
+    }
+
+    #[test]
+    fn old_daemons_lack_operations() {
+        let (mut connection, daemon) = fake_daemon(21, |_| {});
+        match connection.derivation_outputs(Path::new("/nix/store/abc-shell.drv")) {
+            Err(Error::Unsupported {
+                daemon_minor: 21,
+                needed_minor: 22,
+                ..
+            }) => {}
+            other => panic!("{:?}", other),
+        }
+        daemon.join().unwrap();
+    }
+}
@@ -0,0 +1,159 @@
+//! Find the source positions of errors in nix’s output,
+//! so that editors can point at the broken expression.
+
+use crate::builder::BuildError;
+use regex::Regex;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+
+/// An error (or the context of one) at a position in a nix file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Diagnostic {
+    /// The nix file the position is in.
+    pub file: PathBuf,
+    /// 1-based line number.
+    pub line: usize,
+    /// 1-based column number.
+    pub column: usize,
+    /// What nix said about this position.
+    pub message: String,
+}
+
+/// The positions in nix files the logs of a failed build point at.
+pub fn of_build_error(error: &BuildError) -> Vec<Diagnostic> {
+    match error {
+        BuildError::Exit { logs, .. } | BuildError::RemoteBuilder { logs, .. } => {
+            parse(logs.iter().map(|l| String::from_utf8_lossy(l.0.as_bytes())))
+        }
+        BuildError::Build { error } => of_build_error(error),
+        _ => vec![],
+    }
+}
+
+/// Collect the diagnostics from the stderr lines of a failed nix command.
+///
+/// Understands both the single-line errors of nix 2.3
+/// (`error: undefined variable 'x' at /a/shell.nix:3:5`)
+/// and the multi-line errors of later versions, where the position
+/// follows the message on its own line (`at /a/shell.nix:3:5:`).
+/// Positions which are not in a file (like `«string»`) are skipped.
+pub fn parse<I, S>(lines: I) -> Vec<Diagnostic>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    lazy_static::lazy_static! {
+        static ref POSITION: Regex =
+            Regex::new(r"(?:^|\s)at (?P<file>/[^:]+):(?P<line>\d+):(?P<column>\d+)")
+                .expect("invalid regex!");
+        // the source excerpt nix prints below the position
+        static ref EXCERPT: Regex = Regex::new(r"^\s*\d*\|").expect("invalid regex!");
+    }
+
+    let mut diagnostics: Vec<Diagnostic> = vec![];
+    // the last message seen, which a position on its own line belongs to
+    let mut message = String::new();
+    for line in lines {
+        let line = line.as_ref();
+        if line.trim().is_empty() || EXCERPT.is_match(line) {
+            continue;
+        }
+        match POSITION.captures(line) {
+            Some(pos) => {
+                let prefix = clean_message(&line[..pos.get(0).map_or(0, |m| m.start())]);
+                if !prefix.is_empty() {
+                    message = prefix;
+                }
+                let diagnostic = Diagnostic {
+                    file: PathBuf::from(&pos["file"]),
+                    line: pos["line"].parse().unwrap_or(0),
+                    column: pos["column"].parse().unwrap_or(0),
+                    message: message.clone(),
+                };
+                if !diagnostics.contains(&diagnostic) {
+                    diagnostics.push(diagnostic);
+                }
+            }
+            None => message = clean_message(line),
+        }
+    }
+    diagnostics
+}
+
+/// Strip the decoration nix puts around a message.
+fn clean_message(line: &str) -> String {
+    let line = line.trim();
+    let line = line.strip_prefix("error:").unwrap_or(line).trim_start();
+    let line = line.strip_prefix('…').unwrap_or(line).trim_start();
+    line.trim_end_matches(|c: char| c == ':' || c == ',' || c.is_whitespace())
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn diagnostic(file: &str, line: usize, column: usize, message: &str) -> Diagnostic {
+        Diagnostic {
+            file: PathBuf::from(file),
+            line,
+            column,
+            message: message.to_string(),
+        }
+    }
+
+    #[test]
+    fn nix_2_3_errors() {
+        assert_eq!(
+            parse(vec![
+                "error: while evaluating the attribute 'buildInputs' of the derivation 'x' at /p/shell.nix:2:3:",
+                "undefined variable 'foo' at /p/shell.nix:3:5",
+            ]),
+            vec![
+                diagnostic(
+                    "/p/shell.nix",
+                    2,
+                    3,
+                    "while evaluating the attribute 'buildInputs' of the derivation 'x'"
+                ),
+                diagnostic("/p/shell.nix", 3, 5, "undefined variable 'foo'"),
+            ]
+        );
+        assert_eq!(
+            parse(vec![
+                "error: syntax error, unexpected ID, at /p/shell.nix:4:1"
+            ]),
+            vec![diagnostic(
+                "/p/shell.nix",
+                4,
+                1,
+                "syntax error, unexpected ID"
+            )]
+        );
+    }
+
+    #[test]
+    fn multi_line_errors() {
+        assert_eq!(
+            parse(vec![
+                "error: undefined variable 'foo'",
+                "",
+                "       at /p/shell.nix:3:5:",
+                "",
+                "            2| {",
+                "            3|   x = foo;",
+                "             |     ^",
+                "(use '--show-trace' to show detailed location information)",
+            ]),
+            vec![diagnostic("/p/shell.nix", 3, 5, "undefined variable 'foo'")]
+        );
+        assert_eq!(
+            parse(vec![
+                "error: undefined variable 'foo'",
+                "",
+                "       at «string»:1:1:",
+            ]),
+            vec![]
+        );
+    }
+}
@@ -0,0 +1,105 @@
+//! Read the inputs of a flake from its `flake.lock`.
+//!
+//! nix evaluates flakes itself, so we can’t trace the files it reads;
+//! the lock file tells us which inputs live on the local file system.
+
+use crate::watch::WatchPathBuf;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// File name of a flake’s lock file.
+pub const FLAKE_LOCK_FILE_NAME: &str = "flake.lock";
+
+#[derive(Deserialize)]
+struct FlakeLock {
+    nodes: BTreeMap<String, Node>,
+}
+
+#[derive(Deserialize)]
+struct Node {
+    /// Not set for the flake itself.
+    locked: Option<Locked>,
+}
+
+#[derive(Deserialize)]
+struct Locked {
+    #[serde(rename = "type")]
+    type_: String,
+    /// Only set for `path` inputs.
+    path: Option<PathBuf>,
+}
+
+/// The directories of the `path` inputs of the flake in `flake_dir`.
+///
+/// Empty if the flake has no lock file yet or it can’t be parsed,
+/// the evaluation reports the latter.
+pub fn path_inputs(flake_dir: &Path) -> Vec<WatchPathBuf> {
+    match std::fs::read_to_string(flake_dir.join(FLAKE_LOCK_FILE_NAME)) {
+        Ok(contents) => parse_path_inputs(flake_dir, &contents),
+        Err(_) => vec![],
+    }
+}
+
+fn parse_path_inputs(flake_dir: &Path, contents: &str) -> Vec<WatchPathBuf> {
+    let lock: FlakeLock = match serde_json::from_str(contents) {
+        Ok(lock) => lock,
+        Err(_) => return vec![],
+    };
+    lock.nodes
+        .into_values()
+        .filter_map(|node| node.locked)
+        .filter(|locked| locked.type_ == "path")
+        .filter_map(|locked| locked.path)
+        // relative paths are relative to the flake
+        .map(|path| WatchPathBuf::Recursive(flake_dir.join(path)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn path_inputs_from_lock() {
+        let lock = r#"{
+          "nodes": {
+            "nixpkgs": {
+              "locked": {
+                "lastModified": 1638239011,
+                "narHash": "sha256-AjhmbT4UBlJWqxY0ea8a6GU2C2HdKUREkG43oRr3TZg=",
+                "owner": "NixOS",
+                "repo": "nixpkgs",
+                "rev": "a7ecde854aee5c4c7cd6177f54a99d2c1ff28a31",
+                "type": "github"
+              }
+            },
+            "tools": {
+              "locked": {
+                "narHash": "sha256-Ch2T3fLsnRsNXS2BIV0O4c3rZ/AbsSk7C52u+YV5ybc=",
+                "path": "/home/user/src/tools",
+                "type": "path"
+              }
+            },
+            "sub": {
+              "locked": {
+                "path": "./sub",
+                "type": "path"
+              }
+            },
+            "root": {
+              "inputs": { "nixpkgs": "nixpkgs", "sub": "sub", "tools": "tools" }
+            }
+          },
+          "root": "root",
+          "version": 7
+        }"#;
+        assert_eq!(
+            parse_path_inputs(Path::new("/project"), lock),
+            vec![
+                WatchPathBuf::Recursive(PathBuf::from("/project/sub")),
+                WatchPathBuf::Recursive(PathBuf::from("/home/user/src/tools")),
+            ]
+        );
+        assert_eq!(parse_path_inputs(Path::new("/project"), "{}"), vec![]);
+    }
+}
@@ -0,0 +1,174 @@
+//! Run nix with a lower priority and limited resources,
+//! so that background builds don’t slow the rest of the machine down.
+//!
+//! The daemon sets the limits once when it starts, they apply to
+//! every nix process it evaluates or builds with afterwards.
+//! On multi-user installations the nix daemon runs the builders,
+//! so only the evaluation is limited.
+
+use slog::warn;
+use std::ffi::OsString;
+use std::process::Command;
+use std::str::FromStr;
+use std::sync::RwLock;
+
+/// Limits for the nix processes, from the `[nix_limits]` table
+/// of the configuration file.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct ResourceLimits {
+    /// Run nix with this niceness, e.g. `10`.
+    /// `LORRI_NIX_NICE`.
+    pub nice: Option<i32>,
+    /// Run nix in this I/O scheduling class (Linux only).
+    /// `LORRI_NIX_IONICE`.
+    pub ionice: Option<IoClass>,
+    /// Limit the CPU time nix may use, in percent of one CPU, e.g. `"200%"`.
+    /// Needs `systemd-run`. `LORRI_NIX_CPU_QUOTA`.
+    pub cpu_quota: Option<String>,
+    /// Limit the memory nix may use, e.g. `"4G"`.
+    /// Needs `systemd-run`. `LORRI_NIX_MEMORY_MAX`.
+    pub memory_max: Option<String>,
+}
+
+/// The I/O scheduling classes of `ionice`.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum IoClass {
+    /// Only use the disk when no other process does.
+    Idle,
+    /// The normal class, at its lowest priority.
+    BestEffort,
+}
+
+impl FromStr for IoClass {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "idle" => Ok(IoClass::Idle),
+            "best-effort" => Ok(IoClass::BestEffort),
+            _ => Err(format!("{} not in idle,best-effort", s)),
+        }
+    }
+}
+
+lazy_static::lazy_static! {
+    /// The command line nix programs are run with, see `ResourceLimits::wrapper`.
+    static ref WRAPPER: RwLock<Vec<OsString>> = RwLock::new(vec![]);
+}
+
+/// Run the nix processes started from now on with `limits`.
+/// Limits whose program is not installed are skipped with a warning.
+pub fn apply(limits: &ResourceLimits, logger: &slog::Logger) {
+    let wrapper = limits.wrapper(|program| {
+        let installed = super::is_on_path(program);
+        if !installed {
+            warn!(logger, "cannot limit the nix processes, not installed"; "program" => program);
+        }
+        installed
+    });
+    *WRAPPER.write().expect("nix limits lock poisoned") = wrapper;
+}
+
+/// A `Command` which runs the nix program (or build hook) `program` with the limits.
+pub(crate) fn command(program: &str) -> Command {
+    let wrapper = WRAPPER.read().expect("nix limits lock poisoned");
+    match wrapper.split_first() {
+        None => Command::new(program),
+        Some((first, rest)) => {
+            let mut cmd = Command::new(first);
+            cmd.args(rest).arg(program);
+            cmd
+        }
+    }
+}
+
+impl ResourceLimits {
+    /// The command line which runs the program following it with the limits,
+    /// e.g. `nice -n 10`, using the programs `installed` says are.
+    fn wrapper<F>(&self, mut installed: F) -> Vec<OsString>
+    where
+        F: FnMut(&str) -> bool,
+    {
+        let mut wrapper: Vec<String> = vec![];
+        if (self.cpu_quota.is_some() || self.memory_max.is_some()) && installed("systemd-run") {
+            // a transient scope runs the program itself, in the foreground
+            wrapper.extend(
+                ["systemd-run", "--user", "--scope", "--quiet", "--collect"]
+                    .iter()
+                    .map(|s| s.to_string()),
+            );
+            if let Some(quota) = &self.cpu_quota {
+                wrapper.push(format!("--property=CPUQuota={}", quota));
+            }
+            if let Some(max) = &self.memory_max {
+                wrapper.push(format!("--property=MemoryMax={}", max));
+            }
+            wrapper.push(String::from("--"));
+        }
+        match self.nice {
+            Some(nice) if installed("nice") => {
+                wrapper.extend(vec![
+                    String::from("nice"),
+                    String::from("-n"),
+                    nice.to_string(),
+                ]);
+            }
+            _ => {}
+        }
+        match self.ionice {
+            Some(IoClass::Idle) if installed("ionice") => {
+                wrapper.extend(vec!["ionice", "-c", "3"].into_iter().map(String::from));
+            }
+            Some(IoClass::BestEffort) if installed("ionice") => {
+                wrapper.extend(
+                    vec!["ionice", "-c", "2", "-n", "7"]
+                        .into_iter()
+                        .map(String::from),
+                );
+            }
+            _ => {}
+        }
+        wrapper.into_iter().map(OsString::from).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wrapper_command_line() {
+        let limits = ResourceLimits {
+            nice: Some(10),
+            ionice: Some(IoClass::Idle),
+            cpu_quota: Some(String::from("50%")),
+            memory_max: None,
+        };
+        assert_eq!(
+            limits.wrapper(|_| true),
+            vec![
+                "systemd-run",
+                "--user",
+                "--scope",
+                "--quiet",
+                "--collect",
+                "--property=CPUQuota=50%",
+                "--",
+                "nice",
+                "-n",
+                "10",
+                "ionice",
+                "-c",
+                "3"
+            ]
+        );
+        // without systemd, only the priorities are lowered
+        assert_eq!(
+            limits.wrapper(|program| program != "systemd-run"),
+            vec!["nice", "-n", "10", "ionice", "-c", "3"]
+        );
+        assert!(ResourceLimits::default().wrapper(|_| true).is_empty());
+    }
+}
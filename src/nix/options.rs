@@ -1,3 +1,5 @@
+use std::collections::BTreeMap;
+
 /// These options correspond to the nix options in `man nix.conf`
 /// with the same names, though we only support a subset.
 ///
@@ -14,8 +16,10 @@ pub struct NixOptions {
     ///
     /// * `None`: use the ones configured in the nix config
     /// * `Some([])`: use no substituters
-    /// *`Some(list)`: use exactly `list`
+    /// * `Some(list)`: use exactly `list`
     pub substituters: Option<Vec<String>>,
+    /// Any other options, passed as `--option name value`
+    pub options: BTreeMap<String, String>,
 }
 
 impl NixOptions {
@@ -24,6 +28,7 @@ impl NixOptions {
         NixOptions {
             builders: None,
             substituters: None,
+            options: BTreeMap::new(),
         }
     }
 
@@ -50,11 +55,13 @@ impl NixOptions {
     /// - The `builders` list is appended to on the right (if both exist),
     ///   otherwise the existing one is used (or `None` if both are `None`).
     /// - Same for `substituters`.
+    /// - `options` of `other` override the ones with the same name.
     ///
     /// `empty()` and `append()` form a monoid.
     pub fn append(&mut self, other: Self) {
         Self::extend_option_vec(&mut self.builders, other.builders);
         Self::extend_option_vec(&mut self.substituters, other.substituters);
+        self.options.extend(other.options);
     }
 
     /// At the moment there is no distinction between
@@ -63,6 +70,7 @@ impl NixOptions {
         let Self {
             ref builders,
             ref substituters,
+            ref options,
         } = self;
 
         let mut builders_vec = match builders {
@@ -85,6 +93,9 @@ impl NixOptions {
         };
 
         builders_vec.extend(substituters_vec);
+        for (name, value) in options {
+            builders_vec.extend(vec!["--option".to_owned(), name.clone(), value.clone()]);
+        }
         builders_vec
     }
 }
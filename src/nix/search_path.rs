@@ -0,0 +1,170 @@
+//! Find the places `<…>` lookups through the `NIX_PATH` can change,
+//! so that e.g. `nix-channel --update` triggers a build.
+//!
+//! Channels are symlinks (usually to a nix profile) which point into
+//! the nix store. The store paths never change, but the symlinks are
+//! replaced when the channels are updated, so we watch the directories
+//! containing them.
+
+use crate::watch::WatchPathBuf;
+use std::collections::VecDeque;
+use std::path::{Component, Path, PathBuf};
+
+/// The search path nix uses if `NIX_PATH` is not set.
+fn default_nix_path() -> String {
+    let channels = "/nix/var/nix/profiles/per-user/root/channels";
+    match std::env::var("HOME") {
+        Ok(home) => format!(
+            "{}/.nix-defexpr/channels:nixpkgs={}/nixpkgs:{}",
+            home, channels, channels
+        ),
+        Err(_) => format!("nixpkgs={}/nixpkgs:{}", channels, channels),
+    }
+}
+
+/// The directories to watch for changes of the entries of `nix_path`
+/// (the value of `NIX_PATH`, or `None` if it is not set).
+pub fn watch_paths(nix_path: Option<&str>) -> Vec<WatchPathBuf> {
+    let nix_path = match nix_path {
+        Some(nix_path) => nix_path.to_owned(),
+        None => default_nix_path(),
+    };
+    let mut paths: Vec<WatchPathBuf> = entries(&nix_path)
+        .into_iter()
+        .map(Path::new)
+        // relative entries depend on the working directory of nix, so we can’t know them
+        .filter(|path| path.is_absolute())
+        .flat_map(symlinks)
+        .filter_map(|link| {
+            link.parent()
+                .map(|dir| WatchPathBuf::Normal(dir.to_owned()))
+        })
+        .collect();
+    paths.sort();
+    paths.dedup();
+    paths
+}
+
+/// The paths of the entries of `nix_path`, without their `prefix=`.
+///
+/// Like nix, we don’t split at the colons of URIs.
+/// Entries which are URIs are skipped, since they don’t change on disk.
+fn entries(nix_path: &str) -> Vec<&str> {
+    const URI_PREFIXES: &[&str] = &[
+        "http://", "https://", "file://", "channel:", "git://", "s3://", "ssh://",
+    ];
+    let is_uri = |s: &str| URI_PREFIXES.iter().any(|prefix| s.starts_with(prefix));
+
+    let mut entries = vec![];
+    let mut rest = nix_path;
+    while !rest.is_empty() {
+        // `prefix=path`, where the prefix can’t contain a colon
+        let path = match rest.find(['=', ':']) {
+            Some(i) if rest[i..].starts_with('=') => &rest[i + 1..],
+            _ => rest,
+        };
+        let path_len = match is_uri(path) {
+            // skip the colon of the scheme
+            true => {
+                let scheme_end = path.find(':').unwrap_or(0) + 1;
+                path[scheme_end..]
+                    .find(':')
+                    .map_or(path.len(), |i| scheme_end + i)
+            }
+            false => path.find(':').unwrap_or(path.len()),
+        };
+        let entry = &path[..path_len];
+        if !entry.is_empty() && !is_uri(entry) {
+            entries.push(entry);
+        }
+        rest = path[path_len..].strip_prefix(':').unwrap_or("");
+    }
+    entries
+}
+
+/// The symlinks outside of the nix store which are followed when `path` is resolved.
+fn symlinks(path: &Path) -> Vec<PathBuf> {
+    // like the kernel, give up on symlink loops
+    const MAX_LINKS: usize = 40;
+
+    let mut links = vec![];
+    let mut resolved = PathBuf::new();
+    let mut todo: VecDeque<PathBuf> = path
+        .components()
+        .map(|c| PathBuf::from(c.as_os_str()))
+        .collect();
+    while let Some(component) = todo.pop_front() {
+        // store paths are immutable, nothing below them can change
+        if resolved.starts_with("/nix/store") || links.len() > MAX_LINKS {
+            break;
+        }
+        match component.components().next() {
+            Some(Component::RootDir) => resolved.push("/"),
+            Some(Component::ParentDir) => {
+                resolved.pop();
+            }
+            Some(Component::Normal(name)) => {
+                let next = resolved.join(name);
+                match std::fs::read_link(&next) {
+                    Ok(target) => {
+                        links.push(next);
+                        // an absolute target starts over at the root
+                        for c in target.components().rev() {
+                            todo.push_front(PathBuf::from(c.as_os_str()));
+                        }
+                    }
+                    Err(_) => resolved = next,
+                }
+            }
+            _ => {}
+        }
+    }
+    links
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nix_path_entries() {
+        assert_eq!(
+            entries("nixpkgs=/home/u/nixpkgs:/nix/var/nix/profiles/per-user/root/channels"),
+            vec![
+                "/home/u/nixpkgs",
+                "/nix/var/nix/profiles/per-user/root/channels"
+            ]
+        );
+        assert_eq!(
+            entries("nixpkgs=https://example.com/nixpkgs.tar.gz:foo=channel:nixos-21.05:/etc/nix"),
+            vec!["/etc/nix"]
+        );
+        assert_eq!(entries(""), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn channel_symlinks() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let profiles = tmp.path().join("profiles");
+        let defexpr = tmp.path().join("defexpr");
+        std::fs::create_dir_all(profiles.join("channels-1/nixpkgs"))?;
+        std::fs::create_dir(&defexpr)?;
+        std::os::unix::fs::symlink("channels-1", profiles.join("channels"))?;
+        std::os::unix::fs::symlink(profiles.join("channels"), defexpr.join("channels"))?;
+
+        let nix_path = format!("nixpkgs={}", defexpr.join("channels/nixpkgs").display());
+        assert_eq!(
+            watch_paths(Some(&nix_path)),
+            vec![
+                WatchPathBuf::Normal(defexpr.clone()),
+                WatchPathBuf::Normal(profiles.clone())
+            ]
+        );
+        // a plain directory can’t be switched
+        assert_eq!(
+            watch_paths(Some(&profiles.join("channels-1").display().to_string())),
+            vec![]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,367 @@
+//! Find out which nix store lorri talks to, and where it keeps its state.
+
+use crate::builder::BuildError;
+use crate::osstrlines;
+use crate::AbsPathBuf;
+use slog::debug;
+use std::ffi::OsStr;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+
+/// How the nix store is accessed, as configured by `NIX_REMOTE`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StoreKind {
+    /// The store is accessed directly (single-user installation).
+    /// A relocated store (e.g. `local?root=/home/me/nix`) has
+    /// its files below `root` instead of `/`.
+    Local {
+        /// The directory the store is relocated to, if any.
+        root: Option<PathBuf>,
+    },
+    /// The store is accessed through the local nix daemon
+    /// (multi-user installation).
+    Daemon,
+    /// The store lives on another machine (e.g. `ssh://host`),
+    /// so our GC roots can’t protect anything in it.
+    Remote(String),
+}
+
+impl StoreKind {
+    /// Detect the store kind from the `NIX_REMOTE` environment variable.
+    pub fn detect() -> StoreKind {
+        StoreKind::from_nix_remote(std::env::var_os("NIX_REMOTE").as_deref())
+    }
+
+    /// Interpret a value of `NIX_REMOTE`.
+    pub fn from_nix_remote(nix_remote: Option<&OsStr>) -> StoreKind {
+        let nix_remote = match nix_remote {
+            None => return StoreKind::Local { root: None },
+            Some(r) => r.to_string_lossy(),
+        };
+        // a store at a different root, e.g. `/home/me/nix`
+        if nix_remote.starts_with('/') {
+            return StoreKind::Local {
+                root: Some(PathBuf::from(nix_remote.as_ref())),
+            };
+        }
+        let mut scheme_and_params = nix_remote.splitn(2, '?');
+        let scheme = scheme_and_params
+            .next()
+            .unwrap_or("")
+            .split("://")
+            .next()
+            .unwrap_or("");
+        let root = scheme_and_params.next().and_then(|params| {
+            params
+                .split('&')
+                .find_map(|param| param.strip_prefix("root="))
+                .map(PathBuf::from)
+        });
+        match scheme {
+            "" | "local" => StoreKind::Local { root },
+            // `auto` uses the daemon if the store isn’t writable,
+            // which is the case we have to be prepared for
+            "daemon" | "unix" | "auto" => StoreKind::Daemon,
+            _ => StoreKind::Remote(nix_remote.into_owned()),
+        }
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref STORE_DIR: PathBuf = query_store_dir();
+}
+
+/// The nix store directory (usually `/nix/store`).
+pub fn store_dir() -> &'static Path {
+    &STORE_DIR
+}
+
+fn query_store_dir() -> PathBuf {
+    if let Some(dir) = std::env::var_os("NIX_STORE_DIR") {
+        return PathBuf::from(dir);
+    }
+    super::CallOpts::expression("builtins.storeDir")
+        .value::<String>()
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| PathBuf::from("/nix/store"))
+}
+
+/// The nix state directory (usually `/nix/var/nix`) of the store,
+/// which contains the `gcroots` directory.
+///
+/// Respects `NIX_STATE_DIR`, otherwise the state directory
+/// is next to the store, like nix does it by default.
+pub fn state_dir(kind: &StoreKind) -> AbsPathBuf {
+    if let Some(dir) =
+        std::env::var_os("NIX_STATE_DIR").and_then(|dir| AbsPathBuf::new(PathBuf::from(dir)).ok())
+    {
+        return dir;
+    }
+    let state_dir = state_dir_for_store(store_dir());
+    match kind {
+        StoreKind::Local { root: Some(root) } if root.is_absolute() => AbsPathBuf::new_unchecked(
+            root.join(
+                state_dir
+                    .as_path()
+                    .strip_prefix("/")
+                    .unwrap_or(state_dir.as_path()),
+            ),
+        ),
+        _ => state_dir,
+    }
+}
+
+/// The default state directory for a store at `store_dir`,
+/// e.g. `/nix/var/nix` for `/nix/store`.
+fn state_dir_for_store(store_dir: &Path) -> AbsPathBuf {
+    match store_dir.parent() {
+        Some(prefix) if store_dir.is_absolute() => {
+            AbsPathBuf::new_unchecked(prefix.join("var/nix"))
+        }
+        _ => AbsPathBuf::new_unchecked(PathBuf::from("/nix/var/nix")),
+    }
+}
+
+/// Run `nix-store --query` with `args` on `paths`, returning the printed lines.
+pub fn query(
+    args: &[&OsStr],
+    paths: &[&Path],
+    logger: &slog::Logger,
+) -> Result<Vec<PathBuf>, BuildError> {
+    let mut cmd = super::limits::command("nix-store");
+    cmd.arg("--query")
+        .args(args)
+        .args(paths)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit());
+    debug!(logger, "nix-store"; "command" => ?cmd);
+    let output = cmd.output().map_err(|e| match e.kind() {
+        std::io::ErrorKind::NotFound => BuildError::spawn(&cmd, e),
+        _ => BuildError::io(e),
+    })?;
+    if !output.status.success() {
+        return Err(BuildError::exit(&cmd, output.status, vec![]));
+    }
+    osstrlines::Lines::from(BufReader::new(&output.stdout[..]))
+        .map(|line| line.map(PathBuf::from))
+        .collect::<Result<Vec<PathBuf>, _>>()
+        .map_err(BuildError::io)
+}
+
+/// Ask the nix daemon with `query`.
+/// `None` if it can’t answer, then `nix-store` has to
+/// (which also reports invalid paths the way users know).
+#[cfg(feature = "nix-daemon")]
+fn ask_daemon<T, F>(logger: &slog::Logger, query: F) -> Option<T>
+where
+    F: FnOnce(&mut super::daemon::Connection) -> Result<Option<T>, super::daemon::Error>,
+{
+    let mut connection = super::daemon::connect(logger)?;
+    match query(&mut connection) {
+        Ok(answer) => answer,
+        Err(e) => {
+            debug!(logger, "nix daemon query failed, running nix-store instead"; "error" => %e);
+            None
+        }
+    }
+}
+
+/// The store paths `path` refers to.
+pub fn references(path: &Path, logger: &slog::Logger) -> Result<Vec<PathBuf>, BuildError> {
+    #[cfg(feature = "nix-daemon")]
+    {
+        if let Some(references) = ask_daemon(logger, |daemon| {
+            Ok(daemon.query_path_info(path)?.map(|info| info.references))
+        }) {
+            return Ok(references);
+        }
+    }
+    query(&[OsStr::new("--references")], &[path], logger)
+}
+
+/// The output paths of the derivations `drvs`.
+///
+/// The outputs of content-addressed derivations are only known once
+/// they are built, nix looks them up in the realisations of the store.
+/// Derivations which were never built here (e.g. because their outputs were
+/// substituted away) have none, which fails the whole query; in that case
+/// every derivation is queried on its own and the unknown ones are skipped.
+pub fn realised_outputs(drvs: &[&Path], logger: &slog::Logger) -> Result<Vec<PathBuf>, BuildError> {
+    #[cfg(feature = "nix-daemon")]
+    {
+        if let Some(outputs) = ask_daemon(logger, |daemon| {
+            let mut outputs = vec![];
+            for drv in drvs {
+                outputs.extend(
+                    daemon
+                        .derivation_outputs(drv)?
+                        .into_iter()
+                        .flat_map(|(_, path)| path),
+                );
+            }
+            Ok(Some(outputs))
+        }) {
+            return Ok(outputs);
+        }
+    }
+    match query(&[OsStr::new("--outputs")], drvs, logger) {
+        Ok(outputs) => Ok(outputs),
+        Err(e @ BuildError::Spawn { .. }) => Err(e),
+        Err(_) if drvs.len() > 1 => {
+            let mut outputs = vec![];
+            for drv in drvs {
+                outputs.extend(realised_outputs(&[drv], logger)?);
+            }
+            Ok(outputs)
+        }
+        Err(e) => {
+            debug!(logger, "derivation has no known outputs"; "drv" => ?drvs, "error" => %e);
+            Ok(vec![])
+        }
+    }
+}
+
+/// Keep the valid store `paths` alive while the directory `dir` exists,
+/// with indirect GC roots in it like the ones `nix-build` adds for its results.
+///
+/// `nix-store` would build derivations to root them, so it skips them;
+/// they stay alive as long as their outputs do (unless `keep-derivations`
+/// is turned off). The nix daemon can root them as well.
+pub fn add_temporary_roots(
+    paths: &[&Path],
+    dir: &Path,
+    logger: &slog::Logger,
+) -> Result<(), BuildError> {
+    if let StoreKind::Remote(_) = StoreKind::detect() {
+        // the remote store can’t see our roots
+        return Ok(());
+    }
+    #[cfg(feature = "nix-daemon")]
+    {
+        if let Some(()) = ask_daemon(logger, |daemon| {
+            for (i, path) in paths.iter().enumerate() {
+                let link = dir.join(format!("daemon-root-{}", i));
+                std::os::unix::fs::symlink(path, &link)?;
+                daemon.add_indirect_root(&link)?;
+            }
+            Ok(Some(()))
+        }) {
+            return Ok(());
+        }
+    }
+    let paths: Vec<&Path> = paths
+        .iter()
+        .filter(|path| path.extension() != Some(OsStr::new("drv")))
+        .cloned()
+        .collect();
+    if paths.is_empty() {
+        return Ok(());
+    }
+    // realising a path which is valid already does nothing;
+    // the roots are called `root`, `root-2`, …
+    let mut cmd = super::limits::command("nix-store");
+    cmd.arg("--realise")
+        .args(&paths)
+        .arg("--add-root")
+        .arg(dir.join("root"))
+        .arg("--indirect")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::inherit());
+    debug!(logger, "nix-store"; "command" => ?cmd);
+    let status = cmd.status().map_err(|e| match e.kind() {
+        std::io::ErrorKind::NotFound => BuildError::spawn(&cmd, e),
+        _ => BuildError::io(e),
+    })?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(BuildError::exit(&cmd, status, vec![]))
+    }
+}
+
+/// The combined size of the closure of `paths` in bytes.
+///
+/// Paths shared between the closures are only counted once.
+pub fn closure_size(paths: &[&Path], logger: &slog::Logger) -> Result<u64, BuildError> {
+    if paths.is_empty() {
+        return Ok(0);
+    }
+    #[cfg(feature = "nix-daemon")]
+    {
+        if let Some(total) = ask_daemon(logger, |daemon| {
+            let mut seen: std::collections::HashSet<PathBuf> =
+                paths.iter().map(|path| path.to_path_buf()).collect();
+            let mut todo: Vec<PathBuf> = seen.iter().cloned().collect();
+            let mut total = 0;
+            while let Some(path) = todo.pop() {
+                let info = match daemon.query_path_info(&path)? {
+                    Some(info) => info,
+                    None => return Ok(None),
+                };
+                total += info.nar_size;
+                for reference in info.references {
+                    if seen.insert(reference.clone()) {
+                        todo.push(reference);
+                    }
+                }
+            }
+            Ok(Some(total))
+        }) {
+            return Ok(total);
+        }
+    }
+    let closure = query(&[OsStr::new("--requisites")], paths, logger)?;
+    let closure: Vec<&Path> = closure.iter().map(|p| p.as_path()).collect();
+    let mut total = 0;
+    // `--size` prints the NAR size of each path on its own line
+    for size in query(&[OsStr::new("--size")], &closure, logger)? {
+        total += size
+            .to_str()
+            .and_then(|s| s.parse::<u64>().ok())
+            .ok_or_else(|| {
+                BuildError::output(format!("nix-store printed an invalid size: {:?}", size))
+            })?;
+    }
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nix_remote_kinds() {
+        let kind = |s: &str| StoreKind::from_nix_remote(Some(OsStr::new(s)));
+        let local = |root: Option<&str>| StoreKind::Local {
+            root: root.map(PathBuf::from),
+        };
+        assert_eq!(StoreKind::from_nix_remote(None), local(None));
+        assert_eq!(kind(""), local(None));
+        assert_eq!(kind("local"), local(None));
+        assert_eq!(kind("local?root=/home/me/nix"), local(Some("/home/me/nix")));
+        assert_eq!(kind("/home/me/nix"), local(Some("/home/me/nix")));
+        assert_eq!(kind("daemon"), StoreKind::Daemon);
+        assert_eq!(
+            kind("unix:///nix/var/nix/daemon-socket/socket"),
+            StoreKind::Daemon
+        );
+        assert_eq!(
+            kind("ssh://builder"),
+            StoreKind::Remote("ssh://builder".to_string())
+        );
+    }
+
+    #[test]
+    fn state_dir_next_to_store() {
+        assert_eq!(
+            state_dir_for_store(Path::new("/nix/store")).as_path(),
+            Path::new("/nix/var/nix")
+        );
+        assert_eq!(
+            state_dir_for_store(Path::new("/opt/nix/store")).as_path(),
+            Path::new("/opt/nix/var/nix")
+        );
+    }
+}
@@ -0,0 +1,241 @@
+//! Find out which implementation and version of nix is installed,
+//! and whether it supports what lorri wants to use.
+
+use std::fmt;
+use std::process::Command;
+use thiserror::Error;
+
+/// The implementations of nix, which differ in version numbers and flags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flavor {
+    /// Nix from https://nixos.org
+    Nix,
+    /// Lix, a fork of nix 2.18 which starts counting at 2.90
+    Lix,
+    /// Determinate Nix, which prints the nix version it is based on
+    Determinate,
+}
+
+impl fmt::Display for Flavor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Flavor::Nix => "Nix",
+            Flavor::Lix => "Lix",
+            Flavor::Determinate => "Determinate Nix",
+        })
+    }
+}
+
+/// The installed nix, as printed by `nix --version`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NixVersion {
+    /// Which implementation it is.
+    pub flavor: Flavor,
+    /// The version number, e.g. `(2, 18, 1)`;
+    /// for Determinate Nix the version of nix it is based on.
+    pub version: (u32, u32, u32),
+}
+
+impl fmt::Display for NixVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (major, minor, patch) = self.version;
+        write!(f, "{} {}.{}.{}", self.flavor, major, minor, patch)
+    }
+}
+
+/// Things lorri can only do with new enough versions of nix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Feature {
+    /// The `nix` command (`nix eval`, `nix build`), see `Backend::NixCommand`.
+    NixCommand,
+    /// Projects with a `flake.nix`.
+    Flakes,
+    /// `nix print-dev-env --json`, for `env_backend = "print-dev-env"`.
+    PrintDevEnv,
+    /// `nix build --print-out-paths`, which the `nix` command backend builds with.
+    PrintOutPaths,
+    /// Building all outputs of a derivation file with `nix build drv^*`.
+    AllOutputs,
+}
+
+impl Feature {
+    /// Every feature, oldest first.
+    pub const ALL: [Feature; 5] = [
+        Feature::NixCommand,
+        Feature::Flakes,
+        Feature::PrintDevEnv,
+        Feature::PrintOutPaths,
+        Feature::AllOutputs,
+    ];
+
+    /// The oldest version of nix which has the feature.
+    /// Lix and Determinate Nix have all of them.
+    pub fn min_version(self) -> (u32, u32, u32) {
+        match self {
+            // nix 2.3 has a `nix` command, but not `--extra-experimental-features`
+            Feature::NixCommand => (2, 4, 0),
+            Feature::Flakes => (2, 4, 0),
+            // `nix print-dev-env` is from 2.4, its `--json` from 2.8
+            Feature::PrintDevEnv => (2, 8, 0),
+            Feature::PrintOutPaths => (2, 8, 0),
+            Feature::AllOutputs => (2, 13, 0),
+        }
+    }
+
+    /// What to do instead of using the feature.
+    fn alternative(self) -> &'static str {
+        match self {
+            Feature::NixCommand => "set `nix_backend = \"legacy\"` in .lorri.toml",
+            Feature::Flakes => "use a shell.nix instead of the flake",
+            Feature::PrintDevEnv => "remove `env_backend` from .lorri.toml",
+            Feature::PrintOutPaths | Feature::AllOutputs => {
+                "set `nix_backend = \"legacy\"` in .lorri.toml"
+            }
+        }
+    }
+}
+
+impl fmt::Display for Feature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Feature::NixCommand => "the `nix` command",
+            Feature::Flakes => "flakes",
+            Feature::PrintDevEnv => "`nix print-dev-env`",
+            Feature::PrintOutPaths => "`nix build --print-out-paths`",
+            Feature::AllOutputs => "building all outputs with `nix build drv^*`",
+        })
+    }
+}
+
+/// The installed nix does not support a feature lorri needs.
+#[derive(Debug, Error)]
+#[error(
+    "{feature} needs nix {}.{} or newer, but {installed} is installed; \
+     upgrade nix or {}",
+    .feature.min_version().0,
+    .feature.min_version().1,
+    .feature.alternative()
+)]
+pub struct Unsupported {
+    /// What lorri wanted to use.
+    pub feature: Feature,
+    /// The nix that is installed.
+    pub installed: NixVersion,
+}
+
+lazy_static::lazy_static! {
+    static ref DETECTED: Option<NixVersion> = NixVersion::probe();
+}
+
+impl NixVersion {
+    /// The nix installed for this process, or `None` if we can’t tell.
+    /// Only runs nix the first time it is called.
+    pub fn detected() -> Option<&'static NixVersion> {
+        DETECTED.as_ref()
+    }
+
+    /// Ask `nix --version`, or `nix-instantiate --version` if only
+    /// the legacy commands are installed.
+    fn probe() -> Option<NixVersion> {
+        ["nix", "nix-instantiate"].iter().find_map(|executable| {
+            let out = Command::new(executable).arg("--version").output().ok()?;
+            if !out.status.success() {
+                return None;
+            }
+            NixVersion::parse(String::from_utf8_lossy(&out.stdout).lines().next()?)
+        })
+    }
+
+    /// Parse the first line of `nix --version`, e.g.
+    /// `nix (Nix) 2.18.1`, `nix (Lix, like Nix) 2.91.1`
+    /// or `nix (Determinate Nix 3.6.2) 2.29.0`.
+    pub fn parse(line: &str) -> Option<NixVersion> {
+        let flavor = if line.contains("Lix") {
+            Flavor::Lix
+        } else if line.contains("Determinate") {
+            Flavor::Determinate
+        } else {
+            Flavor::Nix
+        };
+        let mut numbers = line.split_whitespace().last()?.split('.').map(|part| {
+            // e.g. `2.4pre20210908_ff36f6d` or `2.25.0-rc1`
+            let digits: String = part.chars().take_while(char::is_ascii_digit).collect();
+            digits.parse::<u32>().ok()
+        });
+        let major = numbers.next()??;
+        let minor = numbers.next().flatten().unwrap_or(0);
+        let patch = numbers.next().flatten().unwrap_or(0);
+        Some(NixVersion {
+            flavor,
+            version: (major, minor, patch),
+        })
+    }
+
+    /// Whether this nix has `feature`.
+    pub fn supports(&self, feature: Feature) -> bool {
+        match self.flavor {
+            Flavor::Lix | Flavor::Determinate => true,
+            Flavor::Nix => self.version >= feature.min_version(),
+        }
+    }
+}
+
+/// Fail if the installed nix doesn’t support `feature`.
+/// If we couldn’t find out which nix is installed, let nix itself fail.
+pub fn require(feature: Feature) -> Result<(), Unsupported> {
+    match NixVersion::detected() {
+        Some(installed) if !installed.supports(feature) => Err(Unsupported {
+            feature,
+            installed: installed.clone(),
+        }),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_versions() {
+        let parse = |line| NixVersion::parse(line).map(|v| (v.flavor, v.version));
+        assert_eq!(parse("nix (Nix) 2.18.1"), Some((Flavor::Nix, (2, 18, 1))));
+        assert_eq!(
+            parse("nix-instantiate (Nix) 2.3.16"),
+            Some((Flavor::Nix, (2, 3, 16)))
+        );
+        assert_eq!(
+            parse("nix (Nix) 2.4pre20210908_ff36f6d"),
+            Some((Flavor::Nix, (2, 4, 0)))
+        );
+        assert_eq!(
+            parse("nix (Lix, like Nix) 2.91.1"),
+            Some((Flavor::Lix, (2, 91, 1)))
+        );
+        assert_eq!(
+            parse("nix (Determinate Nix 3.6.2) 2.29.0"),
+            Some((Flavor::Determinate, (2, 29, 0)))
+        );
+        assert_eq!(parse("command not found"), None);
+    }
+
+    #[test]
+    fn old_nix_lacks_features() {
+        let nix = |version| NixVersion {
+            flavor: Flavor::Nix,
+            version,
+        };
+        assert!(!nix((2, 3, 16)).supports(Feature::Flakes));
+        assert!(nix((2, 4, 0)).supports(Feature::Flakes));
+        assert!(nix((2, 18, 1)).supports(Feature::PrintDevEnv));
+        assert!(!nix((2, 7, 0)).supports(Feature::PrintOutPaths));
+        assert!(nix((2, 8, 0)).supports(Feature::PrintOutPaths));
+        assert!(!nix((2, 12, 1)).supports(Feature::AllOutputs));
+        assert!(nix((2, 13, 0)).supports(Feature::AllOutputs));
+        assert!(NixVersion {
+            flavor: Flavor::Lix,
+            version: (2, 90, 0)
+        }
+        .supports(Feature::NixCommand));
+    }
+}
@@ -1,10 +1,15 @@
 //! Ops are command-line callables.
 
 mod direnv;
+mod doctor;
 pub mod error;
+mod quote;
+
+pub use direnv::DirenvShell;
+pub use doctor::doctor;
 
 use crate::build_loop::BuildLoop;
-use crate::build_loop::{Event, EventI, ReasonI};
+use crate::build_loop::{Event, EventI, LogEvent, ReasonI};
 use crate::builder::OutputPath;
 use crate::cas::ContentAddressable;
 use crate::changelog;
@@ -12,13 +17,18 @@ use crate::cli;
 use crate::cli::ShellOptions;
 use crate::cli::StartUserShellOptions_;
 use crate::cli::WatchOptions;
+use crate::config::Config;
+use crate::daemon;
 use crate::daemon::client;
 use crate::daemon::Daemon;
+use crate::environment::{EnvDiff, BASH_OWN_VARIABLES};
 use crate::nix;
+use crate::nix::diagnostics::{self, Diagnostic};
 use crate::nix::options::NixOptions;
 use crate::nix::CallOpts;
 use crate::ops::direnv::{DirenvVersion, MIN_DIRENV_VERSION};
 use crate::ops::error::{ExitAs, ExitError, ExitErrorType};
+use crate::project::config::ProjectConfig;
 use crate::project::Project;
 use crate::run_async::Async;
 use crate::socket::path::SocketPath;
@@ -26,7 +36,8 @@ use crate::NixFile;
 use crate::VERSION_BUILD_REV;
 use crate::{builder, project};
 
-use std::ffi::OsStr;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ffi::{OsStr, OsString};
 use std::fmt::Debug;
 use std::fs::File;
 use std::io::Write;
@@ -56,22 +67,74 @@ pub fn get_paths() -> Result<crate::constants::Paths, error::ExitError> {
     })
 }
 
-/// Run a BuildLoop for `shell.nix`, watching for input file changes.
-/// Can be used together with `direnv`.
-
-/// See the documentation for lorri::cli::Command::Daemon for details.
-pub fn daemon(opts: crate::cli::DaemonOptions, logger: &slog::Logger) -> Result<(), ExitError> {
-    let extra_nix_options = match opts.extra_nix_options {
+/// The nix options for `--extra-nix-options` or the configuration’s `extra_nix_options`.
+fn extra_nix_options(opts: Option<cli::NixOptions>) -> NixOptions {
+    match opts {
         None => NixOptions::empty(),
         Some(v) => NixOptions {
             builders: v.builders,
             substituters: v.substituters,
+            ..NixOptions::empty()
         },
-    };
+    }
+}
 
-    let user = project::Username::from_env_var().map_err(ExitError::environment_problem)?;
+/// Run a BuildLoop for `shell.nix`, watching for input file changes.
+/// Can be used together with `direnv`.
+///
+/// See the documentation for lorri::cli::Command::Daemon for details.
+pub fn daemon(
+    opts: crate::cli::DaemonOptions,
+    config: &Config,
+    logger: &slog::Logger,
+) -> Result<(), ExitError> {
+    let extra_nix_options = extra_nix_options(
+        opts.extra_nix_options
+            .or_else(|| config.extra_nix_options.clone()),
+    );
 
-    let (mut daemon, build_rx) = Daemon::new(extra_nix_options);
+    let user = project::Username::current().map_err(ExitError::environment_problem)?;
+    let paths = crate::ops::get_paths()?;
+    // background builds shouldn’t slow the machine down
+    nix::limits::apply(&config.nix_limits, logger);
+    match nix::version::NixVersion::detected() {
+        Some(version) => info!(logger, "using nix"; "version" => %version),
+        None => warn!(
+            logger,
+            "could not find out which version of nix is installed"
+        ),
+    }
+
+    let (mut daemon, build_rx) = Daemon::new(daemon::Settings {
+        extra_nix_options,
+        keep_generations: opts.keep_generations,
+        gc_root_ttl: opts
+            .gc_root_ttl_days
+            .map(|days| Duration::from_secs(days * 24 * 60 * 60)),
+        cancel_builds: !opts.no_cancel_builds,
+        max_concurrent_builds: opts
+            .max_concurrent_builds
+            .or(config.max_concurrent_builds)
+            .unwrap_or(crate::config::DEFAULT_MAX_CONCURRENT_BUILDS),
+        local_build_fallback: opts.local_build_fallback,
+        respect_ignore_files: !opts.no_ignore_files,
+        debounce: opts
+            .debounce_ms
+            .map(Duration::from_millis)
+            .unwrap_or_else(|| config.debounce()),
+        poll_interval: opts
+            .poll_interval
+            .map(Duration::from_secs)
+            .or_else(|| config.poll_interval()),
+        poll_fallback: opts.poll_fallback.map(Duration::from_secs),
+        tracked_projects_file: if opts.no_persist_projects {
+            None
+        } else {
+            Some(paths.tracked_projects_file().clone())
+        },
+        http_port: opts.http_port,
+        http_token_file: paths.http_token_file().clone(),
+    });
     let logger2 = logger.clone();
     let build_handle = std::thread::spawn(move || {
         for msg in build_rx {
@@ -80,13 +143,12 @@ pub fn daemon(opts: crate::cli::DaemonOptions, logger: &slog::Logger) -> Result<
     });
     info!(logger, "ready");
 
-    let paths = crate::ops::get_paths()?;
     daemon.serve(
         &SocketPath::from(paths.daemon_socket_file().clone()),
         paths.gc_root_dir(),
         paths.cas_store().clone(),
         user,
-        &logger,
+        logger,
     )?;
     build_handle
         .join()
@@ -101,27 +163,60 @@ pub fn daemon(opts: crate::cli::DaemonOptions, logger: &slog::Logger) -> Result<
 pub fn direnv<W: std::io::Write>(
     project: Project,
     mut shell_output: W,
+    shell: DirenvShell,
+    spawn_daemon: bool,
+    fallback_build: bool,
+    config: &Config,
     logger: &slog::Logger,
 ) -> Result<(), ExitError> {
-    check_direnv_version()?;
+    // only the bash script is evaluated by direnv itself
+    if shell == DirenvShell::Bash {
+        check_direnv_version()?;
+    }
 
     let root_paths = project.root_paths();
-    let paths_are_cached: bool = root_paths.all_exist();
+    let mut paths_are_cached: bool = root_paths.all_exist();
+    if let Err(err) = project.mark_used() {
+        debug!(logger, "could not record project activity"; "error" => %err);
+    }
 
-    let ping_sent = {
-        let address = crate::ops::get_paths()?.daemon_socket_file().clone();
+    let address = crate::ops::get_paths()?.daemon_socket_file().clone();
+    let ping = || {
         debug!(logger, "connecting to socket"; "socket" => address.as_path().display());
         client::create::<client::Ping>(client::Timeout::from_millis(500), logger)
             .and_then(|c| {
                 c.write(&client::Ping {
-                    nix_file: project.nix_file,
+                    env: passed_env(&project.nix_file),
+                    nix_file: project.nix_file.clone(),
+                    attr: project.attr.clone(),
                     rebuild: client::Rebuild::OnlyIfNotYetWatching,
                 })?;
+                // the daemon’s reply tells us it understood the ping
+                c.read()?;
                 Ok(())
             })
-            // TODO: maybe ping should indeed return something so we can at least check whether it parses the message and the version is right. Right now this collapses all of that into a bool …
             .is_ok()
     };
+    let mut ping_sent = ping();
+    if !ping_sent && spawn_daemon {
+        match start_daemon(&SocketPath::from(address.clone()), logger) {
+            Ok(()) => ping_sent = ping(),
+            Err(err) => warn!(logger, "could not start the lorri daemon"; "error" => %err),
+        }
+    }
+    // Without a daemon, build the environment ourselves, which is slow but
+    // leaves direnv with a working environment. The `watch_file` on the
+    // daemon socket below makes direnv ping the daemon once it is started.
+    if !ping_sent && fallback_build {
+        info!(
+            logger,
+            "lorri daemon is not running, building the environment"
+        );
+        match direnv_fallback_build(&project, config, logger) {
+            Ok(()) => paths_are_cached = root_paths.all_exist(),
+            Err(err) => warn!(logger, "could not build the environment"; "error" => %err),
+        }
+    }
 
     match (ping_sent, paths_are_cached) {
         (true, true) => {}
@@ -155,150 +250,1527 @@ pub fn direnv<W: std::io::Write>(
     // In production code, `shell_output` will be stdout so direnv can interpret the output.
     // `shell_output` is an argument so that testing code can inject a different `std::io::Write`
     // in order to inspect the output.
+    // Sourcing the environment is slow, so we emit the `export` statements
+    // it amounts to, which only have to be rendered once per build.
+    let config = ProjectConfig::load(project.nix_file.as_absolute_path()).unwrap_or_default();
+    let export = match paths_are_cached {
+        true => direnv::cached_export(
+            root_paths.shell_gc_root.0.as_path(),
+            shell,
+            &config,
+            &project.cas,
+            logger,
+        )
+        .map_err(|e| debug!(logger, "could not render the direnv export"; "error" => %e))
+        .ok(),
+        false => None,
+    };
+    if shell != DirenvShell::Bash {
+        if config.run_shell_hook {
+            warn!(logger, "the shellHook only runs in bash, ignoring `run_shell_hook`"; "shell" => %shell);
+        }
+        // other shells can’t source `envrc.bash`, so they only get
+        // an environment once it could be rendered
+        if let Some(export) = export {
+            write!(shell_output, "{}", export).expect("failed to write shell output");
+        }
+        return Ok(());
+    }
+    // direnv reloads as soon as an input of the project changes,
+    // and doesn’t keep an environment which lorri knows to be stale
+    let watch_inputs: String = match ping_sent {
+        true => watched_inputs(&project, logger),
+        false => vec![],
+    }
+    .iter()
+    .map(|path| format!("watch_file {}\n", quote::bash_path(path)))
+    .collect();
+    // the hook runs after all variables are set, and its failure
+    // doesn’t keep direnv from loading them
+    let shell_hook = match config.run_shell_hook {
+        true => {
+            r#"
+if [ -n "${shellHook:-}" ]; then
+    eval "$shellHook" || log_error "lorri: the shellHook failed with status $?"
+fi
+"#
+        }
+        _ => "",
+    };
     writeln!(
         shell_output,
         r#"
-EVALUATION_ROOT="{}"
+EVALUATION_ROOT={}
 
-watch_file "{}"
+watch_file {}
 watch_file "$EVALUATION_ROOT"
-
-{}"#,
-        root_paths.shell_gc_root.display(),
-        crate::ops::get_paths()?
-            .daemon_socket_file()
-            .as_path()
-            .to_str()
-            .expect("Socket path is not UTF-8 clean!"),
-        include_str!("./ops/direnv/envrc.bash")
+{}
+{}{}"#,
+        quote::bash_path(root_paths.shell_gc_root.0.as_path()),
+        quote::bash_path(crate::ops::get_paths()?.daemon_socket_file().as_path()),
+        watch_inputs,
+        export.unwrap_or_else(|| config.envrc_prelude() + include_str!("./ops/direnv/envrc.bash")),
+        shell_hook
     )
     .expect("failed to write shell output");
 
-    // direnv provides us with an environment variable if we are inside of its envrc execution.
-    // Thus we can show a warning if the user runs it on their command line.
-    if std::env::var("DIRENV_IN_ENVRC") != Ok(String::from("1")) {
-        warn!(logger, "`lorri direnv` should be executed by direnv from within an `.envrc` file. Run `lorri init` to get started.")
+    // direnv provides us with an environment variable if we are inside of its envrc execution.
+    // Thus we can show a warning if the user runs it on their command line.
+    if std::env::var("DIRENV_IN_ENVRC") != Ok(String::from("1")) {
+        warn!(logger, "`lorri direnv` should be executed by direnv from within an `.envrc` file. Run `lorri init` to get started.")
+    }
+
+    Ok(())
+}
+
+/// Build `project` once, like the daemon would, for `lorri direnv --fallback-build`.
+fn direnv_fallback_build(
+    project: &Project,
+    config: &Config,
+    logger: &slog::Logger,
+) -> Result<(), String> {
+    let user = project::Username::current().map_err(|e| e.to_string())?;
+    let mut build_loop = BuildLoop::new(
+        project,
+        extra_nix_options(config.extra_nix_options.clone()),
+        None,
+        user,
+        logger.clone(),
+    )
+    .map_err(|e| e.to_string())?;
+    build_loop.once(None).map(|_| ()).map_err(|e| e.to_string())
+}
+
+/// The paths the daemon watches for `nix_file`,
+/// empty if it doesn’t watch the project (yet).
+fn watched_inputs(project: &Project, logger: &slog::Logger) -> Vec<PathBuf> {
+    client::create::<client::WatchedPaths>(client::Timeout::from_millis(500), logger)
+        .and_then(|c| {
+            c.write(&client::WatchedPaths {
+                nix_file: project.nix_file.clone(),
+                attr: project.attr.clone(),
+            })?;
+            Ok(c.read()?)
+        })
+        .ok()
+        .flatten()
+        .unwrap_or_default()
+        .into_iter()
+        .map(|watched| watched.path.as_ref().to_owned())
+        .collect()
+}
+
+/// Start `lorri daemon` in the background, unless something listens on `socket` already,
+/// and wait until it listens.
+///
+/// Uses the daemon’s systemd user unit if there is one. Otherwise the daemon
+/// runs in its own session, logging to `daemon.log` next to the socket.
+fn start_daemon(socket: &SocketPath, logger: &slog::Logger) -> std::io::Result<()> {
+    if socket.connect().is_ok() {
+        return Ok(());
+    }
+    let systemd_started = Command::new("systemctl")
+        .args(["--user", "start", "lorri.service"])
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status()
+        .map_or(false, |status| status.success());
+    if systemd_started {
+        info!(logger, "started the lorri daemon through systemd");
+    } else {
+        let log_file = socket.as_absolute_path().with_file_name("daemon.log");
+        // panics end up in the log as well
+        let log = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log_file)?;
+        let mut cmd = Command::new(env::current_exe()?);
+        cmd.arg("daemon")
+            .arg("--log-file")
+            .arg(&log_file)
+            .stdin(std::process::Stdio::null())
+            // direnv waits until our stdout is closed
+            .stdout(std::process::Stdio::null())
+            .stderr(log);
+        // `setsid` is async-signal-safe, so it may run between fork and exec.
+        unsafe {
+            cmd.pre_exec(|| {
+                ::nix::unistd::setsid()
+                    .map(|_| ())
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+            });
+        }
+        cmd.spawn()?;
+        info!(logger, "started the lorri daemon"; "log" => log_file.display());
+    }
+
+    for _ in 0..50 {
+        if socket.connect().is_ok() {
+            return Ok(());
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    Err(io::Error::new(
+        io::ErrorKind::TimedOut,
+        "the daemon did not start listening on its socket",
+    ))
+}
+
+/// Checks `direnv version` against the minimal version lorri requires.
+fn check_direnv_version() -> Result<(), ExitError> {
+    let out = with_command("direnv", |mut cmd| cmd.arg("version").output())?;
+    let version = std::str::from_utf8(&out.stdout)
+        .map_err(|_| ())
+        .and_then(|utf| utf.trim_end().parse::<DirenvVersion>())
+        .map_err(|()| {
+            ExitError::environment_problem(anyhow::anyhow!(
+                "Could not figure out the current `direnv` version (parse error)"
+            ))
+        })?;
+    if version < MIN_DIRENV_VERSION {
+        Err(ExitError::environment_problem(anyhow::anyhow!(
+            "`direnv` is version {}, but >= {} is required for lorri to function",
+            version,
+            MIN_DIRENV_VERSION
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+/// constructs a `Command` out of `executable`
+/// Recognizes the case in which the executable is missing,
+/// and converts it to a corresponding `ExitError`.
+fn with_command<T, F>(executable: &str, cmd: F) -> Result<T, ExitError>
+where
+    F: FnOnce(Command) -> std::io::Result<T>,
+{
+    let res = cmd(Command::new(executable));
+    res.map_err(|err| match err.kind() {
+        std::io::ErrorKind::NotFound => {
+            ExitError::missing_executable(anyhow::anyhow!("`{}`: executable not found", executable))
+        }
+        _ => ExitError::temporary(
+            anyhow::Error::new(err).context(format!("Could not start `{}`", executable)),
+        ),
+    })
+}
+
+/// A GC root of the project, as printed by `lorri info --json`.
+#[derive(Serialize)]
+struct InfoGcRootJson {
+    path: PathBuf,
+    /// The store path it points to
+    target: Option<PathBuf>,
+    /// `null` for the shell GC root
+    generation: Option<u64>,
+    /// Unix timestamp of the root’s creation, in seconds
+    created: Option<u64>,
+}
+
+/// The project, as printed by `lorri info --json`.
+#[derive(Serialize)]
+struct InfoJson {
+    id: String,
+    nix_file: PathBuf,
+    project_dir: PathBuf,
+    /// The file the daemon keeps the state of the project’s builds in
+    status_file: PathBuf,
+    /// Whether the shell GC root points to an existing store path
+    /// and nix knows about it
+    roots_intact: bool,
+    gc_roots: Vec<InfoGcRootJson>,
+    /// `null` if the daemon isn’t running or doesn’t know the project
+    daemon: Option<ProjectJson>,
+    /// `null` if the daemon isn’t running or doesn’t watch the project
+    watched_paths: Option<Vec<WatchedPathJson>>,
+}
+
+/// The info callable is for printing
+///
+/// See the documentation for lorri::cli::Command::Info for more
+/// details.
+pub fn info(
+    project: Project,
+    opts: cli::InfoOptions,
+    logger: &slog::Logger,
+) -> Result<(), ExitError> {
+    let unix_secs = |time: std::time::SystemTime| {
+        time.duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs())
+    };
+    let created = |root: &project::RootPath| {
+        std::fs::symlink_metadata(root.0.as_path())
+            .and_then(|m| m.modified())
+            .ok()
+    };
+
+    let root_info = project.gc_root_info();
+    let shell_target = root_info.store_path();
+    // assume the roots are registered if nix can’t tell, e.g. with the nix daemon
+    let registered = project::Username::current()
+        .ok()
+        .and_then(|user| root_info.reverse_root_exists(&user))
+        .unwrap_or(true);
+    let roots_intact = shell_target.as_ref().map_or(false, |t| t.exists()) && registered;
+
+    let mut gc_roots = vec![InfoGcRootJson {
+        path: root_info.shell_gc_root.0.as_path().to_owned(),
+        target: shell_target,
+        generation: None,
+        created: root_info.created().map(unix_secs),
+    }];
+    for generation in project.generations()? {
+        gc_roots.push(InfoGcRootJson {
+            path: generation.root.0.as_path().to_owned(),
+            created: created(&generation.root).map(unix_secs),
+            target: Some(generation.store_path),
+            generation: Some(generation.number),
+        });
+    }
+
+    // the daemon doesn’t have to run to inspect the project
+    let daemon_status =
+        client::create::<client::Projects>(client::Timeout::from_millis(500), logger)
+            .and_then(|c| {
+                c.write(&client::Projects {})?;
+                Ok(c.read()?)
+            })
+            .ok()
+            .and_then(|projects| {
+                projects
+                    .into_iter()
+                    .find(|p| p.nix_file == project.nix_file && p.attr == project.attr)
+            });
+    let watched_paths =
+        client::create::<client::WatchedPaths>(client::Timeout::from_millis(500), logger)
+            .and_then(|c| {
+                c.write(&client::WatchedPaths {
+                    nix_file: project.nix_file.clone(),
+                    attr: project.attr.clone(),
+                })?;
+                Ok(c.read()?)
+            })
+            .ok()
+            .flatten();
+
+    if opts.json {
+        let json = InfoJson {
+            id: project.hash().to_string(),
+            nix_file: project.nix_file.as_absolute_path().to_owned(),
+            project_dir: root_info.project_dir.as_path().to_owned(),
+            status_file: project.status_file().as_path().to_owned(),
+            roots_intact,
+            gc_roots,
+            daemon: daemon_status.as_ref().map(ProjectJson::from),
+            watched_paths: watched_paths
+                .map(|paths| paths.iter().map(WatchedPathJson::from).collect()),
+        };
+        serde_json::to_writer(std::io::stdout(), &json).expect("couldn't serialize the project");
+        println!();
+        return Ok(());
+    }
+
+    let now = std::time::SystemTime::now();
+    let age = |time: Option<std::time::SystemTime>| match time {
+        Some(time) => format!(
+            "{} ago",
+            human_duration(now.duration_since(time).unwrap_or_default())
+        ),
+        None => String::from("-"),
+    };
+    println!("id: {}", project.hash());
+    println!("shell file: {}", project.nix_file.display());
+    println!("project dir: {}", root_info.project_dir.display());
+    println!("status file: {}", project.status_file().display());
+    if gc_roots[0].target.is_none() {
+        println!("GC roots do not exist. Has the project been built with lorri yet?");
+    } else {
+        println!(
+            "GC roots: {}",
+            if roots_intact { "intact" } else { "broken" }
+        );
+        for root in &gc_roots {
+            println!(
+                "  {:<7} {:>8}  {} -> {}",
+                root.generation
+                    .map_or(String::from("shell"), |g| g.to_string()),
+                age(root
+                    .created
+                    .map(|secs| std::time::UNIX_EPOCH + Duration::from_secs(secs))),
+                root.path.display(),
+                root.target
+                    .as_ref()
+                    .map_or(String::from("?"), |t| t.display().to_string())
+            );
+        }
+    }
+    match &daemon_status {
+        Some(status) => {
+            println!("last build: {}", status_name(status.status));
+            println!("  finished: {}", age(status.last_build_finished));
+            println!(
+                "  duration: {}",
+                status
+                    .last_build_duration
+                    .map_or(String::from("-"), human_duration)
+            );
+        }
+        None => println!("last build: unknown, the daemon does not build the project"),
+    }
+    match &watched_paths {
+        Some(paths) => {
+            println!("watched paths:");
+            for watched in paths {
+                println!("  {}", display_watched_path(watched));
+            }
+        }
+        None => println!("watched paths: unknown, the daemon does not watch the project"),
+    }
+    Ok(())
+}
+
+/// The formats `lorri env` can print the environment in.
+#[derive(Debug, Clone, Copy)]
+pub enum EnvFormat {
+    /// `export NAME='value'` lines
+    Bash,
+    /// `set -gx NAME 'value'` lines
+    Fish,
+    /// A JSON object of the variables
+    Json,
+    /// `NAME="value"` lines, as read by docker compose and most dotenv libraries
+    Dotenv,
+}
+
+impl FromStr for EnvFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bash" => Ok(EnvFormat::Bash),
+            "fish" => Ok(EnvFormat::Fish),
+            "json" => Ok(EnvFormat::Json),
+            "dotenv" => Ok(EnvFormat::Dotenv),
+            _ => Err(format!("{} not in bash,fish,json,dotenv", s)),
+        }
+    }
+}
+
+/// Print the environment of the project’s last successful build,
+/// the way direnv would set it.
+///
+/// See the documentation for lorri::cli::Command::Env for more
+/// details.
+pub fn env(project: Project, format: EnvFormat, logger: &slog::Logger) -> Result<(), ExitError> {
+    let root = cached_root(&project).map_err(|_| {
+        ExitError::expected_error(anyhow::anyhow!(
+            "{} has not been built yet, run `lorri build` first",
+            project.nix_file.display()
+        ))
+    })?;
+    let output = bash_cmd(root, &project, logger)?
+        .args(["-c", "env -0"])
+        .output()
+        .map_err(|e| ExitError::temporary(anyhow::Error::new(e).context("Could not run bash")))?;
+    if !output.status.success() {
+        return Err(ExitError::temporary(anyhow::anyhow!(
+            "Loading the environment failed:\n{}",
+            String::from_utf8_lossy(&output.stderr)
+        )));
+    }
+
+    // only the variables the environment sets or changes
+    let current: BTreeMap<OsString, OsString> = env::vars_os().collect();
+    let vars: BTreeMap<String, String> = output
+        .stdout
+        .split(|b| *b == 0)
+        .filter_map(|var| {
+            let var = String::from_utf8_lossy(var);
+            let (name, value) = var.split_once('=')?;
+            Some((name.to_string(), value.to_string()))
+        })
+        .filter(|(name, value)| {
+            // names no shell can set, which bash passes on from its environment
+            quote::is_variable_name(name)
+                && !BASH_OWN_VARIABLES.contains(&name.as_str())
+                && current.get(OsStr::new(name)) != Some(&OsString::from(value))
+        })
+        .collect();
+
+    let mut out = io::stdout();
+    let written = match format {
+        EnvFormat::Json => {
+            serde_json::to_writer(&mut out, &vars).expect("couldn't serialize the environment");
+            writeln!(out)
+        }
+        EnvFormat::Bash => vars
+            .iter()
+            .try_for_each(|(name, value)| writeln!(out, "export {}={}", name, quote::sh(value))),
+        EnvFormat::Fish => vars
+            .iter()
+            .try_for_each(|(name, value)| writeln!(out, "set -gx {} {}", name, quote::fish(value))),
+        EnvFormat::Dotenv => vars
+            .iter()
+            .try_for_each(|(name, value)| writeln!(out, "{}={}", name, quote::dotenv(value))),
+    };
+    written.map_err(|e| {
+        ExitError::temporary(anyhow::Error::new(e).context("Could not print the environment"))
+    })
+}
+
+/// Print the completion script for `shell`.
+///
+/// See the documentation for lorri::cli::Command::Completions for more
+/// details.
+pub fn completions(shell: structopt::clap::Shell) -> Result<(), ExitError> {
+    use structopt::StructOpt;
+    cli::Arguments::clap().gen_completions_to("lorri", shell, &mut std::io::stdout());
+    Ok(())
+}
+
+/// The kinds of projects `lorri init` can create.
+#[derive(Debug, Clone, Copy)]
+pub enum InitTemplate {
+    /// A shell.nix using the system’s `<nixpkgs>`
+    Shell,
+    /// A flake.nix with a dev shell
+    Flake,
+    /// A shell.nix with nixpkgs pinned by niv
+    Niv,
+    /// A shell.nix with nixpkgs pinned by npins
+    Npins,
+    /// A shell.nix with nixpkgs from a release channel
+    Channel,
+}
+
+impl FromStr for InitTemplate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "shell" => Ok(InitTemplate::Shell),
+            "flake" => Ok(InitTemplate::Flake),
+            "niv" => Ok(InitTemplate::Niv),
+            "npins" => Ok(InitTemplate::Npins),
+            "channel" => Ok(InitTemplate::Channel),
+            _ => Err(format!("{} not in shell,flake,niv,npins,channel", s)),
+        }
+    }
+}
+
+impl InitTemplate {
+    /// The file which pins the inputs, relative to the project,
+    /// and the command which creates it.
+    fn lock_file(self) -> Option<(&'static str, &'static str)> {
+        match self {
+            InitTemplate::Shell | InitTemplate::Channel => None,
+            InitTemplate::Flake => Some(("flake.lock", "nix flake lock")),
+            InitTemplate::Niv => Some(("nix/sources.json", "niv init")),
+            InitTemplate::Npins => Some(("npins/sources.json", "npins init")),
+        }
+    }
+}
+
+/// Bootstrap a new lorri project
+///
+/// See the documentation for lorri::cli::Command::Init for
+/// more details
+pub fn init(
+    template: InitTemplate,
+    default_shell: &str,
+    default_envrc: &str,
+    logger: &slog::Logger,
+) -> Result<(), ExitError> {
+    let (nix_file, contents, msg) = match template {
+        InitTemplate::Shell => (
+            "./shell.nix",
+            default_shell,
+            "Make sure shell.nix is of a form that works with nix-shell.",
+        ),
+        InitTemplate::Flake => (
+            "./flake.nix",
+            include_str!("./ops/init/flake.nix"),
+            "Make sure flake.nix has a devShells.<system>.default output.",
+        ),
+        InitTemplate::Niv => (
+            "./shell.nix",
+            include_str!("./ops/init/niv-shell.nix"),
+            "Make sure shell.nix imports nixpkgs from ./nix/sources.nix.",
+        ),
+        InitTemplate::Npins => (
+            "./shell.nix",
+            include_str!("./ops/init/npins-shell.nix"),
+            "Make sure shell.nix imports nixpkgs from ./npins.",
+        ),
+        InitTemplate::Channel => (
+            "./shell.nix",
+            include_str!("./ops/init/channel-shell.nix"),
+            "Make sure shell.nix is of a form that works with nix-shell.",
+        ),
+    };
+    create_if_missing(Path::new(nix_file), contents, msg, logger).map_err(ExitError::user_error)?;
+
+    create_if_missing(
+        Path::new("./.envrc"),
+        default_envrc,
+        "Please add 'eval \"$(lorri direnv)\"' to .envrc to set up lorri support.",
+        logger,
+    )
+    .map_err(ExitError::user_error)?;
+
+    if let Some((lock_file, lock_command)) = template.lock_file() {
+        create_if_missing(
+            Path::new("./.lorri.toml"),
+            &format!(
+                "# rebuild when the pinned inputs are updated\nwatch = [\"{}\"]\n",
+                lock_file
+            ),
+            &format!(
+                "Please add \"{}\" to `watch` in .lorri.toml, so that updating the pins triggers a build.",
+                lock_file
+            ),
+            logger,
+        )
+        .map_err(ExitError::user_error)?;
+        if !Path::new(lock_file).exists() {
+            info!(logger, "nixpkgs is not pinned yet, please run the command"; "command" => lock_command);
+        }
+    }
+
+    info!(logger, "done");
+    Ok(())
+}
+
+fn create_if_missing(
+    path: &Path,
+    contents: &str,
+    msg: &str,
+    logger: &slog::Logger,
+) -> Result<(), io::Error> {
+    if path.exists() {
+        info!(logger, "file already exists, skipping"; "path" => path.to_str(), "message" => msg);
+        Ok(())
+    } else {
+        let mut f = File::create(path)?;
+        f.write_all(contents.as_bytes())?;
+        info!(logger, "wrote file"; "path" => path.to_str());
+        Ok(())
+    }
+}
+
+/// List the generations of a project or switch to a previous one.
+///
+/// See the documentation for lorri::cli::Command::Rollback for more
+/// details.
+pub fn rollback(
+    project: Project,
+    opts: cli::RollbackOptions,
+    logger: &slog::Logger,
+) -> Result<(), ExitError> {
+    if opts.list {
+        let current = project.current_generation()?.map(|g| g.number);
+        for generation in project.generations()? {
+            println!(
+                "{}{}  {}",
+                generation.number,
+                if Some(generation.number) == current {
+                    " (current)"
+                } else {
+                    ""
+                },
+                generation.store_path.display()
+            );
+        }
+        return Ok(());
+    }
+
+    let generation = project.rollback(opts.generation, logger)?;
+    info!(logger, "rolled back"; "generation" => generation.number, "store_path" => generation.store_path.to_str());
+    Ok(())
+}
+
+/// Remove the GC roots of all projects whose nix file is gone.
+///
+/// See the documentation for lorri::cli::Command::Gc for more
+/// details.
+pub fn gc(
+    opts: cli::GcOptions,
+    gc_root_dir: &crate::AbsPathBuf,
+    logger: &slog::Logger,
+) -> Result<(), ExitError> {
+    let user = project::Username::current().map_err(ExitError::environment_problem)?;
+    let roots = project::list_roots(gc_root_dir).map_err(|e| {
+        ExitError::temporary(anyhow::Error::new(e).context(format!(
+            "Could not list the GC roots in {}",
+            gc_root_dir.display()
+        )))
+    })?;
+
+    let mut removed = 0;
+    for root in roots {
+        let nix_file = match &root.nix_file {
+            Some(f) => f.display().to_string(),
+            None => String::from("<unknown project>"),
+        };
+        match root.nix_file_exists() {
+            Some(true) => println!("{}  {}  (exists)", root.hash, nix_file),
+            // we can’t tell whether the project still exists, so leave it alone
+            None => println!("{}  {}  (skipped)", root.hash, nix_file),
+            Some(false) => {
+                if opts.dry_run {
+                    println!("{}  {}  (missing, would remove)", root.hash, nix_file);
+                } else {
+                    println!("{}  {}  (missing, removing)", root.hash, nix_file);
+                    root.remove(&user).map_err(|e| {
+                        ExitError::temporary(
+                            anyhow::Error::new(e)
+                                .context(format!("Could not remove the GC roots of {}", nix_file)),
+                        )
+                    })?;
+                    debug!(logger, "removed gc roots"; "hash" => &root.hash, "nix_file" => &nix_file);
+                }
+                removed += 1;
+            }
+        }
+    }
+
+    if opts.dry_run {
+        info!(logger, "dry run, nothing removed"; "stale_projects" => removed);
+    } else {
+        info!(logger, "removed stale projects"; "count" => removed);
+    }
+    Ok(())
+}
+
+/// Print the closure size of every project’s GC roots, and the total.
+///
+/// See the documentation for lorri::cli::Command::Du for more details.
+pub fn du(gc_root_dir: &crate::AbsPathBuf, logger: &slog::Logger) -> Result<(), ExitError> {
+    let roots = project::list_roots(gc_root_dir).map_err(|e| {
+        ExitError::temporary(anyhow::Error::new(e).context(format!(
+            "Could not list the GC roots in {}",
+            gc_root_dir.display()
+        )))
+    })?;
+
+    let size_error = |e: builder::BuildError| {
+        ExitError::temporary(anyhow::anyhow!("Could not query the closure size: {}", e))
+    };
+    let mut all_paths = vec![];
+    let mut sizes = vec![];
+    for root in roots {
+        let paths = root.store_paths().map_err(|e| {
+            ExitError::temporary(anyhow::Error::new(e).context(format!(
+                "Could not read the GC roots in {}",
+                root.project_dir.display()
+            )))
+        })?;
+        let path_refs: Vec<&Path> = paths.iter().map(|p| p.as_path()).collect();
+        let size = nix::store::closure_size(&path_refs, logger).map_err(size_error)?;
+        let nix_file = match &root.nix_file {
+            Some(f) => f.display().to_string(),
+            None => format!("<unknown project {}>", root.hash),
+        };
+        sizes.push((size, nix_file));
+        all_paths.extend(paths);
+    }
+
+    sizes.sort_by_key(|(size, _)| std::cmp::Reverse(*size));
+    for (size, nix_file) in sizes {
+        println!("{:>10}  {}", human_size(size), nix_file);
+    }
+    all_paths.sort();
+    all_paths.dedup();
+    let path_refs: Vec<&Path> = all_paths.iter().map(|p| p.as_path()).collect();
+    let total = nix::store::closure_size(&path_refs, logger).map_err(size_error)?;
+    println!(
+        "{:>10}  total (paths shared between projects counted once)",
+        human_size(total)
+    );
+    Ok(())
+}
+
+/// Format a number of bytes with a binary unit, e.g. `1.5 GiB`.
+fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+/// The daemon’s state, as printed by `lorri internal ping --json`.
+#[derive(Serialize)]
+struct DaemonStatusJson {
+    protocol_version: u32,
+    uptime_secs: u64,
+    projects: usize,
+    building: usize,
+    queued: usize,
+}
+
+/// The result of a build, as printed by `lorri build --json`.
+#[derive(Serialize)]
+struct BuildJson {
+    nix_file: PathBuf,
+    success: bool,
+    out_path: Option<PathBuf>,
+    gc_root: Option<PathBuf>,
+    duration_ms: u64,
+    error: Option<BuildErrorJson>,
+}
+
+/// Why a build failed, as printed by `lorri build --json`.
+#[derive(Serialize)]
+struct BuildErrorJson {
+    /// `evaluation`, `build`, `timeout` or `internal`
+    kind: &'static str,
+    message: String,
+    diagnostics: Vec<Diagnostic>,
+}
+
+/// What went wrong in a build.
+#[derive(Clone, Copy)]
+enum BuildFailure {
+    Evaluation,
+    Build,
+    Timeout,
+    Internal,
+}
+
+impl BuildFailure {
+    fn of(error: &builder::BuildError) -> BuildFailure {
+        match error {
+            builder::BuildError::Build { .. } => BuildFailure::Build,
+            builder::BuildError::Timeout { .. } => BuildFailure::Timeout,
+            e if e.is_actionable() => BuildFailure::Evaluation,
+            _ => BuildFailure::Internal,
+        }
+    }
+
+    /// The `kind` of `BuildErrorJson`.
+    fn name(self) -> &'static str {
+        match self {
+            BuildFailure::Evaluation => "evaluation",
+            BuildFailure::Build => "build",
+            BuildFailure::Timeout => "timeout",
+            BuildFailure::Internal => "internal",
+        }
+    }
+
+    /// How lorri exits after a failed one-shot build: 1 if the evaluation
+    /// failed or timed out, 2 if a derivation failed to build,
+    /// 111 if lorri itself ran into a problem.
+    fn exit_error(error: builder::BuildError) -> ExitError {
+        match BuildFailure::of(&error) {
+            BuildFailure::Build => ExitError::build_failure(anyhow::Error::msg(error)),
+            BuildFailure::Internal => ExitError::temporary(anyhow::Error::msg(error)),
+            BuildFailure::Evaluation | BuildFailure::Timeout => {
+                ExitError::expected_error(anyhow::Error::msg(error))
+            }
+        }
+    }
+}
+
+/// Build the project once, without watching it.
+///
+/// See the documentation for lorri::cli::Command::Build for details.
+pub fn build(
+    project: Project,
+    opts: cli::BuildOptions,
+    config: &Config,
+    logger: &slog::Logger,
+) -> Result<(), ExitError> {
+    let user = project::Username::current().map_err(ExitError::temporary)?;
+    let mut build_loop = BuildLoop::new(
+        &project,
+        extra_nix_options(config.extra_nix_options.clone()),
+        None,
+        user,
+        logger.clone(),
+    )
+    .map_err(ExitError::temporary)?;
+    let started = Instant::now();
+    let result = build_loop.once(opts.timeout);
+    let duration = started.elapsed();
+
+    if opts.json {
+        let (out_path, gc_root, error) = match &result {
+            Ok(output) => (
+                std::fs::read_link(output.shell_gc_root.0.as_path()).ok(),
+                Some(output.shell_gc_root.0.as_path().to_owned()),
+                None,
+            ),
+            Err(e) => (
+                None,
+                None,
+                Some(BuildErrorJson {
+                    kind: BuildFailure::of(e).name(),
+                    message: e.to_string(),
+                    diagnostics: diagnostics::of_build_error(e),
+                }),
+            ),
+        };
+        let json = BuildJson {
+            nix_file: project.nix_file.as_absolute_path().to_owned(),
+            success: result.is_ok(),
+            out_path,
+            gc_root,
+            duration_ms: duration.as_millis() as u64,
+            error,
+        };
+        serde_json::to_writer(std::io::stdout(), &json).expect("couldn't serialize the build");
+        println!();
+    } else if let Ok(output) = &result {
+        println!("{}", output.shell_gc_root.display());
+    }
+
+    result.map(|_| ()).map_err(BuildFailure::exit_error)
+}
+
+/// Run a BuildLoop for `shell.nix`, watching for input file changes,
+/// and print the state of the daemon.
+///
+/// Can be used together with `direnv`.
+/// See the documentation for lorri::cli::Command::Ping_ for details.
+pub fn ping(
+    nix_file: NixFile,
+    attr: Option<String>,
+    json: bool,
+    logger: &slog::Logger,
+) -> Result<(), ExitError> {
+    let client = client::create::<client::Ping>(client::Timeout::from_millis(500), logger)?;
+    client.write(&client::Ping {
+        env: passed_env(&nix_file),
+        nix_file,
+        attr,
+        rebuild: client::Rebuild::Always,
+    })?;
+    let status = client.read()?;
+
+    if json {
+        let status = DaemonStatusJson {
+            protocol_version: status.protocol_version,
+            uptime_secs: status.uptime.as_secs(),
+            projects: status.projects,
+            building: status.building,
+            queued: status.queued,
+        };
+        serde_json::to_writer(std::io::stdout(), &status).expect("couldn't serialize the status");
+        println!();
+    } else {
+        println!("protocol version: {}", status.protocol_version);
+        println!("uptime: {}", human_duration(status.uptime));
+        println!("projects: {}", status.projects);
+        println!("building: {}", status.building);
+        println!("queued: {}", status.queued);
+    }
+    Ok(())
+}
+
+/// The variables of our environment the project wants to read during evaluation.
+/// An invalid configuration is reported by the daemon’s build instead.
+fn passed_env(nix_file: &NixFile) -> BTreeMap<String, String> {
+    ProjectConfig::load(nix_file.as_absolute_path())
+        .map(|config| config.passed_env())
+        .unwrap_or_default()
+}
+
+/// Print the output of the daemon’s current build of `nix_file`’s `attr`.
+///
+/// See the documentation for lorri::cli::Command::Logs for details.
+pub fn logs(
+    nix_file: NixFile,
+    attr: Option<String>,
+    follow: bool,
+    logger: &slog::Logger,
+) -> Result<(), ExitError> {
+    let client = client::create::<client::StreamLogs>(
+        // infinite timeout because we are waiting for output indefinitely
+        client::Timeout::Infinite,
+        logger,
+    )?;
+    client.write(&client::StreamLogs { nix_file, attr })?;
+    let stdout = std::io::stdout();
+    let mut stdout = stdout.lock();
+    let write = |stdout: &mut std::io::StdoutLock, bytes: &[u8]| {
+        stdout
+            .write_all(bytes)
+            .and_then(|()| stdout.flush())
+            .map_err(|e| ExitError::temporary(anyhow::Error::new(e)))
+    };
+    loop {
+        match client.read()? {
+            LogEvent::Line(line) => {
+                use std::os::unix::ffi::OsStrExt;
+                write(&mut stdout, line.0.as_bytes())?;
+                write(&mut stdout, b"\n")?;
+            }
+            LogEvent::SectionEnd if !follow => return Ok(()),
+            LogEvent::SectionEnd => {}
+            LogEvent::BuildStarted => info!(logger, "a new build started"),
+        }
+    }
+}
+
+/// Print the output of the builds of `project` the daemon wrote to its build log.
+///
+/// See the documentation for lorri::cli::Command::Logs for details.
+pub fn build_log(project: Project, follow: bool) -> Result<(), ExitError> {
+    use std::os::unix::fs::MetadataExt;
+    let path = project.build_log();
+    let temporary = |e: io::Error| ExitError::temporary(anyhow::Error::new(e));
+    let open = || -> io::Result<Option<(File, u64)>> {
+        match File::open(&path) {
+            Ok(file) => {
+                let inode = file.metadata()?.ino();
+                Ok(Some((file, inode)))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    };
+    let mut current = open().map_err(temporary)?;
+    if current.is_none() && !follow {
+        return Err(ExitError::user_error(anyhow::anyhow!(
+            "the daemon has not built {} yet",
+            project.nix_file.display()
+        )));
+    }
+    let stdout = std::io::stdout();
+    let mut stdout = stdout.lock();
+    loop {
+        if current.is_none() {
+            current = open().map_err(temporary)?;
+        }
+        if let Some((file, inode)) = &mut current {
+            // after a rotation the daemon only writes to the new file,
+            // so the old one can be printed to its end first
+            let rotated = fs::metadata(&path).map_or(true, |m| m.ino() != *inode);
+            io::copy(file, &mut stdout).map_err(temporary)?;
+            if rotated {
+                current = None;
+            }
+        }
+        stdout.flush().map_err(temporary)?;
+        if !follow {
+            return Ok(());
+        }
+        thread::sleep(Duration::from_millis(200));
+    }
+}
+
+/// Pause (or resume) the daemon’s builds of `nix_file`, or of all projects.
+///
+/// Changes which happen while a project is paused are built once it is resumed.
+/// See the documentation for lorri::cli::Command::Pause for details.
+pub fn pause(
+    project: Option<(NixFile, Option<String>)>,
+    paused: bool,
+    logger: &slog::Logger,
+) -> Result<(), ExitError> {
+    let (nix_file, attr) = match project {
+        Some((nix_file, attr)) => (Some(nix_file), attr),
+        None => (None, None),
+    };
+    client::create(client::Timeout::from_millis(500), logger)?.write(&client::Pause {
+        nix_file: nix_file.clone(),
+        attr: attr.clone(),
+        paused,
+    })?;
+    let project = match (&nix_file, &attr) {
+        (Some(nix_file), None) => nix_file.display().to_string(),
+        (Some(nix_file), Some(attr)) => format!("{} -A {}", nix_file.display(), attr),
+        (None, _) => String::from("all projects"),
+    };
+    match paused {
+        true => info!(logger, "paused builds"; "project" => project),
+        false => info!(logger, "resumed builds"; "project" => project),
+    }
+    Ok(())
+}
+
+/// Make the daemon watch and build every project of the workspace
+/// the current directory is in, or list them.
+///
+/// See the documentation for lorri::cli::Command::Workspace for details.
+pub fn workspace(opts: cli::WorkspaceOptions, logger: &slog::Logger) -> Result<(), ExitError> {
+    let cwd = env::current_dir().map_err(ExitError::temporary)?;
+    let (root, config) = project::config::find_workspace(&cwd).ok_or_else(|| {
+        ExitError::user_error(anyhow::anyhow!(
+            "{} is not in a workspace: no {} above it sets `workspace`",
+            cwd.display(),
+            project::config::CONFIG_FILE_NAME
+        ))
+    })?;
+    let members = config.workspace_members(&root);
+    for member in members {
+        println!("{}", member.display());
+        if opts.list {
+            continue;
+        }
+        let nix_file = NixFile::from(crate::AbsPathBuf::new_unchecked(member));
+        // every project builds the attribute its own `.lorri.toml` declares
+        let attr = ProjectConfig::load(nix_file.as_absolute_path())
+            .ok()
+            .and_then(|config| config.attr);
+        let client = client::create::<client::Ping>(client::Timeout::from_millis(500), logger)?;
+        client.write(&client::Ping {
+            env: passed_env(&nix_file),
+            nix_file,
+            attr,
+            rebuild: client::Rebuild::OnlyIfNotYetWatching,
+        })?;
+        // the daemon’s reply tells us it understood the ping
+        client.read()?;
+    }
+    Ok(())
+}
+
+/// Make the daemon stop watching and building `nix_file`.
+///
+/// See the documentation for lorri::cli::Command::Untrack for details.
+pub fn untrack(
+    nix_file: NixFile,
+    attr: Option<String>,
+    remove_roots: bool,
+    logger: &slog::Logger,
+) -> Result<(), ExitError> {
+    client::create(client::Timeout::from_millis(500), logger)?.write(&client::Untrack {
+        nix_file: nix_file.clone(),
+        attr: attr.clone(),
+        remove_roots,
+    })?;
+    info!(logger, "untracked project"; "project" => nix_file.display().to_string(), "attr" => ?attr);
+    Ok(())
+}
+
+/// Show what changed in the environment between two generations of a project.
+///
+/// See the documentation for lorri::cli::Command::Diff for more details.
+pub fn diff(project: Project, opts: cli::DiffOptions) -> Result<(), ExitError> {
+    let generations = project.generations()?;
+    let find = |number: u64| {
+        generations
+            .iter()
+            .find(|g| g.number == number)
+            .cloned()
+            .ok_or(project::RollbackError::NoSuchGeneration(number))
+    };
+    let to = match opts.to {
+        Some(number) => find(number)?,
+        None => project
+            .current_generation()?
+            .or_else(|| generations.last().cloned())
+            .ok_or_else(|| {
+                ExitError::expected_error(anyhow::anyhow!("the project has not been built yet"))
+            })?,
+    };
+    let from = match opts.from {
+        Some(number) => find(number)?,
+        None => generations
+            .iter()
+            .rev()
+            .find(|g| g.number < to.number)
+            .cloned()
+            .ok_or(project::RollbackError::NoPreviousGeneration)?,
+    };
+
+    let config = ProjectConfig::load(project.nix_file.as_absolute_path()).unwrap_or_default();
+    let load = |generation: &project::Generation| {
+        crate::environment::load(generation.root.0.as_path(), &config).map_err(ExitError::temporary)
+    };
+    let changes = EnvDiff::between(&load(&from)?, &load(&to)?, nix::store::store_dir());
+
+    if opts.json {
+        #[derive(Serialize)]
+        struct DiffJson {
+            from: u64,
+            to: u64,
+            #[serde(flatten)]
+            changes: EnvDiff,
+        }
+        let json = DiffJson {
+            from: from.number,
+            to: to.number,
+            changes,
+        };
+        serde_json::to_writer(std::io::stdout(), &json).expect("couldn't serialize the diff");
+        println!();
+        return Ok(());
+    }
+
+    println!("generation {} -> {}", from.number, to.number);
+    if changes.is_empty() {
+        println!("no changes");
+    }
+    for (name, value) in &changes.added {
+        println!("+ {}={}", name, value);
+    }
+    for name in changes.removed.keys() {
+        println!("- {}", name);
+    }
+    for name in changes.changed.keys() {
+        println!("~ {}", name);
+    }
+    for path in &changes.added_store_paths {
+        println!("+ {}", path.display());
+    }
+    for path in &changes.removed_store_paths {
+        println!("- {}", path.display());
+    }
+    Ok(())
+}
+
+/// Install, update or remove the `use lorri` function in direnv’s library.
+///
+/// See the documentation for lorri::cli::Command::DirenvInstall for more
+/// details.
+pub fn direnv_install(
+    opts: cli::DirenvInstallOptions,
+    logger: &slog::Logger,
+) -> Result<(), ExitError> {
+    let file = direnv::integration_file().ok_or_else(|| {
+        ExitError::environment_problem(anyhow::anyhow!(
+            "could not find direnv’s configuration directory"
+        ))
+    })?;
+    let contents = match std::fs::read_to_string(&file) {
+        Ok(contents) => Some(contents),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(ExitError::temporary(e)),
+    };
+    let state = direnv::Integration::of(contents.as_deref());
+    if state == direnv::Integration::Foreign && !opts.force {
+        return Err(ExitError::user_error(anyhow::anyhow!(
+            "{} was not written by lorri, pass --force to replace it",
+            file.display()
+        )));
+    }
+    let path = file.display().to_string();
+
+    if opts.uninstall {
+        match state {
+            direnv::Integration::Missing => {
+                info!(logger, "the direnv integration is not installed"; "path" => path)
+            }
+            _ => {
+                std::fs::remove_file(&file).map_err(ExitError::temporary)?;
+                info!(logger, "removed the direnv integration"; "path" => path);
+            }
+        }
+        return Ok(());
+    }
+
+    match state {
+        direnv::Integration::Current => {
+            info!(logger, "the direnv integration is up to date"; "path" => path);
+        }
+        _ => {
+            if let Some(dir) = file.parent() {
+                std::fs::create_dir_all(dir).map_err(ExitError::temporary)?;
+            }
+            std::fs::write(&file, direnv::INTEGRATION).map_err(ExitError::temporary)?;
+            let msg = match state {
+                direnv::Integration::Stale => "updated a stale direnv integration",
+                direnv::Integration::Foreign => "replaced the file with the direnv integration",
+                _ => "installed the direnv integration",
+            };
+            info!(logger, "{}", msg; "path" => path);
+            info!(logger, "add `use lorri` to the .envrc of your projects");
+        }
+    }
+    Ok(())
+}
+
+/// A project, as printed by `lorri ps --json`.
+#[derive(Serialize)]
+pub(crate) struct ProjectJson {
+    nix_file: PathBuf,
+    /// The attribute of the nix file the project builds, if not the whole file
+    attr: Option<String>,
+    /// See `lorri name`
+    name: Option<String>,
+    status: &'static str,
+    /// Unix timestamps, in seconds.
+    build_started: u64,
+    last_build_finished: Option<u64>,
+    last_build_duration_ms: Option<u64>,
+}
+
+impl From<&daemon::ProjectStatus> for ProjectJson {
+    fn from(project: &daemon::ProjectStatus) -> ProjectJson {
+        let unix_secs = |time: std::time::SystemTime| {
+            time.duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs())
+        };
+        ProjectJson {
+            nix_file: project.nix_file.as_absolute_path().to_owned(),
+            attr: project.attr.clone(),
+            name: project_name(&project.nix_file, project.attr.as_deref()),
+            status: status_name(project.status),
+            build_started: unix_secs(project.build_started),
+            last_build_finished: project.last_build_finished.map(unix_secs),
+            last_build_duration_ms: project.last_build_duration.map(|d| d.as_millis() as u64),
+        }
+    }
+}
+
+/// The name of the project of `nix_file` and `attr`, see `lorri name`.
+fn project_name(nix_file: &NixFile, attr: Option<&str>) -> Option<String> {
+    let paths = get_paths().ok()?;
+    project::alias::name_of(
+        nix_file.as_absolute_path(),
+        attr,
+        paths.aliases_file().as_path(),
+    )
+}
+
+/// Name the project of `nix_file` and `attr` `name`, or print its name.
+///
+/// See the documentation for lorri::cli::Command::Name for details.
+pub fn name(
+    nix_file: &NixFile,
+    attr: Option<&str>,
+    name: Option<String>,
+    paths: &crate::constants::Paths,
+) -> Result<(), ExitError> {
+    let name = match name {
+        None => {
+            if let Some(name) = project_name(nix_file, attr) {
+                println!("{}", name);
+            }
+            return Ok(());
+        }
+        Some(name) => name,
+    };
+    if !project::alias::is_valid(&name) {
+        return Err(ExitError::user_error(anyhow::anyhow!(
+            "`{}` cannot be a name: it may only contain letters, digits, `-`, `_` and `.`, \
+             and must not start with `.` or end with `.nix`",
+            name
+        )));
+    }
+    project::alias::set(
+        paths.aliases_file().as_path(),
+        &name,
+        nix_file.as_absolute_path(),
+        attr,
+    )
+    .map_err(|e| {
+        ExitError::temporary(anyhow::Error::new(e).context(format!(
+            "Could not save the name in {}",
+            paths.aliases_file().display()
+        )))
+    })
+}
+
+/// How `lorri ps` displays a `BuildStatus`.
+fn status_name(status: daemon::BuildStatus) -> &'static str {
+    match status {
+        daemon::BuildStatus::Building => "building",
+        daemon::BuildStatus::Success => "success",
+        daemon::BuildStatus::Failure => "failure",
+    }
+}
+
+/// List the projects the daemon builds.
+///
+/// See the documentation for lorri::cli::Command::Ps for details.
+pub fn ps(opts: cli::PsOptions, logger: &slog::Logger) -> Result<(), ExitError> {
+    let client = client::create::<client::Projects>(client::Timeout::from_millis(1000), logger)?;
+    client.write(&client::Projects {})?;
+    let mut projects = client.read()?;
+    projects.sort_by(|a, b| {
+        (a.nix_file.as_absolute_path(), &a.attr).cmp(&(b.nix_file.as_absolute_path(), &b.attr))
+    });
+
+    if opts.json {
+        let projects: Vec<ProjectJson> = projects.iter().map(ProjectJson::from).collect();
+        serde_json::to_writer(std::io::stdout(), &projects).expect("couldn't serialize projects");
+        println!();
+        return Ok(());
     }
 
+    print_projects(&projects);
     Ok(())
 }
 
-/// Checks `direnv version` against the minimal version lorri requires.
-fn check_direnv_version() -> Result<(), ExitError> {
-    let out = with_command("direnv", |mut cmd| cmd.arg("version").output())?;
-    let version = std::str::from_utf8(&out.stdout)
-        .map_err(|_| ())
-        .and_then(|utf| utf.trim_end().parse::<DirenvVersion>())
-        .map_err(|()| {
-            ExitError::environment_problem(anyhow::anyhow!(
-                "Could not figure out the current `direnv` version (parse error)"
-            ))
-        })?;
-    if version < MIN_DIRENV_VERSION {
-        Err(ExitError::environment_problem(anyhow::anyhow!(
-            "`direnv` is version {}, but >= {} is required for lorri to function",
-            version,
-            MIN_DIRENV_VERSION
-        )))
-    } else {
-        Ok(())
+/// Print `projects` as the table of `lorri ps`.
+fn print_projects(projects: &[daemon::ProjectStatus]) {
+    println!(
+        "{:<9} {:>10} {:>9}  SHELL FILE",
+        "STATUS", "LAST BUILD", "DURATION"
+    );
+    let now = std::time::SystemTime::now();
+    for project in projects {
+        let last_build = match project.last_build_finished {
+            Some(finished) => format!(
+                "{} ago",
+                human_duration(now.duration_since(finished).unwrap_or_default())
+            ),
+            None => String::from("-"),
+        };
+        let duration = match (project.status, project.last_build_duration) {
+            (daemon::BuildStatus::Building, _) => format!(
+                "{}…",
+                human_duration(
+                    now.duration_since(project.build_started)
+                        .unwrap_or_default()
+                )
+            ),
+            (_, Some(duration)) => human_duration(duration),
+            (_, None) => String::from("-"),
+        };
+        let mut shell_file = project.nix_file.display().to_string();
+        if let Some(attr) = &project.attr {
+            shell_file.push_str(&format!(" -A {}", attr));
+        }
+        if let Some(name) = project_name(&project.nix_file, project.attr.as_deref()) {
+            shell_file.push_str(&format!(" ({})", name));
+        }
+        println!(
+            "{:<9} {:>10} {:>9}  {}",
+            status_name(project.status),
+            last_build,
+            duration,
+            shell_file
+        );
     }
 }
 
-/// constructs a `Command` out of `executable`
-/// Recognizes the case in which the executable is missing,
-/// and converts it to a corresponding `ExitError`.
-fn with_command<T, F>(executable: &str, cmd: F) -> Result<T, ExitError>
-where
-    F: FnOnce(Command) -> std::io::Result<T>,
-{
-    let res = cmd(Command::new(executable));
-    res.map_err(|err| match err.kind() {
-        std::io::ErrorKind::NotFound => {
-            ExitError::missing_executable(anyhow::anyhow!("`{}`: executable not found", executable))
+/// Show, rebuild, pause or resume the projects the daemon builds with a tag.
+///
+/// See the documentation for lorri::cli::Command::Group for details.
+pub fn group(opts: cli::GroupOptions, logger: &slog::Logger) -> Result<(), ExitError> {
+    let action = match opts.action.as_str() {
+        "status" => client::GroupAction::Status,
+        "rebuild" => client::GroupAction::Rebuild,
+        "pause" => client::GroupAction::Pause,
+        "resume" => client::GroupAction::Resume,
+        other => {
+            return Err(ExitError::user_error(anyhow::anyhow!(
+                "unknown action `{}`",
+                other
+            )))
         }
-        _ => ExitError::temporary(
-            anyhow::Error::new(err).context(format!("Could not start `{}`", executable)),
-        ),
-    })
-}
+    };
+    let client = client::create::<client::Group>(client::Timeout::from_millis(1000), logger)?;
+    client.write(&client::Group {
+        tag: opts.tag.clone(),
+        action,
+    })?;
+    let mut projects = client.read()?;
+    projects.sort_by(|a, b| {
+        (a.nix_file.as_absolute_path(), &a.attr).cmp(&(b.nix_file.as_absolute_path(), &b.attr))
+    });
+    if projects.is_empty() {
+        return Err(ExitError::user_error(anyhow::anyhow!(
+            "The daemon builds no project tagged `{}`",
+            opts.tag
+        )));
+    }
 
-/// The info callable is for printing
-///
-/// See the documentation for lorri::cli::Command::Info for more
-/// details.
-pub fn info(project: Project) -> Result<(), ExitError> {
-    let root_paths = project.root_paths();
-    let OutputPath { shell_gc_root } = &root_paths;
-    if root_paths.all_exist() {
+    if opts.json {
+        let projects: Vec<ProjectJson> = projects.iter().map(ProjectJson::from).collect();
+        serde_json::to_writer(std::io::stdout(), &projects).expect("couldn't serialize projects");
+        println!();
+    } else {
+        print_projects(&projects);
+        let count = |status| projects.iter().filter(|p| p.status == status).count();
         println!(
-            "GC roots exist, shell_gc_root: {}",
-            shell_gc_root.0.display()
+            "{} projects: {} building, {} success, {} failure",
+            projects.len(),
+            count(daemon::BuildStatus::Building),
+            count(daemon::BuildStatus::Success),
+            count(daemon::BuildStatus::Failure)
         );
-    } else {
-        println!("GC roots do not exist. Has the project been built with lorri yet?",);
+    }
+    match action {
+        client::GroupAction::Status => {}
+        client::GroupAction::Rebuild => {
+            info!(logger, "rebuilding projects"; "tag" => &opts.tag, "projects" => projects.len())
+        }
+        client::GroupAction::Pause => {
+            info!(logger, "paused builds"; "tag" => &opts.tag, "projects" => projects.len())
+        }
+        client::GroupAction::Resume => {
+            info!(logger, "resumed builds"; "tag" => &opts.tag, "projects" => projects.len())
+        }
     }
     Ok(())
 }
 
-/// Bootstrap a new lorri project
+/// List the recent builds of `project`, and statistics about all recorded builds.
 ///
-/// See the documentation for lorri::cli::Command::Init for
-/// more details
-pub fn init(
-    default_shell: &str,
-    default_envrc: &str,
-    logger: &slog::Logger,
-) -> Result<(), ExitError> {
-    create_if_missing(
-        Path::new("./shell.nix"),
-        default_shell,
-        "Make sure shell.nix is of a form that works with nix-shell.",
-        logger,
-    )
-    .map_err(ExitError::user_error)?;
-
-    create_if_missing(
-        Path::new("./.envrc"),
-        default_envrc,
-        "Please add 'eval \"$(lorri direnv)\"' to .envrc to set up lorri support.",
-        logger,
-    )
-    .map_err(ExitError::user_error)?;
+/// See the documentation for lorri::cli::Command::History for details.
+pub fn history(project: Project, opts: cli::HistoryOptions) -> Result<(), ExitError> {
+    let records = project::history::read(project.history_file().as_path())
+        .map_err(|e| ExitError::temporary(anyhow::Error::new(e)))?;
+    let summary = project::history::Summary::of(&records);
+    let recent = &records[records.len().saturating_sub(opts.limit)..];
+
+    if opts.json {
+        serde_json::to_writer(
+            std::io::stdout(),
+            &serde_json::json!({ "builds": recent, "summary": summary }),
+        )
+        .expect("couldn't serialize the history");
+        println!();
+        return Ok(());
+    }
 
-    info!(logger, "done");
+    println!(
+        "{:>10} {:>9}  {:<9}  REASON",
+        "STARTED", "DURATION", "OUTCOME"
+    );
+    let now = std::time::SystemTime::now();
+    for record in recent.iter().rev() {
+        let started = std::time::UNIX_EPOCH + Duration::from_millis(record.started_ms);
+        println!(
+            "{:>10} {:>9}  {:<9}  {}",
+            format!(
+                "{} ago",
+                human_duration(now.duration_since(started).unwrap_or_default())
+            ),
+            human_duration(Duration::from_millis(record.duration_ms)),
+            record.outcome.name(),
+            record.reason
+        );
+    }
+    match summary {
+        Some(summary) => println!(
+            "{} builds, {} succeeded, average {}, p95 {}",
+            summary.builds,
+            summary.successes,
+            human_duration(summary.average()),
+            human_duration(summary.p95())
+        ),
+        None => println!("the daemon has not finished a build of the project yet"),
+    }
     Ok(())
 }
 
-fn create_if_missing(
-    path: &Path,
-    contents: &str,
-    msg: &str,
-    logger: &slog::Logger,
-) -> Result<(), io::Error> {
-    if path.exists() {
-        info!(logger, "file already exists, skipping"; "path" => path.to_str(), "message" => msg);
-        Ok(())
-    } else {
-        let mut f = File::create(path)?;
-        f.write_all(contents.as_bytes())?;
-        info!(logger, "wrote file"; "path" => path.to_str());
-        Ok(())
+/// Format a duration in its largest unit, e.g. `3m`.
+fn human_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m", secs / 60),
+        3600..=86399 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
     }
 }
 
-/// Run a BuildLoop for `shell.nix`, watching for input file changes.
-///
-/// Can be used together with `direnv`.
-/// See the documentation for lorri::cli::Command::Ping_ for details.
-pub fn ping(nix_file: NixFile, logger: &slog::Logger) -> Result<(), ExitError> {
-    client::create(client::Timeout::from_millis(500), logger)?.write(&client::Ping {
-        nix_file,
-        rebuild: client::Rebuild::Always,
-    })?;
-    Ok(())
-}
-
 /// Open up a project shell
 ///
 /// This is the entry point for the `lorri shell` command.
@@ -325,12 +1797,21 @@ pub fn shell(project: Project, opts: ShellOptions, logger: &slog::Logger) -> Res
     let lorri = env::current_exe()
         .with_context(|| "failed to determine lorri executable's path")
         .map_err(ExitError::environment_problem)?;
-    let shell = env::var_os("SHELL").ok_or_else(|| {
-        ExitError::environment_problem(anyhow::anyhow!(
-            "`lorri shell` requires the `SHELL` environment variable to be set"
-        ))
-    })?;
-    let user = project::Username::from_env_var().map_err(ExitError::environment_problem)?;
+    let shell = match env::var_os("SHELL") {
+        Some(shell) if is_supported_shell(Path::new(&shell)) => shell,
+        Some(shell) => {
+            warn!(logger, "lorri shell does not support your shell, starting bash instead"; "shell" => Path::new(&shell).display());
+            OsString::from("bash")
+        }
+        None => {
+            warn!(
+                logger,
+                "the SHELL environment variable is not set, starting bash"
+            );
+            OsString::from("bash")
+        }
+    };
+    let user = project::Username::current().map_err(ExitError::environment_problem)?;
     let cached = cached_root(&project);
     let mut bash_cmd = bash_cmd(
         if opts.cached {
@@ -338,24 +1819,26 @@ pub fn shell(project: Project, opts: ShellOptions, logger: &slog::Logger) -> Res
         } else {
             build_root(&project, cached.is_ok(), user, logger)?
         },
-        &project.cas,
+        &project,
         logger,
     )?;
 
     debug!(logger, "bash_cmd : {:?}", bash_cmd);
-    let status = bash_cmd
-        .args(&[
-            OsStr::new("-c"),
-            OsStr::new(
-                "exec \"$1\" internal start-user-shell --shell-path=\"$2\" --shell-file=\"$3\"",
-            ),
-            OsStr::new("--"),
-            &lorri.as_os_str(),
-            &shell,
-            project.nix_file.as_absolute_path().as_os_str(),
-        ])
-        .status()
-        .expect("failed to execute bash");
+    bash_cmd.args([
+        OsStr::new("-c"),
+        OsStr::new(match project.attr {
+            Some(_) => "exec \"$1\" internal start-user-shell --shell-path=\"$2\" --shell-file=\"$3\" --attr=\"$4\"",
+            None => "exec \"$1\" internal start-user-shell --shell-path=\"$2\" --shell-file=\"$3\"",
+        }),
+        OsStr::new("--"),
+        lorri.as_os_str(),
+        &shell,
+        project.nix_file.as_absolute_path().as_os_str(),
+    ]);
+    if let Some(attr) = &project.attr {
+        bash_cmd.arg(attr);
+    }
+    let status = bash_cmd.status().expect("failed to execute bash");
 
     if !status.success() {
         Err(ExitError::panic(anyhow::anyhow!(
@@ -405,6 +1888,7 @@ fn build_root(
     // TODO: add the ability to pass extra_nix_options to shell
     let run_result = builder::run(
         &project.nix_file,
+        project.attr.as_deref(),
         &project.cas,
         &crate::nix::options::NixOptions::empty(),
         &logger2,
@@ -412,34 +1896,35 @@ fn build_root(
     building.store(false, Ordering::SeqCst);
     progress_thread.block();
 
-    let run_result = run_result
-        .map_err(|e| {
-            if cached {
-                ExitError::temporary(anyhow::anyhow!(
-                    "Build failed. Hint: try running `lorri shell --cached` to use the most \
-                     recent environment that was built successfully.\n\
-                     Build error: {}",
-                    e
-                ))
-            } else {
-                ExitError::temporary(anyhow::anyhow!(
-                    "Build failed. No cached environment available.\n\
-                     Build error: {}",
-                    e
-                ))
-            }
-        })?
-        .result;
+    let run_result = run_result.map_err(|e| {
+        if cached {
+            ExitError::temporary(anyhow::anyhow!(
+                "Build failed. Hint: try running `lorri shell --cached` to use the most \
+                 recent environment that was built successfully.\n\
+                 Build error: {}",
+                e
+            ))
+        } else {
+            ExitError::temporary(anyhow::anyhow!(
+                "Build failed. No cached environment available.\n\
+                 Build error: {}",
+                e
+            ))
+        }
+    })?;
 
-    Ok(project
-        .create_roots(run_result, user, &logger2)
+    let shell_gc_root = project
+        .lock()
+        .and_then(|_lock| {
+            let roots = project.create_roots(run_result.result, user.clone(), &logger2)?;
+            project.root_build_inputs(&run_result.build_inputs, &user, &logger2)?;
+            Ok(roots)
+        })
         .map_err(|e| {
             ExitError::temporary(anyhow::Error::new(e).context("rooting the environment failed"))
         })?
-        .shell_gc_root
-        .0
-        .as_path()
-        .to_owned())
+        .shell_gc_root;
+    Ok(shell_gc_root.0.as_path().to_owned())
 }
 
 fn cached_root(project: &Project) -> Result<PathBuf, ExitError> {
@@ -456,16 +1941,20 @@ fn cached_root(project: &Project) -> Result<PathBuf, ExitError> {
 /// Instantiates a `Command` to start bash.
 pub fn bash_cmd(
     project_root: PathBuf,
-    cas: &ContentAddressable,
+    project: &Project,
     logger: &slog::Logger,
 ) -> Result<Command, ExitError> {
-    let init_file = cas
+    let config = ProjectConfig::load(project.nix_file.as_absolute_path()).unwrap_or_default();
+    let init_file = project
+        .cas
         .file_from_string(&format!(
             r#"
-EVALUATION_ROOT="{}"
+EVALUATION_ROOT={}
 
+{}
 {}"#,
-            project_root.display(),
+            quote::bash_path(&project_root),
+            config.envrc_prelude(),
             include_str!("./ops/direnv/envrc.bash")
         ))
         .expect("failed to write shell output");
@@ -476,13 +1965,7 @@ EVALUATION_ROOT="{}"
         .expect("failed to get runtime closure path");
 
     let mut cmd = Command::new(bash_path.join("bash"));
-    cmd.env(
-        "BASH_ENV",
-        init_file
-            .as_path()
-            .to_str()
-            .expect("script file path not UTF-8 clean"),
-    );
+    cmd.env("BASH_ENV", init_file.as_path());
     Ok(cmd)
 }
 
@@ -501,6 +1984,16 @@ pub fn start_user_shell(project: Project, opts: StartUserShellOptions_) -> Resul
     panic!("failed to exec into '{}': {}", opts.shell_path.display(), e);
 }
 
+/// The shells `lorri shell` knows how to start, see `shell_cmd`.
+const SUPPORTED_SHELLS: &[&str] = &["bash", "zsh", "fish"];
+
+fn is_supported_shell(shell_path: &Path) -> bool {
+    shell_path
+        .file_name()
+        .and_then(OsStr::to_str)
+        .map_or(false, |name| SUPPORTED_SHELLS.contains(&name))
+}
+
 fn shell_cmd(shell_path: &Path, cas: &ContentAddressable, tempdir: &Path) -> Command {
     let mut cmd = Command::new(shell_path);
 
@@ -524,13 +2017,7 @@ PS1="(lorri) $PS1"
 "#,
                 )
                 .expect("failed to write bash init script");
-            cmd.args(&[
-                "--rcfile",
-                rcfile
-                    .as_path()
-                    .to_str()
-                    .expect("file path not UTF-8 clean"),
-            ]);
+            cmd.arg("--rcfile").arg(rcfile.as_path());
         }
         "zsh" => {
             // Zsh does not support anything like bash's --rcfile. However, zsh sources init
@@ -565,7 +2052,21 @@ PS1="(lorri) ${PS1}"
             }
             cmd.env("ZDOTDIR", tempdir);
         }
-        // Add handling for other supported shells here.
+        "fish" => {
+            // fish runs the init command after its configuration, so the prompt
+            // is already defined and we can wrap it.
+            cmd.args([
+                "--init-command",
+                r#"
+functions --copy fish_prompt __lorri_fish_prompt
+function fish_prompt
+    echo -n "(lorri) "
+    __lorri_fish_prompt
+end
+"#,
+            ]);
+        }
+        // Add handling for other supported shells here, and to `SUPPORTED_SHELLS`.
         _ => {}
     }
     cmd
@@ -602,7 +2103,53 @@ impl FromStr for EventKind {
 /// and it relates to this interface.
 #[derive(Serialize)]
 #[serde(transparent)]
-struct StreamEvent(EventI<StreamNixFile, StreamReason, StreamOutputPath, StreamBuildError>);
+pub(crate) struct StreamEvent(
+    EventI<StreamNixFile, StreamReason, StreamOutputPath, StreamBuildError>,
+);
+
+impl From<Event> for StreamEvent {
+    fn from(ev: Event) -> StreamEvent {
+        fn nix_file_string(nix_file: NixFile) -> String {
+            nix_file.display().to_string()
+        }
+        StreamEvent(ev.map(
+            |nix_file| StreamNixFile(nix_file_string(nix_file)),
+            |reason| StreamReason(reason.map(nix_file_string)),
+            |output_path| StreamOutputPath(output_path.map(|o| o.display().to_string())),
+            |build_error| StreamBuildError {
+                message: format!("{}", build_error),
+                diagnostics: diagnostics::of_build_error(&build_error),
+            },
+        ))
+    }
+}
+
+/// A `StreamEvent` with its sequence number, which can be passed to `--since`
+/// when reconnecting.
+#[derive(Serialize)]
+pub(crate) struct SequencedStreamEvent {
+    seq: u64,
+    /// See `daemon::EventCursor`
+    cursor: String,
+    /// The name of the project the event is about, see `lorri name`
+    name: Option<String>,
+    #[serde(flatten)]
+    event: StreamEvent,
+}
+
+impl From<daemon::SequencedEvent> for SequencedStreamEvent {
+    fn from(ev: daemon::SequencedEvent) -> SequencedStreamEvent {
+        SequencedStreamEvent {
+            seq: ev.seq,
+            cursor: ev.cursor().to_string(),
+            name: ev
+                .event
+                .nix_file()
+                .and_then(|nix_file| project_name(nix_file, ev.event.attr())),
+            event: StreamEvent::from(ev.event),
+        }
+    }
+}
 
 /// Nix files are encoded as strings
 #[derive(Serialize)]
@@ -619,18 +2166,23 @@ struct StreamReason(ReasonI<String>);
 #[serde(transparent)]
 struct StreamOutputPath(OutputPath<String>);
 
-/// Just expose the error message for now.
+/// The error message, and where in the nix files the error is.
 #[derive(Serialize)]
 struct StreamBuildError {
     message: String,
+    diagnostics: Vec<Diagnostic>,
 }
 
 /// Run to output a stream of build events in a machine-parseable form.
 ///
 /// See the documentation for lorri::cli::Command::StreamEvents_ for more
 /// details.
-pub fn stream_events(kind: EventKind, logger: &slog::Logger) -> Result<(), ExitError> {
-    let (tx_event, rx_event) = chan::unbounded::<Event>();
+pub fn stream_events(
+    kind: EventKind,
+    since: Option<daemon::EventCursor>,
+    logger: &slog::Logger,
+) -> Result<(), ExitError> {
+    let (tx_event, rx_event) = chan::unbounded::<daemon::SequencedEvent>();
 
     let thread = {
         let address = crate::ops::get_paths()?.daemon_socket_file().clone();
@@ -645,7 +2197,7 @@ pub fn stream_events(kind: EventKind, logger: &slog::Logger) -> Result<(), ExitE
                 &logger2,
             )?;
 
-            client.write(&client::StreamEvents {})?;
+            client.write(&client::StreamEvents { since })?;
             loop {
                 let res = client.read();
                 tx_event
@@ -662,7 +2214,7 @@ pub fn stream_events(kind: EventKind, logger: &slog::Logger) -> Result<(), ExitE
     loop {
         chan::select! {
             recv(rx_event) -> event => match event.expect("rx_event hung up!") {
-                Event::SectionEnd => {
+                daemon::SequencedEvent { event: Event::SectionEnd, .. } => {
                     debug!(logger, "SectionEnd");
                     match kind {
                         // If we only want the snapshot, quit the program
@@ -673,24 +2225,9 @@ pub fn stream_events(kind: EventKind, logger: &slog::Logger) -> Result<(), ExitE
                 }
                 ev => match (snapshot_done, &kind) {
                     (_, EventKind::All) | (false, EventKind::Snapshot) | (true, EventKind::Live) => {
-                        fn nix_file_string(nix_file: NixFile) -> String {
-                            nix_file.display().to_string()
-                        }
-                        serde_json::to_writer(
-                            std::io::stdout(),
-                            &StreamEvent(ev.map(
-                                |nix_file| StreamNixFile(nix_file_string(nix_file)),
-                                |reason| StreamReason(reason.map(nix_file_string)),
-                                |output_path| {
-                                    StreamOutputPath(output_path.map(|o| o.display().to_string()))
-                                },
-                                |build_error| StreamBuildError {
-                                    message: format!("{}", build_error),
-                                },
-                            )),
-                        )
+                        serde_json::to_writer(std::io::stdout(), &SequencedStreamEvent::from(ev))
                             .expect("couldn't serialize event");
-                        write!(std::io::stdout(), "\n").expect("couldn't serialize event");
+                        writeln!(std::io::stdout()).expect("couldn't serialize event");
                         std::io::stdout().flush().expect("couldn't flush serialized event");
                     }
                     _ => (),
@@ -705,6 +2242,263 @@ pub fn stream_events(kind: EventKind, logger: &slog::Logger) -> Result<(), ExitE
     }
 }
 
+/// Print the state of the project’s builds from its status file,
+/// without asking the daemon.
+///
+/// See the documentation for lorri::cli::Command::Status for more details.
+pub fn status(
+    nix_file: &NixFile,
+    attr: Option<&str>,
+    gc_root_dir: &crate::AbsPathBuf,
+) -> Result<(), ExitError> {
+    let status_file = project::status_file_of(nix_file, attr, gc_root_dir);
+    let status = project::status::read(status_file.as_path()).map_err(|e| {
+        ExitError::temporary(
+            anyhow::Error::new(e).context(format!("Could not read {}", status_file.display())),
+        )
+    })?;
+    // changes to these start a new build
+    let config_file = nix_file
+        .as_absolute_path()
+        .with_file_name(project::config::CONFIG_FILE_NAME);
+    let last_change = [nix_file.as_absolute_path(), config_file.as_path()]
+        .iter()
+        .filter_map(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+        .max();
+    println!(
+        "{}",
+        project::status::describe(status.as_ref(), last_change)
+    );
+    Ok(())
+}
+
+/// The diagnostics of a file after a build of a project,
+/// as printed by `lorri internal stream-diagnostics`.
+#[derive(Serialize, Debug, PartialEq)]
+struct FileDiagnostics {
+    nix_file: PathBuf,
+    attr: Option<String>,
+    file: PathBuf,
+    diagnostics: Vec<Diagnostic>,
+}
+
+/// The files of each project (i.e. attribute of a nix file)
+/// which have diagnostics right now.
+#[derive(Default)]
+struct PublishedDiagnostics(HashMap<(NixFile, Option<String>), BTreeSet<PathBuf>>);
+
+impl PublishedDiagnostics {
+    /// The diagnostics to print after `event`: the ones of the files
+    /// the failed build of its project points into, and empty ones
+    /// for the files in which that project’s errors are gone.
+    /// The diagnostics of other attributes of the nix file are kept.
+    fn update(&mut self, event: Event) -> Vec<FileDiagnostics> {
+        let (key, diagnostics) = match event {
+            Event::Completed { nix_file, attr, .. } | Event::Removed { nix_file, attr } => {
+                ((nix_file, attr), vec![])
+            }
+            Event::Failure {
+                nix_file,
+                attr,
+                failure,
+            } => ((nix_file, attr), diagnostics::of_build_error(&failure)),
+            _ => return vec![],
+        };
+        let mut files: BTreeMap<PathBuf, Vec<Diagnostic>> = BTreeMap::new();
+        for diagnostic in diagnostics {
+            files
+                .entry(diagnostic.file.clone())
+                .or_default()
+                .push(diagnostic);
+        }
+        // the errors in these files are gone
+        for file in self.0.remove(&key).unwrap_or_default() {
+            files.entry(file).or_default();
+        }
+        let with_errors: BTreeSet<PathBuf> = files
+            .iter()
+            .filter(|(_, diagnostics)| !diagnostics.is_empty())
+            .map(|(file, _)| file.clone())
+            .collect();
+        if !with_errors.is_empty() {
+            self.0.insert(key.clone(), with_errors);
+        }
+        let (nix_file, attr) = key;
+        files
+            .into_iter()
+            .map(|(file, diagnostics)| FileDiagnostics {
+                nix_file: nix_file.as_absolute_path().to_owned(),
+                attr: attr.clone(),
+                file,
+                diagnostics,
+            })
+            .collect()
+    }
+}
+
+/// Print the diagnostics of each file after every build of the daemon.
+///
+/// See the documentation for lorri::cli::Internal_::StreamDiagnostics_ for more details.
+pub fn stream_diagnostics(logger: &slog::Logger) -> Result<(), ExitError> {
+    let client = client::create::<client::StreamEvents>(
+        // infinite timeout because we are listening indefinitely
+        client::Timeout::Infinite,
+        logger,
+    )?;
+    client.write(&client::StreamEvents { since: None })?;
+    let mut published = PublishedDiagnostics::default();
+    let stdout = std::io::stdout();
+    let mut stdout = stdout.lock();
+    loop {
+        let event = client
+            .read()
+            .map_err(|err| ExitError::temporary(anyhow::Error::new(err)))?
+            .event;
+        for diagnostics in published.update(event) {
+            serde_json::to_writer(&mut stdout, &diagnostics)
+                .expect("couldn't serialize diagnostics");
+            writeln!(stdout).expect("couldn't write diagnostics");
+        }
+        stdout.flush().expect("couldn't flush diagnostics");
+    }
+}
+
+/// A project’s GC roots, as printed by `lorri internal gc-roots --json`.
+#[derive(Serialize)]
+struct GcRootJson {
+    id: String,
+    nix_file: Option<String>,
+    project_dir: String,
+    shell_gc_root: String,
+    store_path: Option<String>,
+    created: Option<u64>,
+    last_used: Option<u64>,
+    reverse_root_exists: Option<bool>,
+}
+
+/// List the GC roots of all projects.
+///
+/// See the documentation for lorri::cli::Internal_::GcRoots_ for more details.
+pub fn gc_roots(opts: cli::GcRoots_, gc_root_dir: &crate::AbsPathBuf) -> Result<(), ExitError> {
+    let user = project::Username::current().map_err(ExitError::environment_problem)?;
+    let roots = project::list_roots(gc_root_dir).map_err(|e| {
+        ExitError::temporary(anyhow::Error::new(e).context(format!(
+            "Could not list the GC roots in {}",
+            gc_root_dir.display()
+        )))
+    })?;
+
+    let unix_secs = |t: std::time::SystemTime| {
+        t.duration_since(std::time::UNIX_EPOCH)
+            .ok()
+            .map(|d| d.as_secs())
+    };
+    let display = |p: &Path| p.display().to_string();
+    if opts.json {
+        let roots: Vec<GcRootJson> = roots
+            .iter()
+            .map(|root| GcRootJson {
+                id: root.hash.clone(),
+                nix_file: root.nix_file.as_deref().map(display),
+                project_dir: display(root.project_dir.as_path()),
+                shell_gc_root: display(root.shell_gc_root.0.as_path()),
+                store_path: root.store_path().as_deref().map(display),
+                created: root.created().and_then(unix_secs),
+                last_used: root.last_used().and_then(unix_secs),
+                reverse_root_exists: root.reverse_root_exists(&user),
+            })
+            .collect();
+        serde_json::to_writer(std::io::stdout(), &roots).expect("couldn't serialize GC roots");
+        println!();
+    } else {
+        for root in roots {
+            println!(
+                "{}  {}  {}",
+                root.hash,
+                root.nix_file
+                    .as_deref()
+                    .map_or_else(|| String::from("<unknown project>"), display),
+                root.store_path()
+                    .as_deref()
+                    .map_or_else(|| String::from("<no root>"), display)
+            );
+        }
+    }
+    Ok(())
+}
+
+/// A watched path, as printed by `lorri internal watched-paths --json`.
+#[derive(Serialize)]
+struct WatchedPathJson {
+    path: String,
+    recursive: bool,
+    reason: String,
+}
+
+impl From<&crate::watch::WatchedPath> for WatchedPathJson {
+    fn from(watched: &crate::watch::WatchedPath) -> WatchedPathJson {
+        WatchedPathJson {
+            path: watched.path.as_ref().display().to_string(),
+            recursive: is_recursive(&watched.path),
+            reason: watched.reason.to_string(),
+        }
+    }
+}
+
+fn is_recursive(path: &crate::watch::WatchPathBuf) -> bool {
+    match path {
+        crate::watch::WatchPathBuf::Recursive(_) => true,
+        crate::watch::WatchPathBuf::Normal(_) => false,
+    }
+}
+
+/// A watched path, a `/**` suffix if it is watched recursively, and why.
+fn display_watched_path(watched: &crate::watch::WatchedPath) -> String {
+    format!(
+        "{}{}  ({})",
+        watched.path.as_ref().display(),
+        if is_recursive(&watched.path) {
+            "/**"
+        } else {
+            ""
+        },
+        watched.reason
+    )
+}
+
+/// Print the paths the daemon watches for `nix_file`, and why.
+///
+/// See the documentation for lorri::cli::Internal_::WatchedPaths_ for more details.
+pub fn watched_paths(
+    opts: cli::WatchedPaths_,
+    nix_file: NixFile,
+    logger: &slog::Logger,
+) -> Result<(), ExitError> {
+    let client =
+        client::create::<client::WatchedPaths>(client::Timeout::from_millis(1000), logger)?;
+    client.write(&client::WatchedPaths {
+        nix_file: nix_file.clone(),
+        attr: opts.attr.clone(),
+    })?;
+    let paths = client.read()?.ok_or_else(|| {
+        ExitError::user_error(anyhow::anyhow!(
+            "The daemon does not watch {} (yet), enter the project (e.g. with `lorri direnv`) first",
+            nix_file.display()
+        ))
+    })?;
+
+    if opts.json {
+        let paths: Vec<WatchedPathJson> = paths.iter().map(WatchedPathJson::from).collect();
+        serde_json::to_writer(std::io::stdout(), &paths).expect("couldn't serialize watched paths");
+        println!();
+    } else {
+        for watched in &paths {
+            println!("{}", display_watched_path(watched));
+        }
+    }
+    Ok(())
+}
+
 /// The source to upgrade to.
 enum UpgradeSource {
     /// A branch in the upstream git repo
@@ -806,7 +2600,7 @@ pub fn upgrade(
             UpgradeSource::Local(ref p) => println!("Upgrading from local path: {}", p.display()),
         }
 
-        let mut expr = nix::CallOpts::file(&upgrade_expr.as_path());
+        let mut expr = nix::CallOpts::file(upgrade_expr.as_path());
 
         match src {
             UpgradeSource::Branch(b) => {
@@ -869,53 +2663,62 @@ pub fn upgrade(
 ///
 /// See the documentation for lorri::cli::Command::Shell for more
 /// details.
-pub fn watch(project: Project, opts: WatchOptions, logger: &slog::Logger) -> Result<(), ExitError> {
-    let user = project::Username::from_env_var().map_err(ExitError::temporary)?;
+pub fn watch(
+    project: Project,
+    opts: WatchOptions,
+    config: &Config,
+    logger: &slog::Logger,
+) -> Result<(), ExitError> {
+    let user = project::Username::current().map_err(ExitError::temporary)?;
     if opts.once {
-        main_run_once(project, user, logger)
+        main_run_once(project, user, opts.timeout, config, logger)
     } else {
-        main_run_forever(project, user, logger)
+        main_run_forever(project, user, config, logger)
     }
 }
 
 fn main_run_once(
     project: Project,
     user: project::Username,
+    build_timeout_secs: Option<u64>,
+    config: &Config,
     logger: &slog::Logger,
 ) -> Result<(), ExitError> {
-    // TODO: add the ability to pass extra_nix_options to watch
-    let mut build_loop = BuildLoop::new(&project, NixOptions::empty(), user, logger.clone())
-        .map_err(ExitError::temporary)?;
-    match build_loop.once() {
+    let mut build_loop = BuildLoop::new(
+        &project,
+        extra_nix_options(config.extra_nix_options.clone()),
+        None,
+        user,
+        logger.clone(),
+    )
+    .map_err(ExitError::temporary)?;
+    match build_loop.once(build_timeout_secs) {
         Ok(msg) => {
             info!(logger, "build message"; "message" => ?msg);
             Ok(())
         }
-        Err(e) => {
-            if e.is_actionable() {
-                // TODO: implement std::io::Error for BuildError to get a backtrace
-                Err(ExitError::expected_error(anyhow::anyhow!("{:#?}", e)))
-            } else {
-                // TODO: implement std::io::Error for BuildError to get a backtrace
-                Err(ExitError::temporary(anyhow::Error::msg(e)))
-            }
-        }
+        Err(e) => Err(BuildFailure::exit_error(e)),
     }
 }
 
 fn main_run_forever(
     project: Project,
     user: project::Username,
+    config: &Config,
     logger: &slog::Logger,
 ) -> Result<(), ExitError> {
     let (tx_build_results, rx_build_results) = chan::unbounded();
     let (tx_ping, rx_ping) = chan::unbounded();
     let logger2 = logger.clone();
-    // TODO: add the ability to pass extra_nix_options to watch
+    let extra_nix_options = extra_nix_options(config.extra_nix_options.clone());
+    let poll_interval = config.poll_interval();
     let build_thread = {
         Async::run(logger, move || {
-            match BuildLoop::new(&project, NixOptions::empty(), user, logger2) {
-                Ok(mut bl) => bl.forever(tx_build_results, rx_ping).never(),
+            match BuildLoop::new(&project, extra_nix_options, poll_interval, user, logger2) {
+                Ok(mut bl) => {
+                    bl.forever(tx_build_results, rx_ping);
+                    Ok(())
+                }
                 Err(e) => Err(ExitError::temporary(e)),
             }
         })
@@ -930,3 +2733,73 @@ fn main_run_forever(
 
     build_thread.block()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AbsPathBuf;
+
+    /// Two attributes of one nix file have their own diagnostics,
+    /// so a build of one doesn’t clear the errors of the other.
+    #[test]
+    fn diagnostics_of_attributes_are_separate() {
+        let nix_file = NixFile::from(AbsPathBuf::new_unchecked(PathBuf::from(
+            "/project/shell.nix",
+        )));
+        let attr = |name: &str| Some(String::from(name));
+        let failure = |attr: Option<String>, line: &str| Event::Failure {
+            nix_file: nix_file.clone(),
+            attr,
+            failure: builder::BuildError::Exit {
+                cmd: String::from("nix-instantiate"),
+                status: Some(1),
+                logs: vec![builder::LogLine::from(String::from(line))],
+            },
+        };
+        let completed = |attr: Option<String>| Event::Completed {
+            nix_file: nix_file.clone(),
+            attr,
+            rooted_output_paths: OutputPath {
+                shell_gc_root: project::RootPath(AbsPathBuf::new_unchecked(PathBuf::from(
+                    "/gc_root/shell_gc_root",
+                ))),
+            },
+            changes: None,
+        };
+        let printed = |diagnostics: Vec<FileDiagnostics>| -> Vec<(Option<String>, String, usize)> {
+            diagnostics
+                .into_iter()
+                .map(|d| (d.attr, d.file.display().to_string(), d.diagnostics.len()))
+                .collect()
+        };
+
+        let mut published = PublishedDiagnostics::default();
+        assert_eq!(
+            printed(published.update(failure(
+                attr("shells.dev"),
+                "error: undefined variable 'foo' at /project/dev.nix:3:5"
+            ))),
+            vec![(attr("shells.dev"), String::from("/project/dev.nix"), 1)]
+        );
+        assert_eq!(
+            printed(published.update(failure(
+                attr("shells.ci"),
+                "error: undefined variable 'bar' at /project/ci.nix:1:1"
+            ))),
+            vec![(attr("shells.ci"), String::from("/project/ci.nix"), 1)]
+        );
+        // only the errors of the attribute which was built are gone
+        assert_eq!(
+            printed(published.update(completed(attr("shells.dev")))),
+            vec![(attr("shells.dev"), String::from("/project/dev.nix"), 0)]
+        );
+        assert!(published.update(completed(attr("shells.dev"))).is_empty());
+        assert_eq!(
+            printed(published.update(Event::Removed {
+                nix_file: nix_file.clone(),
+                attr: attr("shells.ci"),
+            })),
+            vec![(attr("shells.ci"), String::from("/project/ci.nix"), 0)]
+        );
+    }
+}
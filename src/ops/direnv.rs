@@ -1,3 +1,8 @@
+use super::quote;
+use crate::cas::ContentAddressable;
+use crate::project::config::{PathMerge, ProjectConfig};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
 #[derive(PartialEq, Eq, Debug)]
@@ -40,6 +45,325 @@ impl PartialOrd for DirenvVersion {
     }
 }
 
+/// The shells `lorri direnv` can render the environment for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DirenvShell {
+    /// The script direnv evaluates, including its `watch_file` calls
+    Bash,
+    /// `set -gx` statements
+    Fish,
+    /// `set-env` statements
+    Elvish,
+    /// `setenv` statements
+    Tcsh,
+}
+
+impl FromStr for DirenvShell {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bash" => Ok(DirenvShell::Bash),
+            "fish" => Ok(DirenvShell::Fish),
+            "elvish" => Ok(DirenvShell::Elvish),
+            "tcsh" => Ok(DirenvShell::Tcsh),
+            _ => Err(format!("{} not in bash,fish,elvish,tcsh", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for DirenvShell {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.write_str(match self {
+            DirenvShell::Bash => "bash",
+            DirenvShell::Fish => "fish",
+            DirenvShell::Elvish => "elvish",
+            DirenvShell::Tcsh => "tcsh",
+        })
+    }
+}
+
+/// The `use_lorri` function `lorri direnv-install` puts into direnv’s library.
+pub const INTEGRATION: &str = include_str!("./direnv/use_lorri.sh");
+
+/// The first line of every version of `INTEGRATION`,
+/// which tells our copies apart from the user’s own files.
+const INTEGRATION_MARKER: &str = "managed by `lorri direnv-install`";
+
+/// The name of the integration in direnv’s library.
+const INTEGRATION_FILE_NAME: &str = "lorri.sh";
+
+/// The state of a file where the integration belongs.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Integration {
+    /// There is no file.
+    Missing,
+    /// The file is the integration of this lorri version.
+    Current,
+    /// The file is the integration of another lorri version.
+    Stale,
+    /// The file was not written by lorri.
+    Foreign,
+}
+
+impl Integration {
+    /// The state of a file with `contents`, `None` if it doesn’t exist.
+    pub fn of(contents: Option<&str>) -> Integration {
+        match contents {
+            None => Integration::Missing,
+            Some(c) if c == INTEGRATION => Integration::Current,
+            Some(c)
+                if c.lines()
+                    .next()
+                    .map_or(false, |l| l.contains(INTEGRATION_MARKER)) =>
+            {
+                Integration::Stale
+            }
+            Some(_) => Integration::Foreign,
+        }
+    }
+}
+
+/// Where direnv loads its library from: `lib` in `DIRENV_CONFIG`,
+/// or in `direnv` in the user’s configuration directory.
+pub fn integration_file() -> Option<PathBuf> {
+    let config = match std::env::var_os("DIRENV_CONFIG") {
+        Some(dir) => PathBuf::from(dir),
+        None => match std::env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir).join("direnv"),
+            _ => PathBuf::from(std::env::var_os("HOME")?).join(".config/direnv"),
+        },
+    };
+    Some(config.join("lib").join(INTEGRATION_FILE_NAME))
+}
+
+/// Variables `envrc.bash` prepends to the user’s value instead of replacing it;
+/// `PATH` only unless the project’s `path_merge` says otherwise.
+const PREPENDED_VARIABLES: &[&str] = &["PATH", "XDG_DATA_DIRS", "XDG_CONFIG_DIRS"];
+
+/// How a variable of the project combines with the user’s value.
+#[derive(Debug, PartialEq, Eq)]
+enum Combine {
+    /// The project’s value replaces the user’s.
+    Replace,
+    /// The project’s value comes before the user’s, separated by `:`.
+    Prepend,
+    /// The project’s value comes after the user’s, separated by the string.
+    Append(String),
+}
+
+/// The statements setting the environment in the GC root `evaluation_root`
+/// for `shell`, filtered like `config` says, from the CAS if they were rendered before.
+///
+/// The key is the store path the root points to, so a new build
+/// renders them anew.
+pub fn cached_export(
+    evaluation_root: &Path,
+    shell: DirenvShell,
+    config: &ProjectConfig,
+    cas: &ContentAddressable,
+    logger: &slog::Logger,
+) -> std::io::Result<String> {
+    let target = std::fs::read_link(evaluation_root)?;
+    let key = format!(
+        "direnv-export\0{}\0{}\0{:x}",
+        target.display(),
+        shell,
+        md5::compute(config.envrc_prelude() + include_str!("./direnv/envrc.bash"))
+    );
+    if let Some(export) = cas.get_key(&key)? {
+        return Ok(export);
+    }
+    let export = render_export(evaluation_root, shell, config)?;
+    let saved = cas
+        .file_from_string(&export)
+        .and_then(|file| cas.set_key(&key, &file));
+    if let Err(e) = saved {
+        slog::debug!(logger, "could not save the direnv export"; "error" => %e);
+    }
+    Ok(export)
+}
+
+/// Render the environment in the GC root `evaluation_root` as statements of `shell`,
+/// which have the same effect as sourcing it with `envrc.bash`,
+/// but don’t need to evaluate the whole environment of the shell.
+///
+/// Fails if a variable is not UTF-8; bash gets such environments
+/// by sourcing them instead.
+pub fn render_export(
+    evaluation_root: &Path,
+    shell: DirenvShell,
+    config: &ProjectConfig,
+) -> std::io::Result<String> {
+    let render = match shell {
+        DirenvShell::Bash => bash_statement,
+        DirenvShell::Fish => fish_statement,
+        DirenvShell::Elvish => elvish_statement,
+        DirenvShell::Tcsh => tcsh_statement,
+    };
+    let dedup_path = match shell {
+        DirenvShell::Bash => BASH_DEDUP_PATH,
+        DirenvShell::Fish => FISH_DEDUP_PATH,
+        DirenvShell::Elvish => ELVISH_DEDUP_PATH,
+        DirenvShell::Tcsh => TCSH_DEDUP_PATH,
+    };
+    let mut export = String::new();
+    for (name, value, combine) in environment(evaluation_root, config)? {
+        export.push_str(&render(&name, &value, &combine));
+        if name == "PATH" && config.path_dedup {
+            export.push_str(dedup_path);
+        }
+    }
+    Ok(export)
+}
+
+/// Remove directories which appear in `PATH` more than once, keeping the first,
+/// for the project’s `path_dedup`.
+const BASH_DEDUP_PATH: &str = r#"__lorri_path=
+IFS=: read -r -a __lorri_dirs <<< "$PATH"
+for __lorri_dir in "${__lorri_dirs[@]}"; do
+    case ":$__lorri_path:" in
+        *":$__lorri_dir:"*) ;;
+        *) __lorri_path="${__lorri_path:+$__lorri_path:}$__lorri_dir";;
+    esac
+done
+export PATH="$__lorri_path"
+unset __lorri_path __lorri_dirs __lorri_dir
+"#;
+const FISH_DEDUP_PATH: &str = "set -l __lorri_path;
+for __lorri_dir in $PATH; contains -- $__lorri_dir $__lorri_path; or set -a __lorri_path $__lorri_dir; end;
+set -gx PATH $__lorri_path;
+";
+const ELVISH_DEDUP_PATH: &str = "var __lorri_seen = [&]
+set paths = [(for dir $paths { if (not (has-key $__lorri_seen $dir)) { set __lorri_seen[$dir] = $true; put $dir } })]
+";
+// tcsh can’t loop on a single line, so awk removes the duplicates
+const TCSH_DEDUP_PATH: &str =
+    "setenv PATH `printenv PATH | awk -F: -v OFS=: '{ n = 0; for (i = 1; i <= NF; i++) if (seen[$i]++ == 0) $(++n) = $i; NF = n; print }'`;\n";
+
+/// The variables of the environment in `evaluation_root`,
+/// and how they combine with the user’s.
+fn environment(
+    evaluation_root: &Path,
+    config: &ProjectConfig,
+) -> std::io::Result<Vec<(String, String, Combine)>> {
+    let mut appended = appended_variables(&evaluation_root.join("varmap-v1"));
+    Ok(crate::environment::load_exact(evaluation_root, config)?
+        .into_iter()
+        .map(|(name, value)| {
+            let combine = if name == "PATH" {
+                match config.path_merge {
+                    PathMerge::Prepend => Combine::Prepend,
+                    PathMerge::Append => Combine::Append(String::from(":")),
+                    PathMerge::Replace => Combine::Replace,
+                }
+            } else if PREPENDED_VARIABLES.contains(&name.as_str()) || config.is_path_like(&name) {
+                Combine::Prepend
+            } else if let Some(separator) = appended.remove(&name) {
+                Combine::Append(separator)
+            } else {
+                Combine::Replace
+            };
+            (name, value, combine)
+        })
+        .collect())
+}
+
+/// `export NAME='value'`
+fn bash_statement(name: &str, value: &str, combine: &Combine) -> String {
+    let value = quote::sh(value);
+    match combine {
+        Combine::Replace => format!("export {}={}\n", name, value),
+        Combine::Prepend => format!("export {0}={1}\"${{{0}:+:${0}}}\"\n", name, value),
+        // bash reads quotes in `${NAME:+…}` even inside double quotes,
+        // so the separator is quoted outside of them
+        Combine::Append(separator) => format!(
+            "export {0}=\"${{{0}:+${0}}}\"${{{0}:+{1}}}{2}\n",
+            name,
+            quote::sh(separator),
+            value
+        ),
+    }
+}
+
+/// `set -gx NAME 'value'`
+///
+/// fish keeps `PATH` as a list of directories, so it is set from the
+/// split value; other variables are joined with their separator.
+fn fish_statement(name: &str, value: &str, combine: &Combine) -> String {
+    let quoted = quote::fish(value);
+    match combine {
+        Combine::Replace => format!("set -gx {} {};\n", name, quoted),
+        Combine::Prepend if name == "PATH" => {
+            format!("set -gx {0} (string split : {1}) ${0};\n", name, quoted)
+        }
+        Combine::Prepend => format!("set -gx {0} (string join : {1} ${0});\n", name, quoted),
+        Combine::Append(_) if name == "PATH" => {
+            format!("set -gx {0} ${0} (string split : {1});\n", name, quoted)
+        }
+        Combine::Append(separator) => format!(
+            "set -gx {0} (string join {1} ${0} {2});\n",
+            name,
+            quote::fish(separator),
+            quoted
+        ),
+    }
+}
+
+/// `set-env NAME 'value'`
+fn elvish_statement(name: &str, value: &str, combine: &Combine) -> String {
+    let quoted = quote::elvish(value);
+    let name_quoted = quote::elvish(name);
+    match combine {
+        Combine::Replace => format!("set-env {} {}\n", name_quoted, quoted),
+        Combine::Prepend => format!(
+            "if (has-env {0}) {{ set-env {0} {1}':'(get-env {0}) }} else {{ set-env {0} {1} }}\n",
+            name_quoted, quoted
+        ),
+        Combine::Append(separator) => format!(
+            "if (has-env {0}) {{ set-env {0} (get-env {0}){1}{2} }} else {{ set-env {0} {2} }}\n",
+            name_quoted,
+            quote::elvish(separator),
+            quoted
+        ),
+    }
+}
+
+/// `setenv NAME 'value'`
+///
+/// `if` only takes a single command on the same line in tcsh, so a set
+/// variable is extended first and an unset one set afterwards.
+fn tcsh_statement(name: &str, value: &str, combine: &Combine) -> String {
+    let quoted = quote::tcsh(value);
+    match combine {
+        Combine::Replace => format!("setenv {} {};\n", name, quoted),
+        Combine::Prepend => format!(
+            "if ( $?{0} ) setenv {0} {1}:\"${{{0}}}\";\nif ( ! $?{0} ) setenv {0} {1};\n",
+            name, quoted
+        ),
+        Combine::Append(separator) => format!(
+            "if ( $?{0} ) setenv {0} \"${{{0}}}\"{1}{2};\nif ( ! $?{0} ) setenv {0} {2};\n",
+            name,
+            quote::tcsh(separator),
+            quoted
+        ),
+    }
+}
+
+/// The variables `varmap-v1` says to append to, and their separators.
+fn appended_variables(varmap: &Path) -> BTreeMap<String, String> {
+    let content = std::fs::read(varmap).unwrap_or_default();
+    let fields: Vec<String> = content
+        .split(|b| *b == 0)
+        .map(|field| String::from_utf8_lossy(field).into_owned())
+        .collect();
+    fields
+        .chunks_exact(3)
+        .filter(|map| map[0] == "append")
+        .map(|map| (map[1].clone(), map[2].clone()))
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -62,6 +386,147 @@ mod tests {
         eq((5, 0, 1), (1, 0, 0), Ordering::Greater);
     }
 
+    /// The export keeps the user’s PATH and appends like `varmap-v1` says,
+    /// but leaves out the variables `envrc.bash` punts.
+    #[test]
+    fn render_export_of_environment() -> std::io::Result<()> {
+        let root = tempfile::tempdir()?;
+        std::fs::write(
+            root.path().join("bash-export"),
+            r#"declare -x FOO="foo bar"
+declare -x PATH="/project/bin"
+declare -x HOME="/homeless-shelter"
+declare -x CFLAGS="-O2"
+"#,
+        )?;
+        std::fs::write(root.path().join("varmap-v1"), "append\0CFLAGS\0 \0")?;
+
+        let export = render_export(root.path(), DirenvShell::Bash, &ProjectConfig::default())?;
+        let expected = [
+            r#"export FOO='foo bar'"#,
+            r#"export PATH='/project/bin'"${PATH:+:$PATH}""#,
+            r#"export CFLAGS="${CFLAGS:+$CFLAGS}"${CFLAGS:+' '}'-O2'"#,
+            r#"export IN_NIX_SHELL='impure'"#,
+        ];
+        for line in &expected {
+            assert!(export.lines().any(|l| l == *line), "{}", export);
+        }
+        assert!(!export.contains("HOME"), "{}", export);
+
+        let config = ProjectConfig::parse("path_merge = \"append\"\npath_dedup = true").unwrap();
+        let export = render_export(root.path(), DirenvShell::Bash, &config)?;
+        assert!(
+            export.contains(
+                "export PATH=\"${PATH:+$PATH}\"${PATH:+':'}'/project/bin'\n__lorri_path="
+            ),
+            "{}",
+            export
+        );
+        Ok(())
+    }
+
+    /// Values which aren’t UTF-8 can’t be rendered without changing them,
+    /// so they are left to `envrc.bash`.
+    #[test]
+    fn render_export_refuses_non_utf8_values() -> std::io::Result<()> {
+        let root = tempfile::tempdir()?;
+        std::fs::write(
+            root.path().join("bash-export"),
+            &b"declare -x SRC=$'/home/caf\\xe9/project'\n"[..],
+        )?;
+        let err = render_export(root.path(), DirenvShell::Bash, &ProjectConfig::default())
+            .expect_err("the value is not UTF-8");
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        Ok(())
+    }
+
+    #[test]
+    fn integration_state() {
+        assert_eq!(Integration::of(None), Integration::Missing);
+        assert_eq!(Integration::of(Some(INTEGRATION)), Integration::Current);
+        assert_eq!(
+            Integration::of(Some(
+                "# lorri’s direnv integration, managed by `lorri direnv-install`.\nuse_lorri() { :; }\n"
+            )),
+            Integration::Stale
+        );
+        assert_eq!(
+            Integration::of(Some("use_lorri() { eval \"$(lorri direnv)\"; }\n")),
+            Integration::Foreign
+        );
+    }
+
+    /// Evaluating the bash export sets exactly the values of the environment.
+    #[test]
+    fn render_export_roundtrips_exotic_values() -> std::io::Result<()> {
+        let root = tempfile::tempdir()?;
+        std::fs::write(
+            root.path().join("bash-export"),
+            r#"declare -x QUOTES=$'it\'s "quoted" \\'
+declare -x NEWLINES=$'a\nb\n\n'
+declare -x EXPANSIONS='$HOME $(echo no) `echo no` !! *'
+declare -x CFLAGS=$'-O2\n\'x\''
+"#,
+        )?;
+        std::fs::write(root.path().join("varmap-v1"), "append\0CFLAGS\0 '\0")?;
+        let export = render_export(root.path(), DirenvShell::Bash, &ProjectConfig::default())?;
+        let output = std::process::Command::new("bash")
+            .args([
+                "-c",
+                &format!(
+                    "{}\nprintf '%s\\0' \"$QUOTES\" \"$NEWLINES\" \"$EXPANSIONS\" \"$CFLAGS\"",
+                    export
+                ),
+            ])
+            .env_clear()
+            .env("CFLAGS", "-g")
+            .output()?;
+        assert!(output.status.success(), "{:?}", output);
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert_eq!(
+            stdout.split('\0').collect::<Vec<_>>(),
+            vec![
+                "it's \"quoted\" \\",
+                "a\nb\n\n",
+                "$HOME $(echo no) `echo no` !! *",
+                "-g '-O2\n'x'",
+                "",
+            ]
+        );
+        Ok(())
+    }
+
+    /// The other shells get the same variables, quoted for them.
+    #[test]
+    fn render_statements_for_other_shells() {
+        let prepend = Combine::Prepend;
+        let append = Combine::Append(String::from(" "));
+        assert_eq!(
+            fish_statement("PATH", "/a:/b", &prepend),
+            "set -gx PATH (string split : '/a:/b') $PATH;\n"
+        );
+        assert_eq!(
+            fish_statement("FOO", r"it's \ here", &Combine::Replace),
+            "set -gx FOO 'it\\'s \\\\ here';\n"
+        );
+        assert_eq!(
+            fish_statement("CFLAGS", "-O2", &append),
+            "set -gx CFLAGS (string join ' ' $CFLAGS '-O2');\n"
+        );
+        assert_eq!(
+            elvish_statement("FOO", "it's", &Combine::Replace),
+            "set-env 'FOO' 'it''s'\n"
+        );
+        assert_eq!(
+            tcsh_statement("FOO", "hi!\nthere", &Combine::Replace),
+            "setenv FOO 'hi\\!\\\nthere';\n"
+        );
+        assert_eq!(
+            tcsh_statement("PATH", "/a", &prepend),
+            "if ( $?PATH ) setenv PATH '/a':\"${PATH}\";\nif ( ! $?PATH ) setenv PATH '/a';\n"
+        );
+    }
+
     proptest! {
         /// Parsing roundtrip
         #[test]
@@ -6,6 +6,22 @@ function punt () {
     :
 }
 
+# Whether to set a variable of the environment. lorri defines it
+# before this file with the project's `env_allow` and `env_deny` lists.
+if [ "$(type -t lorri_keep_variable)" != function ]; then
+    lorri_keep_variable() {
+        return 0
+    }
+fi
+
+# Whether a variable holds a list of directories, to which the project's
+# are prepended. lorri defines it with the project's `path_like` list.
+if [ "$(type -t lorri_is_path_like)" != function ]; then
+    lorri_is_path_like() {
+        return 1
+    }
+fi
+
 # move "origPreHook" "preHook" "$@";;
 move() {
     srcvarname=$1 # example: varname might contain the string "origPATH"
@@ -21,7 +37,8 @@ move() {
 
     # set $original to the contents of the variable $srcvarname
     # refers to
-    eval "$destvarname=\"${!srcvarname}\""
+    # (printf -v instead of eval, so that the value is never parsed)
+    printf -v "$destvarname" '%s' "${!srcvarname}"
 
     # mark the destvarname as exported so direnv picks it up
     # (shellcheck: we do want to export the content of destvarname!)
@@ -55,7 +72,7 @@ function prepend() {
     # reference, plus the current (updated on the export) contents.
     # however, exclude the ${separator} unless ${original} starts
     # with a value
-    eval "$varname=\"${!varname}${original:+${separator}${original}}\""
+    printf -v "$varname" '%s' "${!varname}${original:+${separator}${original}}"
 }
 
 function append() {
@@ -81,7 +98,35 @@ function append() {
     # reference, plus the current (updated on the export) contents.
     # however, exclude the ${separator} unless ${original} starts
     # with a value
-    eval "$varname=${original:+${original}${separator}}${!varname}"
+    printf -v "$varname" '%s' "${original:+${original}${separator}}${!varname}"
+}
+
+# Merge the project's PATH with the user's like the project's `path_merge`
+# and `path_dedup` say, which lorri sets before this file.
+merge_path() {
+    case "${lorri_path_merge:-prepend}" in
+        append) append "PATH" ":" "$@";;
+        replace) export "${@?}";;
+        *) prepend "PATH" ":" "$@";;
+    esac
+
+    if [ -n "${lorri_path_dedup:-}" ]; then
+        dedup_path
+    fi
+}
+
+# Remove directories which appear in PATH more than once, keeping the first.
+dedup_path() {
+    local dir deduped=
+    local -a dirs
+    IFS=: read -r -a dirs <<< "$PATH"
+    for dir in "${dirs[@]}"; do
+        case ":$deduped:" in
+            *":$dir:"*) ;;
+            *) deduped="${deduped:+$deduped:}$dir";;
+        esac
+    done
+    export PATH="$deduped"
 }
 
 varmap() {
@@ -119,6 +164,9 @@ varmap() {
 function declare() {
     if [ "$1" == "-x" ]; then shift; fi
 
+    # the variables the project filters out
+    if ! lorri_keep_variable "${1%%=*}"; then return; fi
+
     # Some variables require special handling.
     #
     # - punt:    don't set the variable at all
@@ -129,7 +177,7 @@ function declare() {
         "USER="*) punt;;
         "LOGNAME="*) punt;;
         "DISPLAY="*) punt;;
-        "PATH="*) prepend "PATH" ":" "$@";;
+        "PATH="*) merge_path "$@";;
         "TERM="*) punt;;
         "IN_NIX_SHELL="*) punt;;
         "TZ="*) punt;;
@@ -162,7 +210,13 @@ function declare() {
         "XDG_DATA_DIRS="*) prepend "XDG_DATA_DIRS" ":" "$@";;
         "XDG_CONFIG_DIRS="*) prepend "XDG_CONFIG_DIRS" ":" "$@";;
 
-        *) varmap "$@" ;;
+        *)
+            if lorri_is_path_like "${1%%=*}"; then
+                prepend "${1%%=*}" ":" "$@"
+            else
+                varmap "$@"
+            fi
+            ;;
     esac
 }
 
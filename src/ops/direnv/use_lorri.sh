@@ -0,0 +1,11 @@
+# lorri’s direnv integration, managed by `lorri direnv-install`.
+# Don’t edit this file, it is replaced when lorri updates it.
+
+# Load the environment of a lorri project in an .envrc, e.g.
+#
+#     use lorri --attr shells.dev
+#
+# The arguments are passed to `lorri direnv`.
+use_lorri() {
+  eval "$(lorri direnv "$@")"
+}
@@ -0,0 +1,331 @@
+//! `lorri doctor`: check whether everything lorri needs is set up,
+//! and explain how to fix what isn’t.
+
+use super::direnv::{DirenvVersion, MIN_DIRENV_VERSION};
+use crate::constants::Paths;
+use crate::daemon::client;
+use crate::nix::version::{Feature, NixVersion};
+use crate::ops::error::ExitError;
+use crate::project;
+use std::path::Path;
+use std::process::Command;
+
+/// Below this many inotify watches, bigger projects run into the limit.
+const MIN_INOTIFY_WATCHES: u64 = 65536;
+
+/// How bad the result of a check is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Ok,
+    /// lorri works, but not as well as it could
+    Warning,
+    /// lorri can’t work like this
+    Failure,
+}
+
+/// The result of a check, and how to fix the problem it found.
+struct Check {
+    name: &'static str,
+    status: Status,
+    message: String,
+    fix: Option<String>,
+}
+
+impl Check {
+    fn ok(name: &'static str, message: String) -> Check {
+        Check {
+            name,
+            status: Status::Ok,
+            message,
+            fix: None,
+        }
+    }
+
+    fn warning(name: &'static str, message: String, fix: String) -> Check {
+        Check {
+            name,
+            status: Status::Warning,
+            message,
+            fix: Some(fix),
+        }
+    }
+
+    fn failure(name: &'static str, message: String, fix: String) -> Check {
+        Check {
+            name,
+            status: Status::Failure,
+            message,
+            fix: Some(fix),
+        }
+    }
+
+    fn print(&self) {
+        let status = match self.status {
+            Status::Ok => "ok",
+            Status::Warning => "warning",
+            Status::Failure => "FAILED",
+        };
+        println!("{:<8} {}: {}", status, self.name, self.message);
+        if let Some(fix) = &self.fix {
+            println!("{:<8} fix: {}", "", fix);
+        }
+    }
+}
+
+/// Check the nix installation, direnv, the daemon, the watch limits
+/// and the GC roots, and print how to fix any problems.
+///
+/// See the documentation for lorri::cli::Command::Doctor for details.
+pub fn doctor(paths: &Paths, logger: &slog::Logger) -> Result<(), ExitError> {
+    let mut checks = vec![check_nix(), check_direnv(), check_envrc()];
+    checks.push(check_daemon(logger));
+    checks.extend(check_inotify());
+    checks.extend(check_gc_roots(paths.gc_root_dir()));
+
+    for check in &checks {
+        check.print();
+    }
+    let failed = checks
+        .iter()
+        .filter(|check| check.status == Status::Failure)
+        .count();
+    if failed > 0 {
+        Err(ExitError::environment_problem(anyhow::anyhow!(
+            "{} of {} checks failed",
+            failed,
+            checks.len()
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+/// The first line `executable --version` prints (or whatever `args` are).
+fn version_of(executable: &str, args: &[&str]) -> std::io::Result<String> {
+    let out = Command::new(executable).args(args).output()?;
+    if !out.status.success() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!("`{} {}` failed", executable, args.join(" ")),
+        ));
+    }
+    Ok(String::from_utf8_lossy(&out.stdout)
+        .lines()
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_string())
+}
+
+fn check_nix() -> Check {
+    let mut missing = vec![];
+    let mut version = None;
+    for executable in &["nix-instantiate", "nix-store", "nix-build"] {
+        match version_of(executable, &["--version"]) {
+            Ok(v) => {
+                version.get_or_insert(v);
+            }
+            Err(_) => missing.push(*executable),
+        }
+    }
+    match version {
+        Some(version) if missing.is_empty() => match NixVersion::detected() {
+            Some(detected) => {
+                let unsupported: Vec<Feature> = Feature::ALL
+                    .iter()
+                    .cloned()
+                    .filter(|f| !detected.supports(*f))
+                    .collect();
+                // every feature is supported from the version of the newest one on
+                match unsupported.iter().map(|f| f.min_version()).max() {
+                    None => Check::ok("nix", detected.to_string()),
+                    Some((major, minor, _)) => Check::warning(
+                        "nix",
+                        format!(
+                            "{} does not support {}",
+                            detected,
+                            unsupported
+                                .iter()
+                                .map(|f| f.to_string())
+                                .collect::<Vec<_>>()
+                                .join(", ")
+                        ),
+                        format!("upgrade to nix {}.{} or newer", major, minor),
+                    ),
+                }
+            }
+            None => Check::ok("nix", version),
+        },
+        _ => Check::failure(
+            "nix",
+            format!("could not run {}", missing.join(", ")),
+            "install nix (https://nixos.org/download.html) and make sure its commands \
+             are on the PATH of your shell and of the lorri daemon"
+                .to_string(),
+        ),
+    }
+}
+
+fn check_direnv() -> Check {
+    let install = format!(
+        "install direnv {} or newer (https://direnv.net/docs/installation.html)",
+        MIN_DIRENV_VERSION
+    );
+    match version_of("direnv", &["version"]) {
+        Err(err) => Check::failure("direnv", format!("could not run direnv: {}", err), install),
+        Ok(version) => match version.parse::<DirenvVersion>() {
+            Ok(v) if v < MIN_DIRENV_VERSION => {
+                Check::failure("direnv", format!("version {} is too old", v), install)
+            }
+            Ok(v) => Check::ok("direnv", format!("version {}", v)),
+            Err(()) => Check::warning(
+                "direnv",
+                format!("could not parse the version `{}`", version),
+                install,
+            ),
+        },
+    }
+}
+
+/// Whether the project in the current directory is set up for direnv,
+/// and direnv loads it.
+fn check_envrc() -> Check {
+    let envrc = Path::new(".envrc");
+    let contents = match std::fs::read_to_string(envrc) {
+        Ok(contents) => contents,
+        Err(_) => {
+            return Check::ok(
+                ".envrc",
+                "no .envrc in the current directory, skipped".to_string(),
+            )
+        }
+    };
+    if !contents.contains("lorri direnv") {
+        return Check::warning(
+            ".envrc",
+            "does not call `lorri direnv`".to_string(),
+            "add `eval \"$(lorri direnv)\"` to .envrc, or run `lorri init`".to_string(),
+        );
+    }
+    // direnv sets this once it loaded an .envrc
+    if std::env::var_os("DIRENV_DIR").is_none() {
+        return Check::warning(
+            ".envrc",
+            "direnv did not load it".to_string(),
+            "install the direnv hook for your shell (https://direnv.net/docs/hook.html) \
+             and run `direnv allow`"
+                .to_string(),
+        );
+    }
+    Check::ok(".envrc", "calls `lorri direnv` and is loaded".to_string())
+}
+
+fn check_daemon(logger: &slog::Logger) -> Check {
+    let projects = client::create::<client::Projects>(client::Timeout::from_millis(1000), logger)
+        .and_then(|c| {
+            c.write(&client::Projects {})?;
+            Ok(c.read()?)
+        });
+    match projects {
+        Ok(projects) => Check::ok(
+            "daemon",
+            format!("running, building {} projects", projects.len()),
+        ),
+        Err(err) => Check::failure(
+            "daemon",
+            err.message(),
+            "start `lorri daemon`, e.g. as a systemd user service \
+             (see contrib/daemon.md) or with `lorri direnv --spawn-daemon`"
+                .to_string(),
+        ),
+    }
+}
+
+/// The inotify limits, on Linux.
+fn check_inotify() -> Option<Check> {
+    let watches = std::fs::read_to_string("/proc/sys/fs/inotify/max_user_watches").ok()?;
+    let watches = watches.trim().parse::<u64>().ok()?;
+    Some(if watches < MIN_INOTIFY_WATCHES {
+        Check::warning(
+            "inotify",
+            format!("only {} files can be watched", watches),
+            "raise the limit with `sysctl fs.inotify.max_user_watches=524288` \
+             (`boot.kernel.sysctl` on NixOS)"
+                .to_string(),
+        )
+    } else {
+        Check::ok("inotify", format!("{} files can be watched", watches))
+    })
+}
+
+fn check_gc_roots(gc_root_dir: &crate::AbsPathBuf) -> Vec<Check> {
+    let roots = match project::list_roots(gc_root_dir) {
+        Ok(roots) => roots,
+        Err(err) => {
+            return vec![Check::failure(
+                "GC roots",
+                format!("could not list {}: {}", gc_root_dir.display(), err),
+                format!(
+                    "make sure {} is a directory you can write to",
+                    gc_root_dir.display()
+                ),
+            )]
+        }
+    };
+    let mut checks = vec![];
+
+    let probe = gc_root_dir.join(".lorri-doctor");
+    match std::fs::write(&probe, "") {
+        Ok(()) => {
+            let _ = std::fs::remove_file(&probe);
+        }
+        Err(err) => checks.push(Check::failure(
+            "GC roots",
+            format!("cannot write to {}: {}", gc_root_dir.display(), err),
+            format!("make sure {} belongs to you", gc_root_dir.display()),
+        )),
+    }
+
+    let missing_projects = roots
+        .iter()
+        .filter(|root| root.nix_file_exists() == Some(false))
+        .count();
+    if missing_projects > 0 {
+        checks.push(Check::warning(
+            "GC roots",
+            format!(
+                "{} projects don’t exist anymore, but their environments are kept",
+                missing_projects
+            ),
+            "remove them with `lorri gc`".to_string(),
+        ));
+    }
+
+    let unregistered = project::Username::current()
+        .ok()
+        .map(|user| {
+            roots
+                .iter()
+                .filter(|root| root.reverse_root_exists(&user) == Some(false))
+                .count()
+        })
+        .unwrap_or(0);
+    if unregistered > 0 {
+        checks.push(Check::warning(
+            "GC roots",
+            format!(
+                "nix does not know the GC roots of {} projects, so it can delete their environments",
+                unregistered
+            ),
+            "enter the projects again, or rebuild them with `lorri build`".to_string(),
+        ));
+    }
+
+    if checks.is_empty() {
+        checks.push(Check::ok(
+            "GC roots",
+            format!("{} projects in {}", roots.len(), gc_root_dir.display()),
+        ));
+    }
+    checks
+}
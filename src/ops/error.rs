@@ -7,6 +7,7 @@
 ///
 /// All these commands exit
 /// - 1 if they encounter an expected error
+/// - 2 if a nix expression evaluated fine, but its build failed
 /// - 100 if they encounter a permanent error – “the user is holding it wrong”
 /// - 101 if they encounter a programming error, like a panic or failed assert
 /// - 111 if they encounter a temporary error, such as resource exhaustion
@@ -34,6 +35,18 @@ impl ExitError {
         }
     }
 
+    /// Exit 2 to signify that a nix expression could be evaluated,
+    /// but building it failed.
+    pub fn build_failure<E>(err: E) -> ExitError
+    where
+        E: Into<anyhow::Error>,
+    {
+        ExitError {
+            exitcode: 2,
+            error: err.into(),
+        }
+    }
+
     /// Exit 100 to signify a user error (“the user is holding it wrong”).
     /// This is a permanent error, if the program is executed the same way
     /// it should crash with 100 again.
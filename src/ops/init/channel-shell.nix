@@ -0,0 +1,12 @@
+# nixpkgs follows this channel instead of the system’s <nixpkgs>,
+# change it to upgrade
+{ pkgs ? import (fetchTarball "https://nixos.org/channels/nixos-23.05/nixexprs.tar.xz") {} }:
+
+pkgs.mkShell {
+  buildInputs = [
+    pkgs.hello
+
+    # keep this line if you use bash
+    pkgs.bashInteractive
+  ];
+}
@@ -0,0 +1,13 @@
+# nixpkgs is pinned in nix/sources.json, update it with `niv update nixpkgs`
+{ sources ? import ./nix/sources.nix
+, pkgs ? import sources.nixpkgs {}
+}:
+
+pkgs.mkShell {
+  buildInputs = [
+    pkgs.hello
+
+    # keep this line if you use bash
+    pkgs.bashInteractive
+  ];
+}
@@ -0,0 +1,13 @@
+# nixpkgs is pinned in npins/sources.json, update it with `npins update nixpkgs`
+{ sources ? import ./npins
+, pkgs ? import sources.nixpkgs {}
+}:
+
+pkgs.mkShell {
+  buildInputs = [
+    pkgs.hello
+
+    # keep this line if you use bash
+    pkgs.bashInteractive
+  ];
+}
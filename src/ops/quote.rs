@@ -0,0 +1,210 @@
+//! Quote values for the shells and file formats lorri prints environments in.
+//!
+//! Every function returns a single word which evaluates to exactly `value`,
+//! including newlines, quotes and the characters the shell would expand.
+//! Values never contain NUL bytes, since the environment they come from
+//! can’t hold them.
+
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+/// Whether `name` can be set as a variable by every shell,
+/// i.e. consists of letters, digits and `_` and doesn’t start with a digit.
+pub fn is_variable_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        _ => false,
+    }
+}
+
+/// Quote `value` for POSIX shells.
+pub fn sh(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+/// Quote `path` for bash. Paths needn’t be UTF-8,
+/// so the bytes which aren’t are written as `$'\xNN'`.
+pub fn bash_path(path: &Path) -> String {
+    let mut word = String::new();
+    let mut bytes = path.as_os_str().as_bytes();
+    loop {
+        let (valid, invalid) = match std::str::from_utf8(bytes) {
+            Ok(valid) => (valid, &[][..]),
+            Err(e) => {
+                let (valid, rest) = bytes.split_at(e.valid_up_to());
+                let invalid_len = e.error_len().unwrap_or(rest.len());
+                let valid = std::str::from_utf8(valid).expect("checked to be UTF-8");
+                (valid, &rest[..invalid_len])
+            }
+        };
+        if !valid.is_empty() || (word.is_empty() && invalid.is_empty()) {
+            word.push_str(&sh(valid));
+        }
+        if invalid.is_empty() {
+            return word;
+        }
+        word.push_str("$'");
+        for byte in invalid {
+            word.push_str(&format!("\\x{:02x}", byte));
+        }
+        word.push('\'');
+        bytes = &bytes[valid.len() + invalid.len()..];
+    }
+}
+
+/// Quote `value` for fish, which allows escaping in single quotes.
+pub fn fish(value: &str) -> String {
+    format!("'{}'", value.replace('\\', r"\\").replace('\'', r"\'"))
+}
+
+/// Quote `value` for elvish, where a single quote is escaped by doubling it.
+pub fn elvish(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+/// Quote `value` for tcsh, which expands `!` and ends a command
+/// at a newline even in single quotes.
+pub fn tcsh(value: &str) -> String {
+    let escaped = value
+        .replace('\'', r"'\''")
+        .replace('!', r"\!")
+        .replace('\n', "\\\n");
+    format!("'{}'", escaped)
+}
+
+/// Quote `value` for dotenv files.
+pub fn dotenv(value: &str) -> String {
+    let escaped = value
+        .replace('\\', r"\\")
+        .replace('"', r#"\""#)
+        .replace('$', r"\$")
+        .replace('\n', r"\n")
+        .replace('\r', r"\r");
+    format!("\"{}\"", escaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    /// Values which break naive quoting.
+    const ADVERSARIAL: &[&str] = &[
+        "",
+        " ",
+        "it's",
+        "'",
+        "''",
+        "\"",
+        "a\nb",
+        "\n",
+        "trailing\n\n",
+        "\\",
+        "\\'",
+        "a\\\nb",
+        "$HOME",
+        "${HOME}",
+        "$(echo no)",
+        "`echo no`",
+        "!!",
+        "!$",
+        "*",
+        "~",
+        "a\tb\r",
+        "#comment",
+        "; echo no",
+        "ünïcödé ✓",
+    ];
+
+    fn bash_eval(word: &str) -> String {
+        let output = Command::new("bash")
+            .args(["-c", &format!("printf '%s' {}", word)])
+            .env_clear()
+            .output()
+            .expect("failed to run bash");
+        assert!(output.status.success(), "{:?}", output);
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    #[test]
+    fn sh_roundtrips_adversarial_values() {
+        for value in ADVERSARIAL {
+            assert_eq!(&bash_eval(&sh(value)), value);
+        }
+    }
+
+    #[test]
+    fn bash_path_roundtrips_any_bytes() {
+        use std::ffi::OsStr;
+        for bytes in &[
+            &b""[..],
+            b"/home/user/my project's/shell.nix",
+            b"/caf\xe9/\xff\xfe $x/shell.nix",
+            b"\xe9",
+        ] {
+            let path = Path::new(OsStr::from_bytes(bytes));
+            let output = Command::new("bash")
+                .args(["-c", &format!("printf '%s' {}", bash_path(path))])
+                .env_clear()
+                .output()
+                .expect("failed to run bash");
+            assert!(output.status.success(), "{:?}", output);
+            assert_eq!(&output.stdout[..], *bytes);
+        }
+    }
+
+    /// Read a single-quoted word in which `escape` is the escape
+    /// for the quote, and `\\` for a backslash if `backslash` is set.
+    fn unquote_single(word: &str, escape: &str, backslash: bool) -> String {
+        assert!(
+            word.starts_with('\'') && word.ends_with('\'') && word.len() >= 2,
+            "{}",
+            word
+        );
+        let mut inner = &word[1..word.len() - 1];
+        let mut value = String::new();
+        while !inner.is_empty() {
+            if inner.starts_with(escape) {
+                value.push('\'');
+                inner = &inner[escape.len()..];
+            } else if backslash && inner.starts_with(r"\\") {
+                value.push('\\');
+                inner = &inner[2..];
+            } else {
+                let c = inner.chars().next().unwrap();
+                // an unescaped quote would end the word
+                assert_ne!(c, '\'', "{}", word);
+                value.push(c);
+                inner = &inner[c.len_utf8()..];
+            }
+        }
+        value
+    }
+
+    #[test]
+    fn other_dialects_roundtrip_adversarial_values() {
+        for value in ADVERSARIAL {
+            assert_eq!(&unquote_single(&fish(value), r"\'", true), value);
+            assert_eq!(&unquote_single(&elvish(value), "''", false), value);
+        }
+        assert_eq!(fish(r"it's \ here"), r"'it\'s \\ here'");
+        assert_eq!(elvish("it's\n$x"), "'it''s\n$x'");
+        assert_eq!(tcsh("it's"), r"'it'\''s'");
+        assert_eq!(tcsh("hi!\nthere"), "'hi\\!\\\nthere'");
+        assert_eq!(dotenv("a\"b\\c$d\ne\r"), r#""a\"b\\c\$d\ne\r""#);
+    }
+
+    #[test]
+    fn variable_names() {
+        assert!(is_variable_name("PATH"));
+        assert!(is_variable_name("_x1"));
+        assert!(!is_variable_name(""));
+        assert!(!is_variable_name("1X"));
+        assert!(!is_variable_name("a.b"));
+        assert!(!is_variable_name("a-b"));
+        assert!(!is_variable_name("A B"));
+    }
+}
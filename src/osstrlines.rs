@@ -59,9 +59,9 @@ impl<B: BufRead> Iterator for Lines<B> {
         match self.buf.read_until(b'\n', &mut buf) {
             Ok(0) => None,
             Ok(_n) => {
-                if buf.ends_with(&[b'\n']) {
+                if buf.ends_with(b"\n") {
                     buf.pop();
-                    if buf.ends_with(&[b'\r']) {
+                    if buf.ends_with(b"\r") {
                         buf.pop();
                     }
                 }
@@ -58,13 +58,25 @@ pub fn reduce_paths(paths: &[WatchPathBuf]) -> HashSet<WatchPathBuf> {
     reduced
         .into_iter()
         .fold::<HashSet<WatchPathBuf>, _>(HashSet::new(), |mut set, new_path| {
-            if !set.iter().any(|path| new_path.as_ref().starts_with(path)) {
+            if !set.iter().any(|path| covers(path, new_path.as_ref())) {
                 set.insert(new_path);
             }
             set
         })
 }
 
+/// Whether watching `watched` notices all changes of `path`.
+///
+/// Only recursive watches cover the paths below them, e.g. a file the
+/// evaluation read from a directory whose listing a `watch` pattern was
+/// matched against has to be watched itself.
+fn covers(watched: &WatchPathBuf, path: &Path) -> bool {
+    match watched {
+        WatchPathBuf::Recursive(watched) => path.starts_with(watched),
+        WatchPathBuf::Normal(watched) => path == watched,
+    }
+}
+
 /// Reduce a path coming from a user's channel to the location where
 /// the channel becomes switchable.
 ///
@@ -113,7 +125,11 @@ fn reduce_channel_path(path: &WatchPathBuf) -> ReductionOp {
     //     segments: 1 2   3   4     5         6     7       8      9    10
     let channel_version_root_segments = 9;
 
-    if !path.as_ref().starts_with(nix_profile) {
+    // the profile directories themselves (e.g. where the `channels` link is switched)
+    // are already as short as they can be
+    if !path.as_ref().starts_with(nix_profile)
+        || path.as_ref().iter().count() < channel_version_root_segments
+    {
         return ReductionOp::NoOpinion;
     }
 
@@ -172,3 +188,26 @@ fn reduce_nix_store_path(path: &WatchPathBuf) -> ReductionOp {
 
     ReductionOp::NoOpinion
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_recursive_paths_cover_their_children() {
+        let paths = vec![
+            WatchPathBuf::Normal(PathBuf::from("/home/user/shared")),
+            WatchPathBuf::Normal(PathBuf::from("/home/user/shared/nix/common.nix")),
+            WatchPathBuf::Recursive(PathBuf::from("/home/user/project")),
+            WatchPathBuf::Normal(PathBuf::from("/home/user/project/shell.nix")),
+        ];
+        let expected: HashSet<WatchPathBuf> = vec![
+            WatchPathBuf::Normal(PathBuf::from("/home/user/shared")),
+            WatchPathBuf::Normal(PathBuf::from("/home/user/shared/nix/common.nix")),
+            WatchPathBuf::Recursive(PathBuf::from("/home/user/project")),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(reduce_paths(&paths), expected);
+    }
+}
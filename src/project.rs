@@ -1,14 +1,29 @@
 //! Wrap a nix file and manage corresponding state.
 
-use slog::debug;
+pub mod alias;
+pub mod config;
+pub mod history;
+pub mod status;
+
+use slog::{debug, warn};
 use thiserror::Error;
 
-use crate::builder::{OutputPath, RootedPath};
+use crate::builder::{BuildInputs, OutputPath, RootedPath};
 use crate::cas::ContentAddressable;
+use crate::nix::store::{self, StoreKind};
+use crate::nix::StorePath;
+use crate::ops::error::{ExitAs, ExitErrorType};
 use crate::{AbsPathBuf, NixFile};
-use std::ffi::OsString;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
 use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
+use std::rc::{Rc, Weak};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+pub use lorri_client::event::RootPath;
 
 /// A “project” knows how to handle the lorri state
 /// for a given nix file.
@@ -17,15 +32,26 @@ pub struct Project {
     /// Absolute path to this project’s nix file.
     pub nix_file: NixFile,
 
+    /// The attribute of the nix file’s expression to build,
+    /// or the whole expression if `None`.
+    pub attr: Option<String>,
+
     /// Directory in which this project’s
     /// garbage collection roots are stored.
     gc_root_path: AbsPathBuf,
 
-    /// Hash of the nix file’s absolute path.
+    /// Directory which contains all of this project’s state,
+    /// i.e. the parent of `gc_root_path`.
+    project_dir: AbsPathBuf,
+
+    /// Hash of the nix file’s absolute path (and the attribute, if any).
     hash: String,
 
     /// Content-addressable store to save static files in
     pub cas: ContentAddressable,
+
+    /// How many generations of the environment to keep as GC roots.
+    pub keep_generations: usize,
 }
 
 impl Project {
@@ -37,19 +63,47 @@ impl Project {
         gc_root_dir: &AbsPathBuf,
         cas: ContentAddressable,
     ) -> std::io::Result<Project> {
-        let hash = format!(
-            "{:x}",
-            md5::compute(nix_file.as_absolute_path().as_os_str().as_bytes())
-        );
-        let project_gc_root = gc_root_dir.join(&hash).join("gc_root");
+        Self::new_with_attr(nix_file, None, gc_root_dir, cas)
+    }
+
+    /// Like `new`, but only build the attribute `attr` of the nix file.
+    ///
+    /// Each attribute of a nix file is a separate project with its own GC roots.
+    pub fn new_with_attr(
+        nix_file: NixFile,
+        attr: Option<String>,
+        gc_root_dir: &AbsPathBuf,
+        cas: ContentAddressable,
+    ) -> std::io::Result<Project> {
+        let hash = project_hash(&nix_file, attr.as_deref());
+        let project_dir = gc_root_dir.join(&hash);
+        let project_gc_root = project_dir.join("gc_root");
 
         std::fs::create_dir_all(&project_gc_root)?;
 
+        // Remember which nix file this project belongs to,
+        // so that we can find out later whether it still exists (see `list_roots`).
+        let nix_file_symlink = project_dir.join(NIX_FILE_SYMLINK);
+        match std::fs::read_link(&nix_file_symlink) {
+            Ok(target) if target == nix_file.as_absolute_path() => {}
+            Ok(_) => {
+                std::fs::remove_file(&nix_file_symlink)?;
+                std::os::unix::fs::symlink(nix_file.as_absolute_path(), &nix_file_symlink)?;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                std::os::unix::fs::symlink(nix_file.as_absolute_path(), &nix_file_symlink)?;
+            }
+            Err(e) => return Err(e),
+        }
+
         Ok(Project {
             nix_file,
+            attr,
             gc_root_path: project_gc_root,
+            project_dir,
             hash,
             cas,
+            keep_generations: DEFAULT_KEEP_GENERATIONS,
         })
     }
 
@@ -71,7 +125,59 @@ impl Project {
         }
     }
 
+    /// The project’s GC roots, as `list_roots` finds them.
+    pub fn gc_root_info(&self) -> GcRootInfo {
+        GcRootInfo {
+            hash: self.hash.clone(),
+            nix_file: Some(self.nix_file.as_absolute_path().to_owned()),
+            project_dir: self.project_dir.clone(),
+            shell_gc_root: RootPath(self.shell_gc_root()),
+        }
+    }
+
+    /// Record that the project was just built or used,
+    /// which keeps its GC roots from expiring (see `GcRootInfo::last_used`).
+    pub fn mark_used(&self) -> std::io::Result<()> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .expect("system time is before the unix epoch")
+            .as_secs();
+        std::fs::write(self.project_dir.join(LAST_USED_FILE), format!("{}\n", now))
+    }
+
+    /// The file the daemon writes the output of the project’s builds to.
+    pub fn build_log(&self) -> AbsPathBuf {
+        self.project_dir.join(BUILD_LOG_FILE)
+    }
+
+    /// The file the daemon records the project’s builds in, see `history`.
+    pub fn history_file(&self) -> AbsPathBuf {
+        self.project_dir.join(HISTORY_FILE)
+    }
+
+    /// The file the daemon keeps the state of the project’s builds in, see `status`.
+    pub fn status_file(&self) -> AbsPathBuf {
+        self.project_dir.join(STATUS_FILE)
+    }
+
+    /// Directory containing a numbered symlink for each kept generation.
+    fn generations_dir(&self) -> AbsPathBuf {
+        self.gc_root_path.join(GENERATIONS_DIR)
+    }
+
+    /// Lock the project’s GC roots, see `ProjectLock`.
+    /// Hold it while calling `create_roots` and `root_build_inputs`.
+    pub fn lock(&self) -> Result<ProjectLock, AddRootError> {
+        ProjectLock::acquire(&self.project_dir).map_err(|source| AddRootError {
+            source,
+            msg: format!("Failed to lock {}", self.project_dir.display()),
+        })
+    }
+
     /// Create roots to store paths.
+    ///
+    /// Every new store path is also recorded as a new generation,
+    /// of which the last `keep_generations` are kept as GC roots.
     pub fn create_roots(
         &self,
         // Important: this intentionally only allows creating
@@ -84,96 +190,954 @@ impl Project {
 where {
         let store_path = &path.path;
 
+        // the forward GC root that points from the store path to our cache gc_roots dir
         debug!(logger, "adding root"; "from" => store_path.as_path().to_str(), "to" => self.shell_gc_root().display());
-        std::fs::remove_file(&self.shell_gc_root())
-            .or_else(|e| AddRootError::remove(e, &self.shell_gc_root().as_path()))?;
+        replace_symlink(store_path.as_path(), self.shell_gc_root().as_path())?;
 
-        // the forward GC root that points from the store path to our cache gc_roots dir
-        std::os::unix::fs::symlink(store_path.as_path(), &self.shell_gc_root()).map_err(|e| {
-            AddRootError::symlink(e, store_path.as_path(), self.shell_gc_root().as_path())
+        // We register a garbage collection root, which points back to our `~/.cache/lorri/gc_roots` directory,
+        // so that nix won’t delete our shell environment.
+        let registry = RootRegistry::detect(&user)?;
+        debug!(logger, "connecting root"; "from" => self.shell_gc_root().display(), "registry" => ?registry);
+        registry.register(
+            self.shell_gc_root().as_path(),
+            &reverse_root_name(self.hash()),
+        )?;
+
+        self.add_generation(store_path.as_path(), &registry, logger)?;
+
+        self.mark_used().map_err(|source| AddRootError {
+            source,
+            msg: format!(
+                "Failed to record the build time in {}",
+                self.project_dir.display()
+            ),
         })?;
 
-        // the reverse GC root that points from nix to our cache gc_roots dir
-        // TODO: check nix state dir at startup, like USER.
-        let nix_var_nix = || AbsPathBuf::new_unchecked(PathBuf::from("/nix/var/nix/"));
-        let nix_gc_root_user_dir = std::env::var_os("NIX_STATE_DIR")
-            .map_or_else(
-                || Ok(nix_var_nix()),
-                |path| AbsPathBuf::new(PathBuf::from(path)),
-            )
-            .unwrap_or_else(|_pb| nix_var_nix())
-            .join(PathBuf::from("gcroots/per-user"))
-            .join(user.0);
+        // TODO: don’t return the RootPath here
+        Ok(OutputPath {
+            shell_gc_root: RootPath(self.shell_gc_root()),
+        })
+    }
 
-        // The user directory sometimes doesn’t exist,
-        // but we can create it (it’s root but `rwxrwxrwx`)
-        if !nix_gc_root_user_dir.as_path().is_dir() {
-            std::fs::create_dir_all(&nix_gc_root_user_dir.as_path()).map_err(|source| {
-                AddRootError {
+    /// Record `store_path` as the newest generation (unless it already is)
+    /// and remove all generations older than the last `keep_generations`.
+    fn add_generation(
+        &self,
+        store_path: &Path,
+        registry: &RootRegistry,
+        logger: &slog::Logger,
+    ) -> Result<(), AddRootError> {
+        let generations_dir = self.generations_dir();
+        std::fs::create_dir_all(generations_dir.as_path()).map_err(|source| AddRootError {
+            source,
+            msg: format!(
+                "Failed to create the generations directory: {}",
+                generations_dir.display()
+            ),
+        })?;
+        let mut generations = self.generations().map_err(|source| AddRootError {
+            source,
+            msg: format!(
+                "Failed to list the generations in {}",
+                generations_dir.display()
+            ),
+        })?;
+
+        let number = match generations.last() {
+            // a rebuild without changes does not create a new generation
+            Some(last) if last.store_path == store_path => return Ok(()),
+            Some(last) => last.number + 1,
+            None => 1,
+        };
+        let root = generations_dir.join(number.to_string());
+        debug!(logger, "adding generation"; "generation" => number, "to" => store_path.to_str());
+        replace_symlink(store_path, root.as_path())?;
+        registry.register(
+            root.as_path(),
+            &generation_reverse_root_name(self.hash(), number),
+        )?;
+        generations.push(Generation {
+            number,
+            root: RootPath(root),
+            store_path: store_path.to_owned(),
+        });
+
+        let keep = std::cmp::max(self.keep_generations, 1);
+        if generations.len() > keep {
+            let outdated = generations.len() - keep;
+            for old in generations.drain(..outdated) {
+                debug!(logger, "removing generation"; "generation" => old.number);
+                registry.unregister(&generation_reverse_root_name(self.hash(), old.number))?;
+                std::fs::remove_file(old.root.0.as_path())
+                    .or_else(|e| AddRootError::remove(e, old.root.0.as_path()))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// All generations of this project that are still kept, oldest first.
+    pub fn generations(&self) -> std::io::Result<Vec<Generation>> {
+        let generations_dir = self.generations_dir();
+        let mut res = vec![];
+        let entries = match std::fs::read_dir(generations_dir.as_path()) {
+            Ok(entries) => entries,
+            // no build happened yet
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(res),
+            Err(e) => return Err(e),
+        };
+        for entry in entries {
+            let entry = entry?;
+            let number = match entry.file_name().to_str().map(|n| n.parse::<u64>()) {
+                Some(Ok(number)) => number,
+                // not a generation symlink
+                _ => continue,
+            };
+            let root = generations_dir.join(entry.file_name());
+            let store_path = std::fs::read_link(root.as_path())?;
+            res.push(Generation {
+                number,
+                root: RootPath(root),
+                store_path,
+            });
+        }
+        res.sort_by_key(|g| g.number);
+        Ok(res)
+    }
+
+    /// The generation the shell GC root currently points to, if any.
+    pub fn current_generation(&self) -> std::io::Result<Option<Generation>> {
+        let current = match std::fs::read_link(self.shell_gc_root().as_path()) {
+            Ok(current) => current,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        Ok(self
+            .generations()?
+            .into_iter()
+            .rev()
+            .find(|g| g.store_path == current))
+    }
+
+    /// Point the shell GC root to a previous generation.
+    ///
+    /// If `to` is `None`, the generation before the current one is used.
+    /// The daemon will move the root to a new generation on the next build.
+    pub fn rollback(
+        &self,
+        to: Option<u64>,
+        logger: &slog::Logger,
+    ) -> Result<Generation, RollbackError> {
+        let _lock = self.lock().map_err(RollbackError::Root)?;
+        let generations = self.generations().map_err(RollbackError::Io)?;
+        let target = match to {
+            Some(number) => generations
+                .into_iter()
+                .find(|g| g.number == number)
+                .ok_or(RollbackError::NoSuchGeneration(number))?,
+            None => {
+                let current = self
+                    .current_generation()
+                    .map_err(RollbackError::Io)?
+                    .map(|g| g.number);
+                generations
+                    .into_iter()
+                    .rev()
+                    .find(|g| match current {
+                        Some(current) => g.number < current,
+                        None => true,
+                    })
+                    .ok_or(RollbackError::NoPreviousGeneration)?
+            }
+        };
+        debug!(logger, "rolling back"; "generation" => target.number, "to" => target.store_path.to_str());
+        replace_symlink(&target.store_path, self.shell_gc_root().as_path())
+            .map_err(RollbackError::Root)?;
+        Ok(target)
+    }
+
+    /// Directory containing the GC roots to the build-time dependencies.
+    fn build_inputs_dir(&self) -> AbsPathBuf {
+        self.gc_root_path.join(BUILD_INPUTS_DIR)
+    }
+
+    /// Root the build-time dependencies of the current environment,
+    /// replacing the roots of the previous build.
+    ///
+    /// Should be called after `create_roots`.
+    pub fn root_build_inputs(
+        &self,
+        inputs: &BuildInputs,
+        user: &Username,
+        logger: &slog::Logger,
+    ) -> Result<(), AddRootError> {
+        let registry = RootRegistry::detect(user)?;
+        self.root_build_inputs_with(inputs.drv.as_path(), &inputs.paths, &registry, logger)
+    }
+
+    /// Like `root_build_inputs`, registering the roots with `registry`.
+    fn root_build_inputs_with(
+        &self,
+        drv: &Path,
+        paths: &[StorePath],
+        registry: &RootRegistry,
+        logger: &slog::Logger,
+    ) -> Result<(), AddRootError> {
+        let build_inputs_dir = self.build_inputs_dir();
+        std::fs::create_dir_all(build_inputs_dir.as_path()).map_err(|source| AddRootError {
+            source,
+            msg: format!(
+                "Failed to create the build inputs directory: {}",
+                build_inputs_dir.display()
+            ),
+        })?;
+
+        // symlink name -> store path
+        let mut wanted: HashMap<OsString, &Path> = paths
+            .iter()
+            .filter_map(|p| Some((p.as_path().file_name()?.to_owned(), p.as_path())))
+            .collect();
+        if registry.can_register_drv_files() {
+            wanted.insert(OsString::from("drv"), drv);
+        } else {
+            warn!(
+                logger,
+                "not rooting the derivation, nix-collect-garbage may delete it; \
+                 make the GC root directory writable or build lorri with the nix-daemon feature";
+                "drv" => drv.display()
+            );
+        }
+
+        // remove the roots which are not needed anymore
+        let entries =
+            std::fs::read_dir(build_inputs_dir.as_path()).map_err(|source| AddRootError {
+                source,
+                msg: format!(
+                    "Failed to list the build inputs in {}",
+                    build_inputs_dir.display()
+                ),
+            })?;
+        for entry in entries {
+            let name = entry
+                .map_err(|source| AddRootError {
                     source,
                     msg: format!(
-                        "Failed to create missing nix user gc directory: {}",
-                        nix_gc_root_user_dir.display()
+                        "Failed to list the build inputs in {}",
+                        build_inputs_dir.display()
                     ),
+                })?
+                .file_name();
+            if wanted.contains_key(&name) {
+                continue;
+            }
+            registry.unregister(&build_input_reverse_root_name(self.hash(), &name))?;
+            let root = build_inputs_dir.join(&name);
+            std::fs::remove_file(root.as_path())
+                .or_else(|e| AddRootError::remove(e, root.as_path()))?;
+        }
+
+        debug!(logger, "rooting build inputs"; "count" => wanted.len(), "dir" => build_inputs_dir.display());
+        for (name, store_path) in wanted {
+            let root = build_inputs_dir.join(&name);
+            if std::fs::read_link(root.as_path()).ok().as_deref() != Some(store_path) {
+                replace_symlink(store_path, root.as_path())?;
+            }
+            registry.register(
+                root.as_path(),
+                &build_input_reverse_root_name(self.hash(), &name),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Name of the directory in a project’s GC root directory which
+/// contains the roots to the build-time dependencies.
+const BUILD_INPUTS_DIR: &str = "build_inputs";
+
+/// Name of the directory in a project’s GC root directory which
+/// contains one symlink per generation.
+const GENERATIONS_DIR: &str = "generations";
+
+/// How many generations are kept per project by default.
+pub const DEFAULT_KEEP_GENERATIONS: usize = 5;
+
+/// A successful build of a project, kept as GC root.
+///
+/// Generations are numbered in increasing order,
+/// starting at 1 for the first build of a project.
+#[derive(Debug, Clone)]
+pub struct Generation {
+    /// The number of this generation.
+    pub number: u64,
+    /// The symlink to the generation’s store path.
+    pub root: RootPath,
+    /// The store path of the generation.
+    pub store_path: PathBuf,
+}
+
+/// Error conditions encountered when rolling back to a previous generation.
+#[derive(Error, Debug)]
+pub enum RollbackError {
+    /// The requested generation does not exist (anymore).
+    #[error("Generation {0} does not exist")]
+    NoSuchGeneration(u64),
+    /// There is no generation older than the current one.
+    #[error("There is no previous generation to roll back to")]
+    NoPreviousGeneration,
+    /// The generations could not be read.
+    #[error("Could not read the project generations")]
+    Io(#[source] std::io::Error),
+    /// The GC root could not be moved.
+    #[error("Could not update the GC root")]
+    Root(#[source] AddRootError),
+}
+
+impl ExitAs for RollbackError {
+    fn exit_as(&self) -> ExitErrorType {
+        use ExitErrorType::*;
+        use RollbackError::*;
+        match self {
+            NoSuchGeneration(_) => UserError,
+            NoPreviousGeneration => ExpectedError,
+            Io(_) => Temporary,
+            Root(_) => Temporary,
+        }
+    }
+}
+
+/// Replace the symlink at `link` (if any) with a symlink pointing to `target`.
+///
+/// The new symlink is created next to `link` and then renamed over it,
+/// so there is no moment in which `link` does not exist
+/// (which would let a concurrent `nix-collect-garbage` delete the target).
+fn replace_symlink(target: &Path, link: &Path) -> Result<(), AddRootError> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let mut tmp_name = OsString::from(".");
+    tmp_name.push(link.file_name().unwrap_or_default());
+    tmp_name.push(format!(
+        ".tmp-{}-{}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::SeqCst)
+    ));
+    let tmp = link.with_file_name(tmp_name);
+
+    std::os::unix::fs::symlink(target, &tmp).map_err(|e| AddRootError::symlink(e, target, &tmp))?;
+    std::fs::rename(&tmp, link).map_err(|source| {
+        let _ = std::fs::remove_file(&tmp);
+        AddRootError {
+            source,
+            msg: format!("Failed to move {} to {}", tmp.display(), link.display()),
+        }
+    })
+}
+
+/// The id of the project of `nix_file` and `attr`, the name of its state directory.
+pub(crate) fn project_hash(nix_file: &NixFile, attr: Option<&str>) -> String {
+    let mut id = nix_file.as_absolute_path().as_os_str().as_bytes().to_vec();
+    if let Some(attr) = attr {
+        id.push(b'#');
+        id.extend_from_slice(attr.as_bytes());
+    }
+    format!("{:x}", md5::compute(&id))
+}
+
+/// The status file (see `Project::status_file`) of the project of `nix_file`
+/// and `attr`, without setting up its state directory like `Project::new` does.
+pub fn status_file_of(
+    nix_file: &NixFile,
+    attr: Option<&str>,
+    gc_root_dir: &AbsPathBuf,
+) -> AbsPathBuf {
+    gc_root_dir
+        .join(project_hash(nix_file, attr))
+        .join(STATUS_FILE)
+}
+
+/// Name of the symlink in a project’s state directory
+/// which points to the project’s nix file.
+const NIX_FILE_SYMLINK: &str = "nix_file";
+
+/// Name of the file in a project’s state directory to which
+/// the daemon writes the output of the project’s builds.
+const BUILD_LOG_FILE: &str = "build.log";
+
+/// Name of the file in a project’s state directory
+/// which records the project’s builds, see `history`.
+const HISTORY_FILE: &str = "history.jsonl";
+
+/// Name of the file in a project’s state directory
+/// which says whether the project builds, see `status`.
+const STATUS_FILE: &str = "status.json";
+
+/// Name of the file in a project’s state directory which contains
+/// the time (in seconds since the epoch) the project was last built or used.
+const LAST_USED_FILE: &str = "last_used";
+
+/// Name of the file in a project’s state directory
+/// which is locked while its GC roots change, see `ProjectLock`.
+const LOCK_FILE: &str = "lock";
+
+/// While this is held, no other lorri process (or thread) changes
+/// the project’s GC roots, so that e.g. `lorri watch` and the daemon
+/// don’t replace each other’s roots halfway.
+///
+/// Released when it is dropped, or when the process dies.
+/// Locking again in a thread which holds it returns another handle
+/// to the same lock, which is released once all handles are dropped.
+pub struct ProjectLock(#[allow(dead_code)] Rc<std::fs::File>);
+
+thread_local! {
+    /// The locks the current thread holds, by project directory.
+    static HELD_LOCKS: RefCell<HashMap<PathBuf, Weak<std::fs::File>>> =
+        RefCell::new(HashMap::new());
+}
+
+impl ProjectLock {
+    /// Lock the project with the state directory `project_dir`,
+    /// waiting until whoever holds the lock releases it.
+    fn acquire(project_dir: &AbsPathBuf) -> std::io::Result<ProjectLock> {
+        let held = HELD_LOCKS.with(|held| {
+            let mut held = held.borrow_mut();
+            held.retain(|_, file| file.strong_count() > 0);
+            held.get(project_dir.as_path()).and_then(Weak::upgrade)
+        });
+        // flock would wait for ourselves forever
+        if let Some(file) = held {
+            return Ok(ProjectLock(file));
+        }
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(project_dir.join(LOCK_FILE).as_path())?;
+        ::nix::fcntl::flock(file.as_raw_fd(), ::nix::fcntl::FlockArg::LockExclusive).map_err(
+            |e| match e.as_errno() {
+                Some(errno) => std::io::Error::from_raw_os_error(errno as i32),
+                None => std::io::Error::new(std::io::ErrorKind::Other, e),
+            },
+        )?;
+        let file = Rc::new(file);
+        HELD_LOCKS.with(|held| {
+            held.borrow_mut()
+                .insert(project_dir.as_path().to_owned(), Rc::downgrade(&file))
+        });
+        Ok(ProjectLock(file))
+    }
+}
+
+/// The directory nix uses for per-user GC roots.
+fn nix_gc_root_user_dir(kind: &StoreKind, user: &Username) -> AbsPathBuf {
+    store::state_dir(kind)
+        .join(PathBuf::from("gcroots/per-user"))
+        .join(&user.0)
+}
+
+/// How we tell nix about our GC roots.
+#[derive(Debug)]
+enum RootRegistry {
+    /// Symlink them from the per-user GC root directory.
+    UserDir(AbsPathBuf),
+    /// We can’t write to the GC root directory,
+    /// so we ask the nix daemon to register them for us.
+    Daemon,
+    /// The store is on another machine, it can’t see our roots.
+    /// Holds its URI, which only shows up in the debug log.
+    Unsupported(#[allow(dead_code)] String),
+}
+
+impl RootRegistry {
+    /// Find out how roots can be registered with the store `user` is using.
+    fn detect(user: &Username) -> Result<RootRegistry, AddRootError> {
+        let kind = StoreKind::detect();
+        if let StoreKind::Remote(uri) = kind {
+            return Ok(RootRegistry::Unsupported(uri));
+        }
+        let user_dir = nix_gc_root_user_dir(&kind, user);
+
+        // The user directory sometimes doesn’t exist,
+        // but we can usually create it (it’s root but `rwxrwxrwx`)
+        let created = if user_dir.as_path().is_dir() {
+            Ok(())
+        } else {
+            std::fs::create_dir_all(user_dir.as_path())
+        };
+        let writable = created.and_then(|()| {
+            ::nix::unistd::access(user_dir.as_path(), ::nix::unistd::AccessFlags::W_OK)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::PermissionDenied, e))
+        });
+        match (writable, kind) {
+            (Ok(()), _) => Ok(RootRegistry::UserDir(user_dir)),
+            (Err(_), StoreKind::Daemon) => Ok(RootRegistry::Daemon),
+            (Err(source), _) => Err(AddRootError {
+                source,
+                msg: format!(
+                    "Failed to create missing nix user gc directory: {}",
+                    user_dir.display()
+                ),
+            }),
+        }
+    }
+
+    /// Register the symlink `root` as GC root under `name`.
+    fn register(&self, root: &Path, name: &OsStr) -> Result<(), AddRootError> {
+        match self {
+            RootRegistry::UserDir(dir) => {
+                let reverse = dir.join(name);
+                if std::fs::read_link(reverse.as_path()).ok().as_deref() == Some(root) {
+                    return Ok(());
+                }
+                replace_symlink(root, reverse.as_path())
+            }
+            RootRegistry::Daemon => {
+                let target = std::fs::read_link(root).map_err(|source| AddRootError {
+                    source,
+                    msg: format!("Failed to read the GC root {}", root.display()),
+                })?;
+                let failed = |source| AddRootError {
+                    source,
+                    msg: format!("Failed to register the GC root {}", root.display()),
+                };
+                #[cfg(feature = "nix-daemon")]
+                {
+                    let logger = slog::Logger::root(slog::Discard, slog::o!());
+                    if let Some(mut connection) = crate::nix::daemon::connect(&logger) {
+                        return connection.add_indirect_root(root).map_err(|e| {
+                            failed(std::io::Error::new(std::io::ErrorKind::Other, e))
+                        });
+                    }
                 }
-            })?
+                let status = daemon_add_root_command(&target, root)
+                    .map_err(failed)?
+                    .stdin(std::process::Stdio::null())
+                    .stdout(std::process::Stdio::null())
+                    .status()
+                    .map_err(failed)?;
+                if status.success() {
+                    Ok(())
+                } else {
+                    Err(failed(std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        format!("nix-store exited with {}", status),
+                    )))
+                }
+            }
+            RootRegistry::Unsupported(_) => Ok(()),
         }
+    }
 
-        // We register a garbage collection root, which points back to our `~/.cache/lorri/gc_roots` directory,
-        // so that nix won’t delete our shell environment.
-        let nix_gc_root_user_dir_root =
-            nix_gc_root_user_dir.join(format!("{}-{}", self.hash(), "shell_gc_root"));
-
-        debug!(logger, "connecting root"; "from" => self.shell_gc_root().display(), "to" => nix_gc_root_user_dir_root.display());
-        std::fs::remove_file(&nix_gc_root_user_dir_root.as_path())
-            .or_else(|err| AddRootError::remove(err, &nix_gc_root_user_dir_root.as_path()))?;
-
-        std::os::unix::fs::symlink(&self.shell_gc_root(), &nix_gc_root_user_dir_root.as_path())
-            .map_err(|e| {
-                AddRootError::symlink(
-                    e,
-                    self.shell_gc_root().as_path(),
-                    &nix_gc_root_user_dir_root.as_path(),
-                )
-            })?;
+    /// Whether `register` can register roots to `.drv` files.
+    ///
+    /// Without the `nix-daemon` feature or a reachable nix daemon,
+    /// the daemon registry falls back to `nix-store`, which can’t.
+    fn can_register_drv_files(&self) -> bool {
+        match self {
+            RootRegistry::UserDir(_) | RootRegistry::Unsupported(_) => true,
+            #[cfg(feature = "nix-daemon")]
+            RootRegistry::Daemon => {
+                let logger = slog::Logger::root(slog::Discard, slog::o!());
+                crate::nix::daemon::connect(&logger).is_some()
+            }
+            #[cfg(not(feature = "nix-daemon"))]
+            RootRegistry::Daemon => false,
+        }
+    }
 
-        // TODO: don’t return the RootPath here
-        Ok(OutputPath {
-            shell_gc_root: RootPath(self.shell_gc_root()),
+    /// Whether the symlink `root` is registered as GC root under `name`.
+    ///
+    /// Roots registered by the daemon can’t be checked and
+    /// are assumed to be there.
+    fn is_registered(&self, root: &Path, name: &OsStr) -> bool {
+        match self {
+            RootRegistry::UserDir(dir) => {
+                std::fs::read_link(dir.join(name).as_path()).ok().as_deref() == Some(root)
+            }
+            RootRegistry::Daemon | RootRegistry::Unsupported(_) => true,
+        }
+    }
+
+    /// Remove the GC root registered as `name`.
+    ///
+    /// Roots registered by the daemon are removed by nix
+    /// once the symlink they point to is gone.
+    fn unregister(&self, name: &OsStr) -> Result<(), AddRootError> {
+        match self {
+            RootRegistry::UserDir(dir) => {
+                let reverse = dir.join(name);
+                std::fs::remove_file(reverse.as_path())
+                    .or_else(|e| AddRootError::remove(e, reverse.as_path()))
+            }
+            RootRegistry::Daemon | RootRegistry::Unsupported(_) => Ok(()),
+        }
+    }
+}
+
+/// A GC root which keeps a store path alive until it is dropped,
+/// e.g. a derivation `nix eval` printed until it is built.
+#[derive(Debug)]
+pub struct TemporaryRoot {
+    registry: RootRegistry,
+    name: OsString,
+    /// Holds the symlink the registered root points to.
+    _dir: tempfile::TempDir,
+}
+
+impl TemporaryRoot {
+    /// Register a root to `path` with the store `user` is using.
+    pub fn new(path: &Path, user: &Username) -> Result<TemporaryRoot, AddRootError> {
+        let registry = RootRegistry::detect(user)?;
+        let dir = tempfile::TempDir::new().map_err(|source| AddRootError {
+            source,
+            msg: String::from("Failed to create a temporary GC root directory"),
+        })?;
+        let root = dir.path().join("root");
+        std::os::unix::fs::symlink(path, &root)
+            .map_err(|e| AddRootError::symlink(e, path, &root))?;
+        // unique, because the temporary directory is
+        let name = OsString::from(format!(
+            "lorri-tmp-{:x}",
+            md5::compute(root.as_os_str().as_bytes())
+        ));
+        registry.register(&root, &name)?;
+        Ok(TemporaryRoot {
+            registry,
+            name,
+            _dir: dir,
         })
     }
 }
 
-/// A path to a gc root.
-#[derive(Hash, PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
-pub struct RootPath(pub AbsPathBuf);
+impl Drop for TemporaryRoot {
+    fn drop(&mut self) {
+        // nothing to do about it, nix ignores the dangling root
+        let _ = self.registry.unregister(&self.name);
+    }
+}
 
-impl RootPath {
-    /// `display` the path.
-    pub fn display(&self) -> std::path::Display {
-        self.0.display()
+/// The `nix-store` command which makes the nix daemon record the symlink
+/// `root` to `target` as indirect GC root.
+///
+/// `nix-store` only adds roots while realising paths, which builds derivations,
+/// so this refuses `.drv` files and paths which are not in the store yet;
+/// the daemon’s `AddIndirectRoot` operation (with the `nix-daemon` feature)
+/// can register those.
+fn daemon_add_root_command(target: &Path, root: &Path) -> std::io::Result<std::process::Command> {
+    if target.extension() == Some(OsStr::new("drv")) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "nix-store would build {} to register it; \
+                 make the GC root directory writable or build lorri with the nix-daemon feature",
+                target.display()
+            ),
+        ));
+    }
+    if std::fs::symlink_metadata(target).is_err() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("{} is not in the nix store", target.display()),
+        ));
     }
+    // realising a path which is valid already does nothing
+    let mut cmd = std::process::Command::new("nix-store");
+    cmd.arg("--realise")
+        .arg(target)
+        .arg("--add-root")
+        .arg(root)
+        .arg("--indirect");
+    Ok(cmd)
+}
+
+/// The name of the reverse GC root of the project with `hash`,
+/// which nix follows back into our `gc_roots` directory.
+fn reverse_root_name(hash: &str) -> OsString {
+    OsString::from(format!("{}-{}", hash, "shell_gc_root"))
+}
+
+/// The name of the reverse GC root of generation `number` of the project with `hash`.
+fn generation_reverse_root_name(hash: &str, number: u64) -> OsString {
+    OsString::from(format!("{}-generation-{}", hash, number))
+}
+
+/// The name of the reverse GC root of the build input root `name` of the project with `hash`.
+fn build_input_reverse_root_name(hash: &str, name: &OsStr) -> OsString {
+    let mut file_name = OsString::from(format!("{}-build-input-", hash));
+    file_name.push(name);
+    file_name
+}
+
+/// What `GcRootInfo::repair` had to fix.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Repaired {
+    /// Roots which were missing from nix’ GC root directory.
+    pub reregistered: usize,
+    /// Roots pointing to store paths which don’t exist anymore.
+    pub removed_dangling: usize,
 }
 
-impl OutputPath<RootPath> {
-    /// Check whether all all GC roots exist.
-    pub fn all_exist(&self) -> bool {
-        let crate::builder::OutputPath { shell_gc_root } = self;
+/// A project’s GC roots, as found in the GC root directory.
+///
+/// Unlike `Project`, this does not require the nix file to still exist.
+#[derive(Debug, Clone)]
+pub struct GcRootInfo {
+    /// Hash of the nix file’s absolute path (the project id).
+    pub hash: String,
+    /// The nix file this project was created for.
+    /// `None` if the project was registered by a lorri version
+    /// which did not record its nix file yet.
+    pub nix_file: Option<PathBuf>,
+    /// Directory containing all state of the project.
+    pub project_dir: AbsPathBuf,
+    /// The forward GC root to the shell environment.
+    pub shell_gc_root: RootPath,
+}
 
-        shell_gc_root.0.as_path().exists()
+impl GcRootInfo {
+    /// The last time the project was built or used.
+    ///
+    /// Projects registered before lorri recorded this fall back
+    /// to the modification time of their GC root.
+    pub fn last_used(&self) -> Option<std::time::SystemTime> {
+        let recorded = std::fs::read_to_string(self.project_dir.join(LAST_USED_FILE))
+            .ok()
+            .and_then(|s| s.trim().parse::<u64>().ok())
+            .map(|secs| std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs));
+        recorded.or_else(|| self.created())
+    }
+
+    /// The time the shell GC root was last (re-)created.
+    pub fn created(&self) -> Option<std::time::SystemTime> {
+        std::fs::symlink_metadata(self.shell_gc_root.0.as_path())
+            .and_then(|m| m.modified())
+            .ok()
+    }
+
+    /// The store path of the environment, if it is still rooted.
+    pub fn store_path(&self) -> Option<PathBuf> {
+        std::fs::read_link(self.shell_gc_root.0.as_path()).ok()
+    }
+
+    /// Whether nix knows about the shell GC root.
+    ///
+    /// `None` if we can’t tell, because the roots are registered
+    /// through the nix daemon or the store is remote.
+    pub fn reverse_root_exists(&self, user: &Username) -> Option<bool> {
+        match RootRegistry::detect(user).ok()? {
+            registry @ RootRegistry::UserDir(_) => Some(registry.is_registered(
+                self.shell_gc_root.0.as_path(),
+                &reverse_root_name(&self.hash),
+            )),
+            RootRegistry::Daemon | RootRegistry::Unsupported(_) => None,
+        }
+    }
+
+    /// Whether the project (still) has a GC root to its environment.
+    pub fn has_roots(&self) -> bool {
+        std::fs::symlink_metadata(self.shell_gc_root.0.as_path()).is_ok()
+    }
+
+    /// All GC root symlinks of this project,
+    /// with the name of their reverse root.
+    fn roots(&self) -> std::io::Result<Vec<(AbsPathBuf, OsString)>> {
+        let gc_root = self.project_dir.join("gc_root");
+        let mut roots = vec![(self.shell_gc_root.0.clone(), reverse_root_name(&self.hash))];
+        let dir_entries = |dir: &str| -> std::io::Result<Vec<OsString>> {
+            match std::fs::read_dir(gc_root.join(dir).as_path()) {
+                Ok(entries) => entries
+                    .map(|entry| entry.map(|e| e.file_name()))
+                    // skip temporary files of `replace_symlink`
+                    .filter(|name| match name {
+                        Ok(name) => !name.as_bytes().starts_with(b"."),
+                        Err(_) => true,
+                    })
+                    .collect(),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(vec![]),
+                Err(e) => Err(e),
+            }
+        };
+        for name in dir_entries(GENERATIONS_DIR)? {
+            if let Some(number) = name.to_str().and_then(|n| n.parse::<u64>().ok()) {
+                roots.push((
+                    gc_root.join(GENERATIONS_DIR).join(&name),
+                    generation_reverse_root_name(&self.hash, number),
+                ));
+            }
+        }
+        for name in dir_entries(BUILD_INPUTS_DIR)? {
+            roots.push((
+                gc_root.join(BUILD_INPUTS_DIR).join(&name),
+                build_input_reverse_root_name(&self.hash, &name),
+            ));
+        }
+        Ok(roots)
+    }
+
+    /// All store paths this project keeps alive:
+    /// the environment, its generations and its build inputs.
+    pub fn store_paths(&self) -> std::io::Result<Vec<PathBuf>> {
+        let mut res = vec![];
+        for (root, _) in self.roots()? {
+            match std::fs::read_link(root.as_path()) {
+                // dangling roots don’t keep anything alive
+                Ok(path) if path.exists() => res.push(path),
+                Ok(_) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        res.sort();
+        res.dedup();
+        Ok(res)
+    }
+
+    /// Make sure every GC root of this project is registered with nix,
+    /// and remove roots whose store path was already garbage collected
+    /// (the daemon will rebuild the environment on the next use).
+    pub fn repair(&self, user: &Username, logger: &slog::Logger) -> Result<Repaired, AddRootError> {
+        let _lock = ProjectLock::acquire(&self.project_dir).map_err(|source| AddRootError {
+            source,
+            msg: format!("Failed to lock {}", self.project_dir.display()),
+        })?;
+        let registry = RootRegistry::detect(user)?;
+        let mut repaired = Repaired::default();
+        let roots = self.roots().map_err(|source| AddRootError {
+            source,
+            msg: format!(
+                "Failed to list the GC roots in {}",
+                self.project_dir.display()
+            ),
+        })?;
+        for (root, name) in roots {
+            let target = match std::fs::read_link(root.as_path()) {
+                Ok(target) => target,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(source) => {
+                    return Err(AddRootError {
+                        source,
+                        msg: format!("Failed to read the GC root {}", root.display()),
+                    })
+                }
+            };
+            if !target.exists() {
+                debug!(logger, "removing dangling root"; "root" => root.display(), "to" => target.display());
+                registry.unregister(&name)?;
+                std::fs::remove_file(root.as_path())
+                    .or_else(|e| AddRootError::remove(e, root.as_path()))?;
+                repaired.removed_dangling += 1;
+            } else if !registry.is_registered(root.as_path(), &name) {
+                debug!(logger, "re-registering root"; "root" => root.display());
+                registry.register(root.as_path(), &name)?;
+                repaired.reregistered += 1;
+            }
+        }
+        Ok(repaired)
+    }
+
+    /// Whether the nix file of the project still exists.
+    ///
+    /// `None` if we don’t know which nix file the project belongs to.
+    pub fn nix_file_exists(&self) -> Option<bool> {
+        self.nix_file.as_ref().map(|f| f.is_file())
+    }
+
+    /// Delete all GC roots of this project, including the reverse
+    /// roots nix uses to find them, and the project state directory.
+    pub fn remove(&self, user: &Username) -> std::io::Result<()> {
+        let _lock = ProjectLock::acquire(&self.project_dir)?;
+        self.remove_roots_locked(user)?;
+        std::fs::remove_dir_all(self.project_dir.as_path())
+    }
+
+    /// Delete all GC roots of this project, including the reverse
+    /// roots nix uses to find them, but keep the project state,
+    /// so that the project can be rebuilt.
+    pub fn remove_roots(&self, user: &Username) -> std::io::Result<()> {
+        let _lock = ProjectLock::acquire(&self.project_dir)?;
+        self.remove_roots_locked(user)
+    }
+
+    fn remove_roots_locked(&self, user: &Username) -> std::io::Result<()> {
+        // Roots registered through the daemon vanish with our symlinks,
+        // so only the per-user directory has to be cleaned up.
+        let user_dir = nix_gc_root_user_dir(&StoreKind::detect(), user);
+        match std::fs::read_dir(user_dir.as_path()) {
+            Ok(entries) => {
+                let prefix = format!("{}-", self.hash);
+                for entry in entries {
+                    let entry = entry?;
+                    if entry.file_name().as_bytes().starts_with(prefix.as_bytes()) {
+                        std::fs::remove_file(entry.path())?;
+                    }
+                }
+            }
+            // no reverse roots were ever created
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        let gc_root = self.project_dir.join("gc_root");
+        std::fs::remove_dir_all(gc_root.as_path()).or_else(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => Ok(()),
+            _ => Err(e),
+        })?;
+        std::fs::create_dir_all(gc_root.as_path())
     }
 }
 
+/// List the GC roots of all projects in `gc_root_dir`
+/// (as returned by `Paths.gc_root_dir()`).
+pub fn list_roots(gc_root_dir: &AbsPathBuf) -> std::io::Result<Vec<GcRootInfo>> {
+    let mut res = vec![];
+    for entry in std::fs::read_dir(gc_root_dir)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let hash = match entry.file_name().into_string() {
+            Ok(hash) => hash,
+            // not a directory created by lorri
+            Err(_) => continue,
+        };
+        let project_dir = gc_root_dir.join(&hash);
+        let nix_file = std::fs::read_link(project_dir.join(NIX_FILE_SYMLINK)).ok();
+        res.push(GcRootInfo {
+            shell_gc_root: RootPath(project_dir.join("gc_root").join("shell_gc_root")),
+            hash,
+            nix_file,
+            project_dir,
+        });
+    }
+    res.sort_by(|a, b| a.hash.cmp(&b.hash));
+    Ok(res)
+}
+
 /// Username of the logged in (OS) user.
 #[derive(Clone)]
 pub struct Username(OsString);
 
 impl Username {
     /// Read the username from the `USER` env var.
-    pub fn from_env_var() -> anyhow::Result<Username> {
-        std::env::var_os("USER")
-            .ok_or_else(|| anyhow::anyhow!("Environment variable 'USER' must be set"))
-            .map(Username)
+    ///
+    /// `USER` is not set in every environment (e.g. systemd services
+    /// or containers), so fall back to looking up the current uid.
+    pub fn current() -> anyhow::Result<Username> {
+        match std::env::var_os("USER") {
+            Some(user) if !user.is_empty() => Ok(Username(user)),
+            _ => Self::from_uid(::nix::unistd::getuid()),
+        }
+    }
+
+    /// Look up the name of the user with `uid` in the user database.
+    fn from_uid(uid: ::nix::unistd::Uid) -> anyhow::Result<Username> {
+        match ::nix::unistd::User::from_uid(uid) {
+            Ok(Some(user)) => Ok(Username(OsString::from(user.name))),
+            Ok(None) => Err(anyhow::anyhow!(
+                "Environment variable 'USER' is not set and there is no user with uid {}",
+                uid
+            )),
+            Err(e) => Err(anyhow::Error::new(e).context(format!(
+                "Environment variable 'USER' is not set and looking up the user with uid {} failed",
+                uid
+            ))),
+        }
     }
 }
 
@@ -208,3 +1172,92 @@ impl AddRootError {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn derivations_are_never_built_to_register_them() -> std::io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let drv = dir.path().join("aaaa-shell.drv");
+        let output = dir.path().join("aaaa-shell");
+        std::fs::write(&drv, "Derive(…)")?;
+        std::fs::create_dir(&output)?;
+        let root = dir.path().join("root");
+
+        let err = daemon_add_root_command(&drv, &root).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        // not valid yet, so `--realise` would have to build or substitute it
+        let missing = dir.path().join("bbbb-missing");
+        assert_eq!(
+            daemon_add_root_command(&missing, &root).unwrap_err().kind(),
+            std::io::ErrorKind::NotFound
+        );
+
+        let cmd = daemon_add_root_command(&output, &root)?;
+        assert_eq!(
+            format!("{:?}", cmd),
+            format!(
+                "\"nix-store\" \"--realise\" {:?} \"--add-root\" {:?} \"--indirect\"",
+                output, root
+            )
+        );
+        Ok(())
+    }
+
+    /// Without the nix-daemon feature, the daemon registry can only root
+    /// store paths, so the build inputs are rooted without the derivation.
+    #[cfg(not(feature = "nix-daemon"))]
+    #[test]
+    fn build_inputs_skip_the_drv_if_it_cant_be_rooted() -> std::io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let abs = |p: PathBuf| AbsPathBuf::new(p).unwrap();
+        let nix_file = NixFile::from(abs(dir.path().join("shell.nix")));
+        std::fs::write(nix_file.as_absolute_path(), "{}")?;
+        let cas = ContentAddressable::new(abs(dir.path().join("cas")))?;
+        let project = Project::new(nix_file, &abs(dir.path().join("gc_roots")), cas)?;
+        let drv = dir.path().join("aaaa-shell.drv");
+        std::fs::write(&drv, "Derive(…)")?;
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let drv_root = project.build_inputs_dir().join("drv");
+
+        project
+            .root_build_inputs_with(&drv, &[], &RootRegistry::Daemon, &logger)
+            .unwrap();
+        assert!(std::fs::symlink_metadata(drv_root.as_path()).is_err());
+
+        let user_dir = dir.path().join("gcroots");
+        std::fs::create_dir(&user_dir)?;
+        project
+            .root_build_inputs_with(&drv, &[], &RootRegistry::UserDir(abs(user_dir)), &logger)
+            .unwrap();
+        assert_eq!(std::fs::read_link(drv_root.as_path())?, drv);
+        Ok(())
+    }
+
+    #[test]
+    fn project_lock_waits_until_it_is_released() -> std::io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let project_dir = AbsPathBuf::new(dir.path().to_owned()).unwrap();
+        let lock = ProjectLock::acquire(&project_dir)?;
+        // the thread holding it can lock it again
+        drop(ProjectLock::acquire(&project_dir)?);
+
+        let (tx_locked, rx_locked) = std::sync::mpsc::channel();
+        let project_dir2 = project_dir.clone();
+        let waiting = std::thread::spawn(move || {
+            let _lock = ProjectLock::acquire(&project_dir2).unwrap();
+            tx_locked.send(()).unwrap();
+        });
+        assert!(rx_locked
+            .recv_timeout(std::time::Duration::from_millis(200))
+            .is_err());
+        drop(lock);
+        rx_locked
+            .recv_timeout(std::time::Duration::from_secs(5))
+            .expect("the lock was not released");
+        waiting.join().unwrap();
+        Ok(())
+    }
+}
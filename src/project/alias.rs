@@ -0,0 +1,181 @@
+//! Human-readable names for projects, which commands accept
+//! instead of the path of a project’s nix file.
+//!
+//! A project is named by the `name` in its `.lorri.toml`,
+//! or by `lorri name`, which records the name in `aliases.json`
+//! in lorri’s cache directory and takes precedence.
+
+use crate::project::config::ProjectConfig;
+use crate::AbsPathBuf;
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// The names set with `lorri name`, and the projects they stand for.
+pub type Aliases = BTreeMap<String, Aliased>;
+
+/// The project a name stands for.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Aliased {
+    /// A project which builds its whole nix file,
+    /// saved as just the nix file like names were before they
+    /// could stand for attributes.
+    NixFile(PathBuf),
+    /// A project which builds an attribute of its nix file.
+    Attr {
+        /// The nix file of the project.
+        nix_file: PathBuf,
+        /// The attribute the project builds.
+        attr: String,
+    },
+}
+
+impl Aliased {
+    fn new(nix_file: &Path, attr: Option<&str>) -> Aliased {
+        match attr {
+            None => Aliased::NixFile(nix_file.to_owned()),
+            Some(attr) => Aliased::Attr {
+                nix_file: nix_file.to_owned(),
+                attr: attr.to_owned(),
+            },
+        }
+    }
+
+    /// The nix file and attribute of the project.
+    fn into_parts(self) -> (PathBuf, Option<String>) {
+        match self {
+            Aliased::NixFile(nix_file) => (nix_file, None),
+            Aliased::Attr { nix_file, attr } => (nix_file, Some(attr)),
+        }
+    }
+}
+
+/// Whether `name` can name a project: it must not be confused with a path.
+pub fn is_valid(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && !name.ends_with(".nix")
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+/// The names in `file`, none if it doesn’t exist.
+pub fn read(file: &Path) -> io::Result<Aliases> {
+    match std::fs::read(file) {
+        Ok(contents) => Ok(serde_json::from_slice(&contents)?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Aliases::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Name the project of `nix_file` and `attr` `name` in `file`,
+/// replacing the project’s previous name and the previous project of that name.
+pub fn set(file: &Path, name: &str, nix_file: &Path, attr: Option<&str>) -> io::Result<()> {
+    let project = Aliased::new(nix_file, attr);
+    let mut aliases = read(file)?;
+    aliases.retain(|_, aliased| *aliased != project);
+    aliases.insert(name.to_owned(), project);
+    let dir = file.parent().unwrap_or_else(|| Path::new("."));
+    let mut tmp = tempfile::NamedTempFile::new_in(dir)?;
+    serde_json::to_writer_pretty(&mut tmp, &aliases)?;
+    tmp.write_all(b"\n")?;
+    tmp.persist(file).map_err(|e| e.error)?;
+    Ok(())
+}
+
+/// The nix file and attribute of the project named `name`: set with `lorri name`,
+/// or else the `name` in the `.lorri.toml` of a project lorri built before
+/// (whose attribute is the one its `.lorri.toml` declares, so `None` here).
+pub fn resolve(
+    name: &str,
+    aliases_file: &Path,
+    gc_root_dir: &AbsPathBuf,
+) -> Option<(PathBuf, Option<String>)> {
+    if let Some(aliased) = read(aliases_file).ok()?.remove(name) {
+        return Some(aliased.into_parts());
+    }
+    crate::project::list_roots(gc_root_dir)
+        .ok()?
+        .into_iter()
+        .filter_map(|root| root.nix_file)
+        .find(|nix_file| {
+            ProjectConfig::load(nix_file)
+                .map_or(false, |config| config.name.as_deref() == Some(name))
+        })
+        .map(|nix_file| (nix_file, None))
+}
+
+/// The name of the project of `nix_file` and `attr`, if it has one.
+pub fn name_of(nix_file: &Path, attr: Option<&str>, aliases_file: &Path) -> Option<String> {
+    let project = Aliased::new(nix_file, attr);
+    let aliases = read(aliases_file).unwrap_or_default();
+    aliases
+        .into_iter()
+        .find(|(_, aliased)| *aliased == project)
+        .map(|(name, _)| name)
+        .or_else(|| ProjectConfig::load(nix_file).ok()?.name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_are_set_and_resolved() -> io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let file = tmp.path().join("aliases.json");
+        let gc_root_dir = AbsPathBuf::new_unchecked(tmp.path().join("gc_roots"));
+        std::fs::create_dir(gc_root_dir.as_path())?;
+        let backend = Path::new("/work/backend/shell.nix");
+        let frontend = Path::new("/work/frontend/shell.nix");
+
+        set(&file, "backend", backend, None)?;
+        set(&file, "frontend", frontend, None)?;
+        set(&file, "admin", frontend, Some("shells.admin"))?;
+        // renamed
+        set(&file, "api", backend, None)?;
+        assert_eq!(
+            resolve("api", &file, &gc_root_dir),
+            Some((backend.to_owned(), None))
+        );
+        assert_eq!(resolve("backend", &file, &gc_root_dir), None);
+        assert_eq!(
+            resolve("admin", &file, &gc_root_dir),
+            Some((frontend.to_owned(), Some(String::from("shells.admin"))))
+        );
+        assert_eq!(
+            name_of(frontend, None, &file),
+            Some(String::from("frontend"))
+        );
+        assert_eq!(
+            name_of(frontend, Some("shells.admin"), &file),
+            Some(String::from("admin"))
+        );
+        Ok(())
+    }
+
+    /// Names saved before they could stand for attributes are still read.
+    #[test]
+    fn names_of_nix_files_are_read() -> io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let file = tmp.path().join("aliases.json");
+        std::fs::write(&file, r#"{ "backend": "/work/backend/shell.nix" }"#)?;
+        assert_eq!(
+            read(&file)?.remove("backend"),
+            Some(Aliased::NixFile(PathBuf::from("/work/backend/shell.nix")))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn valid_names() {
+        assert!(is_valid("backend"));
+        assert!(is_valid("web-2.0"));
+        assert!(!is_valid("shell.nix"));
+        assert!(!is_valid("nix/dev"));
+        assert!(!is_valid(".."));
+        assert!(!is_valid(""));
+    }
+}
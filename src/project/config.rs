@@ -0,0 +1,856 @@
+//! Per-project configuration, read from a `.lorri.toml` file
+//! next to the project’s nix file.
+//!
+//! All fields are optional, a missing file is the same as an empty one.
+
+use crate::nix::Backend;
+use crate::watch::WatchPathBuf;
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::path::{Component, Path, PathBuf};
+use thiserror::Error;
+
+/// Name of the per-project configuration file.
+pub const CONFIG_FILE_NAME: &str = ".lorri.toml";
+
+/// Paths whose changes don’t trigger builds, unless `exclude` is set:
+/// the `result` symlinks of `nix-build`, direnv’s cache
+/// and the output directories of common build tools.
+pub const DEFAULT_EXCLUDE: &[&str] = &[
+    "result",
+    "result-*",
+    ".direnv/",
+    "target/",
+    "dist-newstyle/",
+    ".stack-work/",
+    "_build/",
+];
+
+/// Variables of the environment which are not set, unless `env_deny` is set:
+/// the ones which only make sense inside nix’s build sandbox.
+pub const DEFAULT_ENV_DENY: &[&str] = &[
+    "NIX_BUILD_TOP",
+    "NIX_BUILD_CORES",
+    "NIX_LOG_FD",
+    "TMPDIR",
+    "TEMPDIR",
+    "TMP",
+    "TEMP",
+];
+
+/// Variables which hold a list of directories, so the project’s directories
+/// are put before the user’s instead of replacing them, unless `path_like` is set.
+pub const DEFAULT_PATH_LIKE: &[&str] = &[
+    "PKG_CONFIG_PATH",
+    "PYTHONPATH",
+    "MANPATH",
+    "INFOPATH",
+    "CMAKE_PREFIX_PATH",
+    "PERL5LIB",
+];
+
+/// Settings a project can override.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct ProjectConfig {
+    /// The name commands accept instead of the path of the nix file,
+    /// unless one was set with `lorri name`.
+    pub name: Option<String>,
+    /// Tags which group projects, e.g. `work`, for `lorri group`.
+    pub tags: Vec<String>,
+    /// The nix file commands use when they are run in this directory
+    /// without `--shell-file`, relative to it, e.g. `nix/dev.nix`.
+    /// Its settings are read from the `.lorri.toml` next to it.
+    pub shell_file: Option<PathBuf>,
+    /// The attribute of the nix file’s expression to build,
+    /// unless `--attr` is given.
+    pub attr: Option<String>,
+    /// Remove the project’s GC roots if it has not been built or used
+    /// for this many days. `0` means the roots never expire.
+    /// Overrides the daemon’s `--gc-root-ttl-days`.
+    pub gc_root_ttl_days: Option<u64>,
+    /// For flakes, the attribute path of the shell to build, e.g. `devShells.x86_64-linux.ci`.
+    /// Defaults to `devShells.<system>.default` (or `devShell.<system>`).
+    pub flake_attr: Option<String>,
+    /// Start builds right away instead of waiting in the daemon’s build queue,
+    /// even if `--max-concurrent-builds` builds are already running.
+    pub build_immediately: bool,
+    /// Download the shell’s dependencies from the substituters as soon as
+    /// it is evaluated, while it is built.
+    pub prefetch: bool,
+    /// When the daemon shows a desktop notification about a finished build.
+    pub notify: Notify,
+    /// Where the daemon pushes the environment after a successful build,
+    /// from the `[push]` table.
+    pub push: Push,
+    /// A shell command the daemon runs in the project directory after every
+    /// build which succeeded, failed or timed out. It gets the outcome in
+    /// `LORRI_OUTCOME` and the paths in `LORRI_NIX_FILE`, `LORRI_ROOT_PATH`
+    /// and (if the build succeeded) `LORRI_OUT_PATH`.
+    pub post_build: Option<String>,
+    /// A shell command the builder runs in the project directory before
+    /// every evaluation, e.g. to generate nix files. If it fails,
+    /// so does the build.
+    pub pre_eval: Option<String>,
+    /// Kill builds which take longer than this many seconds.
+    /// Unset or `0` means builds can take as long as they need.
+    pub build_timeout_secs: Option<u64>,
+    /// Wait until no files changed for this many milliseconds before building.
+    /// Overrides the daemon’s `--debounce-ms`.
+    pub debounce_ms: Option<u64>,
+    /// Check the watched files for changes every this many seconds,
+    /// instead of relying on the file system to report them
+    /// (which e.g. NFS and Docker bind mounts don’t).
+    /// Overrides the daemon’s `--poll-interval`.
+    pub poll_interval_secs: Option<u64>,
+    /// Environment variables the evaluation can read with `builtins.getEnv`.
+    /// The daemon gets their values from the client which entered the project,
+    /// e.g. `lorri direnv`, since its own environment usually doesn’t have them.
+    pub pass_env: Vec<String>,
+    /// How the environment of the shell is recorded.
+    pub env_backend: EnvBackend,
+    /// Which nix commands evaluate and build this project,
+    /// `legacy` or `nix-command`. Overrides the `nix_backend`
+    /// of the global configuration.
+    pub nix_backend: Option<Backend>,
+    /// Run the shell’s `shellHook` in the project directory whenever
+    /// `lorri direnv` loads the environment, like `nix-shell` does.
+    /// Off by default, because the build already ran it once,
+    /// in the build directory.
+    pub run_shell_hook: bool,
+    /// If set, only these variables of the environment are set
+    /// by `lorri direnv`, `lorri shell` and `lorri env`.
+    /// `*` matches any number of characters, `?` a single one.
+    pub env_allow: Option<Vec<String>>,
+    /// Variables of the environment which are not set, in the same syntax.
+    /// Replaces `DEFAULT_ENV_DENY`, `[]` denies nothing.
+    pub env_deny: Option<Vec<String>>,
+    /// How the project’s `PATH` is combined with the user’s.
+    pub path_merge: PathMerge,
+    /// Remove directories which appear in `PATH` more than once,
+    /// keeping the first.
+    pub path_dedup: bool,
+    /// Variables which hold a `:` separated list of directories, to which
+    /// the project’s directories are prepended instead of replacing the user’s,
+    /// in the same syntax as `env_allow`. Replaces `DEFAULT_PATH_LIKE`.
+    pub path_like: Option<Vec<String>>,
+    /// Files to watch in addition to the ones the evaluation reads,
+    /// relative to the nix file’s directory. `*` and `?` match within
+    /// a path component, `**` matches any number of directories.
+    pub watch: Vec<String>,
+    /// Paths whose changes don’t trigger builds, in `.gitignore` syntax,
+    /// relative to the nix file’s directory. Files the evaluation reads
+    /// are still watched. Replaces `DEFAULT_EXCLUDE`, `[]` excludes nothing.
+    pub exclude: Option<Vec<String>>,
+    /// Extra arguments for nix, from the `[nix]` table.
+    pub nix: NixArguments,
+    /// Makes this directory the root of a workspace of several projects:
+    /// their nix files, relative to it, in the same syntax as `watch`,
+    /// e.g. `services/*/shell.nix`.
+    pub workspace: Vec<String>,
+    /// The nix files of other projects this one uses files of,
+    /// relative to this directory, e.g. `../common/shell.nix`.
+    /// The daemon builds them first, and rebuilds this project
+    /// when their files change.
+    pub depends_on: Vec<PathBuf>,
+}
+
+/// The ways lorri can record the environment of a shell.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum EnvBackend {
+    /// Build a copy of the shell derivation whose builder dumps its environment.
+    KeepEnvHack,
+    /// Use `nix print-dev-env`, like `nix develop` does.
+    /// Works for derivations the keep-env-hack breaks on,
+    /// but needs nix 2.13 or newer.
+    /// Shells with `__structuredAttrs` always use it.
+    PrintDevEnv,
+}
+
+impl Default for EnvBackend {
+    fn default() -> EnvBackend {
+        EnvBackend::KeepEnvHack
+    }
+}
+
+/// Which finished builds of a project the daemon notifies about.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Notify {
+    /// None of them.
+    Never,
+    /// Failed (and timed out) builds, and the first successful one after them.
+    Failures,
+    /// Every build.
+    Always,
+}
+
+impl Default for Notify {
+    fn default() -> Notify {
+        Notify::Never
+    }
+}
+
+/// The binary caches a project’s environment is pushed to,
+/// so that others can substitute it instead of building it.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct Push {
+    /// Push to this Cachix cache with `cachix push`.
+    pub cachix: Option<String>,
+    /// Copy to this store with `nix copy --to`, e.g. `s3://team-cache`.
+    pub to: Option<String>,
+}
+
+impl Push {
+    /// Whether there is anywhere to push to.
+    pub fn is_empty(&self) -> bool {
+        self.cachix.is_none() && self.to.is_none()
+    }
+}
+
+/// The ways the project’s `PATH` can be combined with the user’s.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum PathMerge {
+    /// The project’s directories come first, so they shadow the user’s tools.
+    Prepend,
+    /// The user’s directories come first, so they shadow the project’s tools.
+    Append,
+    /// Only the project’s directories are used.
+    Replace,
+}
+
+impl Default for PathMerge {
+    fn default() -> PathMerge {
+        PathMerge::Prepend
+    }
+}
+
+impl PathMerge {
+    /// The name in `.lorri.toml`.
+    pub fn name(self) -> &'static str {
+        match self {
+            PathMerge::Prepend => "prepend",
+            PathMerge::Append => "append",
+            PathMerge::Replace => "replace",
+        }
+    }
+}
+
+/// Extra arguments lorri passes to nix when it builds the project.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct NixArguments {
+    /// Arguments to the function in the nix file, like `--arg name value`.
+    /// The values are nix expressions.
+    pub args: BTreeMap<String, String>,
+    /// Arguments to the function in the nix file, like `--argstr name value`.
+    pub argstrs: BTreeMap<String, String>,
+    /// Nix settings for the evaluation and build, like `--option name value`.
+    pub options: BTreeMap<String, String>,
+}
+
+/// Error conditions encountered when reading the project configuration.
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    /// The file exists, but could not be read.
+    #[error("Could not read the project configuration {0}")]
+    Io(PathBuf, #[source] std::io::Error),
+    /// The file is not a valid configuration.
+    #[error("Invalid project configuration in {0}")]
+    Parse(PathBuf, #[source] toml::de::Error),
+}
+
+impl ProjectConfig {
+    /// Read the configuration belonging to `nix_file`.
+    pub fn load(nix_file: &Path) -> Result<ProjectConfig, ConfigError> {
+        Self::load_file(Self::file_for(nix_file))
+    }
+
+    /// Read the configuration in `dir`, e.g. to find out
+    /// which nix file it declares.
+    pub fn load_dir(dir: &Path) -> Result<ProjectConfig, ConfigError> {
+        Self::load_file(dir.join(CONFIG_FILE_NAME))
+    }
+
+    fn load_file(file: PathBuf) -> Result<ProjectConfig, ConfigError> {
+        match std::fs::read_to_string(&file) {
+            Ok(contents) => Self::parse(&contents).map_err(|e| ConfigError::Parse(file, e)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(ProjectConfig::default()),
+            Err(e) => Err(ConfigError::Io(file, e)),
+        }
+    }
+
+    /// The path of the configuration file belonging to `nix_file`.
+    pub fn file_for(nix_file: &Path) -> PathBuf {
+        nix_file
+            .parent()
+            .unwrap_or_else(|| Path::new("/"))
+            .join(CONFIG_FILE_NAME)
+    }
+
+    /// The values of the `pass_env` variables which are set in our environment.
+    pub fn passed_env(&self) -> BTreeMap<String, String> {
+        self.pass_env
+            .iter()
+            .filter_map(|name| std::env::var(name).ok().map(|value| (name.clone(), value)))
+            .collect()
+    }
+
+    /// The `env_deny` patterns, or the default ones.
+    pub fn env_deny_patterns(&self) -> Vec<String> {
+        match &self.env_deny {
+            Some(patterns) => patterns.clone(),
+            None => DEFAULT_ENV_DENY.iter().map(|p| p.to_string()).collect(),
+        }
+    }
+
+    /// The `path_like` patterns, or the default ones.
+    pub fn path_like_patterns(&self) -> Vec<String> {
+        match &self.path_like {
+            Some(patterns) => patterns.clone(),
+            None => DEFAULT_PATH_LIKE.iter().map(|p| p.to_string()).collect(),
+        }
+    }
+
+    /// Whether the variable `name` is one of the `path_like` ones.
+    pub fn is_path_like(&self, name: &str) -> bool {
+        self.path_like_patterns()
+            .iter()
+            .any(|pattern| matches_name(pattern, name))
+    }
+
+    /// The settings `envrc.bash` reads, to be put before it:
+    /// the bash function `lorri_keep_variable NAME`, which succeeds
+    /// if `env_allow` and `env_deny` keep the variable
+    /// (and always for `shellHook` if `run_shell_hook` is set),
+    /// `lorri_is_path_like NAME` for the `path_like` variables,
+    /// and how `PATH` is merged.
+    pub fn envrc_prelude(&self) -> String {
+        fn alternatives(patterns: &[String]) -> String {
+            patterns
+                .iter()
+                .map(String::as_str)
+                .map(case_pattern)
+                .collect::<Vec<_>>()
+                .join("|")
+        }
+        let mut script = String::from("lorri_keep_variable() {\n");
+        if self.run_shell_hook {
+            // `lorri direnv` needs it to run the hook
+            script.push_str("    if [ \"$1\" = shellHook ]; then return 0; fi\n");
+        }
+        let deny = self.env_deny_patterns();
+        if !deny.is_empty() {
+            script.push_str(&format!(
+                "    case \"$1\" in {}) return 1;; esac\n",
+                alternatives(&deny)
+            ));
+        }
+        match &self.env_allow {
+            None => script.push_str("    return 0\n"),
+            Some(allow) if allow.is_empty() => script.push_str("    return 1\n"),
+            Some(allow) => script.push_str(&format!(
+                "    case \"$1\" in {}) return 0;; esac\n    return 1\n",
+                alternatives(allow)
+            )),
+        }
+        script.push_str("}\n");
+        script.push_str("lorri_is_path_like() {\n");
+        let path_like = self.path_like_patterns();
+        if !path_like.is_empty() {
+            script.push_str(&format!(
+                "    case \"$1\" in {}) return 0;; esac\n",
+                alternatives(&path_like)
+            ));
+        }
+        script.push_str("    return 1\n}\n");
+        script.push_str(&format!("lorri_path_merge={}\n", self.path_merge.name()));
+        if self.path_dedup {
+            script.push_str("lorri_path_dedup=1\n");
+        }
+        script
+    }
+
+    /// The `exclude` patterns, or the default ones.
+    pub fn exclude_patterns(&self) -> Vec<String> {
+        match &self.exclude {
+            Some(patterns) => patterns.clone(),
+            None => DEFAULT_EXCLUDE.iter().map(|p| p.to_string()).collect(),
+        }
+    }
+
+    /// The paths to watch for the `watch` patterns of `nix_file`’s project.
+    ///
+    /// Besides the files which match right now, this includes the directories
+    /// whose listings the patterns were matched against, so that new files
+    /// which match trigger a build as well.
+    pub fn watch_paths(&self, nix_file: &Path) -> Vec<WatchPathBuf> {
+        let dir = nix_file.parent().unwrap_or_else(|| Path::new("/"));
+        let mut paths = vec![];
+        for pattern in &self.watch {
+            let components: Vec<&str> = Path::new(pattern)
+                .components()
+                .filter_map(|c| match c {
+                    Component::Normal(name) => name.to_str(),
+                    Component::ParentDir => Some(".."),
+                    _ => None,
+                })
+                .collect();
+            let base = if pattern.starts_with('/') {
+                PathBuf::from("/")
+            } else {
+                dir.to_owned()
+            };
+            expand_glob(base, &components, false, &mut paths);
+        }
+        paths.sort();
+        paths.dedup();
+        paths
+    }
+
+    /// The nix files of the workspace in `root`, which exist right now.
+    pub fn workspace_members(&self, root: &Path) -> Vec<PathBuf> {
+        let mut members: Vec<PathBuf> = ProjectConfig {
+            watch: self.workspace.clone(),
+            ..ProjectConfig::default()
+        }
+        .watch_paths(&root.join(CONFIG_FILE_NAME))
+        .into_iter()
+        .map(|path| path.as_ref().to_owned())
+        .filter(|path| path.is_file())
+        .collect();
+        members.sort();
+        members.dedup();
+        members
+    }
+
+    /// The absolute nix files of the `depends_on` projects of `nix_file`.
+    pub fn dependencies(&self, nix_file: &Path) -> Vec<PathBuf> {
+        let dir = nix_file.parent().unwrap_or_else(|| Path::new("/"));
+        self.depends_on
+            .iter()
+            .map(|dependency| {
+                // lexically, like the nix files of projects are
+                let mut path = PathBuf::new();
+                for component in dir.join(dependency).components() {
+                    match component {
+                        Component::CurDir => {}
+                        Component::ParentDir => {
+                            path.pop();
+                        }
+                        component => path.push(component),
+                    }
+                }
+                path
+            })
+            .filter(|path| path != nix_file)
+            .collect()
+    }
+
+    /// Parse the contents of a configuration file.
+    pub fn parse(contents: &str) -> Result<ProjectConfig, toml::de::Error> {
+        toml::from_str(contents)
+    }
+}
+
+/// The root of the workspace `dir` is in, and its configuration:
+/// the closest directory above (or at) `dir` whose `.lorri.toml`
+/// sets `workspace`.
+pub fn find_workspace(dir: &Path) -> Option<(PathBuf, ProjectConfig)> {
+    dir.ancestors().find_map(|ancestor| {
+        // a broken configuration elsewhere is not this project’s problem
+        ProjectConfig::load_dir(ancestor)
+            .ok()
+            .filter(|config| !config.workspace.is_empty())
+            .map(|config| (ancestor.to_owned(), config))
+    })
+}
+
+/// The member of a workspace which is closest to `dir`: the one in the
+/// nearest directory above (or at) `dir`, if any.
+pub fn nearest_member<'a>(members: &'a [PathBuf], dir: &Path) -> Option<&'a PathBuf> {
+    members
+        .iter()
+        .filter(|member| {
+            member
+                .parent()
+                .map_or(false, |member_dir| dir.starts_with(member_dir))
+        })
+        .max_by_key(|member| member.components().count())
+}
+
+/// `pattern` as a pattern of a bash `case`, where only `*` and `?` are special.
+fn case_pattern(pattern: &str) -> String {
+    pattern
+        .chars()
+        .flat_map(|c| match c {
+            '*' | '?' => vec![c],
+            c if c.is_ascii_alphanumeric() || c == '_' => vec![c],
+            c => vec!['\\', c],
+        })
+        .collect()
+}
+
+/// Whether `name` matches `pattern`, where `*` matches any number
+/// of characters and `?` a single one, like `case_pattern` in bash.
+fn matches_name(pattern: &str, name: &str) -> bool {
+    match pattern.chars().next() {
+        None => name.is_empty(),
+        Some('*') => (0..=name.len())
+            .filter(|i| name.is_char_boundary(*i))
+            .any(|i| matches_name(&pattern[1..], &name[i..])),
+        Some(p) => match name.chars().next() {
+            Some(n) if p == '?' || p == n => {
+                matches_name(&pattern[p.len_utf8()..], &name[n.len_utf8()..])
+            }
+            _ => false,
+        },
+    }
+}
+
+fn is_glob(component: &str) -> bool {
+    component.contains(['*', '?'])
+}
+
+/// Collect the paths matching `components` below `base` into `paths`.
+///
+/// A path without globs is watched even if it doesn’t exist yet.
+/// Below a glob, only existing paths are, since the directories
+/// the glob was expanded in are watched for new entries.
+fn expand_glob(base: PathBuf, components: &[&str], globbed: bool, paths: &mut Vec<WatchPathBuf>) {
+    let (component, rest) = match components.split_first() {
+        None if globbed && std::fs::symlink_metadata(&base).is_err() => return,
+        None => return paths.push(WatchPathBuf::Normal(base)),
+        Some((component, rest)) if !is_glob(component) => {
+            return expand_glob(base.join(component), rest, globbed, paths)
+        }
+        Some((component, rest)) => (*component, rest),
+    };
+    let entries: Vec<PathBuf> = match std::fs::read_dir(&base) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .collect(),
+        Err(_) => return,
+    };
+    paths.push(WatchPathBuf::Normal(base.clone()));
+    if component == "**" {
+        // zero directories, or one more and `**` again
+        expand_glob(base, rest, true, paths);
+        for entry in entries.into_iter().filter(|entry| entry.is_dir()) {
+            expand_glob(entry, components, true, paths);
+        }
+    } else {
+        let regex = glob_regex(component);
+        for entry in entries {
+            if entry
+                .file_name()
+                .and_then(|name| name.to_str())
+                .map_or(false, |name| regex.is_match(name))
+            {
+                expand_glob(entry, rest, true, paths);
+            }
+        }
+    }
+}
+
+/// A regex matching the file names `*` and `?` in `component` stand for.
+fn glob_regex(component: &str) -> Regex {
+    let mut regex = String::from("^");
+    for c in component.chars() {
+        match c {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    Regex::new(&regex).expect("invalid glob regex")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_config_is_default() {
+        assert_eq!(ProjectConfig::parse("").unwrap(), ProjectConfig::default());
+    }
+
+    #[test]
+    fn parse_options() {
+        let with = |config: fn(&mut ProjectConfig)| {
+            let mut c = ProjectConfig::default();
+            config(&mut c);
+            c
+        };
+        let cases: Vec<(&str, ProjectConfig)> = vec![
+            (
+                "gc_root_ttl_days = 30",
+                with(|c| c.gc_root_ttl_days = Some(30)),
+            ),
+            (
+                "flake_attr = \"devShells.x86_64-linux.ci\"",
+                with(|c| c.flake_attr = Some(String::from("devShells.x86_64-linux.ci"))),
+            ),
+            (
+                "shell_file = \"nix/dev.nix\"\nattr = \"shells.ci\"",
+                with(|c| {
+                    c.shell_file = Some(PathBuf::from("nix/dev.nix"));
+                    c.attr = Some(String::from("shells.ci"));
+                }),
+            ),
+            ("run_shell_hook = true", with(|c| c.run_shell_hook = true)),
+            ("debounce_ms = 2000", with(|c| c.debounce_ms = Some(2000))),
+            (
+                "poll_interval_secs = 5",
+                with(|c| c.poll_interval_secs = Some(5)),
+            ),
+            (
+                "notify = \"failures\"",
+                with(|c| c.notify = Notify::Failures),
+            ),
+            (
+                "[push]\ncachix = \"team\"",
+                with(|c| c.push.cachix = Some(String::from("team"))),
+            ),
+            (
+                "post_build = \"make tags\"",
+                with(|c| c.post_build = Some(String::from("make tags"))),
+            ),
+            (
+                "pre_eval = \"crate2nix generate\"",
+                with(|c| c.pre_eval = Some(String::from("crate2nix generate"))),
+            ),
+            (
+                "tags = [\"work\", \"oss\"]",
+                with(|c| c.tags = vec![String::from("work"), String::from("oss")]),
+            ),
+            (
+                "build_timeout_secs = 600",
+                with(|c| c.build_timeout_secs = Some(600)),
+            ),
+            (
+                "pass_env = [\"MY_TOKEN\"]",
+                with(|c| c.pass_env = vec![String::from("MY_TOKEN")]),
+            ),
+            (
+                "env_backend = \"print-dev-env\"",
+                with(|c| c.env_backend = EnvBackend::PrintDevEnv),
+            ),
+            (
+                "nix_backend = \"nix-command\"",
+                with(|c| c.nix_backend = Some(Backend::NixCommand)),
+            ),
+        ];
+        for (toml, expected) in cases {
+            assert_eq!(ProjectConfig::parse(toml).unwrap(), expected, "{}", toml);
+        }
+
+        for invalid in &[
+            "gc_root_ttl_days = \"soon\"",
+            "debounce_ms = -1",
+            "notify = \"sometimes\"",
+            "env_backend = \"nix-shell\"",
+            "nix_backend = \"nix-env\"",
+        ] {
+            assert!(ProjectConfig::parse(invalid).is_err(), "{}", invalid);
+        }
+
+        let default = ProjectConfig::default();
+        assert!(!default.run_shell_hook);
+        assert_eq!(default.notify, Notify::Never);
+        assert!(default.push.is_empty());
+        assert_eq!(default.pre_eval, None);
+    }
+
+    #[test]
+    fn env_filter() -> std::io::Result<()> {
+        let keeps = |config: &str, name: &str| -> std::io::Result<bool> {
+            let script = format!(
+                "{}lorri_keep_variable {}",
+                ProjectConfig::parse(config).unwrap().envrc_prelude(),
+                name
+            );
+            Ok(std::process::Command::new("bash")
+                .args(["-c", &script])
+                .status()?
+                .success())
+        };
+        assert!(keeps("", "FOO")?);
+        assert!(!keeps("", "NIX_BUILD_TOP")?);
+        assert!(keeps("env_deny = []", "NIX_BUILD_TOP")?);
+        assert!(!keeps("env_deny = [\"GO*\"]", "GOPATH")?);
+        assert!(keeps("env_deny = [\"GO*\"]", "TMPDIR")?);
+        assert!(keeps("env_allow = [\"PATH\", \"CARGO_?\"]", "CARGO_X")?);
+        assert!(!keeps("env_allow = [\"PATH\", \"CARGO_?\"]", "CARGO_XY")?);
+        assert!(!keeps("env_allow = []", "PATH")?);
+        assert!(!keeps("env_allow = [\"PATH\"]", "shellHook")?);
+        assert!(keeps(
+            "env_allow = [\"PATH\"]\nrun_shell_hook = true",
+            "shellHook"
+        )?);
+        assert!(keeps(
+            "env_deny = [\"*\"]\nrun_shell_hook = true",
+            "shellHook"
+        )?);
+        // special characters are matched literally
+        assert!(!keeps("env_allow = [\"A;B\"]", "AB")?);
+        Ok(())
+    }
+
+    #[test]
+    fn path_like_variables() {
+        let config = ProjectConfig::default();
+        assert!(config.is_path_like("PYTHONPATH"));
+        assert!(!config.is_path_like("CFLAGS"));
+        let config = ProjectConfig::parse("path_like = [\"GO*\", \"LUA_?PATH\"]").unwrap();
+        assert!(config.is_path_like("GOPATH"));
+        assert!(config.is_path_like("LUA_CPATH"));
+        assert!(!config.is_path_like("LUA_PATH"));
+        assert!(!config.is_path_like("PYTHONPATH"));
+    }
+
+    #[test]
+    fn parse_path_merge() {
+        let config = ProjectConfig::parse("path_merge = \"append\"\npath_dedup = true").unwrap();
+        assert_eq!(config.path_merge, PathMerge::Append);
+        assert!(config
+            .envrc_prelude()
+            .contains("lorri_path_merge=append\nlorri_path_dedup=1\n"));
+        assert_eq!(ProjectConfig::default().path_merge, PathMerge::Prepend);
+        assert!(ProjectConfig::parse("path_merge = \"interleave\"").is_err());
+    }
+
+    #[test]
+    fn dependencies() {
+        let config = ProjectConfig::parse(
+            "depends_on = [\"../common/shell.nix\", \"/libs/./ui/default.nix\", \"shell.nix\"]",
+        )
+        .unwrap();
+        assert_eq!(
+            config.dependencies(Path::new("/work/app/shell.nix")),
+            vec![
+                PathBuf::from("/work/common/shell.nix"),
+                PathBuf::from("/libs/ui/default.nix")
+            ]
+        );
+    }
+
+    #[test]
+    fn workspace_members() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let root = tmp.path();
+        for dir in &["services/api", "services/web", "services/web/admin", "docs"] {
+            std::fs::create_dir_all(root.join(dir))?;
+        }
+        for file in &[
+            "shell.nix",
+            "services/api/shell.nix",
+            "services/web/shell.nix",
+        ] {
+            std::fs::write(root.join(file), "")?;
+        }
+        let config = ProjectConfig::parse(
+            "workspace = [\"shell.nix\", \"services/*/shell.nix\", \"missing/shell.nix\"]",
+        )
+        .unwrap();
+        let members = config.workspace_members(root);
+        assert_eq!(
+            members,
+            vec![
+                root.join("services/api/shell.nix"),
+                root.join("services/web/shell.nix"),
+                root.join("shell.nix"),
+            ]
+        );
+        assert_eq!(
+            nearest_member(&members, &root.join("services/web/admin")),
+            Some(&root.join("services/web/shell.nix"))
+        );
+        assert_eq!(
+            nearest_member(&members, &root.join("docs")),
+            Some(&root.join("shell.nix"))
+        );
+        assert_eq!(nearest_member(&members, Path::new("/elsewhere")), None);
+        Ok(())
+    }
+
+    #[test]
+    fn parse_exclude() {
+        assert_eq!(
+            ProjectConfig::parse("exclude = [\"build/\"]")
+                .unwrap()
+                .exclude_patterns(),
+            vec![String::from("build/")]
+        );
+        assert!(ProjectConfig::parse("exclude = []")
+            .unwrap()
+            .exclude_patterns()
+            .is_empty());
+        assert!(ProjectConfig::default()
+            .exclude_patterns()
+            .contains(&String::from("result")));
+    }
+
+    #[test]
+    fn watch_globs() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let dir = tmp.path();
+        std::fs::create_dir_all(dir.join("nested/deeper"))?;
+        for file in &["poetry.lock", "a.json", "b.json", "nested/deeper/c.json"] {
+            std::fs::write(dir.join(file), "")?;
+        }
+        let config = ProjectConfig {
+            watch: vec![
+                String::from("poetry.lock"),
+                String::from("not-there-yet"),
+                String::from("*.json"),
+                String::from("**/c.json"),
+            ],
+            ..ProjectConfig::default()
+        };
+        let normal = |p: &str| WatchPathBuf::Normal(dir.join(p));
+        let mut expected = vec![
+            normal("poetry.lock"),
+            normal("not-there-yet"),
+            normal(""),
+            normal("a.json"),
+            normal("b.json"),
+            normal("nested"),
+            normal("nested/deeper"),
+            normal("nested/deeper/c.json"),
+        ];
+        expected.sort();
+        assert_eq!(config.watch_paths(&dir.join("shell.nix")), expected);
+        Ok(())
+    }
+
+    #[test]
+    fn parse_nix_arguments() {
+        let config = ProjectConfig::parse(
+            r#"
+            [nix]
+            args = { withDocs = "true" }
+            argstrs = { name = "lorri" }
+            options = { sandbox = "false" }
+            "#,
+        )
+        .unwrap();
+        let map = |k: &str, v: &str| {
+            let mut map = BTreeMap::new();
+            map.insert(k.to_string(), v.to_string());
+            map
+        };
+        assert_eq!(
+            config.nix,
+            NixArguments {
+                args: map("withDocs", "true"),
+                argstrs: map("name", "lorri"),
+                options: map("sandbox", "false"),
+            }
+        );
+    }
+}
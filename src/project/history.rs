@@ -0,0 +1,178 @@
+//! The daemon’s record of a project’s builds, as listed by `lorri history`.
+//!
+//! Every finished build is a JSON line in `history.jsonl`
+//! in the project’s state directory; only the latest `MAX_RECORDS` are kept.
+
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// How many builds are remembered per project.
+const MAX_RECORDS: usize = 500;
+
+/// A finished (or cancelled) build.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BuildRecord {
+    /// When the build started, in milliseconds since the epoch
+    pub started_ms: u64,
+    /// How long the build took, in milliseconds
+    pub duration_ms: u64,
+    /// How the build ended
+    pub outcome: Outcome,
+    /// Why the build started, e.g. `files changed: shell.nix`
+    pub reason: String,
+    /// The environment it built, if it succeeded
+    pub out_path: Option<PathBuf>,
+}
+
+/// How a build ended.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Outcome {
+    /// The environment was built
+    Success,
+    /// The evaluation or build failed
+    Failure,
+    /// The build exceeded the project’s `build_timeout_secs`
+    Timeout,
+    /// A newer build replaced it before it finished
+    Cancelled,
+}
+
+impl Outcome {
+    /// How `lorri history` prints the outcome.
+    pub fn name(self) -> &'static str {
+        match self {
+            Outcome::Success => "success",
+            Outcome::Failure => "failure",
+            Outcome::Timeout => "timeout",
+            Outcome::Cancelled => "cancelled",
+        }
+    }
+}
+
+/// Append `record` to the history in `path`,
+/// dropping the oldest records beyond `MAX_RECORDS`.
+pub fn record(path: &Path, record: BuildRecord) -> io::Result<()> {
+    let mut records = read(path)?;
+    records.push(record);
+    let keep_from = records.len().saturating_sub(MAX_RECORDS);
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let mut tmp = tempfile::NamedTempFile::new_in(dir)?;
+    for record in &records[keep_from..] {
+        serde_json::to_writer(&mut tmp, record)?;
+        tmp.write_all(b"\n")?;
+    }
+    tmp.persist(path).map_err(|e| e.error)?;
+    Ok(())
+}
+
+/// The builds recorded in `path`, oldest first.
+///
+/// Lines which can’t be parsed (e.g. of a newer lorri) are skipped.
+pub fn read(path: &Path) -> io::Result<Vec<BuildRecord>> {
+    let file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    let mut records = vec![];
+    for line in io::BufReader::new(file).lines() {
+        if let Ok(record) = serde_json::from_str(&line?) {
+            records.push(record);
+        }
+    }
+    Ok(records)
+}
+
+/// Statistics over the durations of some builds.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct Summary {
+    /// How many builds there were
+    pub builds: usize,
+    /// How many of them succeeded
+    pub successes: usize,
+    /// The average duration of the finished (not cancelled) builds, in milliseconds
+    pub average_ms: u64,
+    /// The duration 95% of the finished builds took at most, in milliseconds
+    pub p95_ms: u64,
+}
+
+impl Summary {
+    /// The statistics of `records`, `None` if no build finished.
+    pub fn of(records: &[BuildRecord]) -> Option<Summary> {
+        let mut durations: Vec<u64> = records
+            .iter()
+            .filter(|r| r.outcome != Outcome::Cancelled)
+            .map(|r| r.duration_ms)
+            .collect();
+        if durations.is_empty() {
+            return None;
+        }
+        durations.sort_unstable();
+        // nearest-rank percentile
+        let p95_rank = (durations.len() * 95 + 99) / 100;
+        Some(Summary {
+            builds: records.len(),
+            successes: records
+                .iter()
+                .filter(|r| r.outcome == Outcome::Success)
+                .count(),
+            average_ms: durations.iter().sum::<u64>() / durations.len() as u64,
+            p95_ms: durations[p95_rank - 1],
+        })
+    }
+
+    /// The average duration.
+    pub fn average(&self) -> Duration {
+        Duration::from_millis(self.average_ms)
+    }
+
+    /// The 95th percentile duration.
+    pub fn p95(&self) -> Duration {
+        Duration::from_millis(self.p95_ms)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build(duration_ms: u64, outcome: Outcome) -> BuildRecord {
+        BuildRecord {
+            started_ms: 0,
+            duration_ms,
+            outcome,
+            reason: String::from("ping"),
+            out_path: None,
+        }
+    }
+
+    #[test]
+    fn records_are_appended_and_summarized() -> io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let path = tmp.path().join("history.jsonl");
+        assert_eq!(read(&path)?, vec![]);
+        for ms in 1..=20 {
+            record(&path, build(ms * 100, Outcome::Success))?;
+        }
+        record(&path, build(100_000, Outcome::Cancelled))?;
+        record(&path, build(50, Outcome::Failure))?;
+
+        let records = read(&path)?;
+        assert_eq!(records.len(), 22);
+        assert_eq!(records[0], build(100, Outcome::Success));
+        assert_eq!(
+            Summary::of(&records),
+            Some(Summary {
+                builds: 22,
+                successes: 20,
+                // the cancelled build doesn’t count
+                average_ms: (21_000 + 50) / 21,
+                p95_ms: 1900,
+            })
+        );
+        assert_eq!(Summary::of(&[build(1, Outcome::Cancelled)]), None);
+        Ok(())
+    }
+}
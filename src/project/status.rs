@@ -0,0 +1,156 @@
+//! The state of a project’s builds as a small JSON file, `status.json`
+//! in the project’s state directory, so that shell prompts can show it
+//! without talking to the daemon.
+//!
+//! The daemon rewrites it (atomically) whenever a build starts or finishes.
+//! Its format is a stable interface: fields are only ever added,
+//! and `version` changes if an existing one changes.
+
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The version of the file’s format.
+pub const VERSION: u32 = 1;
+
+/// The state of a project’s builds.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum State {
+    /// A build is waiting in the build queue or running
+    Building,
+    /// The last build succeeded
+    Success,
+    /// The last build failed
+    Failure,
+    /// The last build took longer than the project’s `build_timeout_secs`
+    Timeout,
+}
+
+impl State {
+    /// How `lorri status` prints the state.
+    pub fn name(self) -> &'static str {
+        match self {
+            State::Building => "building",
+            State::Success => "success",
+            State::Failure => "failure",
+            State::Timeout => "timeout",
+        }
+    }
+}
+
+/// The contents of the status file.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Status {
+    /// See `VERSION`
+    pub version: u32,
+    /// The project’s nix file
+    pub nix_file: PathBuf,
+    /// The state of its builds
+    pub status: State,
+    /// Since when the project is in this state, in seconds since the epoch
+    pub since: u64,
+}
+
+impl Status {
+    /// The project of `nix_file` is in `state` as of now.
+    pub fn now(nix_file: &Path, state: State) -> Status {
+        Status {
+            version: VERSION,
+            nix_file: nix_file.to_owned(),
+            status: state,
+            since: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        }
+    }
+}
+
+/// What `lorri status` prints for a project in `status`, whose files
+/// last changed at `last_change`: `untracked` if it was never built,
+/// `stale` if its files changed after the last build finished.
+pub fn describe(status: Option<&Status>, last_change: Option<SystemTime>) -> &'static str {
+    match status {
+        None => "untracked",
+        Some(status) => {
+            // `since` is in whole seconds
+            let changed_after = last_change.map_or(false, |changed| {
+                changed
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(false, |changed| changed.as_secs() > status.since)
+            });
+            // the daemon would be building it if it saw the change
+            if changed_after && status.status != State::Building {
+                "stale"
+            } else {
+                status.status.name()
+            }
+        }
+    }
+}
+
+/// Replace the status file at `path`, so that readers never see half of it.
+pub fn write(path: &Path, status: &Status) -> io::Result<()> {
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let mut tmp = tempfile::NamedTempFile::new_in(dir)?;
+    serde_json::to_writer(&mut tmp, status)?;
+    tmp.write_all(b"\n")?;
+    tmp.persist(path).map_err(|e| e.error)?;
+    Ok(())
+}
+
+/// The status in `path`, `None` if the project was never built by the daemon.
+pub fn read(path: &Path) -> io::Result<Option<Status>> {
+    match std::fs::read(path) {
+        Ok(contents) => Ok(Some(serde_json::from_slice(&contents)?)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_file_format() -> io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let path = tmp.path().join("status.json");
+        assert_eq!(read(&path)?, None);
+        let status = Status {
+            version: VERSION,
+            nix_file: PathBuf::from("/project/shell.nix"),
+            status: State::Building,
+            since: 1_600_000_000,
+        };
+        write(&path, &status)?;
+        // prompts rely on this
+        assert_eq!(
+            std::fs::read_to_string(&path)?,
+            "{\"version\":1,\"nix_file\":\"/project/shell.nix\",\"status\":\"building\",\"since\":1600000000}\n"
+        );
+        assert_eq!(read(&path)?, Some(status));
+        Ok(())
+    }
+
+    #[test]
+    fn describe_status() {
+        use std::time::Duration;
+        let status = |state| Status {
+            version: VERSION,
+            nix_file: PathBuf::from("/project/shell.nix"),
+            status: state,
+            since: 1000,
+        };
+        let at = |secs| Some(UNIX_EPOCH + Duration::from_secs(secs));
+        assert_eq!(describe(None, at(0)), "untracked");
+        assert_eq!(describe(Some(&status(State::Success)), at(999)), "success");
+        assert_eq!(describe(Some(&status(State::Failure)), None), "failure");
+        assert_eq!(describe(Some(&status(State::Success)), at(1001)), "stale");
+        assert_eq!(
+            describe(Some(&status(State::Building)), at(1001)),
+            "building"
+        );
+    }
+}
@@ -1,4 +1,47 @@
 //! Modules to set up communication between client and server over a unix socket.
-pub mod communicate;
-pub mod path;
-pub mod read_writer;
+//!
+//! They live in the `lorri-client` crate, so that other tools can talk to the daemon.
+pub use lorri_client::{communicate, path, read_writer};
+
+use crate::ops::error::{ExitAs, ExitErrorType};
+use communicate::client::{Error, InitError};
+use path::BindError;
+
+impl ExitAs for BindError {
+    fn exit_as(&self) -> ExitErrorType {
+        use BindError::*;
+        use ExitErrorType::*;
+        match self {
+            OtherProcessListening { .. } => UserError,
+            OtherProcessNotListening { .. } => UserError,
+            Io(_) => Temporary,
+            Unix(_) => Temporary,
+        }
+    }
+}
+
+impl ExitAs for Error {
+    fn exit_as(&self) -> ExitErrorType {
+        use Error::*;
+        match self {
+            // This should really never happen.
+            NotConnected => ExitErrorType::Panic,
+            Message(_) => ExitErrorType::Temporary,
+        }
+    }
+}
+
+impl ExitAs for InitError {
+    fn exit_as(&self) -> ExitErrorType {
+        use InitError::*;
+        match self {
+            SocketConnect(_, _) => ExitErrorType::Temporary,
+            StaleSocket(_) => ExitErrorType::Temporary,
+            ForeignSocket(_, _) => ExitErrorType::EnvironmentProblem,
+            ServerHandshake(_) => ExitErrorType::Temporary,
+            OutdatedDaemon | DaemonTooOld { .. } | DaemonTooNew { .. } | Unsupported(_) => {
+                ExitErrorType::UserError
+            }
+        }
+    }
+}
@@ -1,60 +1,196 @@
 //! Recursively watch paths for changes, in an extensible and
 //! cross-platform way.
 
+mod ignore;
+
 use crossbeam_channel as chan;
-use notify::event::ModifyKind;
-use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
-use slog::{debug, info};
-use std::collections::HashSet;
+use ignore::Ignores;
+use notify::event::{ModifyKind, RenameMode};
+use notify::{EventKind, PollWatcher, RecommendedWatcher, RecursiveMode, Watcher};
+use slog::{debug, info, warn};
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
-/// Represents if a path to watch should be watched recursively by the watcher or not
-#[derive(Debug, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
-pub enum WatchPathBuf {
-    /// This path should be watched recursively. Equivalent to Normal for non-directory.
-    Recursive(PathBuf),
-    /// This path should not be watched recursively. For directories, only the list of files is
-    /// watched.
-    Normal(PathBuf),
+pub use lorri_client::watch::{WatchPathBuf, WatchReason, WatchedPath};
+
+/// A dynamic list of paths to watch for changes, and
+/// react to changes when they occur.
+pub struct Watch {
+    /// Event receiver. Process using `Watch::process`.
+    pub rx: chan::Receiver<notify::Result<notify::Event>>,
+    notify: Notifier,
+    watches: HashSet<PathBuf>,
+    /// The ignore rules of the recursively watched directories.
+    ignores: Vec<Ignores>,
+    /// The files the evaluation read, which are watched even if they are
+    /// below a recursively watched directory which ignores them.
+    /// See `set_read_files`.
+    read_files: HashSet<PathBuf>,
+    /// Whether to skip paths ignored by the `.gitignore` and `.ignore`
+    /// files of recursively watched directories.
+    pub respect_ignore_files: bool,
+    /// Paths which are never watched recursively and whose changes are skipped,
+    /// unless they are watched explicitly. See `set_excludes`.
+    excludes: Option<Ignores>,
+    /// If set, paths which can’t be watched because the file system’s
+    /// watch limit is reached are polled in this interval instead.
+    pub poll_fallback: Option<Duration>,
+    /// Polls the paths `notify` can’t watch, see `poll_fallback`.
+    fallback: Option<PollWatcher>,
+    /// Whether the watch limit was reached since `take_watch_limit_reached`.
+    watch_limit_reached: bool,
+    /// Sending end of `rx`, for the `fallback`.
+    tx: chan::Sender<notify::Result<notify::Event>>,
+    logger: slog::Logger,
+}
+
+/// How a `Watch` learns about changes.
+enum Notifier {
+    /// The file system tells us (e.g. inotify on Linux).
+    Native(RecommendedWatcher),
+    /// We look at the watched paths in an interval,
+    /// for file systems which don’t report changes (e.g. NFS or Docker bind mounts).
+    Poll(PollWatcher),
+    /// The file system tells a `SharedWatcher`, which passes it on.
+    Shared(SharedRegistration),
 }
 
-impl AsRef<Path> for WatchPathBuf {
-    fn as_ref(&self) -> &Path {
+impl Notifier {
+    fn watch(&mut self, path: &Path, mode: RecursiveMode) -> Result<(), notify::Error> {
         match self {
-            WatchPathBuf::Recursive(path) => path.as_ref(),
-            WatchPathBuf::Normal(path) => path.as_ref(),
+            Notifier::Native(watcher) => watcher.watch(path, mode),
+            Notifier::Poll(watcher) => watcher.watch(path, mode),
+            Notifier::Shared(registration) => registration.watch(path, mode),
         }
     }
 }
 
-impl AsMut<PathBuf> for WatchPathBuf {
-    fn as_mut(&mut self) -> &mut PathBuf {
-        match self {
-            WatchPathBuf::Recursive(ref mut path) => path,
-            WatchPathBuf::Normal(ref mut path) => path,
+type EventSender = chan::Sender<notify::Result<notify::Event>>;
+
+/// One file system watcher for several `Watch`es (the daemon’s projects),
+/// so that the paths they have in common, like the files shared by the
+/// projects of a workspace, are watched once.
+///
+/// A path stays watched as long as one `Watch` watches it.
+/// Every `Watch` gets all events, and picks the ones for its own paths.
+#[derive(Clone)]
+pub struct SharedWatcher(Arc<Mutex<SharedState>>);
+
+struct SharedState {
+    watcher: RecommendedWatcher,
+    /// How many `Watch`es watch each path.
+    counts: HashMap<PathBuf, usize>,
+    /// The `Watch`es the events are passed on to.
+    subscribers: Arc<Mutex<Vec<EventSender>>>,
+}
+
+impl SharedWatcher {
+    /// Start the file system watcher. Its events are passed on by a thread,
+    /// which stops once all clones of the `SharedWatcher` are dropped.
+    pub fn try_new() -> Result<SharedWatcher, notify::Error> {
+        let (tx, rx) = chan::unbounded();
+        let watcher = Watcher::new(tx, Duration::from_millis(100))?;
+        let subscribers: Arc<Mutex<Vec<EventSender>>> = Arc::new(Mutex::new(vec![]));
+        let passed_on = subscribers.clone();
+        std::thread::spawn(move || {
+            for event in rx {
+                passed_on
+                    .lock()
+                    .expect("subscribers of the shared watcher poisoned")
+                    // a `Watch` which is dropped doesn’t receive anymore
+                    .retain(|tx: &EventSender| tx.send(copy_event(&event)).is_ok());
+            }
+        });
+        Ok(SharedWatcher(Arc::new(Mutex::new(SharedState {
+            watcher,
+            counts: HashMap::new(),
+            subscribers,
+        }))))
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, SharedState> {
+        self.0.lock().expect("shared watcher poisoned")
+    }
+
+    /// Pass all events on to `tx`.
+    fn subscribe(&self, tx: EventSender) {
+        self.lock()
+            .subscribers
+            .lock()
+            .expect("subscribers of the shared watcher poisoned")
+            .push(tx)
+    }
+
+    /// Watch `path` for one more `Watch`.
+    fn register(&self, path: &Path, mode: RecursiveMode) -> Result<(), notify::Error> {
+        let mut state = self.lock();
+        if let Some(count) = state.counts.get_mut(path) {
+            *count += 1;
+            return Ok(());
         }
+        state.watcher.watch(path, mode)?;
+        state.counts.insert(path.to_owned(), 1);
+        Ok(())
+    }
+
+    /// Stop watching `path` for one `Watch`, and at all if it was the last.
+    fn release(&self, path: &Path) {
+        let mut state = self.lock();
+        let last = match state.counts.get_mut(path) {
+            Some(count) => {
+                *count -= 1;
+                *count == 0
+            }
+            None => return,
+        };
+        if last {
+            state.counts.remove(path);
+            // it might be gone already
+            let _ = state.watcher.unwatch(path);
+        }
+    }
+
+    /// How many `Watch`es watch `path`.
+    #[cfg(test)]
+    fn count(&self, path: &Path) -> usize {
+        self.lock().counts.get(path).copied().unwrap_or(0)
     }
 }
 
-impl WatchPathBuf {
-    /// Create a new WatchPathBuf of the same variant, but with this PathBuf instead.
-    pub fn replace(&self, path: PathBuf) -> WatchPathBuf {
-        match self {
-            WatchPathBuf::Normal(_) => WatchPathBuf::Normal(path),
-            WatchPathBuf::Recursive(_) => WatchPathBuf::Recursive(path),
+/// Every subscriber gets its own copy of an event.
+fn copy_event(event: &notify::Result<notify::Event>) -> notify::Result<notify::Event> {
+    match event {
+        Ok(event) => Ok(event.clone()),
+        Err(err) => Err(notify::Error::generic(&err.to_string())),
+    }
+}
+
+/// The paths one `Watch` watches with a `SharedWatcher`,
+/// which are released when it is dropped.
+struct SharedRegistration {
+    shared: SharedWatcher,
+    paths: HashSet<PathBuf>,
+}
+
+impl SharedRegistration {
+    fn watch(&mut self, path: &Path, mode: RecursiveMode) -> Result<(), notify::Error> {
+        if self.paths.contains(path) {
+            return Ok(());
         }
+        self.shared.register(path, mode)?;
+        self.paths.insert(path.to_owned());
+        Ok(())
     }
 }
 
-/// A dynamic list of paths to watch for changes, and
-/// react to changes when they occur.
-pub struct Watch {
-    /// Event receiver. Process using `Watch::process`.
-    pub rx: chan::Receiver<notify::Result<notify::Event>>,
-    notify: RecommendedWatcher,
-    watches: HashSet<PathBuf>,
-    logger: slog::Logger,
+impl Drop for SharedRegistration {
+    fn drop(&mut self) {
+        for path in &self.paths {
+            self.shared.release(path)
+        }
+    }
 }
 
 /// A debug message string that can only be displayed via `Debug`.
@@ -71,13 +207,58 @@ impl Watch {
     /// Instantiate a new Watch.
     pub fn try_new(logger: slog::Logger) -> Result<Watch, notify::Error> {
         let (tx, rx) = chan::unbounded();
+        let notify = Notifier::Native(Watcher::new(tx.clone(), Duration::from_millis(100))?);
+        Ok(Watch::with_notifier(notify, tx, rx, logger))
+    }
+
+    /// Instantiate a new Watch which checks the watched paths for changes
+    /// every `interval`, instead of relying on the file system to report them.
+    pub fn try_new_polling(
+        interval: Duration,
+        logger: slog::Logger,
+    ) -> Result<Watch, notify::Error> {
+        let (tx, rx) = chan::unbounded();
+        let notify = Notifier::Poll(Watcher::new(tx.clone(), interval)?);
+        Ok(Watch::with_notifier(notify, tx, rx, logger))
+    }
+
+    /// Instantiate a new Watch which watches its paths with `shared`.
+    pub fn new_shared(shared: &SharedWatcher, logger: slog::Logger) -> Watch {
+        let (tx, rx) = chan::unbounded();
+        shared.subscribe(tx.clone());
+        let notify = Notifier::Shared(SharedRegistration {
+            shared: shared.clone(),
+            paths: HashSet::new(),
+        });
+        Watch::with_notifier(notify, tx, rx, logger)
+    }
 
-        Ok(Watch {
-            notify: Watcher::new(tx, Duration::from_millis(100))?,
+    fn with_notifier(
+        notify: Notifier,
+        tx: chan::Sender<notify::Result<notify::Event>>,
+        rx: chan::Receiver<notify::Result<notify::Event>>,
+        logger: slog::Logger,
+    ) -> Watch {
+        Watch {
+            notify,
             watches: HashSet::new(),
+            ignores: vec![],
+            read_files: HashSet::new(),
+            respect_ignore_files: true,
+            excludes: None,
+            poll_fallback: None,
+            fallback: None,
+            watch_limit_reached: false,
+            tx,
             rx,
             logger,
-        })
+        }
+    }
+
+    /// Whether some paths couldn’t be watched (or are polled instead)
+    /// because the file system’s watch limit was reached since the last call.
+    pub fn take_watch_limit_reached(&mut self) -> bool {
+        std::mem::replace(&mut self.watch_limit_reached, false)
     }
 
     /// Process `notify::Event`s coming in via `Watch::rx`.
@@ -91,9 +272,13 @@ impl Watch {
             Ok(event) => {
                 self.log_event(&event);
                 let notify::Event { paths, kind, .. } = event;
-                let interesting_paths: Vec<PathBuf> = paths
+                let interesting_paths: Vec<PathBuf> = self
+                    .reduce_rename(paths, &kind)
                     .into_iter()
+                    .filter(|p| !self.is_editor_temp_file(p))
                     .filter(|p| Self::path_is_interesting(&self.watches, p, &kind, &self.logger))
+                    .filter(|p| !self.is_ignored(p))
+                    .filter(|p| !self.is_excluded(p))
                     .collect();
                 match interesting_paths.is_empty() {
                     true => None,
@@ -103,17 +288,76 @@ impl Watch {
         }
     }
 
+    /// Editors save atomically by writing a temporary file and renaming it
+    /// over the original, so a rename within a directory is a change of its
+    /// destination. Its source only changes if it is watched itself.
+    fn reduce_rename(&self, paths: Vec<PathBuf>, kind: &EventKind) -> Vec<PathBuf> {
+        match (kind, paths.as_slice()) {
+            (EventKind::Modify(ModifyKind::Name(RenameMode::Both)), [from, to])
+                if from.parent() == to.parent() && !self.watches.contains(from) =>
+            {
+                debug!(self.logger, "reducing rename to its destination";
+                       "from" => from.to_str(), "to" => to.to_str());
+                vec![to.clone()]
+            }
+            _ => paths,
+        }
+    }
+
+    /// Whether `path` is a temporary or backup file of an editor
+    /// (which is only interesting if it is watched itself).
+    fn is_editor_temp_file(&self, path: &Path) -> bool {
+        let temp = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .map_or(false, is_editor_temp_file_name)
+            && !self.watches.contains(path);
+        if temp {
+            debug!(self.logger, "ignoring event for editor temporary file"; "path" => path.to_str());
+        }
+        temp
+    }
+
     /// Extend the watch list with an additional list of paths.
     /// Note: Watch maintains a list of already watched paths, and
     /// will not add duplicates.
     pub fn extend(&mut self, paths: Vec<WatchPathBuf>) -> Result<(), notify::Error> {
         for path in paths {
             let recursive_paths = match path {
-                WatchPathBuf::Recursive(path) => walk_path_topo(path)?,
+                WatchPathBuf::Recursive(path) => {
+                    let root = path.canonicalize().unwrap_or_else(|_| path.clone());
+                    let mut paths = self.walk_recursive(path)?;
+                    // the walk skipped them if they are ignored
+                    paths.extend(
+                        self.read_files
+                            .iter()
+                            .filter(|read| read.starts_with(&root))
+                            .cloned(),
+                    );
+                    paths
+                }
                 WatchPathBuf::Normal(path) => vec![path],
             };
             for p in recursive_paths {
-                let p = p.canonicalize()?;
+                let p = match p.canonicalize() {
+                    Ok(p) => p,
+                    // a file which doesn’t exist yet is noticed
+                    // when it’s created in its directory
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                        match p.parent().map(Path::canonicalize) {
+                            Some(Ok(parent)) => parent,
+                            _ => {
+                                debug!(
+                                    self.logger,
+                                    "Skipping watching {}: does not exist",
+                                    p.display()
+                                );
+                                continue;
+                            }
+                        }
+                    }
+                    Err(e) => return Err(e.into()),
+                };
                 match Self::extend_filter(p) {
                     Err(FilteredOut { reason, path }) => {
                         debug!(
@@ -132,6 +376,88 @@ impl Watch {
         Ok(())
     }
 
+    /// Remember the files the evaluation read, so that they are watched when
+    /// a directory above them is watched recursively (into which the paths
+    /// to watch are reduced) and its ignore files or excludes skip them.
+    pub fn set_read_files(&mut self, paths: &[WatchPathBuf]) {
+        self.read_files = paths
+            .iter()
+            .filter_map(|path| match path {
+                WatchPathBuf::Normal(path) => path.canonicalize().ok(),
+                WatchPathBuf::Recursive(_) => None,
+            })
+            .collect();
+    }
+
+    /// Exclude the paths matching `patterns` (in `.gitignore` syntax,
+    /// relative to `root`) from recursive watches, and skip their changes.
+    ///
+    /// Paths which are watched explicitly (e.g. because the evaluation
+    /// read them) are not affected.
+    pub fn set_excludes(&mut self, root: &Path, patterns: &[String]) {
+        let root = root.canonicalize().unwrap_or_else(|_| root.to_owned());
+        let excludes = Ignores::parse(root, &patterns.join("\n"));
+        self.excludes = match excludes.is_empty() {
+            true => None,
+            false => Some(excludes),
+        };
+    }
+
+    /// List all paths to watch below `path`, skipping the
+    /// ones its ignore files ignore and the excluded ones.
+    fn walk_recursive(&mut self, path: PathBuf) -> Result<Vec<PathBuf>, std::io::Error> {
+        let ignores = match self.respect_ignore_files && path.is_dir() {
+            true => Ignores::read(&path.canonicalize()?)?,
+            false => None,
+        };
+        let excluded = |p: &Path, is_dir| {
+            self.excludes
+                .as_ref()
+                .map_or(false, |excludes| excludes.is_ignored(p, is_dir))
+        };
+        match ignores {
+            None => walk_path_topo(path, &excluded),
+            Some(ignores) => {
+                let paths = walk_path_topo(ignores.root().to_owned(), &|p, is_dir| {
+                    ignores.is_ignored(p, is_dir) || excluded(p, is_dir)
+                })?;
+                // the ignore files might have changed since we last read them
+                self.ignores.retain(|i| i.root() != ignores.root());
+                self.ignores.push(ignores);
+                Ok(paths)
+            }
+        }
+    }
+
+    /// Whether the ignore files of a watched directory ignore `path`,
+    /// and it is not watched explicitly (like `is_excluded`).
+    fn is_ignored(&self, path: &Path) -> bool {
+        let is_dir = path.is_dir();
+        let ignored = !self.watches.contains(path)
+            && self
+                .ignores
+                .iter()
+                .any(|ignores| ignores.is_ignored(path, is_dir));
+        if ignored {
+            debug!(self.logger, "ignoring event for ignored path"; "path" => path.to_str());
+        }
+        ignored
+    }
+
+    /// Whether `path` is excluded and not watched explicitly.
+    fn is_excluded(&self, path: &Path) -> bool {
+        let excluded = match &self.excludes {
+            Some(excludes) => {
+                !self.watches.contains(path) && excludes.is_ignored(path, path.is_dir())
+            }
+            None => false,
+        };
+        if excluded {
+            debug!(self.logger, "ignoring event for excluded path"; "path" => path.to_str());
+        }
+        excluded
+    }
+
     fn extend_filter(path: PathBuf) -> Result<PathBuf, FilteredOut<'static>> {
         if path.starts_with(Path::new("/nix/store")) {
             Err(FilteredOut {
@@ -159,7 +485,7 @@ impl Watch {
         if !self.watches.contains(&path) {
             debug!(self.logger, "watching path"; "path" => path.to_str());
 
-            self.notify.watch(&path, RecursiveMode::NonRecursive)?;
+            self.watch_path(&path)?;
             self.watches.insert(path.clone());
         }
 
@@ -167,13 +493,48 @@ impl Watch {
             if !self.watches.contains(parent) {
                 debug!(self.logger, "watching parent path"; "parent_path" => parent.to_str());
 
-                self.notify.watch(&parent, RecursiveMode::NonRecursive)?;
+                self.watch_path(parent)?;
             }
         }
 
         Ok(())
     }
 
+    /// Watch `path` with `notify`, or with the `fallback`
+    /// if the file system can’t watch any more paths.
+    fn watch_path(&mut self, path: &Path) -> Result<(), notify::Error> {
+        match self.notify.watch(path, RecursiveMode::NonRecursive) {
+            Err(err) if is_watch_limit(&err) => {
+                if !self.watch_limit_reached {
+                    warn!(
+                        self.logger,
+                        "reached the limit of watched files, changes to some files {}. \
+                         On Linux, raise the limit with `sysctl fs.inotify.max_user_watches=<number>`",
+                        match self.poll_fallback {
+                            Some(_) => "are polled for instead",
+                            None => "will be missed",
+                        };
+                        "path" => path.to_str()
+                    );
+                }
+                self.watch_limit_reached = true;
+                match self.poll_fallback {
+                    Some(interval) => {
+                        if self.fallback.is_none() {
+                            self.fallback = Some(Watcher::new(self.tx.clone(), interval)?);
+                        }
+                        self.fallback
+                            .as_mut()
+                            .expect("the fallback was just created")
+                            .watch(path, RecursiveMode::NonRecursive)
+                    }
+                    None => Ok(()),
+                }
+            }
+            res => res,
+        }
+    }
+
     fn path_is_interesting(
         watches: &HashSet<PathBuf>,
         path: &Path,
@@ -192,27 +553,61 @@ impl Watch {
                 // a Nix channel is updated we receive many other types of events, so
                 // ignoring these metadata modifications will not impact lorri's
                 // ability to correctly watch for channel changes.
-                EventKind::Modify(ModifyKind::Metadata(_)) => {
-                    if path.starts_with(Path::new("/nix/var/nix/profiles/per-user")) {
-                        debug!(logger, "ignoring spurious metadata change event within the profiles dir"; "path" => path.to_str());
-                        false
-                    } else {
-                        true
-                    }
+                EventKind::Modify(ModifyKind::Metadata(_))
+                    if path.starts_with(Path::new("/nix/var/nix/profiles/per-user")) =>
+                {
+                    debug!(logger, "ignoring spurious metadata change event within the profiles dir"; "path" => path.to_str());
+                    false
                 }
                 _ => true,
             }
     }
 }
 
+/// Whether `name` is the name of a file editors create while saving:
+/// vim’s swap files, the file it checks that it may write to and backups,
+/// emacs’ lock and auto-save files, and the temporary files of JetBrains IDEs.
+fn is_editor_temp_file_name(name: &str) -> bool {
+    let vim_swap = name.starts_with('.')
+        && [".swp", ".swo", ".swx"]
+            .iter()
+            .any(|suffix| name.ends_with(suffix));
+    vim_swap
+        || name == "4913"
+        || name.ends_with('~')
+        || name.starts_with(".#")
+        || (name.len() > 1 && name.starts_with('#') && name.ends_with('#'))
+        || name.ends_with("___jb_tmp___")
+        || name.ends_with("___jb_old___")
+}
+
+/// Whether `err` means that the file system can’t watch any more paths
+/// (inotify returns `ENOSPC` once `fs.inotify.max_user_watches` is reached).
+fn is_watch_limit(err: &notify::Error) -> bool {
+    match &err.kind {
+        notify::ErrorKind::Io(err) => {
+            err.raw_os_error() == Some(::nix::errno::Errno::ENOSPC as i32)
+        }
+        _ => false,
+    }
+}
+
 /// Lists the dirs and files in a directory, as two vectors.
 /// Given path must be a readable directory.
-fn list_dir(dir: &Path) -> Result<(Vec<PathBuf>, Vec<PathBuf>), std::io::Error> {
+/// Paths for which `ignored(path, is_dir)` is true are skipped.
+fn list_dir(
+    dir: &Path,
+    ignored: &dyn Fn(&Path, bool) -> bool,
+) -> Result<(Vec<PathBuf>, Vec<PathBuf>), std::io::Error> {
     let mut dirs = vec![];
     let mut files = vec![];
     for entry in std::fs::read_dir(dir)? {
         let entry = entry?;
-        if entry.file_type()?.is_dir() {
+        let is_dir = entry.file_type()?.is_dir();
+        if ignored(&entry.path(), is_dir) {
+            continue;
+        }
+        if is_dir {
             dirs.push(entry.path())
         } else {
             files.push(entry.path())
@@ -225,10 +620,14 @@ fn list_dir(dir: &Path) -> Result<(Vec<PathBuf>, Vec<PathBuf>), std::io::Error>
 /// Recurses into directories.
 ///
 /// Returns the given path first, then a topologically sorted list of children, if any.
+/// Children for which `ignored(path, is_dir)` is true are skipped (and not recursed into).
 ///
 /// All files have to be readable, or the function aborts.
 /// TODO: gracefully skip unreadable files.
-fn walk_path_topo(path: PathBuf) -> Result<Vec<PathBuf>, std::io::Error> {
+fn walk_path_topo(
+    path: PathBuf,
+    ignored: &dyn Fn(&Path, bool) -> bool,
+) -> Result<Vec<PathBuf>, std::io::Error> {
     // push our own path first
     let mut res = vec![path.clone()];
 
@@ -237,7 +636,7 @@ fn walk_path_topo(path: PathBuf) -> Result<Vec<PathBuf>, std::io::Error> {
         return Ok(res);
     }
 
-    let (dirs, mut files) = list_dir(&path)?;
+    let (dirs, mut files) = list_dir(&path, ignored)?;
     // plain files
     res.append(&mut files);
 
@@ -250,7 +649,7 @@ fn walk_path_topo(path: PathBuf) -> Result<Vec<PathBuf>, std::io::Error> {
             None => break,
             Some(dir) => {
                 res.push(dir.clone());
-                let (dirs, mut files) = list_dir(&dir)?;
+                let (dirs, mut files) = list_dir(&dir, ignored)?;
                 res.append(&mut files);
                 work.append(&mut std::collections::VecDeque::from(dirs));
             }
@@ -313,7 +712,9 @@ fn path_match(watched_paths: &HashSet<PathBuf>, event_path: &Path, logger: &slog
 
 #[cfg(test)]
 mod tests {
-    use super::{Watch, WatchPathBuf};
+    use super::{SharedWatcher, Watch, WatchPathBuf};
+    use notify::event::{ModifyKind, RenameMode};
+    use notify::EventKind;
     use std::ffi::OsStr;
     use std::path::PathBuf;
     use std::thread::sleep;
@@ -347,7 +748,7 @@ mod tests {
         S: AsRef<OsStr>,
     {
         let ret = std::process::Command::new("bash")
-            .args(&["-euc", command, "--"])
+            .args(["-euc", command, "--"])
             .args(args)
             .status()
             .expect("bash should start properly, regardless of exit code");
@@ -366,12 +767,12 @@ mod tests {
     #[test]
     #[should_panic]
     fn expect_bash_can_fail() {
-        expect_bash(r#"exit "$1""#, &["1"]);
+        expect_bash(r#"exit "$1""#, ["1"]);
     }
 
     #[test]
     fn expect_bash_can_pass() {
-        expect_bash(r#"exit "$1""#, &["0"]);
+        expect_bash(r#"exit "$1""#, ["0"]);
     }
 
     /// upper bound of watcher (if it’s hit, something is broken)
@@ -389,17 +790,13 @@ mod tests {
     fn file_changed(watch: &Watch, file_name: &str) -> (bool, Vec<PathBuf>) {
         let mut reasons = Vec::new();
         let mut changed = false;
-        for event in process_all(watch) {
-            if let Some(files) = event {
-                reasons.extend_from_slice(&files);
-                changed = changed
-                    || files
-                        .iter()
-                        .map(|p| p.file_name())
-                        .filter(|f| f.is_some())
-                        .map(|f| f.unwrap())
-                        .any(|f| f == file_name)
-            }
+        for files in process_all(watch).into_iter().flatten() {
+            reasons.extend_from_slice(&files);
+            changed = changed
+                || files
+                    .iter()
+                    .filter_map(|p| p.file_name())
+                    .any(|f| f == file_name)
         }
         (changed, reasons)
     }
@@ -451,17 +848,17 @@ mod tests {
             Watch::try_new(crate::logging::test_logger()).expect("failed creating Watch");
         let temp = tempdir().unwrap();
 
-        expect_bash(r#"mkdir -p "$1"/foo"#, &[temp.path().as_os_str()]);
-        expect_bash(r#"touch "$1"/foo/bar"#, &[temp.path().as_os_str()]);
+        expect_bash(r#"mkdir -p "$1"/foo"#, [temp.path().as_os_str()]);
+        expect_bash(r#"touch "$1"/foo/bar"#, [temp.path().as_os_str()]);
         watcher
             .extend(vec![WatchPathBuf::Recursive(temp.path().to_path_buf())])
             .unwrap();
 
-        expect_bash(r#"echo 1 > "$1/baz""#, &[temp.path().as_os_str()]);
+        expect_bash(r#"echo 1 > "$1/baz""#, [temp.path().as_os_str()]);
         sleep(upper_watcher_timeout());
         assert_file_changed(&watcher, "baz");
 
-        expect_bash(r#"echo 1 > "$1/foo/bar""#, &[temp.path().as_os_str()]);
+        expect_bash(r#"echo 1 > "$1/foo/bar""#, [temp.path().as_os_str()]);
         sleep(upper_watcher_timeout());
         assert_file_changed(&watcher, "bar");
     }
@@ -472,17 +869,17 @@ mod tests {
             Watch::try_new(crate::logging::test_logger()).expect("failed creating Watch");
         let temp = tempdir().unwrap();
 
-        expect_bash(r#"mkdir -p "$1"/foo"#, &[temp.path().as_os_str()]);
-        expect_bash(r#"touch "$1"/foo/bar"#, &[temp.path().as_os_str()]);
+        expect_bash(r#"mkdir -p "$1"/foo"#, [temp.path().as_os_str()]);
+        expect_bash(r#"touch "$1"/foo/bar"#, [temp.path().as_os_str()]);
         watcher
             .extend(vec![WatchPathBuf::Normal(temp.path().to_path_buf())])
             .unwrap();
 
-        expect_bash(r#"touch "$1/baz""#, &[temp.path().as_os_str()]);
+        expect_bash(r#"touch "$1/baz""#, [temp.path().as_os_str()]);
         sleep(upper_watcher_timeout());
         assert_file_changed(&watcher, "baz");
 
-        expect_bash(r#"echo 1 > "$1/foo/bar""#, &[temp.path().as_os_str()]);
+        expect_bash(r#"echo 1 > "$1/foo/bar""#, [temp.path().as_os_str()]);
         sleep(upper_watcher_timeout());
         assert!(no_changes(&watcher));
     }
@@ -493,14 +890,66 @@ mod tests {
             Watch::try_new(crate::logging::test_logger()).expect("failed creating Watch");
         let temp = tempdir().unwrap();
 
-        expect_bash(r#"mkdir -p "$1""#, &[temp.path().as_os_str()]);
-        expect_bash(r#"touch "$1/foo""#, &[temp.path().as_os_str()]);
+        expect_bash(r#"mkdir -p "$1""#, [temp.path().as_os_str()]);
+        expect_bash(r#"touch "$1/foo""#, [temp.path().as_os_str()]);
         watcher
             .extend(vec![WatchPathBuf::Recursive(temp.path().join("foo"))])
             .unwrap();
         macos_eat_late_notifications(&mut watcher);
 
-        expect_bash(r#"echo 1 > "$1/foo""#, &[temp.path().as_os_str()]);
+        expect_bash(r#"echo 1 > "$1/foo""#, [temp.path().as_os_str()]);
+        sleep(upper_watcher_timeout());
+        assert_file_changed(&watcher, "foo");
+    }
+
+    #[test]
+    fn shared_watch_specific_file() {
+        let shared = SharedWatcher::try_new().expect("failed creating SharedWatcher");
+        let mut first = Watch::new_shared(&shared, crate::logging::test_logger());
+        let mut second = Watch::new_shared(&shared, crate::logging::test_logger());
+        let temp = tempdir().unwrap();
+        let foo = temp.path().canonicalize().unwrap().join("foo");
+
+        expect_bash(r#"touch "$1/foo""#, [temp.path().as_os_str()]);
+        for watcher in &mut [&mut first, &mut second] {
+            watcher
+                .extend(vec![WatchPathBuf::Normal(foo.clone())])
+                .unwrap();
+            macos_eat_late_notifications(watcher);
+        }
+        assert_eq!(shared.count(&foo), 2);
+
+        expect_bash(r#"echo 1 > "$1/foo""#, [temp.path().as_os_str()]);
+        sleep(upper_watcher_timeout());
+        assert_file_changed(&first, "foo");
+        assert_file_changed(&second, "foo");
+
+        // the file stays watched for the other watch
+        drop(first);
+        assert_eq!(shared.count(&foo), 1);
+        expect_bash(r#"echo 2 > "$1/foo""#, [temp.path().as_os_str()]);
+        sleep(upper_watcher_timeout());
+        assert_file_changed(&second, "foo");
+
+        drop(second);
+        assert_eq!(shared.count(&foo), 0);
+    }
+
+    #[test]
+    fn polling_watch_specific_file() {
+        let mut watcher =
+            Watch::try_new_polling(Duration::from_millis(100), crate::logging::test_logger())
+                .expect("failed creating Watch");
+        let temp = tempdir().unwrap();
+
+        expect_bash(r#"touch "$1/foo""#, [temp.path().as_os_str()]);
+        watcher
+            .extend(vec![WatchPathBuf::Normal(temp.path().join("foo"))])
+            .unwrap();
+        sleep(upper_watcher_timeout());
+        process_all(&watcher);
+
+        expect_bash(r#"echo 1 > "$1/foo""#, [temp.path().as_os_str()]);
         sleep(upper_watcher_timeout());
         assert_file_changed(&watcher, "foo");
     }
@@ -512,30 +961,197 @@ mod tests {
             Watch::try_new(crate::logging::test_logger()).expect("failed creating Watch");
         let temp = tempdir().unwrap();
 
-        expect_bash(r#"mkdir -p "$1""#, &[temp.path().as_os_str()]);
-        expect_bash(r#"touch "$1/foo""#, &[temp.path().as_os_str()]);
+        expect_bash(r#"mkdir -p "$1""#, [temp.path().as_os_str()]);
+        expect_bash(r#"touch "$1/foo""#, [temp.path().as_os_str()]);
         watcher
             .extend(vec![WatchPathBuf::Recursive(temp.path().join("foo"))])
             .unwrap();
         macos_eat_late_notifications(&mut watcher);
 
         // bar is not watched, expect error
-        expect_bash(r#"echo 1 > "$1/bar""#, &[temp.path().as_os_str()]);
+        expect_bash(r#"echo 1 > "$1/bar""#, [temp.path().as_os_str()]);
         sleep(upper_watcher_timeout());
         assert!(no_changes(&watcher));
 
         // Rename bar to foo, expect a notification
-        expect_bash(r#"mv "$1/bar" "$1/foo""#, &[temp.path().as_os_str()]);
+        expect_bash(r#"mv "$1/bar" "$1/foo""#, [temp.path().as_os_str()]);
         sleep(upper_watcher_timeout());
         assert_file_changed(&watcher, "foo");
 
         // Do it a second time
-        expect_bash(r#"echo 1 > "$1/bar""#, &[temp.path().as_os_str()]);
+        expect_bash(r#"echo 1 > "$1/bar""#, [temp.path().as_os_str()]);
         sleep(upper_watcher_timeout());
         assert!(no_changes(&watcher));
 
         // Rename bar to foo, expect a notification
-        expect_bash(r#"mv "$1/bar" "$1/foo""#, &[temp.path().as_os_str()]);
+        expect_bash(r#"mv "$1/bar" "$1/foo""#, [temp.path().as_os_str()]);
+        sleep(upper_watcher_timeout());
+        assert_file_changed(&watcher, "foo");
+    }
+
+    #[test]
+    fn coalesce_atomic_saves() {
+        let mut watcher =
+            Watch::try_new(crate::logging::test_logger()).expect("failed creating Watch");
+        let temp = tempdir().unwrap();
+
+        expect_bash(r#"touch "$1/foo""#, [temp.path().as_os_str()]);
+        watcher
+            .extend(vec![WatchPathBuf::Normal(temp.path().to_path_buf())])
+            .unwrap();
+        macos_eat_late_notifications(&mut watcher);
+
+        // vim checks whether it can write to the directory, then saves via a backup
+        expect_bash(
+            r#"touch "$1/4913" && rm "$1/4913" && touch "$1/.foo.swp""#,
+            [temp.path().as_os_str()],
+        );
+        sleep(upper_watcher_timeout());
+        assert!(no_changes(&watcher));
+        expect_bash(
+            r#"echo 1 > "$1/foo~" && mv "$1/foo~" "$1/foo""#,
+            [temp.path().as_os_str()],
+        );
+        sleep(upper_watcher_timeout());
+        let (changed, reasons) = file_changed(&watcher, "foo");
+        assert!(changed);
+        assert!(
+            reasons
+                .iter()
+                .all(|p| p.file_name() == Some(OsStr::new("foo"))),
+            "{:?}",
+            reasons
+        );
+
+        // a rename is a change of its destination
+        let dir = temp.path().canonicalize().unwrap();
+        let rename = notify::Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::Both)))
+            .add_path(dir.join(".foo.tmp1234"))
+            .add_path(dir.join("foo"));
+        assert_eq!(watcher.process(Ok(rename)), Some(vec![dir.join("foo")]));
+    }
+
+    #[test]
+    fn skip_ignored_paths() {
+        let mut watcher =
+            Watch::try_new(crate::logging::test_logger()).expect("failed creating Watch");
+        let temp = tempdir().unwrap();
+
+        expect_bash(r#"mkdir -p "$1"/target/debug"#, [temp.path().as_os_str()]);
+        expect_bash(
+            r#"printf 'target/\n*.swp\n' > "$1"/.gitignore"#,
+            [temp.path().as_os_str()],
+        );
+        watcher
+            .extend(vec![WatchPathBuf::Recursive(temp.path().to_path_buf())])
+            .unwrap();
+        let root = temp.path().canonicalize().unwrap();
+        assert!(!watcher.watches.contains(&root.join("target/debug")));
+        macos_eat_late_notifications(&mut watcher);
+
+        expect_bash(r#"touch "$1"/.foo.swp"#, [temp.path().as_os_str()]);
+        expect_bash(
+            r#"touch "$1"/target/debug/lorri"#,
+            [temp.path().as_os_str()],
+        );
+        sleep(upper_watcher_timeout());
+        assert!(no_changes(&watcher));
+
+        expect_bash(r#"touch "$1"/foo"#, [temp.path().as_os_str()]);
+        sleep(upper_watcher_timeout());
+        assert_file_changed(&watcher, "foo");
+    }
+
+    #[test]
+    fn watch_ignored_paths_the_evaluation_read() {
+        let mut watcher =
+            Watch::try_new(crate::logging::test_logger()).expect("failed creating Watch");
+        let temp = tempdir().unwrap();
+
+        expect_bash(r#"mkdir -p "$1"/shared/nix"#, [temp.path().as_os_str()]);
+        expect_bash(
+            r#"printf 'shared/\n' > "$1"/.gitignore"#,
+            [temp.path().as_os_str()],
+        );
+        expect_bash(
+            r#"touch "$1"/shared/nix/common.nix"#,
+            [temp.path().as_os_str()],
+        );
+        watcher
+            .extend(vec![
+                WatchPathBuf::Recursive(temp.path().to_path_buf()),
+                WatchPathBuf::Normal(temp.path().join("shared/nix/common.nix")),
+            ])
+            .unwrap();
+        macos_eat_late_notifications(&mut watcher);
+
+        expect_bash(
+            r#"echo 1 > "$1"/shared/nix/common.nix"#,
+            [temp.path().as_os_str()],
+        );
+        sleep(upper_watcher_timeout());
+        assert_file_changed(&watcher, "common.nix");
+    }
+
+    #[test]
+    fn watch_ignored_paths_the_evaluation_read_below_a_recursive_watch() {
+        let mut watcher =
+            Watch::try_new(crate::logging::test_logger()).expect("failed creating Watch");
+        let temp = tempdir().unwrap();
+
+        // `src = ./.;` and `import ./local.nix`, which git ignores
+        expect_bash(
+            r#"printf 'local.nix\n' > "$1"/.gitignore"#,
+            [temp.path().as_os_str()],
+        );
+        expect_bash(r#"touch "$1"/local.nix"#, [temp.path().as_os_str()]);
+        let read = vec![
+            WatchPathBuf::Recursive(temp.path().to_path_buf()),
+            WatchPathBuf::Normal(temp.path().join("local.nix")),
+        ];
+        watcher.set_read_files(&read);
+        // the build loop only watches what the paths were reduced to
+        watcher
+            .extend(
+                crate::pathreduction::reduce_paths(&read)
+                    .into_iter()
+                    .collect(),
+            )
+            .unwrap();
+        macos_eat_late_notifications(&mut watcher);
+
+        expect_bash(r#"echo 1 > "$1"/local.nix"#, [temp.path().as_os_str()]);
+        sleep(upper_watcher_timeout());
+        assert_file_changed(&watcher, "local.nix");
+    }
+
+    #[test]
+    fn skip_excluded_paths() {
+        let mut watcher =
+            Watch::try_new(crate::logging::test_logger()).expect("failed creating Watch");
+        let temp = tempdir().unwrap();
+
+        expect_bash(r#"mkdir -p "$1"/target/debug"#, [temp.path().as_os_str()]);
+        watcher.set_excludes(
+            temp.path(),
+            &[String::from("target/"), String::from("result")],
+        );
+        watcher
+            .extend(vec![WatchPathBuf::Recursive(temp.path().to_path_buf())])
+            .unwrap();
+        let root = temp.path().canonicalize().unwrap();
+        assert!(!watcher.watches.contains(&root.join("target/debug")));
+        macos_eat_late_notifications(&mut watcher);
+
+        expect_bash(r#"ln -s /tmp "$1"/result"#, [temp.path().as_os_str()]);
+        expect_bash(
+            r#"touch "$1"/target/debug/lorri"#,
+            [temp.path().as_os_str()],
+        );
+        sleep(upper_watcher_timeout());
+        assert!(no_changes(&watcher));
+
+        expect_bash(r#"touch "$1"/foo"#, [temp.path().as_os_str()]);
         sleep(upper_watcher_timeout());
         assert_file_changed(&watcher, "foo");
     }
@@ -550,7 +1166,7 @@ mod tests {
             std::fs::write(temp.path().join(dir).join(file), [])?;
         }
 
-        let res = super::walk_path_topo(temp.path().to_owned())?;
+        let res = super::walk_path_topo(temp.path().to_owned(), &|_, _| false)?;
 
         // check that the list is topolocially sorted
         // by making sure *no* later path is a prefix of a previous path.
@@ -570,7 +1186,7 @@ mod tests {
         // paths as the original list.
         let mut res2 = res.clone();
         res2.sort();
-        let mut all_paths = vec![
+        let mut all_paths = [
             // our given path first
             "", "a", // direct files come before nested directories
             "a/b", "a/c", "x", "a/d", "a/d/e", "x/y", "x/y/z",
@@ -587,11 +1203,8 @@ mod tests {
     fn extend_filter() {
         let nix = PathBuf::from("/nix/store/njlavpa90laywf22b1myif5101qhln8r-hello-2.10");
         match super::Watch::extend_filter(nix.clone()) {
-            Ok(path) => assert!(false, "{:?} should be filtered!", path),
-            Err(super::FilteredOut { path, reason }) => {
-                drop(reason);
-                assert_eq!(path, nix)
-            }
+            Ok(path) => panic!("{:?} should be filtered!", path),
+            Err(super::FilteredOut { path, .. }) => assert_eq!(path, nix),
         }
 
         let other = PathBuf::from("/home/foo/project/foobar.nix");
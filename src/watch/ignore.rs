@@ -0,0 +1,233 @@
+//! Parse the `.gitignore` and `.ignore` files of watched directories,
+//! so that changes to editor caches and build artifacts don’t trigger builds.
+//!
+//! Supports the common subset of the gitignore syntax:
+//! comments, negation with `!`, directory-only patterns ending in `/`,
+//! anchored patterns containing a `/`, and `*`, `?`, `[…]` and `**`.
+
+use regex::Regex;
+use std::path::{Path, PathBuf};
+
+/// The files ignore rules are read from, in order of precedence.
+const IGNORE_FILES: &[&str] = &[".gitignore", ".ignore"];
+
+/// The ignore rules of a watched directory.
+#[derive(Debug)]
+pub struct Ignores {
+    root: PathBuf,
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug)]
+struct Rule {
+    /// Matches paths relative to the root.
+    regex: Regex,
+    /// The rule re-includes paths an earlier rule ignored.
+    negated: bool,
+    /// The rule only matches directories.
+    dir_only: bool,
+}
+
+impl Ignores {
+    /// Read the ignore files in `root`.
+    ///
+    /// `None` if there are no ignore files or they contain no rules.
+    pub fn read(root: &Path) -> std::io::Result<Option<Ignores>> {
+        let mut contents = String::new();
+        for file in IGNORE_FILES {
+            match std::fs::read_to_string(root.join(file)) {
+                Ok(c) => {
+                    contents.push_str(&c);
+                    contents.push('\n');
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let ignores = Ignores::parse(root.to_owned(), &contents);
+        Ok(match ignores.is_empty() {
+            true => None,
+            false => Some(ignores),
+        })
+    }
+
+    /// Parse the contents of ignore files in `root`.
+    /// Invalid patterns are skipped.
+    pub fn parse(root: PathBuf, contents: &str) -> Ignores {
+        Ignores {
+            root,
+            rules: contents.lines().filter_map(Rule::parse).collect(),
+        }
+    }
+
+    /// Whether there are no rules.
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// The directory the rules apply to.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Whether `path` is ignored.
+    ///
+    /// Like git, a path in an ignored directory is ignored as well,
+    /// even if a later rule re-includes it.
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        let relative = match path.strip_prefix(&self.root) {
+            Ok(relative) => relative,
+            Err(_) => return false,
+        };
+        let components: Vec<&str> = match relative
+            .components()
+            .map(|c| c.as_os_str().to_str())
+            .collect::<Option<_>>()
+        {
+            Some(components) => components,
+            // we can’t match non-unicode paths
+            None => return false,
+        };
+        (1..=components.len()).any(|len| {
+            let prefix = components[..len].join("/");
+            self.matches(&prefix, len < components.len() || is_dir)
+        })
+    }
+
+    /// Whether the last rule matching `relative` ignores it.
+    fn matches(&self, relative: &str, is_dir: bool) -> bool {
+        self.rules
+            .iter()
+            .rev()
+            .find(|rule| (is_dir || !rule.dir_only) && rule.regex.is_match(relative))
+            .map_or(false, |rule| !rule.negated)
+    }
+}
+
+impl Rule {
+    /// Parse one line of an ignore file, `None` if it is no rule.
+    fn parse(line: &str) -> Option<Rule> {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let (negated, pattern) = match line.strip_prefix('!') {
+            Some(pattern) => (true, pattern),
+            None => (false, line),
+        };
+        // `\!` and `\#` start patterns with a literal `!` or `#`
+        let pattern = pattern.strip_prefix('\\').unwrap_or(pattern);
+        let (dir_only, pattern) = match pattern.strip_suffix('/') {
+            Some(pattern) => (true, pattern),
+            None => (false, pattern),
+        };
+        // a pattern with a slash only matches relative to the root
+        let anchored = pattern.contains('/');
+        let pattern = pattern.strip_prefix('/').unwrap_or(pattern);
+        if pattern.is_empty() {
+            return None;
+        }
+
+        let mut regex = String::from(if anchored { "^" } else { "^(.*/)?" });
+        let components: Vec<&str> = pattern.split('/').collect();
+        for (i, component) in components.iter().enumerate() {
+            let last = i + 1 == components.len();
+            if *component == "**" {
+                regex.push_str(if last { ".*" } else { "(.*/)?" });
+            } else {
+                push_glob(&mut regex, component);
+                if !last {
+                    regex.push('/');
+                }
+            }
+        }
+        regex.push('$');
+
+        Some(Rule {
+            regex: Regex::new(&regex).ok()?,
+            negated,
+            dir_only,
+        })
+    }
+}
+
+/// Translate the glob `component` to a regex matching within one path component.
+fn push_glob(regex: &mut String, component: &str) {
+    let mut chars = component.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            '\\' => {
+                if let Some(c) = chars.next() {
+                    regex.push_str(&regex::escape(&c.to_string()))
+                }
+            }
+            '[' => match chars.as_str().find(']') {
+                Some(end) => {
+                    let class = &chars.as_str()[..end];
+                    regex.push('[');
+                    for (i, c) in class.chars().enumerate() {
+                        match c {
+                            '!' if i == 0 => regex.push('^'),
+                            '\\' | '[' | '&' | '~' => {
+                                regex.push('\\');
+                                regex.push(c)
+                            }
+                            c => regex.push(c),
+                        }
+                    }
+                    regex.push(']');
+                    chars = chars.as_str()[end + 1..].chars();
+                }
+                None => regex.push_str(r"\["),
+            },
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gitignore_rules() {
+        let ignores = Ignores::parse(
+            PathBuf::from("/project"),
+            r#"
+# editor caches
+*.sw[op]
+.#*
+/result*
+target/
+node_modules
+docs/**/*.html
+!docs/**/index.html
+\#notes
+"#,
+        );
+        let ignored = |path: &str, is_dir: bool| ignores.is_ignored(Path::new(path), is_dir);
+
+        assert!(ignored("/project/.shell.nix.swp", false));
+        assert!(ignored("/project/src/.main.rs.swo", false));
+        assert!(!ignored("/project/src/main.rs", false));
+        assert!(ignored("/project/src/.#main.rs", false));
+        assert!(ignored("/project/result-2", false));
+        // anchored to the root
+        assert!(!ignored("/project/src/result", false));
+        // only directories
+        assert!(ignored("/project/target", true));
+        assert!(ignored("/project/target/debug/lorri", false));
+        assert!(!ignored("/project/target", false));
+        assert!(ignored(
+            "/project/frontend/node_modules/x/package.json",
+            false
+        ));
+        assert!(ignored("/project/docs/a/b/page.html", false));
+        assert!(!ignored("/project/docs/a/index.html", false));
+        assert!(ignored("/project/#notes", false));
+        // outside of the root
+        assert!(!ignored("/other/result", false));
+    }
+}
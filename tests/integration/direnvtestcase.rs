@@ -5,6 +5,7 @@ use lorri::build_loop::BuildLoop;
 use lorri::builder;
 use lorri::builder::BuildError;
 use lorri::cas::ContentAddressable;
+use lorri::config::Config;
 use lorri::nix::options::NixOptions;
 use lorri::ops;
 use lorri::project;
@@ -55,18 +56,28 @@ impl DirenvTestCase {
         BuildLoop::new(
             &self.project,
             NixOptions::empty(),
-            project::Username::from_env_var().unwrap(),
+            None,
+            project::Username::current().unwrap(),
             self.logger.clone(),
         )
         .expect("could not set up build loop")
-        .once()
+        .once(None)
     }
 
     /// Run `direnv allow` and then `direnv export json`, and return
     /// the environment DirEnv would produce.
     pub fn get_direnv_variables(&self) -> DirenvEnv {
         let envrc = File::create(self.projectdir.path().join(".envrc")).unwrap();
-        ops::direnv(self.project.clone(), envrc, &self.logger).unwrap();
+        ops::direnv(
+            self.project.clone(),
+            envrc,
+            ops::DirenvShell::Bash,
+            false,
+            false,
+            &Config::default(),
+            &self.logger,
+        )
+        .unwrap();
 
         {
             let mut allow = self.direnv_cmd();
@@ -76,7 +87,7 @@ impl DirenvTestCase {
         }
 
         let mut env = self.direnv_cmd();
-        env.args(&["export", "json"]);
+        env.args(["export", "json"]);
         let result = env.output().expect("Failed to run direnv export json");
         if !result.status.success() {
             println!("stderr: {}", String::from_utf8_lossy(&result.stderr));
@@ -95,9 +106,9 @@ impl DirenvTestCase {
         d.env_remove("DIRENV_MTIME");
         d.env_remove("DIRENV_WATCHES");
         d.env_remove("DIRENV_DIFF");
-        d.env("DIRENV_CONFIG", &self.projectdir.path());
-        d.env("XDG_CONFIG_HOME", &self.projectdir.path());
-        d.current_dir(&self.projectdir.path());
+        d.env("DIRENV_CONFIG", self.projectdir.path());
+        d.env("XDG_CONFIG_HOME", self.projectdir.path());
+        d.current_dir(self.projectdir.path());
 
         d
     }
@@ -116,9 +127,9 @@ impl DirenvEnv {
     /// Makes asserts nicer, like:
     ///
     ///    assert!(env.get_env("foo"), Value("bar"));
-    pub fn get_env<'a, 'b>(&'a self, key: &'b str) -> DirenvValue {
+    pub fn get_env(&self, key: &str) -> DirenvValue<'_> {
         match self.0.get(key) {
-            Some(Some(val)) => DirenvValue::Value(&val),
+            Some(Some(val)) => DirenvValue::Value(val),
             Some(None) => DirenvValue::Unset,
             None => DirenvValue::NotSet,
         }
@@ -130,7 +141,7 @@ impl DirenvEnv {
         F: Fn(&str) -> bool,
     {
         let mut new = self.0.to_owned();
-        new.retain(|k, _| f(&k));
+        new.retain(|k, _| f(k));
         new
     }
 }
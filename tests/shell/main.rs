@@ -32,7 +32,7 @@ fn loads_env() {
 
     // Launch as a real user
     let res = Command::new(cargo_bin("lorri"))
-        .args(&[
+        .args([
             "shell",
             "--shell-file",
             project
@@ -49,9 +49,9 @@ fn loads_env() {
 
     let logger = lorri::logging::test_logger();
 
-    let output = ops::bash_cmd(build(&project, &logger), &project.cas, &logger)
+    let output = ops::bash_cmd(build(&project, &logger), &project, &logger)
         .unwrap()
-        .args(&["-c", "echo $MY_ENV_VAR"])
+        .args(["-c", "echo $MY_ENV_VAR"])
         .output()
         .expect("failed to run shell");
 
@@ -86,13 +86,14 @@ fn build(project: &Project, logger: &slog::Logger) -> PathBuf {
         .create_roots(
             builder::run(
                 &project.nix_file,
+                None,
                 &project.cas,
                 &NixOptions::empty(),
                 logger,
             )
             .unwrap()
             .result,
-            project::Username::from_env_var().unwrap(),
+            project::Username::current().unwrap(),
             logger,
         )
         .unwrap()